@@ -0,0 +1,103 @@
+//! Applies a sequence of filesystem operations decoded from the fuzz input to an in-process `ICFS` and checks the
+//! inode maps stay consistent after each of them. Run with `cargo +nightly fuzz run operations`.
+//!
+//! Every operation starts with an opcode byte followed by its arguments. Inodes are encoded as a single byte and
+//! names are picked from a small set so that operations frequently collide on the same entries.
+#![no_main]
+
+use std::ffi::OsString;
+use icfs::{Config, ICFS};
+use icfs::storage::{FileStorage, Overlay};
+use libfuzzer_sys::fuzz_target;
+
+struct Decoder<'a>{
+    data: &'a [u8],
+}
+impl Decoder<'_>{
+    fn byte(&mut self) -> Option<u8>{
+        let (first, rest) = self.data.split_first()?;
+        self.data = rest;
+        Some(*first)
+    }
+    fn u16(&mut self) -> Option<u16>{
+        Some(u16::from_le_bytes([self.byte()?, self.byte()?]))
+    }
+    fn inode(&mut self) -> Option<u64>{
+        Some(1 + (self.byte()? % 16) as u64)
+    }
+    fn name(&mut self) -> Option<OsString>{
+        Some(OsString::from((self.byte()? % 8).to_string()))
+    }
+    /// Permission bits including setuid, setgid and sticky.
+    fn mode(&mut self) -> Option<u32>{
+        Some(self.u16()? as u32 & 0o7777)
+    }
+    /// User or group picked from a few ids, root among them.
+    fn owner(&mut self) -> Option<u32>{
+        Some((self.byte()? % 3) as u32)
+    }
+    /// Creates either open existing files or fail on them with `O_EXCL`.
+    fn create_flags(&mut self) -> Option<i32>{
+        Some(if self.byte()? % 2 == 0{ 0 } else { libc::O_EXCL })
+    }
+    /// Any combination of `RENAME_NOREPLACE`, `RENAME_EXCHANGE` and `RENAME_WHITEOUT`, including the invalid ones.
+    fn rename_flags(&mut self) -> Option<u32>{
+        Some((self.byte()? % 8) as u32)
+    }
+}
+
+fn apply(filesystem: &mut ICFS, decoder: &mut Decoder) -> Option<()>{
+    match decoder.byte()? % 11{
+        0 => {
+            let _ = filesystem.do_create(decoder.inode()?, &decoder.name()?, decoder.mode()?, decoder.create_flags()?, decoder.owner()?, decoder.owner()?);
+        }
+        1 => {
+            let _ = filesystem.do_mkdir(decoder.inode()?, &decoder.name()?, decoder.mode()?, decoder.owner()?, decoder.owner()?);
+        }
+        2 => {
+            let inode = decoder.inode()?;
+            let offset = decoder.u16()?;
+            let data = vec![decoder.byte()?; decoder.byte()? as usize];
+            let _ = filesystem.do_write(inode, 0, offset as i64, &data);
+        }
+        3 => {
+            let _ = filesystem.do_read(decoder.inode()?, decoder.u16()? as i64, decoder.u16()? as u32);
+        }
+        4 => {
+            let _ = filesystem.do_unlink(decoder.inode()?, &decoder.name()?);
+        }
+        5 => {
+            let _ = filesystem.do_rmdir(decoder.inode()?, &decoder.name()?);
+        }
+        6 => {
+            let _ = filesystem.do_rename(decoder.inode()?, &decoder.name()?, decoder.inode()?, &decoder.name()?, decoder.rename_flags()?);
+        }
+        7 => {
+            let _ = filesystem.do_lookup(decoder.inode()?, &decoder.name()?);
+        }
+        8 => {
+            let _ = filesystem.do_readdir(decoder.inode()?);
+        }
+        9 => {
+            let inode = decoder.inode()?;
+            //the root is never forgotten by the kernel
+            if inode != 1{
+                filesystem.remove_inode(inode);
+            }
+        }
+        _ => {
+            let _ = filesystem.do_getattr(decoder.inode()?);
+        }
+    }
+    Some(())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut filesystem = ICFS::new(Overlay::new(FileStorage::new(), FileStorage::new()), Config::default());
+    let mut decoder = Decoder{ data };
+    while apply(&mut filesystem, &mut decoder).is_some(){
+        if let Err(error) = filesystem.check_consistency(){
+            panic!("{error}");
+        }
+    }
+});
@@ -0,0 +1,159 @@
+//! POSIX access control lists, kept in the `system.posix_acl_access` and `system.posix_acl_default` xattrs in the
+//! binary format the kernel uses for them.
+//!
+//! The access ACL of an entry replaces its permission bits when checking access. The default ACL of a directory is
+//! inherited by the entries created in it, as their access ACL and, for directories, as their default ACL as well.
+
+use std::str::FromStr;
+
+pub const ACCESS_XATTR: &str = "system.posix_acl_access";
+pub const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+const VERSION: u32 = 2;
+const TAG_USER_OBJ: u16 = 0x01;
+const TAG_USER: u16 = 0x02;
+const TAG_GROUP_OBJ: u16 = 0x04;
+const TAG_GROUP: u16 = 0x08;
+const TAG_MASK: u16 = 0x10;
+const TAG_OTHER: u16 = 0x20;
+const UNDEFINED_ID: u32 = u32::MAX;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Acl{
+    entries: Vec<AclEntry>,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AclEntry{
+    pub tag: AclTag,
+    /// Combination of the read (4), write (2) and execute (1) bits.
+    pub perm: u16,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AclTag{
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    /// Upper bound of the permissions granted by the named and group entries.
+    Mask,
+    Other,
+}
+impl Acl{
+    /// Parses the xattr representation of an ACL, returning `None` if it is malformed or incomplete.
+    pub fn from_xattr(value: &[u8]) -> Option<Self>{
+        let (header, mut rest) = value.split_at_checked(4)?;
+        if u32::from_le_bytes(header.try_into().ok()?) != VERSION{
+            return None;
+        }
+        let mut entries = Vec::new();
+        while !rest.is_empty(){
+            let (entry, remaining) = rest.split_at_checked(8)?;
+            rest = remaining;
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let perm = u16::from_le_bytes([entry[2], entry[3]]);
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            let tag = match tag{
+                TAG_USER_OBJ => AclTag::UserObj,
+                TAG_USER => AclTag::User(id),
+                TAG_GROUP_OBJ => AclTag::GroupObj,
+                TAG_GROUP => AclTag::Group(id),
+                TAG_MASK => AclTag::Mask,
+                TAG_OTHER => AclTag::Other,
+                _ => return None,
+            };
+            entries.push(AclEntry{ tag, perm });
+        }
+        Acl::new(entries)
+    }
+    /// Checks that the entries form a valid ACL, with one of each object entry and a mask if there are named ones.
+    pub fn new(entries: Vec<AclEntry>) -> Option<Self>{
+        let count = |matches: fn(&AclTag) -> bool| entries.iter().filter(|entry| matches(&entry.tag)).count();
+        let named = count(|tag| matches!(tag, AclTag::User(_) | AclTag::Group(_)));
+        let mask = count(|tag| *tag == AclTag::Mask);
+        let valid = entries.iter().all(|entry| entry.perm <= 0o7)
+            && count(|tag| *tag == AclTag::UserObj) == 1
+            && count(|tag| *tag == AclTag::GroupObj) == 1
+            && count(|tag| *tag == AclTag::Other) == 1
+            && (mask == 1 || (mask == 0 && named == 0));
+        valid.then_some(Acl{ entries })
+    }
+    pub fn to_xattr(&self) -> Vec<u8>{
+        let mut value = VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries{
+            let (tag, id) = match entry.tag{
+                AclTag::UserObj => (TAG_USER_OBJ, UNDEFINED_ID),
+                AclTag::User(id) => (TAG_USER, id),
+                AclTag::GroupObj => (TAG_GROUP_OBJ, UNDEFINED_ID),
+                AclTag::Group(id) => (TAG_GROUP, id),
+                AclTag::Mask => (TAG_MASK, UNDEFINED_ID),
+                AclTag::Other => (TAG_OTHER, UNDEFINED_ID),
+            };
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&entry.perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+    /// Permission bits of a file with this ACL, where the group bits show the mask if there is one.
+    pub fn mode(&self) -> u16{
+        let group = self.perm(AclTag::Mask).unwrap_or_else(|| self.perm(AclTag::GroupObj).unwrap_or(0));
+        self.perm(AclTag::UserObj).unwrap_or(0) << 6 | group << 3 | self.perm(AclTag::Other).unwrap_or(0)
+    }
+    /// Permission bits the ACL grants the user `uid` in the group `gid` on an entry owned by `owner` and `group`.
+    pub fn granted(&self, uid: u32, gid: u32, owner: u32, group: u32) -> u16{
+        let mask = self.perm(AclTag::Mask).unwrap_or(0o7);
+        if uid == owner{
+            return self.perm(AclTag::UserObj).unwrap_or(0);
+        }
+        if let Some(perm) = self.perm(AclTag::User(uid)){
+            return perm & mask;
+        }
+        let groups = self.entries.iter()
+            .filter(|entry| entry.tag == AclTag::Group(gid) || (gid == group && entry.tag == AclTag::GroupObj))
+            .map(|entry| entry.perm)
+            .collect::<Vec<_>>();
+        if !groups.is_empty(){
+            return groups.into_iter().fold(0, |granted, perm| granted | perm) & mask;
+        }
+        self.perm(AclTag::Other).unwrap_or(0)
+    }
+    fn perm(&self, tag: AclTag) -> Option<u16>{
+        self.entries.iter().find(|entry| entry.tag == tag).map(|entry| entry.perm)
+    }
+}
+/// Parses the short text form of `setfacl`, such as `u::rwx,u:1000:rw-,g::r-x,m::rwx,o::---`.
+impl FromStr for Acl{
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, String>{
+        let mut entries = Vec::new();
+        for entry in text.split(',').map(str::trim).filter(|entry| !entry.is_empty()){
+            let invalid = || format!("invalid ACL entry {entry:?}");
+            let mut fields = entry.split(':');
+            let (Some(kind), Some(qualifier), Some(perms), None) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+                return Err(invalid());
+            };
+            let id = || qualifier.parse::<u32>().map_err(|_| invalid());
+            let tag = match (kind, qualifier.is_empty()){
+                ("u" | "user", true) => AclTag::UserObj,
+                ("u" | "user", false) => AclTag::User(id()?),
+                ("g" | "group", true) => AclTag::GroupObj,
+                ("g" | "group", false) => AclTag::Group(id()?),
+                ("m" | "mask", true) => AclTag::Mask,
+                ("o" | "other", true) => AclTag::Other,
+                _ => return Err(invalid()),
+            };
+            let mut perm = 0;
+            for (bit, char) in perms.chars().enumerate(){
+                perm |= match (bit, char){
+                    (0, 'r') => 0o4,
+                    (1, 'w') => 0o2,
+                    (2, 'x') => 0o1,
+                    (0..=2, '-') => 0,
+                    _ => return Err(invalid()),
+                };
+            }
+            entries.push(AclEntry{ tag, perm });
+        }
+        Acl::new(entries).ok_or_else(|| format!("incomplete ACL {text:?}"))
+    }
+}
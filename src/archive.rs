@@ -0,0 +1,186 @@
+//! Conversion between a `FileStorage` and tar archives.
+//!
+//! Directories, regular files and symbolic links are imported and exported with their permission bits and
+//! modification time, while devices, fifos and other special entries are skipped. Hard links share the contents of the
+//! file they link to. Long names are supported through the GNU and PAX extensions understood by the `tar` crate.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use globset::GlobSet;
+use indexmap::map::Entry;
+use tar::{Archive, Builder, EntryType, Header};
+use crate::snapshot::invalid_data;
+use crate::storage::{is_excluded, FileStorage, FileStorageEntry, FileStoragePath, Metadata, Overlay};
+
+impl FileStorage{
+    /// Builds a storage from the entries of the tar archive at `path`. Parent directories missing from the archive
+    /// are created with default metadata. The first `strip` components of every path are dropped, along with the
+    /// entries that have no more components than that. Entries matching `exclude` are skipped together with everything
+    /// below them, as are hard links to skipped files.
+    pub fn import_tar(path: &Path, strip: usize, exclude: &GlobSet) -> io::Result<Self>{
+        let mut storage = FileStorage::new();
+        let mut archive = Archive::new(BufReader::new(File::open(path)?));
+        for entry in archive.entries()?{
+            let mut entry = entry?;
+            let parts = archive_path(&entry.path()?)?;
+            if strip > 0 && parts.len() <= strip{
+                continue;
+            }
+            let parts = &parts[strip.min(parts.len())..];
+            if is_excluded_below(exclude, parts){
+                continue;
+            }
+            let header = entry.header();
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(header.mtime()?);
+            let metadata = Metadata{
+                atime: mtime,
+                mtime,
+                ctime: mtime,
+                crtime: mtime,
+                perm: (header.mode()? & 0o7777) as u16,
+                ..Metadata::now()
+            };
+            let imported = match header.entry_type(){
+                EntryType::Directory => FileStorageEntry::Directory(Arc::default(), metadata),
+                EntryType::Regular | EntryType::Continuous => {
+                    let mut data = Vec::new();
+                    entry.read_to_end(&mut data)?;
+                    FileStorageEntry::File(data.into(), metadata)
+                }
+                EntryType::Symlink => {
+                    let target = entry.link_name()?.ok_or_else(|| invalid_data("symbolic link without a target"))?;
+                    FileStorageEntry::Symlink(target.into_owned().into_os_string(), metadata)
+                }
+                EntryType::Link => {
+                    let target = entry.link_name()?.ok_or_else(|| invalid_data("hard link without a target"))?;
+                    let target = archive_path(&target)?;
+                    let target = &target[strip.min(target.len())..];
+                    if is_excluded_below(exclude, target){
+                        continue;
+                    }
+                    match lookup(&storage.root, target){
+                        Some(file @ FileStorageEntry::File(..)) => file.clone(),
+                        _ => return Err(invalid_data("hard link to a missing file")),
+                    }
+                }
+                _ => continue,
+            };
+            insert(&mut storage, parts, imported)?;
+        }
+        Ok(storage)
+    }
+}
+impl Overlay{
+    /// Writes the merged tree as a tar archive to `writer`, parents before their children and siblings ordered by
+    /// name. Snapshots are left out.
+    pub fn export_tar(&self, writer: impl Write) -> io::Result<()>{
+        let mut builder = Builder::new(writer);
+        let mut pending = vec![FileStoragePath::root()];
+        while let Some(path) = pending.pop(){
+            let Some(entry) = self.lookup(&path) else {
+                continue;
+            };
+            let Some(metadata) = entry.metadata() else {
+                continue;
+            };
+            let mut header = Header::new_gnu();
+            header.set_mode(metadata.perm.into());
+            header.set_mtime(metadata.mtime.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |mtime| mtime.as_secs()));
+            header.set_size(0);
+            let name = path.to_path(Path::new(""));
+            match entry{
+                FileStorageEntry::Directory(..) => {
+                    header.set_entry_type(EntryType::Directory);
+                    //directories are conventionally named with a trailing slash
+                    let mut name = name.into_os_string();
+                    name.push(if path.depth() == 0 { "./" } else { "/" });
+                    builder.append_data(&mut header, &name, io::empty())?;
+                    let mut names = self.list(&path).unwrap_or_default();
+                    names.sort();
+                    //pushed in reverse so the children are popped in order
+                    for name in names.iter().rev(){
+                        let child = path.with_pushed(name);
+                        if !self.is_snapshot(&child){
+                            pending.push(child);
+                        }
+                    }
+                }
+                FileStorageEntry::File(data, _) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(data.len());
+                    builder.append_data(&mut header, &name, data.load()?.as_slice())?;
+                }
+                FileStorageEntry::Symlink(target, _) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    builder.append_link(&mut header, &name, Path::new(target))?;
+                }
+                FileStorageEntry::Whiteout => {}
+            }
+        }
+        builder.into_inner()?.flush()
+    }
+}
+
+/// Splits a path of an archive entry into its names, ignoring leading slashes and `.` components.
+fn archive_path(path: &Path) -> io::Result<Vec<OsString>>{
+    let mut parts = Vec::new();
+    for component in path.components(){
+        match component{
+            Component::Normal(name) => parts.push(name.to_os_string()),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return Err(invalid_data("archive entry escapes the root")),
+        }
+    }
+    Ok(parts)
+}
+/// Whether the archive entry at `parts` or one of the directories it is in matches `exclude`.
+fn is_excluded_below(exclude: &GlobSet, parts: &[OsString]) -> bool{
+    let mut path = PathBuf::new();
+    parts.iter().any(|part| {
+        path.push(part);
+        is_excluded(exclude, &path)
+    })
+}
+fn lookup<'a>(root: &'a FileStorageEntry, parts: &[OsString]) -> Option<&'a FileStorageEntry>{
+    let mut current = root;
+    for part in parts{
+        current = current.as_dir()?.get(part)?;
+    }
+    Some(current)
+}
+/// Places `entry` at `parts` in `storage`, creating missing parents. A directory replacing an existing one only
+/// updates its metadata, so entries listed before their directory are kept.
+fn insert(storage: &mut FileStorage, parts: &[OsString], entry: FileStorageEntry) -> io::Result<()>{
+    let Some((name, parents)) = parts.split_last() else {
+        //the archive root only carries the metadata of the root directory
+        if let (FileStorageEntry::Directory(_, metadata), FileStorageEntry::Directory(_, imported)) = (&mut storage.root, entry){
+            *metadata = imported;
+        }
+        return Ok(());
+    };
+    let parent = FileStoragePath::from_components(parents);
+    if storage.ensure_dirs(&parent).is_err(){
+        return Err(invalid_data("archive entry below a non-directory"));
+    }
+    let Some(directory) = storage.lookup_mut(&parent).and_then(FileStorageEntry::as_dir_mut) else {
+        return Err(invalid_data("archive entry below a non-directory"));
+    };
+    match (directory.entry(name.clone()), entry){
+        (Entry::Occupied(mut occupied), FileStorageEntry::Directory(_, imported)) if occupied.get().is_dir() => {
+            if let Some(metadata) = occupied.get_mut().metadata_mut(){
+                *metadata = imported;
+            }
+        }
+        (Entry::Occupied(mut occupied), entry) => {
+            occupied.insert(entry);
+        }
+        (Entry::Vacant(vacant), entry) => {
+            vacant.insert(entry);
+        }
+    }
+    Ok(())
+}
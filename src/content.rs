@@ -0,0 +1,407 @@
+//! Contents of regular files, held in memory or spilled to an anonymous temp file on the host.
+//!
+//! Contents spill once they grow past the configured threshold, after which reads and writes go to the temp file
+//! through `pread` and `pwrite`, so huge files don't have to fit in memory. The temp file is created with
+//! `O_TMPFILE` and disappears with the last copy of the contents.
+//!
+//! Files extended by truncating them to a larger size end in a hole that reads as zeroes without taking up memory.
+//! The hole is only filled in once a write lands in it, up to the end of that write.
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::ops::{Deref, Range};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::sync::Arc;
+
+/// Size of the chunks spilled contents are copied in.
+const COPY_CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub enum FileContent{
+    /// Shared between copies of the entry and only duplicated once one of them is modified.
+    Memory(Arc<Vec<u8>>),
+    /// In-memory contents followed by a hole of zeroes up to the total length.
+    Sparse(Arc<Vec<u8>>, u64),
+    /// Shared the same way, the temp file is copied before a shared one is modified.
+    Spilled(Arc<Spill>),
+}
+#[derive(Debug)]
+pub struct Spill{
+    file: File,
+    len: u64,
+}
+/// Bytes read from file contents.
+#[derive(Clone, Debug)]
+pub enum ReadData{
+    /// Range of an in-memory buffer, shared instead of copied.
+    Shared(Arc<Vec<u8>>, Range<usize>),
+    /// Bytes copied out of spilled contents or across the start of a hole.
+    Owned(Vec<u8>),
+}
+impl Deref for ReadData{
+    type Target = [u8];
+    fn deref(&self) -> &[u8]{
+        match self{
+            ReadData::Shared(data, range) => &data[range.clone()],
+            ReadData::Owned(data) => data,
+        }
+    }
+}
+impl Default for FileContent{
+    fn default() -> Self{
+        FileContent::Memory(Arc::default())
+    }
+}
+impl From<Vec<u8>> for FileContent{
+    fn from(data: Vec<u8>) -> Self{
+        FileContent::Memory(Arc::new(data))
+    }
+}
+impl FileContent{
+    pub fn len(&self) -> u64{
+        match self{
+            FileContent::Memory(data) => data.len() as u64,
+            FileContent::Sparse(_, len) => *len,
+            FileContent::Spilled(spill) => spill.len,
+        }
+    }
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+    pub fn is_spilled(&self) -> bool{
+        matches!(self, FileContent::Spilled(_))
+    }
+    /// Reads up to `size` bytes at `offset`, fewer at the end of the contents.
+    pub fn read(&self, offset: u64, size: usize) -> io::Result<Vec<u8>>{
+        let start = offset.min(self.len());
+        let end = offset.saturating_add(size as u64).min(self.len());
+        match self{
+            FileContent::Memory(data) => Ok(data[start as usize..end as usize].to_vec()),
+            FileContent::Sparse(data, _) => {
+                let mut read = data[(start as usize).min(data.len())..(end as usize).min(data.len())].to_vec();
+                read.resize((end - start) as usize, 0);
+                Ok(read)
+            }
+            FileContent::Spilled(spill) => {
+                let mut data = vec![0; (end - start) as usize];
+                spill.file.read_exact_at(&mut data, start)?;
+                Ok(data)
+            }
+        }
+    }
+    /// Reads like `read`, but shares the in-memory buffer when the range lies within it. The buffer is only copied
+    /// if the contents are modified while the returned data is still around.
+    pub fn read_shared(&self, offset: u64, size: usize) -> io::Result<ReadData>{
+        let start = offset.min(self.len()) as usize;
+        let end = offset.saturating_add(size as u64).min(self.len()) as usize;
+        match self{
+            FileContent::Memory(data) => Ok(ReadData::Shared(data.clone(), start..end)),
+            FileContent::Sparse(data, _) if end <= data.len() => Ok(ReadData::Shared(data.clone(), start..end)),
+            FileContent::Sparse(..) | FileContent::Spilled(_) => self.read(offset, size).map(ReadData::Owned),
+        }
+    }
+    /// Overwrites the contents at `offset` with `data`, extending them if it reaches past the end. A write starting
+    /// past the end leaves a hole in between, like `set_len` does. Running out of memory for in-memory contents fails
+    /// with `OutOfMemory` rather than aborting.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()>{
+        let end = offset + data.len() as u64;
+        if let FileContent::Memory(buffer) | FileContent::Sparse(buffer, _) = self{
+            //reserved up front, so running out of memory leaves the contents as they were
+            let missing = (end as usize).saturating_sub(buffer.len());
+            Arc::make_mut(buffer).try_reserve_exact(missing).map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        }
+        if offset > self.len(){
+            self.set_len(offset)?;
+        }
+        match self{
+            FileContent::Memory(buffer) | FileContent::Sparse(buffer, _) => {
+                let buffer = Arc::make_mut(buffer);
+                if end as usize > buffer.len(){
+                    buffer.resize(end as usize, 0);
+                }
+                buffer[offset as usize..end as usize].copy_from_slice(data);
+            }
+            FileContent::Spilled(spill) => {
+                let spill = unshare(spill)?;
+                spill.file.write_all_at(data, offset)?;
+                spill.len = spill.len.max(end);
+            }
+        }
+        //the hole is only filled in up to the end of the write, and disappears once a write reaches its end
+        if let FileContent::Sparse(buffer, len) = self{
+            if buffer.len() as u64 >= *len{
+                *self = FileContent::Memory(buffer.clone());
+            }
+        }
+        Ok(())
+    }
+    /// Asks the host to load `len` bytes of spilled contents at `offset` into its page cache ahead of a read. In-memory
+    /// contents are ready anyway.
+    pub fn prefetch(&self, offset: u64, len: u32){
+        if let FileContent::Spilled(spill) = self{
+            if len > 0 && offset < spill.len{
+                //the descriptor stays open while the spill is borrowed, and the advice is only a hint that reads don't
+                //depend on
+                unsafe{
+                    libc::posix_fadvise(spill.file.as_raw_fd(), offset as i64, len.into(), libc::POSIX_FADV_WILLNEED);
+                }
+            }
+        }
+    }
+    /// Truncates the contents or extends them with a hole to `len` bytes. Truncated buffers give back the memory they
+    /// no longer need.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()>{
+        if self.len() == len{
+            return Ok(());
+        }
+        match self{
+            FileContent::Memory(buffer) | FileContent::Sparse(buffer, _) => {
+                if len as usize <= buffer.len(){
+                    match Arc::get_mut(buffer){
+                        Some(owned) => {
+                            owned.truncate(len as usize);
+                            owned.shrink_to_fit();
+                        }
+                        //copying only the kept part spares copying the whole buffer just to truncate it
+                        None => *buffer = Arc::new(buffer[..len as usize].to_vec()),
+                    }
+                    *self = FileContent::Memory(buffer.clone());
+                } else {
+                    *self = FileContent::Sparse(buffer.clone(), len);
+                }
+            }
+            FileContent::Spilled(spill) => {
+                let spill = unshare(spill)?;
+                spill.file.set_len(len)?;
+                spill.len = len;
+            }
+        }
+        Ok(())
+    }
+    /// Overwrites the part of `range` within the contents with zeroes. Holes already read as zeroes and spilled
+    /// contents are zeroed in chunks, so neither is loaded into memory.
+    pub fn zero_range(&mut self, range: Range<u64>) -> io::Result<()>{
+        let end = range.end.min(self.len());
+        if range.start >= end{
+            return Ok(());
+        }
+        match self{
+            FileContent::Memory(buffer) | FileContent::Sparse(buffer, _) => {
+                let zeroed = (range.start as usize).min(buffer.len())..(end as usize).min(buffer.len());
+                if !zeroed.is_empty(){
+                    Arc::make_mut(buffer)[zeroed].fill(0);
+                }
+            }
+            FileContent::Spilled(spill) => {
+                let spill = unshare(spill)?;
+                let zeroes = vec![0; COPY_CHUNK];
+                let mut offset = range.start;
+                while offset < end{
+                    let chunk = (end - offset).min(COPY_CHUNK as u64) as usize;
+                    spill.file.write_all_at(&zeroes[..chunk], offset)?;
+                    offset += chunk as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Removes `range` from the contents, moving everything after it down. The range must end within the contents.
+    /// Spilled contents are moved in chunks, and only the part of a hole that was removed disappears.
+    pub fn collapse_range(&mut self, range: Range<u64>) -> io::Result<()>{
+        let len = self.len();
+        debug_assert!(range.start <= range.end && range.end <= len);
+        match self{
+            FileContent::Memory(buffer) => {
+                Arc::make_mut(buffer).drain(range.start as usize..range.end as usize);
+            }
+            FileContent::Sparse(buffer, sparse_len) => {
+                let removed = (range.start as usize).min(buffer.len())..(range.end as usize).min(buffer.len());
+                if !removed.is_empty(){
+                    Arc::make_mut(buffer).drain(removed);
+                }
+                *sparse_len -= range.end - range.start;
+                if buffer.len() as u64 >= *sparse_len{
+                    *self = FileContent::Memory(buffer.clone());
+                }
+            }
+            FileContent::Spilled(spill) => {
+                let spill = unshare(spill)?;
+                let mut chunk = vec![0; COPY_CHUNK];
+                let mut offset = range.end;
+                while offset < len{
+                    let size = (len - offset).min(COPY_CHUNK as u64) as usize;
+                    spill.file.read_exact_at(&mut chunk[..size], offset)?;
+                    spill.file.write_all_at(&chunk[..size], offset - (range.end - range.start))?;
+                    offset += size as u64;
+                }
+                spill.len = len - (range.end - range.start);
+                spill.file.set_len(spill.len)?;
+            }
+        }
+        Ok(())
+    }
+    /// Moves in-memory contents larger than `threshold` bytes to a temp file. A trailing hole stays a hole in the
+    /// temp file.
+    pub fn spill(&mut self, threshold: u64) -> io::Result<()>{
+        let len = self.len();
+        if let FileContent::Memory(data) | FileContent::Sparse(data, _) = self{
+            if len > threshold{
+                let file = temp_file()?;
+                file.write_all_at(data, 0)?;
+                file.set_len(len)?;
+                *self = FileContent::Spilled(Arc::new(Spill{ file, len }));
+            }
+        }
+        Ok(())
+    }
+    /// Returns the whole contents as a buffer, reading spilled contents from their temp file and filling in holes.
+    pub fn load(&self) -> io::Result<Arc<Vec<u8>>>{
+        match self{
+            FileContent::Memory(data) => Ok(data.clone()),
+            FileContent::Sparse(..) | FileContent::Spilled(_) => Ok(Arc::new(self.read(0, self.len() as usize)?)),
+        }
+    }
+    /// Writes the whole contents to `writer`, copying spilled contents and holes in chunks.
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()>{
+        match self{
+            FileContent::Memory(data) => writer.write_all(data),
+            FileContent::Sparse(..) | FileContent::Spilled(_) => {
+                let mut offset = 0;
+                while offset < self.len(){
+                    let chunk = self.read(offset, COPY_CHUNK)?;
+                    writer.write_all(&chunk)?;
+                    offset += chunk.len() as u64;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl PartialEq for FileContent{
+    /// Spilled contents are only compared by identity, as reading them back would defeat spilling.
+    fn eq(&self, other: &Self) -> bool{
+        match (self, other){
+            (FileContent::Memory(data), FileContent::Memory(other)) => data == other,
+            (FileContent::Sparse(data, len), FileContent::Sparse(other, other_len)) => len == other_len && data == other,
+            (FileContent::Spilled(spill), FileContent::Spilled(other)) => Arc::ptr_eq(spill, other),
+            _ => false,
+        }
+    }
+}
+
+/// Returns the spill for modification, copying its temp file first if other contents share it.
+fn unshare(spill: &mut Arc<Spill>) -> io::Result<&mut Spill>{
+    if Arc::get_mut(spill).is_none(){
+        let file = temp_file()?;
+        let mut offset = 0;
+        let mut chunk = vec![0; COPY_CHUNK];
+        while offset < spill.len{
+            let len = COPY_CHUNK.min((spill.len - offset) as usize);
+            spill.file.read_exact_at(&mut chunk[..len], offset)?;
+            file.write_all_at(&chunk[..len], offset)?;
+            offset += len as u64;
+        }
+        *spill = Arc::new(Spill{ file, len: spill.len });
+    }
+    Ok(Arc::get_mut(spill).expect("freshly copied spill is unshared"))
+}
+fn temp_file() -> io::Result<File>{
+    OpenOptions::new().read(true).write(true).mode(0o600).custom_flags(libc::O_TMPFILE).open(env::temp_dir())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn contents(content: &FileContent) -> Vec<u8>{
+        content.read(0, content.len() as usize).unwrap()
+    }
+
+    #[test]
+    fn writes_past_the_end_leave_a_hole(){
+        let mut memory = FileContent::from(b"ab".to_vec());
+        memory.write(4, b"cd").unwrap();
+        assert_eq!(contents(&memory), b"ab\0\0cd");
+        let mut sparse = FileContent::from(b"ab".to_vec());
+        sparse.set_len(4).unwrap();
+        sparse.write(6, b"cd").unwrap();
+        assert_eq!(contents(&sparse), b"ab\0\0\0\0cd");
+        let mut spilled = FileContent::from(b"ab".to_vec());
+        spilled.spill(0).unwrap();
+        assert!(matches!(spilled, FileContent::Spilled(_)));
+        spilled.write(4, b"cd").unwrap();
+        assert_eq!(contents(&spilled), b"ab\0\0cd");
+    }
+    #[test]
+    fn a_write_far_past_the_end_fails_without_aborting(){
+        let mut content = FileContent::default();
+        let error = content.write(u64::MAX / 4, b"x").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::OutOfMemory);
+        assert_eq!(content.len(), 0);
+    }
+    #[test]
+    fn range_operations_keep_the_representation(){
+        let mut memory = FileContent::from(b"abcdefgh".to_vec());
+        memory.zero_range(2..4).unwrap();
+        memory.collapse_range(0..1).unwrap();
+        assert_eq!(contents(&memory), b"b\0\0efgh");
+        let mut sparse = FileContent::from(b"abcd".to_vec());
+        sparse.set_len(1 << 40).unwrap();
+        sparse.zero_range(1..1 << 39).unwrap();
+        sparse.collapse_range(2..(1 << 40) - 2).unwrap();
+        assert!(matches!(&sparse, FileContent::Sparse(buffer, 4) if buffer.len() == 2));
+        assert_eq!(contents(&sparse), b"a\0\0\0");
+        let mut spilled = FileContent::from((0..200_000).map(|byte| byte as u8).collect::<Vec<_>>());
+        spilled.spill(0).unwrap();
+        spilled.zero_range(0..100_000).unwrap();
+        spilled.collapse_range(1..100_000).unwrap();
+        assert!(spilled.is_spilled());
+        let mut expected = vec![0];
+        expected.extend((100_000..200_000).map(|byte| byte as u8));
+        assert_eq!(contents(&spilled), expected);
+    }
+    #[test]
+    fn truncating_gives_back_the_memory_of_the_buffer(){
+        let mut content = FileContent::from(vec![1; 1 << 20]);
+        content.set_len(10).unwrap();
+        assert!(matches!(&content, FileContent::Memory(buffer) if buffer.capacity() < 1 << 10));
+        assert_eq!(contents(&content), [1; 10]);
+        //a shared buffer only copies the part that is kept
+        let shared = Arc::new(vec![2; 1 << 20]);
+        let mut content = FileContent::Memory(shared.clone());
+        content.set_len(10).unwrap();
+        assert!(matches!(&content, FileContent::Memory(buffer) if buffer.capacity() < 1 << 10 && !Arc::ptr_eq(buffer, &shared)));
+        assert_eq!(shared.len(), 1 << 20);
+        //truncating into the hole of sparse contents keeps the data before it
+        let mut sparse = FileContent::from(b"data".to_vec());
+        sparse.set_len(1 << 30).unwrap();
+        sparse.set_len(2).unwrap();
+        assert_eq!(contents(&sparse), b"da");
+    }
+    #[test]
+    fn shared_reads_match_copied_reads(){
+        let mut memory = FileContent::from(b"shared bytes".to_vec());
+        let mut sparse = FileContent::from(b"sparse".to_vec());
+        sparse.set_len(10).unwrap();
+        let mut spilled = FileContent::from(b"spilled".to_vec());
+        spilled.spill(0).unwrap();
+        for content in [&memory, &sparse, &spilled]{
+            for (offset, size) in [(0, 4), (2, 100), (5, 3), (100, 4)]{
+                assert_eq!(&*content.read_shared(offset, size).unwrap(), content.read(offset, size).unwrap());
+            }
+        }
+        let FileContent::Memory(buffer) = &memory else { unreachable!() };
+        assert!(matches!(memory.read_shared(0, 6).unwrap(), ReadData::Shared(data, _) if Arc::ptr_eq(&data, buffer)));
+        //only reads ending inside the buffer of sparse contents can share it
+        assert!(matches!(sparse.read_shared(0, 6).unwrap(), ReadData::Shared(..)));
+        assert!(matches!(sparse.read_shared(4, 4).unwrap(), ReadData::Owned(data) if data == b"se\0\0"));
+        assert!(matches!(spilled.read_shared(0, 4).unwrap(), ReadData::Owned(..)));
+        //data handed out stays as it was read while the contents change
+        let read = memory.read_shared(0, 6).unwrap();
+        memory.write(0, b"SHARED").unwrap();
+        assert_eq!(&*read, b"shared");
+        assert_eq!(contents(&memory), b"SHARED bytes");
+    }
+}
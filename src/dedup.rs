@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use sha2::{Digest, Sha256};
+
+/// Hash function contents are addressed by. Buffers with the same hash are still compared before being shared, so
+/// collisions only cost a comparison.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm{
+    #[default]
+    Xxh3,
+    Blake3,
+    Sha256,
+}
+impl HashAlgorithm{
+    /// Hash of `data`, the first 8 bytes of the digest for the cryptographic hashes.
+    pub fn hash(self, data: &[u8]) -> u64{
+        let prefix = |digest: &[u8]| u64::from_le_bytes(digest[..8].try_into().expect("digests are longer than 8 bytes"));
+        match self{
+            HashAlgorithm::Xxh3 => xxhash_rust::xxh3::xxh3_64(data),
+            HashAlgorithm::Blake3 => prefix(blake3::hash(data).as_bytes()),
+            HashAlgorithm::Sha256 => prefix(&Sha256::digest(data)),
+        }
+    }
+}
+impl FromStr for HashAlgorithm{
+    type Err = String;
+    fn from_str(name: &str) -> Result<Self, String>{
+        match name{
+            "xxh3" => Ok(HashAlgorithm::Xxh3),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => Err(format!("unknown hash algorithm {name}")),
+        }
+    }
+}
+
+/// Content-addressed table used to share the buffers of files with identical contents. Files keep owning their
+/// buffers through `Arc`s, so modifying a shared buffer copies it first and the other files stay unaffected.
+#[derive(Default)]
+pub struct ContentTable{
+    algorithm: HashAlgorithm,
+    contents: HashMap<u64, Vec<Weak<Vec<u8>>>>,
+}
+impl ContentTable{
+    pub fn new(algorithm: HashAlgorithm) -> Self{
+        ContentTable{ algorithm, contents: HashMap::new() }
+    }
+    pub fn algorithm(&self) -> HashAlgorithm{
+        self.algorithm
+    }
+    /// Replaces `content` with an already known buffer holding the same bytes, or records it for later files.
+    pub fn intern(&mut self, content: &mut Arc<Vec<u8>>){
+        let candidates = self.contents.entry(self.algorithm.hash(content)).or_default();
+        candidates.retain(|candidate| candidate.strong_count() > 0);
+        for candidate in candidates.iter(){
+            if let Some(candidate) = candidate.upgrade(){
+                if Arc::ptr_eq(&candidate, content){
+                    return;
+                }
+                if candidate == *content{
+                    *content = candidate;
+                    return;
+                }
+            }
+        }
+        candidates.push(Arc::downgrade(content));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn interning_shares_identical_buffers_only(){
+        let mut table = ContentTable::default();
+        let first = Arc::new(b"contents".to_vec());
+        let (mut same, mut other) = (Arc::new(b"contents".to_vec()), Arc::new(b"different".to_vec()));
+        table.intern(&mut first.clone());
+        table.intern(&mut same);
+        table.intern(&mut other);
+        assert!(Arc::ptr_eq(&first, &same));
+        assert_eq!(*other, b"different");
+        //a dropped buffer isn't handed out again
+        drop((first, same));
+        let mut again = Arc::new(b"contents".to_vec());
+        let copy = again.clone();
+        table.intern(&mut again);
+        assert!(Arc::ptr_eq(&again, &copy));
+    }
+    #[test]
+    fn every_algorithm_shares_identical_buffers_under_its_own_hash(){
+        for algorithm in [HashAlgorithm::Xxh3, HashAlgorithm::Blake3, HashAlgorithm::Sha256]{
+            let mut table = ContentTable::new(algorithm);
+            let (mut first, mut same) = (Arc::new(b"contents".to_vec()), Arc::new(b"contents".to_vec()));
+            table.intern(&mut first);
+            table.intern(&mut same);
+            assert!(Arc::ptr_eq(&first, &same), "{algorithm:?}");
+            assert_eq!(table.contents.keys().collect::<Vec<_>>(), [&algorithm.hash(b"contents")]);
+        }
+        //the digests of no bytes at all, little endian
+        assert_eq!(HashAlgorithm::Xxh3.hash(b""), 0x2d06_8005_38d3_94c2);
+        assert_eq!(HashAlgorithm::Blake3.hash(b""), 0xa6a1_f9f5_b949_13af);
+        assert_eq!(HashAlgorithm::Sha256.hash(b""), 0x141c_fc98_42c4_b0e3);
+    }
+    #[test]
+    fn algorithms_parse_from_their_names(){
+        assert_eq!("xxh3".parse(), Ok(HashAlgorithm::Xxh3));
+        assert_eq!("blake3".parse(), Ok(HashAlgorithm::Blake3));
+        assert_eq!("sha256".parse(), Ok(HashAlgorithm::Sha256));
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+}
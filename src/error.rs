@@ -0,0 +1,102 @@
+/// Failure of a filesystem operation, mapped to an errno only when replying to the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError{
+    NotFound,
+    NotADir,
+    IsADir,
+    Exists,
+    NotEmpty,
+    /// The operation would nest directories deeper than allowed.
+    TooDeep,
+    InvalidName,
+    NameTooLong,
+    BadHandle,
+    /// The handle table is full.
+    TooManyOpenFiles,
+    InvalidArgument,
+    /// The requested mode of an operation isn't implemented.
+    Unsupported,
+    NoAttribute,
+    /// The requesting user lacks the permission bits the operation needs.
+    PermissionDenied,
+    /// The operation isn't allowed on the entry for anyone.
+    NotPermitted,
+    /// The filesystem has no free space left.
+    NoSpace,
+    /// The owner of the file has used up their quota.
+    QuotaExceeded,
+    /// The entry belongs to a read-only part of the filesystem.
+    ReadOnly,
+    /// The operation would grow a file past the largest size it can have.
+    FileTooBig,
+    /// Resolving a path followed too many symbolic links.
+    Loop,
+    /// The operation would link or move an entry to another logical device.
+    CrossDevice,
+    /// Accessing the host failed.
+    Io,
+    /// An internal invariant was violated.
+    Internal,
+}
+impl FsError{
+    pub fn to_errno(self) -> i32{
+        match self{
+            FsError::NotFound => libc::ENOENT,
+            FsError::NotADir => libc::ENOTDIR,
+            FsError::IsADir => libc::EISDIR,
+            FsError::Exists => libc::EEXIST,
+            FsError::NotEmpty => libc::ENOTEMPTY,
+            FsError::TooDeep => libc::ENAMETOOLONG,
+            FsError::InvalidName => libc::EINVAL,
+            FsError::NameTooLong => libc::ENAMETOOLONG,
+            FsError::BadHandle => libc::EBADF,
+            FsError::TooManyOpenFiles => libc::EMFILE,
+            FsError::InvalidArgument => libc::EINVAL,
+            FsError::Unsupported => libc::EOPNOTSUPP,
+            FsError::NoAttribute => libc::ENODATA,
+            FsError::PermissionDenied => libc::EACCES,
+            FsError::NotPermitted => libc::EPERM,
+            FsError::NoSpace => libc::ENOSPC,
+            FsError::QuotaExceeded => libc::EDQUOT,
+            FsError::ReadOnly => libc::EROFS,
+            FsError::FileTooBig => libc::EFBIG,
+            FsError::Loop => libc::ELOOP,
+            FsError::CrossDevice => libc::EXDEV,
+            FsError::Io | FsError::Internal => libc::EIO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn errors_map_to_their_errno(){
+        for (error, errno) in [
+            (FsError::NotFound, libc::ENOENT),
+            (FsError::NotADir, libc::ENOTDIR),
+            (FsError::IsADir, libc::EISDIR),
+            (FsError::Exists, libc::EEXIST),
+            (FsError::NotEmpty, libc::ENOTEMPTY),
+            (FsError::TooDeep, libc::ENAMETOOLONG),
+            (FsError::InvalidName, libc::EINVAL),
+            (FsError::BadHandle, libc::EBADF),
+            (FsError::TooManyOpenFiles, libc::EMFILE),
+            (FsError::Unsupported, libc::EOPNOTSUPP),
+            (FsError::NoAttribute, libc::ENODATA),
+            (FsError::PermissionDenied, libc::EACCES),
+            (FsError::NotPermitted, libc::EPERM),
+            (FsError::NoSpace, libc::ENOSPC),
+            (FsError::QuotaExceeded, libc::EDQUOT),
+            (FsError::ReadOnly, libc::EROFS),
+            (FsError::FileTooBig, libc::EFBIG),
+            (FsError::Loop, libc::ELOOP),
+            (FsError::CrossDevice, libc::EXDEV),
+            (FsError::Io, libc::EIO),
+            (FsError::Internal, libc::EIO),
+        ]{
+            assert_eq!(error.to_errno(), errno, "{error:?}");
+        }
+    }
+}
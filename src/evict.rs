@@ -0,0 +1,124 @@
+//! Eviction of file contents to the host once the contents held in memory exceed the configured capacity.
+//!
+//! Every read and write records the access on the file's inode. When the contents of the accessed files grow past
+//! the capacity, the least recently accessed ones are written to a spill directory next to the backing file and
+//! replaced by empty buffers, until they are accessed again.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use log::error;
+use crate::ICFS;
+use crate::content::FileContent;
+use crate::error::FsError;
+use crate::storage::FileStorageEntry;
+
+impl ICFS{
+    /// Marks `ino` as the most recently accessed file and evicts other files if the capacity is exceeded.
+    pub(crate) fn record_access(&mut self, ino: u64){
+        if !self.config.evict{
+            return;
+        }
+        self.access_tick += 1;
+        self.accesses.insert(ino, self.access_tick);
+        self.enforce_capacity(ino);
+    }
+    /// Loads the contents of `ino` back into memory if they were evicted.
+    pub(crate) fn restore(&mut self, ino: u64) -> Result<(), FsError>{
+        let Some(len) = self.evicted.remove(&ino) else {
+            return Ok(());
+        };
+        let spill = self.spill_path(ino);
+        let data = match fs::read(&spill){
+            Ok(data) => data,
+            Err(error) => {
+                error!("failed to restore evicted contents from {}: {error}", spill.display());
+                self.evicted.insert(ino, len);
+                return Err(FsError::Io);
+            }
+        };
+        if let Some(content) = self.upper_content_mut(ino){
+            *content = Arc::new(data);
+        }
+        let _ = fs::remove_file(spill);
+        Ok(())
+    }
+    /// Forgets the evicted contents of `ino` along with their spill file, once the file they belong to is gone.
+    pub(crate) fn discard_evicted(&mut self, ino: u64){
+        if self.evicted.remove(&ino).is_some(){
+            let spill = self.spill_path(ino);
+            if let Err(error) = fs::remove_file(&spill){
+                error!("failed to remove evicted contents at {}: {error}", spill.display());
+            }
+        }
+    }
+    /// Loads all evicted contents back into memory, before the tree is saved.
+    pub(crate) fn restore_all(&mut self){
+        let inodes = self.evicted.keys().cloned().collect::<Vec<_>>();
+        for inode in inodes{
+            let _ = self.restore(inode);
+        }
+    }
+    /// Evicts the least recently accessed files other than `keep` until the resident contents fit the capacity.
+    fn enforce_capacity(&mut self, keep: u64){
+        let Some(capacity) = self.config.capacity else {
+            return;
+        };
+        let mut resident = Vec::new();
+        for (&inode, &tick) in &self.accesses{
+            //spilled contents already live on the host, and buffers take up their capacity rather than their length
+            if let Some(FileStorageEntry::File(FileContent::Memory(content), _)) = self.get_entry(inode){
+                if !self.evicted.contains_key(&inode){
+                    resident.push((tick, inode, content.capacity() as u64));
+                }
+            }
+        }
+        let mut total = resident.iter().map(|(_, _, len)| len).sum::<u64>();
+        resident.sort();
+        for (_, inode, len) in resident{
+            if total <= capacity{
+                break;
+            }
+            if inode == keep || len == 0{
+                continue;
+            }
+            match self.evict(inode){
+                Ok(true) => total -= len,
+                Ok(false) => {}
+                Err(error) => error!("failed to evict inode {inode}: {error}"),
+            }
+        }
+    }
+    /// Moves the contents of `ino` to its spill file, returning whether there was anything to evict. Files only
+    /// present in the lower layer stay resident as their contents are shared with it.
+    fn evict(&mut self, ino: u64) -> io::Result<bool>{
+        let spill = self.spill_path(ino);
+        let Some(content) = self.upper_content_mut(ino) else {
+            return Ok(false);
+        };
+        if let Some(directory) = spill.parent(){
+            fs::create_dir_all(directory)?;
+        }
+        fs::write(&spill, content.as_slice())?;
+        let len = content.len() as u64;
+        *content = Arc::default();
+        self.evicted.insert(ino, len);
+        Ok(true)
+    }
+    fn upper_content_mut(&mut self, ino: u64) -> Option<&mut Arc<Vec<u8>>>{
+        let entry = match self.inode_to_file.get(&ino){
+            Some(path) => self.files.lookup_upper_mut(path),
+            None => self.unlinked.get_mut(&ino),
+        };
+        match entry?.as_file_mut()?{
+            FileContent::Memory(content) => Some(content),
+            FileContent::Sparse(..) | FileContent::Spilled(..) => None,
+        }
+    }
+    pub(crate) fn spill_path(&self, ino: u64) -> PathBuf{
+        let mut directory = self.config.backing_file.clone().unwrap_or_default().into_os_string();
+        directory.push(".evicted");
+        PathBuf::from(directory).join(ino.to_string())
+    }
+}
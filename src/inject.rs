@@ -0,0 +1,132 @@
+//! Random failures of chosen FUSE operations, for testing how applications cope with errors.
+//!
+//! Failing operations are rejected before the filesystem handles them, so they change nothing and aren't traced.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::str::FromStr;
+
+/// Errors injected into the operations they name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorInjection{
+    rules: Vec<InjectionRule>,
+}
+#[derive(Clone, Debug, PartialEq)]
+struct InjectionRule{
+    operation: String,
+    errno: i32,
+    /// Chance of failing each call, between 0 and 1.
+    probability: f64,
+}
+impl ErrorInjection{
+    /// Returns the errno `operation` fails with this time, if any, drawing from `random` once per rule naming it.
+    pub fn errno(&self, operation: &str, random: &mut Random) -> Option<i32>{
+        self.rules.iter()
+            .filter(|rule| rule.operation == operation)
+            .find(|rule| random.next_f64() < rule.probability)
+            .map(|rule| rule.errno)
+    }
+}
+/// Parses comma separated `OPERATION:ERRNO:PROBABILITY` rules such as `read:EIO:0.01,write:ENOSPC:0.001`. Errnos
+/// are given by name or number.
+impl FromStr for ErrorInjection{
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, String>{
+        let mut rules = Vec::new();
+        for rule in text.split(',').map(str::trim).filter(|rule| !rule.is_empty()){
+            let invalid = || format!("invalid error injection {rule:?}");
+            let mut fields = rule.split(':');
+            let (Some(operation), Some(errno), Some(probability), None) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+                return Err(invalid());
+            };
+            let errno = errno_by_name(errno).or_else(|| errno.parse().ok().filter(|errno| *errno > 0)).ok_or_else(invalid)?;
+            let probability = probability.parse().ok().filter(|probability| (0.0..=1.0).contains(probability)).ok_or_else(invalid)?;
+            if operation.is_empty(){
+                return Err(invalid());
+            }
+            rules.push(InjectionRule{ operation: operation.to_string(), errno, probability });
+        }
+        Ok(ErrorInjection{ rules })
+    }
+}
+
+/// Pseudo-random numbers from SplitMix64, seeded differently on every run.
+pub struct Random{
+    state: u64,
+}
+impl Default for Random{
+    fn default() -> Self{
+        Random{ state: RandomState::new().hash_one(0u8) }
+    }
+}
+impl Random{
+    /// Returns a number in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64{
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        //the top 53 bits fill the mantissa exactly
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn errno_by_name(name: &str) -> Option<i32>{
+    Some(match name{
+        "EPERM" => libc::EPERM,
+        "ENOENT" => libc::ENOENT,
+        "EINTR" => libc::EINTR,
+        "EIO" => libc::EIO,
+        "EBADF" => libc::EBADF,
+        "EAGAIN" => libc::EAGAIN,
+        "ENOMEM" => libc::ENOMEM,
+        "EACCES" => libc::EACCES,
+        "EBUSY" => libc::EBUSY,
+        "EEXIST" => libc::EEXIST,
+        "ENOTDIR" => libc::ENOTDIR,
+        "EISDIR" => libc::EISDIR,
+        "EINVAL" => libc::EINVAL,
+        "EMFILE" => libc::EMFILE,
+        "EFBIG" => libc::EFBIG,
+        "ENOSPC" => libc::ENOSPC,
+        "EROFS" => libc::EROFS,
+        "ENAMETOOLONG" => libc::ENAMETOOLONG,
+        "ENOTEMPTY" => libc::ENOTEMPTY,
+        "ELOOP" => libc::ELOOP,
+        "ENODATA" => libc::ENODATA,
+        "EOPNOTSUPP" => libc::EOPNOTSUPP,
+        "ETIMEDOUT" => libc::ETIMEDOUT,
+        "EDQUOT" => libc::EDQUOT,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn rules_parse_errnos_by_name_or_number(){
+        let injection: ErrorInjection = "read:EIO:0.01, write:28:1".parse().unwrap();
+        assert_eq!(injection.rules, [
+            InjectionRule{ operation: "read".into(), errno: libc::EIO, probability: 0.01 },
+            InjectionRule{ operation: "write".into(), errno: libc::ENOSPC, probability: 1.0 },
+        ]);
+        assert_eq!("".parse(), Ok(ErrorInjection::default()));
+        for invalid in ["read:EIO", "read:EIO:0.5:1", ":EIO:0.5", "read:ENOPE:0.5", "read:-1:0.5", "read:EIO:1.5"]{
+            assert!(invalid.parse::<ErrorInjection>().is_err(), "{invalid}");
+        }
+    }
+    #[test]
+    fn certain_rules_always_fail_and_others_never(){
+        let injection: ErrorInjection = "read:EIO:1,write:ENOSPC:0".parse().unwrap();
+        let mut random = Random::default();
+        for _ in 0..1000{
+            assert_eq!(injection.errno("read", &mut random), Some(libc::EIO));
+            assert_eq!(injection.errno("write", &mut random), None);
+            assert_eq!(injection.errno("open", &mut random), None);
+            assert!((0.0..1.0).contains(&random.next_f64()));
+        }
+    }
+}
@@ -0,0 +1,3523 @@
+pub mod acl;
+pub mod archive;
+pub mod content;
+pub mod dedup;
+pub mod error;
+pub mod evict;
+pub mod inject;
+pub mod mirror;
+pub mod profile;
+pub mod serve;
+pub mod snapshot;
+pub mod space;
+pub mod storage;
+pub mod trace;
+pub mod watch;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime};
+use fuser::{FileAttr, Filesystem, FileType, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow};
+use log::{error, trace, warn};
+use unicode_normalization::UnicodeNormalization;
+use acl::Acl;
+use content::{FileContent, ReadData};
+use dedup::{ContentTable, HashAlgorithm};
+use error::FsError;
+use inject::{ErrorInjection, Random};
+use mirror::Mirror;
+use profile::{Profiler, Timer};
+use space::{StatfsSource, Usage};
+use storage::{Directory, FileStorageEntry, FileStoragePath, Metadata, Overlay};
+use trace::{TraceRecord, Traced, Tracer};
+use watch::{ChangeKind, Watchers};
+
+const TTL: Duration = Duration::new(1, 0);
+/// Space a directory entry is assumed to take up when reporting directory sizes.
+const DIRENT_SIZE: u64 = 32;
+const BLOCK_SIZE: u64 = 4096;
+/// Longest name accepted unless configured otherwise, matching the usual host `NAME_MAX`.
+const NAME_MAX: u32 = 255;
+/// Appends through a handle are buffered until they reach this size.
+const COALESCE_LIMIT: usize = 64 * 1024;
+/// Largest write requested from the kernel, so large sequential writes arrive in few requests.
+const MAX_WRITE: u32 = 1024 * 1024;
+/// Read-only xattr of files holding the MIME type sniffed from their first bytes.
+pub const MIME_TYPE_XATTR: &str = "user.mime_type";
+/// Xattr of directories holding the umask, as octal text, that applies to the entries created in them instead of the
+/// umask of the creating process. It is passed on to new subdirectories.
+pub const DEFAULT_MODE_XATTR: &str = "user.default_mode";
+/// Xattr holding the SELinux label of an entry, `Config::selinux_context` for the entries without a label of their
+/// own.
+pub const SELINUX_XATTR: &str = "security.selinux";
+/// Bytes at the start of a file looked at to tell its MIME type.
+const SNIFF_LEN: usize = 8192;
+/// Capabilities requested in `init` in addition to the defaults of `fuser`, each only if the kernel supports it.
+/// With writeback caching the kernel may flush dirty pages past the end of the file before the ones leading up to
+/// it, which `do_write` fills in as a hole. With `FUSE_DONT_MASK` the kernel leaves the umask to `creation_mode`, which
+/// skips or replaces it for directories with a default ACL or umask of their own.
+const CAPABILITIES: [u32; 5] = [fuser::consts::FUSE_DO_READDIRPLUS, fuser::consts::FUSE_BIG_WRITES, fuser::consts::FUSE_WRITEBACK_CACHE, fuser::consts::FUSE_ATOMIC_O_TRUNC, fuser::consts::FUSE_DONT_MASK];
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct ICFS{
+    config: Config,
+    pub files: Overlay,
+    inode_to_file: HashMap<u64, FileStoragePath>,
+    file_to_inode: HashMap<FileStoragePath, u64>,
+    unused_inodes: HashSet<u64>,
+    /// Generations of the inodes that were reused, incremented on every reuse so the kernel can tell the entries
+    /// apart. Inodes that were never reused are in generation 0.
+    generations: HashMap<u64, u64>,
+    handles: HashMap<u64, Handle>,
+    /// Buffered appends by the handle they were written through.
+    pending_writes: HashMap<u64, PendingWrite>,
+    next_handle: u64,
+    contents: ContentTable,
+    /// Files unlinked while still open, kept alive until the kernel forgets their inode.
+    unlinked: HashMap<u64, FileStorageEntry>,
+    profiler: Option<Arc<Profiler>>,
+    mirror: Option<Mirror>,
+    /// Last access of every file read or written, by inode, for evicting the least recently used contents.
+    accesses: HashMap<u64, u64>,
+    access_tick: u64,
+    /// Sizes of the files whose contents were evicted to the host.
+    evicted: HashMap<u64, u64>,
+    /// Blocks taken up by the files of the live tree, kept up to date as they change.
+    used_blocks: u64,
+    /// Bytes of file contents owned by every user, kept up to date like `used_blocks`.
+    owned_bytes: HashMap<u32, u64>,
+    /// Space counted in `used_blocks` and `owned_bytes` for every file with an inode.
+    counted: HashMap<u64, Usage>,
+    /// Entries of the live tree, kept up to date as they are created and removed.
+    used_inodes: u64,
+    /// Capabilities from `CAPABILITIES` the kernel granted in `init`.
+    capabilities: u32,
+    /// Largest write the kernel was told to send in `init`.
+    max_write: u32,
+    /// Most bytes the kernel was told it may read ahead in `init`.
+    max_readahead: u32,
+    /// Successful writes so far, for turning read-only after the configured number.
+    writes: u64,
+    /// Draws deciding which operations fail with injected errors.
+    random: Random,
+    watchers: Watchers,
+    tracer: Option<Tracer>,
+}
+/// Inode, type and name of an entry listed by `readdir`.
+pub type DirectoryEntry = (u64, FileType, OsString);
+/// What an open file handle was opened on.
+enum Handle{
+    File(u64),
+    /// Names offered by `readdir` through the handle so far, in order. The cookie of an entry is its position in
+    /// this list, so resuming after it skips exactly the entries offered before, however the directory changed since.
+    Directory(Vec<OsString>),
+}
+/// Contiguous appends to the end of a file that haven't been applied to its contents yet.
+struct PendingWrite{
+    inode: u64,
+    offset: i64,
+    data: Vec<u8>,
+}
+impl PendingWrite{
+    fn end(&self) -> i64{
+        self.offset + self.data.len() as i64
+    }
+}
+impl ICFS{
+    pub fn new(files: Overlay, config: Config) -> Self{
+        let contents = ContentTable::new(config.hash_algorithm);
+        let mut fs = ICFS{
+            config,
+            files,
+            inode_to_file: HashMap::new(),
+            file_to_inode: HashMap::new(),
+            unused_inodes: HashSet::new(),
+            generations: HashMap::new(),
+            handles: HashMap::new(),
+            pending_writes: HashMap::new(),
+            next_handle: 1,
+            contents,
+            unlinked: HashMap::new(),
+            profiler: None,
+            mirror: None,
+            accesses: HashMap::new(),
+            access_tick: 0,
+            evicted: HashMap::new(),
+            used_blocks: 0,
+            owned_bytes: HashMap::new(),
+            counted: HashMap::new(),
+            used_inodes: 0,
+            capabilities: 0,
+            max_write: 0,
+            max_readahead: 0,
+            writes: 0,
+            random: Random::default(),
+            watchers: Watchers::default(),
+            tracer: None,
+        };
+        if fs.config.profile{
+            fs.profiler = Some(Arc::default());
+        }
+        fs.mirror = fs.config.mirror.clone().map(Mirror::new);
+        if let Some(path) = &fs.config.trace_file{
+            match Tracer::open(path){
+                Ok(tracer) => fs.tracer = Some(tracer),
+                Err(error) => error!("failed to open trace file {}: {error}", path.display()),
+            }
+        }
+        if let Some(acl) = &fs.config.default_acl{
+            if let Some(metadata) = fs.files.lookup_mut(&FileStoragePath::root()).and_then(FileStorageEntry::metadata_mut){
+                metadata.xattrs.insert(acl::DEFAULT_XATTR.into(), acl.to_xattr());
+            }
+        }
+        if let Some(directory) = fs.files.lookup_upper_mut(&FileStoragePath::root()).and_then(FileStorageEntry::as_dir_mut){
+            directory.reserve(fs.config.dir_initial_capacity);
+        }
+        fs.used_blocks = fs.count_used_blocks();
+        fs.owned_bytes = fs.count_owned_bytes();
+        fs.used_inodes = fs.count_used_inodes();
+        fs.create_inode(FileStoragePath::root());
+        fs
+    }
+    pub fn create_inode(&mut self, path: FileStoragePath) -> u64{
+        if let Some(inode) = self.file_to_inode.get(&path){
+            return *inode;
+        }
+        //the free set iterates in a random order, so deterministic inodes always reuse the lowest free one
+        let free = if self.config.deterministic_inodes{
+            self.unused_inodes.iter().min()
+        } else {
+            self.unused_inodes.iter().next()
+        };
+        let inode = if let Some(inode) = free.cloned(){
+            self.unused_inodes.remove(&inode);
+            *self.generations.entry(inode).or_default() += 1;
+            inode
+        } else {
+            //with no gaps left, the inodes in use are exactly 1 up to their count, so the new one follows them and 0,
+            //which the kernel reserves, is never handed out nor ends up in the free set
+            (self.inode_to_file.len() + self.unlinked.len()) as u64 + 1
+        };
+        debug_assert_ne!(inode, 0, "inode 0 is reserved by the kernel");
+        self.count_inode(inode, &path);
+        self.file_to_inode.insert(path.clone(), inode);
+        self.inode_to_file.insert(inode, path);
+        inode
+    }
+    /// Releases `ino` once the kernel dropped all its references, logging it only at trace level as it happens for
+    /// every inode the kernel evicts from its cache.
+    pub fn do_forget(&mut self, ino: u64){
+        trace!("forget inode {ino}");
+        self.remove_inode(ino);
+    }
+    pub fn remove_inode(&mut self, inode: u64) {
+        if self.evicted.contains_key(&inode){
+            let path = self.inode_to_file.get(&inode);
+            if path.is_some_and(|path| self.file_to_inode.get(path) == Some(&inode) && self.files.lookup(path).is_some()){
+                //evicted contents are found through the inode, which stays taken until they are accessed again
+                //rather than loading them back past the capacity
+                return;
+            }
+            self.discard_evicted(inode);
+        }
+        self.accesses.remove(&inode);
+        let path = if let Some(path) = self.inode_to_file.remove(&inode){
+            path
+        } else if self.unlinked.remove(&inode).is_some(){
+            self.uncount_inode(inode, None);
+            self.unused_inodes.insert(inode);
+            self.compact_free_inodes();
+            return;
+        } else {
+            warn!("trying to remove non-existent inode {inode}");
+            return;
+        };
+        //the path may have been taken over by another inode since the entry of this one was unlinked
+        if self.file_to_inode.get(&path) == Some(&inode){
+            self.file_to_inode.remove(&path);
+            self.uncount_inode(inode, Some(&path));
+        } else {
+            self.uncount_inode(inode, None);
+        }
+        self.unused_inodes.insert(inode);
+        self.compact_free_inodes();
+    }
+    /// Drops the free inodes at the top of the range handed out so far, so the next new inodes are taken from there
+    /// again and the free set stays as small as the gaps below the highest inode in use.
+    fn compact_free_inodes(&mut self){
+        //inodes in use and free ones are always exactly 1 up to the highest inode handed out
+        let mut highest = (self.inode_to_file.len() + self.unlinked.len() + self.unused_inodes.len()) as u64;
+        while self.unused_inodes.remove(&highest){
+            //the inode comes back as a new one, which the kernel mustn't confuse with its last use
+            *self.generations.entry(highest).or_default() += 1;
+            highest -= 1;
+        }
+        if self.unused_inodes.capacity() > 4 * self.unused_inodes.len().max(64){
+            self.unused_inodes.shrink_to_fit();
+        }
+    }
+    /// Generation of `inode`, which changes whenever the inode is reused for another entry.
+    pub fn generation(&self, inode: u64) -> u64{
+        self.generations.get(&inode).copied().unwrap_or(0)
+    }
+    /// Returns a stream of the changes made from now on, one line of JSON per change. Fails with `EOPNOTSUPP` unless
+    /// watching is enabled.
+    pub fn subscribe(&mut self) -> Result<Receiver<String>, FsError>{
+        if !self.config.watch{
+            return Err(FsError::Unsupported);
+        }
+        Ok(self.watchers.subscribe())
+    }
+    /// Table of the contents shared between files.
+    pub fn contents(&self) -> &ContentTable{
+        &self.contents
+    }
+    pub fn profiler(&self) -> Option<&Arc<Profiler>>{
+        self.profiler.as_ref()
+    }
+    fn time(&self, operation: &'static str) -> Option<Timer>{
+        self.profiler.as_ref().map(|profiler| profiler.time(operation))
+    }
+    /// Decides whether this call of `operation` fails with an injected error, returning its errno.
+    fn injected(&mut self, operation: &str) -> Option<i32>{
+        self.config.inject_errors.as_ref()?.errno(operation, &mut self.random)
+    }
+    /// Serves the request `operation` the way every handler does: timed when profiling, failed with an injected error
+    /// before `run` gets to it, and traced with `record` along with its result. Failures come back as the errno to
+    /// reply with.
+    fn dispatch<T: Traced>(&mut self, operation: &'static str, run: impl FnOnce(&mut Self) -> Result<T, FsError>, record: impl FnOnce() -> TraceRecord) -> Result<T, i32>{
+        let _timer = self.time(operation);
+        if let Some(errno) = self.injected(operation){
+            return Err(errno);
+        }
+        let result = run(self);
+        self.trace(&result, record);
+        result.map_err(FsError::to_errno)
+    }
+    /// Reports a violated internal invariant. Unless the filesystem runs with `panic_on_bug`, the error is logged
+    /// and the request fails with `EIO` so a single bad request doesn't take down the whole mount.
+    fn bug(&self, message: &str) -> FsError{
+        if self.config.panic_on_bug{
+            panic!("{message}");
+        }
+        error!("internal error: {message}");
+        FsError::Internal
+    }
+    /// Capabilities negotiated with the kernel in `init`, 0 before mounting.
+    pub fn capabilities(&self) -> u32{
+        self.capabilities
+    }
+    /// Largest write the kernel may send, as negotiated in `init`, 0 before mounting.
+    pub fn max_write(&self) -> u32{
+        self.max_write
+    }
+    /// Most bytes the kernel may read ahead, as negotiated in `init`, 0 before mounting or if the kernel's default
+    /// was kept.
+    pub fn max_readahead(&self) -> u32{
+        self.max_readahead
+    }
+    fn allocate_handle(&mut self, handle: Handle) -> Result<u64, FsError>{
+        self.check_open_files()?;
+        let fh = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(fh, handle);
+        Ok(fh)
+    }
+    /// Fails with `EMFILE` once as many handles are open as `max_open_files` allows.
+    fn check_open_files(&self) -> Result<(), FsError>{
+        match self.config.max_open_files{
+            Some(max_open_files) if self.handles.len() >= max_open_files => Err(FsError::TooManyOpenFiles),
+            _ => Ok(()),
+        }
+    }
+    pub fn get_entry(&self, inode: u64) -> Option<&FileStorageEntry>{
+        match self.inode_to_file.get(&inode){
+            Some(path) => self.files.lookup(path),
+            None => self.unlinked.get(&inode),
+        }
+    }
+    pub fn get_entry_mut(&mut self, inode: u64) -> Option<&mut FileStorageEntry>{
+        match self.inode_to_file.get(&inode){
+            Some(path) => self.files.lookup_mut(path),
+            None => self.unlinked.get_mut(&inode),
+        }
+    }
+    pub fn get_inode_attrs(&self, inode: u64) -> Option<FileAttr>{
+        let entry = self.get_entry(inode)?;
+        //every kind of entry is spelled out, so new ones have to decide on their attributes here
+        let (kind, size, metadata) = match entry{
+            FileStorageEntry::File(data, metadata) => (FileType::RegularFile, self.file_size(inode, data.len()), metadata),
+            FileStorageEntry::Directory(_, metadata) => (FileType::Directory, self.directory_size(inode), metadata),
+            FileStorageEntry::Symlink(target, metadata) => (FileType::Symlink, target.len() as u64, metadata),
+            //whiteouts only mask lower entries and are never looked up
+            FileStorageEntry::Whiteout => return None,
+        };
+        Some(FileAttr {
+            ino: inode,
+            size,
+            blocks: 0,
+            atime: metadata.atime,
+            mtime: metadata.mtime,
+            ctime: metadata.ctime,
+            crtime: metadata.crtime,
+            kind,
+            perm: match kind{
+                FileType::RegularFile if self.config.deny_exec => metadata.perm & !0o111,
+                _ => metadata.perm,
+            },
+            nlink: self.link_count(inode, entry),
+            uid: metadata.uid,
+            gid: metadata.gid,
+            //no entry is a device
+            rdev: 0,
+            blksize: 0,
+            flags: 0,
+        })
+    }
+    /// Size of the file `inode` whose contents hold `len` bytes, including evicted contents and buffered appends.
+    fn file_size(&self, inode: u64, len: u64) -> u64{
+        let len = self.evicted.get(&inode).copied().unwrap_or(len);
+        self.pending_writes.values().filter(|pending| pending.inode == inode).fold(len, |size, pending| size.max(pending.end() as u64))
+    }
+    /// Number of names referring to `inode`. A directory is named by its parent, its own `.` and the `..` of every
+    /// subdirectory, so moving a directory changes the count of both the old and the new parent.
+    fn link_count(&self, inode: u64, entry: &FileStorageEntry) -> u32{
+        let Some(path) = self.inode_to_file.get(&inode) else {
+            //unlinked files have no name left
+            return 0;
+        };
+        match entry{
+            FileStorageEntry::Directory(..) => {
+                let subdirectories = self.files.list(path).unwrap_or_default().into_iter()
+                    .filter(|name| self.files.lookup(&path.with_pushed(name)).is_some_and(FileStorageEntry::is_dir))
+                    .count();
+                2 + subdirectories as u32
+            }
+            FileStorageEntry::File(..) | FileStorageEntry::Symlink(..) => 1,
+            FileStorageEntry::Whiteout => 0,
+        }
+    }
+    /// Size of the entry table of a directory, counting "." and ".." and rounded up to whole blocks.
+    fn directory_size(&self, inode: u64) -> u64{
+        let children = self.inode_to_file.get(&inode).and_then(|path| self.files.list(path)).map_or(0, |names| names.len());
+        ((children as u64 + 2) * DIRENT_SIZE).div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+    }
+    /// Assigns an inode to `path` for an entry reply and returns its attributes. If the attributes can't be built,
+    /// an inode that was newly created for this reply is released again so it doesn't linger in the inode maps.
+    fn entry_attrs(&mut self, path: FileStoragePath) -> Result<FileAttr, FsError>{
+        let existed = self.file_to_inode.contains_key(&path);
+        let inode = self.create_inode(path);
+        match self.get_inode_attrs(inode){
+            Some(attr) => Ok(attr),
+            None => {
+                if !existed{
+                    self.remove_inode(inode);
+                }
+                Err(FsError::NotFound)
+            }
+        }
+    }
+    /// Resolves the path of `name` inside the directory `parent`, failing with the appropriate error if `parent`
+    /// is missing or is not a directory. With normalized names, a name equal to an existing one under NFC resolves
+    /// to the existing entry, which keeps the form it was first created with.
+    fn child_path(&self, parent: u64, name: &OsStr) -> Result<FileStoragePath, FsError>{
+        match self.get_entry(parent){
+            Some(FileStorageEntry::Directory(..)) => match self.inode_to_file.get(&parent){
+                Some(path) => Ok(path.with_pushed(&self.existing_name(path, name))),
+                None => Err(self.bug("directory inode has no path")),
+            },
+            Some(_) => Err(FsError::NotADir),
+            None => Err(FsError::NotFound),
+        }
+    }
+    /// Finds the child of `directory` that `name` refers to under NFC normalization, falling back to `name` itself.
+    /// Names that aren't valid UTF-8 are never normalized.
+    fn existing_name(&self, directory: &FileStoragePath, name: &OsStr) -> OsString{
+        let Some(utf8) = name.to_str().filter(|_| self.config.normalize_names) else {
+            return name.to_os_string();
+        };
+        if self.files.contains(&directory.with_pushed(name)){
+            return name.to_os_string();
+        }
+        let normalized = utf8.nfc().collect::<String>();
+        self.files.list(directory).unwrap_or_default().into_iter()
+            .find(|child| child.to_str().is_some_and(|child| child.nfc().eq(normalized.chars())))
+            .unwrap_or_else(|| name.to_os_string())
+    }
+    /// Rejects names longer than the name length limit, and names containing control characters when strict names
+    /// are enabled.
+    fn check_name(&self, name: &OsStr) -> Result<(), FsError>{
+        if name.len() > self.config.name_max() as usize{
+            return Err(FsError::NameTooLong);
+        }
+        if self.config.strict_names && name.as_bytes().iter().any(u8::is_ascii_control){
+            return Err(FsError::InvalidName);
+        }
+        Ok(())
+    }
+    /// Rejects modifications of the read-only snapshots.
+    fn check_writable(&self, path: &FileStoragePath) -> Result<(), FsError>{
+        if self.files.is_snapshot(path) || self.worn_out(){
+            return Err(FsError::ReadOnly);
+        }
+        if self.config.read_only_paths.iter().any(|read_only| path.starts_with(read_only)){
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+    fn check_inode_writable(&self, ino: u64) -> Result<(), FsError>{
+        match self.inode_to_file.get(&ino){
+            Some(path) => self.check_writable(path),
+            None if self.worn_out() => Err(FsError::ReadOnly),
+            None => Ok(()),
+        }
+    }
+    /// Whether the configured number of writes has been reached, after which the whole filesystem is read-only.
+    fn worn_out(&self) -> bool{
+        self.config.ro_after.is_some_and(|ro_after| self.writes >= ro_after)
+    }
+    /// Rejects directories nested `depth` levels below the root when that exceeds the configured maximum depth.
+    fn check_depth(&self, depth: usize) -> Result<(), FsError>{
+        if self.config.max_depth.is_some_and(|max_depth| depth > max_depth){
+            return Err(FsError::TooDeep);
+        }
+        Ok(())
+    }
+    /// Checks that the inode maps are mutually consistent and the count of used blocks is right, returning a
+    /// description of the first violation found.
+    pub fn check_consistency(&self) -> Result<(), String>{
+        for (inode, path) in &self.inode_to_file{
+            if self.file_to_inode.get(path) != Some(inode){
+                return Err(format!("inode {inode} is not mapped back from its path"));
+            }
+            if self.unused_inodes.contains(inode){
+                return Err(format!("inode {inode} is both used and free"));
+            }
+        }
+        for inode in self.unlinked.keys(){
+            if self.inode_to_file.contains_key(inode) || self.unused_inodes.contains(inode){
+                return Err(format!("unlinked inode {inode} is still in use or free"));
+            }
+        }
+        if self.inode_to_file.len() != self.file_to_inode.len(){
+            return Err("inode maps differ in size".to_string());
+        }
+        let counted = self.count_used_blocks();
+        if self.used_blocks() != counted{
+            return Err(format!("{} blocks are counted as used, but the files take up {counted}", self.used_blocks()));
+        }
+        let owners = |owned: &HashMap<u32, u64>| owned.iter().filter(|(_, bytes)| **bytes > 0).map(|(uid, bytes)| (*uid, *bytes)).collect::<HashMap<_, _>>();
+        let (counted, owned) = (owners(&self.owned_bytes), owners(&self.count_owned_bytes()));
+        if counted != owned{
+            return Err(format!("bytes counted per owner {counted:?} differ from the bytes their files take up {owned:?}"));
+        }
+        let counted = self.count_used_inodes();
+        if self.used_inodes() != counted{
+            return Err(format!("{} inodes are counted as used, but the tree has {counted} entries", self.used_inodes()));
+        }
+        Ok(())
+    }
+    pub fn do_lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, FsError>{
+        //inode 0 is never handed out, so nothing can be looked up below it
+        if parent == 0{
+            return Err(FsError::NotFound);
+        }
+        let path = self.child_path(parent, name)?;
+        if !self.files.contains(&path){
+            return Err(FsError::NotFound);
+        }
+        let path = if self.config.follow_symlinks{
+            self.files.resolve(&path)?
+        } else {
+            path
+        };
+        self.entry_attrs(path)
+    }
+    /// Returns the attributes of `ino`. Files unlinked while open keep their inode until they are released, so
+    /// `fstat` on them is answered without looking at the handle.
+    pub fn do_getattr(&mut self, ino: u64) -> Result<FileAttr, FsError>{
+        if ino == 0{
+            return Err(FsError::NotFound);
+        }
+        match self.get_entry(ino){
+            Some(_) => self.get_inode_attrs(ino).ok_or(FsError::NotFound),
+            None => Err(FsError::NotFound),
+        }
+    }
+    /// Checks whether the user `uid` in the group `gid` may access `ino` as asked by `mask`, a combination of `R_OK`,
+    /// `W_OK` and `X_OK`. Root may access anything, others are checked against the access ACL if there is one, or
+    /// else the owner, group or other bits depending on how they relate to the owner of the entry. With `deny_exec`,
+    /// no one may execute files.
+    pub fn do_access(&self, ino: u64, uid: u32, gid: u32, mask: i32) -> Result<(), FsError>{
+        let entry = self.get_entry(ino).ok_or(FsError::NotFound)?;
+        let metadata = entry.metadata().ok_or(FsError::NotFound)?;
+        if self.config.deny_exec && entry.is_file() && mask & libc::X_OK != 0{
+            return Err(FsError::PermissionDenied);
+        }
+        if uid == 0{
+            return Ok(());
+        }
+        let granted = match metadata.xattrs.get(OsStr::new(acl::ACCESS_XATTR)).and_then(|value| Acl::from_xattr(value)){
+            Some(acl) => acl.granted(uid, gid, metadata.uid, metadata.gid),
+            None if uid == metadata.uid => (metadata.perm >> 6) & 0o7,
+            None if gid == metadata.gid => (metadata.perm >> 3) & 0o7,
+            None => metadata.perm & 0o7,
+        };
+        if (mask & 0o7) as u16 & !granted != 0{
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+    /// Creates the directory `name` in `parent` with the permission bits of `mode`, which already has the umask applied,
+    /// owned by `uid` and `gid`.
+    pub fn do_mkdir(&mut self, parent: u64, name: &OsStr, mode: u32, uid: u32, gid: u32) -> Result<FileAttr, FsError>{
+        self.check_name(name)?;
+        let path = self.child_path(parent, name)?;
+        self.check_writable(&path)?;
+        self.check_depth(path.depth())?;
+        let metadata = self.inherited_metadata(parent, mode, true, uid, gid);
+        let directory = Directory::with_capacity_and_hasher(self.config.dir_initial_capacity, Default::default());
+        self.files.insert(&path, FileStorageEntry::Directory(Arc::new(directory), metadata))?;
+        self.count_entry();
+        if let Some(mirror) = &self.mirror{
+            mirror.mkdir(&path);
+        }
+        self.watchers.publish(ChangeKind::Create, &path, None);
+        self.entry_attrs(path)
+    }
+    /// Metadata of an entry created in `parent` with the permission bits of `mode` by `uid` and `gid`. The default ACL
+    /// of the parent becomes the access ACL of the entry, limiting its permission bits, and is passed on to directories
+    /// along with the default mode of the parent.
+    fn inherited_metadata(&self, parent: u64, mode: u32, directory: bool, uid: u32, gid: u32) -> Metadata{
+        let mut metadata = Metadata{ perm: permissions(mode), uid, gid, ..Metadata::now() };
+        if let Some((value, acl)) = self.default_acl(parent){
+            metadata.perm &= !0o777 | acl.mode();
+            if directory{
+                metadata.xattrs.insert(acl::DEFAULT_XATTR.into(), value.clone());
+            }
+            metadata.xattrs.insert(acl::ACCESS_XATTR.into(), value);
+        }
+        if let (true, Some(value)) = (directory, self.default_mode_xattr(parent)){
+            metadata.xattrs.insert(DEFAULT_MODE_XATTR.into(), value.clone());
+        }
+        metadata
+    }
+    fn default_acl(&self, ino: u64) -> Option<(Vec<u8>, Acl)>{
+        let value = self.get_entry(ino)?.metadata()?.xattrs.get(OsStr::new(acl::DEFAULT_XATTR))?;
+        Some((value.clone(), Acl::from_xattr(value)?))
+    }
+    fn default_mode_xattr(&self, ino: u64) -> Option<&Vec<u8>>{
+        self.get_entry(ino)?.metadata()?.xattrs.get(OsStr::new(DEFAULT_MODE_XATTR))
+    }
+    /// Mode of an entry created in `parent`, where the umask of the request only applies if the parent has no default
+    /// ACL. The default mode of the parent replaces the umask of the request. Relies on `FUSE_DONT_MASK`, without it
+    /// the kernel would have applied the umask already.
+    fn creation_mode(&self, parent: u64, mode: u32, umask: u32) -> u32{
+        if self.default_acl(parent).is_some(){
+            return mode;
+        }
+        let umask = self.default_mode_xattr(parent).and_then(|value| parse_default_mode(value)).unwrap_or(umask);
+        mode & !umask
+    }
+    pub fn do_symlink(&mut self, parent: u64, name: &OsStr, target: &Path, uid: u32, gid: u32) -> Result<FileAttr, FsError>{
+        self.check_name(name)?;
+        let path = self.child_path(parent, name)?;
+        self.check_writable(&path)?;
+        let metadata = Metadata{ uid, gid, ..Metadata::now() };
+        self.files.insert(&path, FileStorageEntry::Symlink(target.as_os_str().to_os_string(), metadata))?;
+        self.count_entry();
+        if let Some(mirror) = &self.mirror{
+            mirror.symlink(&path, target);
+        }
+        self.watchers.publish(ChangeKind::Create, &path, None);
+        self.entry_attrs(path)
+    }
+    pub fn do_readlink(&self, ino: u64) -> Result<Vec<u8>, FsError>{
+        match self.get_entry(ino){
+            Some(FileStorageEntry::Symlink(target, _)) => Ok(target.as_bytes().to_vec()),
+            Some(_) => Err(FsError::InvalidArgument),
+            None => Err(FsError::NotFound),
+        }
+    }
+    pub fn do_unlink(&mut self, parent: u64, name: &OsStr) -> Result<(), FsError>{
+        let path = self.child_path(parent, name)?;
+        self.check_writable(&path)?;
+        if self.files.lookup(&path).is_some_and(FileStorageEntry::is_dir){
+            return Err(FsError::IsADir);
+        }
+        let inode = self.file_to_inode.get(&path).copied();
+        let entry = self.files.remove(&path)?;
+        self.uncount_entry();
+        if let Some(mirror) = &self.mirror{
+            mirror.unlink(&path);
+        }
+        self.watchers.publish(ChangeKind::Delete, &path, None);
+        match inode{
+            Some(inode) => {
+                if self.is_open(inode){
+                    //detach the inode from the path without freeing it, so the open handles keep working
+                    self.inode_to_file.remove(&inode);
+                    self.file_to_inode.remove(&path);
+                    self.unlinked.insert(inode, entry);
+                } else {
+                    self.discard_evicted(inode);
+                }
+                self.recount(inode);
+            }
+            None => self.uncount_removed(&entry),
+        }
+        Ok(())
+    }
+    fn is_open(&self, inode: u64) -> bool{
+        self.handles.values().any(|handle| matches!(handle, Handle::File(open) if *open == inode))
+    }
+    /// Removes the empty directory `name` in `parent`, failing with `ENOTEMPTY` while it still has entries.
+    pub fn do_rmdir(&mut self, parent: u64, name: &OsStr) -> Result<(), FsError>{
+        let path = self.child_path(parent, name)?;
+        self.check_writable(&path)?;
+        self.files.remove_dir(&path)?;
+        self.uncount_entry();
+        if let Some(mirror) = &self.mirror{
+            mirror.rmdir(&path);
+        }
+        self.watchers.publish(ChangeKind::Delete, &path, None);
+        Ok(())
+    }
+    /// Reads up to `size` bytes of the file `ino` at `offset`, sharing in-memory contents rather than copying them.
+    /// Fewer bytes are only returned at the end of the file or when `read_chunk_size` is smaller, so a large read the
+    /// kernel splits into requests of its maximum read size reassembles into the contents. Negative offsets fail with
+    /// `EINVAL`.
+    pub fn do_read(&mut self, ino: u64, offset: i64, size: u32) -> Result<ReadData, FsError>{
+        if offset < 0{
+            return Err(FsError::InvalidArgument);
+        }
+        self.check_file(ino)?;
+        self.flush_writes(ino)?;
+        self.restore(ino)?;
+        self.record_access(ino);
+        let now = SystemTime::now();
+        let (data, update_atime) = match self.get_entry(ino){
+            Some(FileStorageEntry::File(content, metadata)) => {
+                let size = match self.config.read_chunk_size{
+                    Some(chunk_size) => size.min(chunk_size),
+                    None => size,
+                } as usize;
+                let data = match content.read_shared(offset as u64, size){
+                    Ok(data) => data,
+                    Err(error) => {
+                        error!("failed to read spilled contents of inode {ino}: {error}");
+                        return Err(FsError::Io);
+                    }
+                };
+                //the next read of a sequential reader starts where this one ended
+                content.prefetch(offset as u64 + data.len() as u64, self.max_readahead);
+                (data, self.config.atime.should_update(metadata, now))
+            }
+            Some(FileStorageEntry::Directory(..) | FileStorageEntry::Whiteout) => return Err(FsError::IsADir),
+            Some(FileStorageEntry::Symlink(..)) => return Err(FsError::InvalidArgument),
+            None => return Err(FsError::NotFound),
+        };
+        if update_atime{
+            //lower layer entries are read-only, so their atime isn't worth a copy-up
+            //neither are unlinked files, which have no path left
+            let path = self.inode_to_file.get(&ino);
+            if let Some(metadata) = path.and_then(|path| self.files.lookup_upper_mut(path)).and_then(FileStorageEntry::metadata_mut){
+                metadata.atime = now;
+            }
+        }
+        Ok(data)
+    }
+    /// Opens a handle on `ino`. With `O_DIRECTORY` it has to be a directory, with `O_NOFOLLOW` it must not be a
+    /// symbolic link. Files opened with `O_TRUNC` are emptied.
+    pub fn do_open(&mut self, ino: u64, flags: i32) -> Result<u64, FsError>{
+        match self.get_entry(ino){
+            None => return Err(FsError::NotFound),
+            Some(FileStorageEntry::Directory(..)) => {}
+            Some(_) if flags & libc::O_DIRECTORY != 0 => return Err(FsError::NotADir),
+            Some(FileStorageEntry::Symlink(..)) if flags & libc::O_NOFOLLOW != 0 => return Err(FsError::Loop),
+            Some(_) => {}
+        }
+        //fail before truncating the file, an open that returns EMFILE must leave its contents alone
+        self.check_open_files()?;
+        if flags & libc::O_TRUNC != 0 && self.get_entry(ino).is_some_and(FileStorageEntry::is_file){
+            self.truncate_on_open(ino)?;
+        }
+        self.allocate_handle(Handle::File(ino))
+    }
+    /// Empties the file `ino` opened with `O_TRUNC` the way a `setattr` to size 0 does, so the space and quota its
+    /// contents were counted against are freed along with them.
+    fn truncate_on_open(&mut self, ino: u64) -> Result<FileAttr, FsError>{
+        self.do_setattr(ino, None, None, None, Some(0), None, None, None)
+    }
+    pub fn do_opendir(&mut self, ino: u64) -> Result<u64, FsError>{
+        match self.get_entry(ino){
+            Some(FileStorageEntry::Directory(..)) => self.allocate_handle(Handle::Directory(Vec::new())),
+            Some(_) => Err(FsError::NotADir),
+            None => Err(FsError::NotFound),
+        }
+    }
+    /// Applies the appends buffered through the handle `fh`, keeping them buffered if that fails so the error is
+    /// reported to the `close` that flushed them.
+    pub fn do_flush(&mut self, fh: u64) -> Result<(), FsError>{
+        let Some(pending) = self.pending_writes.remove(&fh) else {
+            return Ok(());
+        };
+        let applied = self.apply_write(pending.inode, pending.offset, &pending.data);
+        let inode = pending.inode;
+        if applied.is_err(){
+            self.pending_writes.insert(fh, pending);
+        }
+        self.recount(inode);
+        applied.map(drop)
+    }
+    /// Releases the handle `fh`, applying the appends still buffered through it. The handle is gone even if they
+    /// fail to apply, which is reported instead of dropping them silently.
+    pub fn do_release(&mut self, ino: u64, fh: u64) -> Result<(), FsError>{
+        let flushed = self.do_flush(fh);
+        if let Some(pending) = self.pending_writes.remove(&fh){
+            error!("dropping {} buffered bytes of inode {} that failed to apply", pending.data.len(), pending.inode);
+            self.recount(pending.inode);
+        }
+        if let Some(Handle::File(_)) = self.handles.remove(&fh){
+            if self.config.dedup && !self.evicted.contains_key(&ino){
+                let path = self.inode_to_file.get(&ino);
+                if let Some(FileContent::Memory(content)) = path.and_then(|path| self.files.lookup_upper_mut(path)).and_then(FileStorageEntry::as_file_mut){
+                    self.contents.intern(content);
+                }
+            }
+        }
+        flushed
+    }
+    /// Writes `data` to the file `ino`. Writes through a handle opened with `opendir` fail with `EBADF`, while
+    /// writes targeting a directory inode otherwise fail with `EISDIR`. Appends through a file handle are buffered
+    /// and applied together once they stop being contiguous, grow past `COALESCE_LIMIT`, or the file is read,
+    /// synced or released. They are checked against the free space and quota before being buffered, and failing to
+    /// apply them is reported by the flush, `fsync` or release applying them. Writes beyond the configured
+    /// `max_write_size` are cut short. Writes starting past the end leave a hole that reads as zeroes. With
+    /// `sync_writes`, the backing file is saved before the write returns.
+    pub fn do_write(&mut self, ino: u64, fh: u64, offset: i64, data: &[u8]) -> Result<u32, FsError>{
+        self.check_inode_writable(ino)?;
+        let data = match self.config.max_write_size{
+            Some(max_write_size) => &data[..data.len().min(max_write_size as usize)],
+            None => data,
+        };
+        let written = self.buffer_write(ino, fh, offset, data);
+        self.recount(ino);
+        let written = written?;
+        self.writes += 1;
+        if let Some(path) = self.inode_to_file.get(&ino){
+            self.watchers.publish(ChangeKind::Modify, path, None);
+        }
+        if self.config.sync_writes{
+            self.do_syncfs()?;
+        }
+        Ok(written)
+    }
+    /// Writes `data` to the file `ino`, buffering appends through the handle `fh`.
+    fn buffer_write(&mut self, ino: u64, fh: u64, offset: i64, data: &[u8]) -> Result<u32, FsError>{
+        if let Some(Handle::Directory(_)) = self.handles.get(&fh){
+            return Err(FsError::BadHandle);
+        }
+        if offset < 0{
+            return Err(FsError::InvalidArgument);
+        }
+        self.check_file(ino)?;
+        self.check_space(ino, offset as u64 + data.len() as u64)?;
+        if let Some(pending) = self.pending_writes.get_mut(&fh){
+            if pending.inode == ino && pending.end() == offset && pending.data.len() + data.len() <= COALESCE_LIMIT{
+                pending.data.extend_from_slice(data);
+                self.touch(ino);
+                return Ok(data.len() as u32);
+            }
+        }
+        self.flush_writes(ino)?;
+        if let (Some(Handle::File(_)), Some(FileStorageEntry::File(content, _))) = (self.handles.get(&fh), self.get_entry(ino)){
+            if offset == content.len() as i64 && data.len() < COALESCE_LIMIT{
+                self.pending_writes.insert(fh, PendingWrite{ inode: ino, offset, data: data.to_vec() });
+                self.touch(ino);
+                return Ok(data.len() as u32);
+            }
+        }
+        self.apply_write(ino, offset, data)
+    }
+    /// Applies the buffered appends to the file `ino`. Appends that fail to apply stay buffered, so the next flush,
+    /// `fsync` or release reports the failure again rather than the data going missing.
+    pub fn flush_writes(&mut self, ino: u64) -> Result<(), FsError>{
+        let handles = self.pending_writes.iter().filter(|(_, pending)| pending.inode == ino).map(|(fh, _)| *fh).collect::<Vec<_>>();
+        for fh in handles{
+            self.do_flush(fh)?;
+        }
+        Ok(())
+    }
+    /// Checks that `ino` is a regular file before operating on its contents. The entry is looked up anew by every
+    /// operation, so whatever the caller saw when opening it doesn't matter.
+    fn check_file(&self, ino: u64) -> Result<(), FsError>{
+        match self.get_entry(ino){
+            Some(FileStorageEntry::File(..)) => Ok(()),
+            Some(FileStorageEntry::Directory(..) | FileStorageEntry::Whiteout) => Err(FsError::IsADir),
+            Some(FileStorageEntry::Symlink(..)) => Err(FsError::InvalidArgument),
+            None => Err(FsError::NotFound),
+        }
+    }
+    /// Updates the modification time of `ino` for a write that was buffered.
+    fn touch(&mut self, ino: u64){
+        if let Some(metadata) = self.get_entry_mut(ino).and_then(FileStorageEntry::metadata_mut){
+            let now = SystemTime::now();
+            metadata.mtime = now;
+            metadata.ctime = now;
+        }
+    }
+    fn apply_write(&mut self, ino: u64, offset: i64, data: &[u8]) -> Result<u32, FsError>{
+        self.restore(ino)?;
+        self.record_access(ino);
+        let spill_threshold = self.config.spill_threshold;
+        match self.get_entry_mut(ino){
+            Some(FileStorageEntry::File(content, metadata)) => {
+                let written = content.write(offset as u64, data).and_then(|()| match spill_threshold{
+                    Some(threshold) => content.spill(threshold),
+                    None => Ok(()),
+                });
+                match written{
+                    Ok(()) => {}
+                    Err(error) if error.kind() == io::ErrorKind::OutOfMemory => return Err(FsError::NoSpace),
+                    Err(error) => {
+                        error!("failed to write spilled contents of inode {ino}: {error}");
+                        return Err(FsError::Io);
+                    }
+                }
+                let now = SystemTime::now();
+                metadata.mtime = now;
+                metadata.ctime = now;
+                if let (Some(mirror), Some(path)) = (&self.mirror, self.inode_to_file.get(&ino)){
+                    mirror.write(path, offset as u64, data);
+                }
+                Ok(data.len() as u32)
+            }
+            Some(FileStorageEntry::Directory(..) | FileStorageEntry::Whiteout) => Err(FsError::IsADir),
+            Some(FileStorageEntry::Symlink(..)) => Err(FsError::InvalidArgument),
+            None => Err(FsError::NotFound),
+        }
+    }
+    /// Preallocates, zeroes or collapses the range of `length` bytes at `offset` of the file `ino`. Without
+    /// `FALLOC_FL_KEEP_SIZE`, allocating or zeroing past the end extends the file with zeroes. Collapsed ranges must
+    /// be block aligned and end before the end of the file. Ranges ending past the largest possible file size fail with
+    /// `EFBIG`, and preallocating more than there is memory for fails with `ENOSPC`.
+    pub fn do_fallocate(&mut self, ino: u64, offset: i64, length: i64, mode: i32) -> Result<(), FsError>{
+        const SUPPORTED: i32 = libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_COLLAPSE_RANGE;
+        if mode & !SUPPORTED != 0{
+            return Err(FsError::Unsupported);
+        }
+        if offset < 0 || length <= 0{
+            return Err(FsError::InvalidArgument);
+        }
+        self.check_inode_writable(ino)?;
+        self.check_file(ino)?;
+        self.flush_writes(ino)?;
+        self.restore(ino)?;
+        self.record_access(ino);
+        let end = offset.checked_add(length).ok_or(FsError::FileTooBig)?;
+        let (start, end) = (offset as usize, end as usize);
+        if mode & (libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_COLLAPSE_RANGE) == 0{
+            self.check_space(ino, end as u64)?;
+        }
+        let spill_threshold = self.config.spill_threshold;
+        let Some(FileStorageEntry::File(content, metadata)) = self.get_entry_mut(ino) else {
+            return Err(self.bug("file turned into another kind of entry"));
+        };
+        let len = content.len() as usize;
+        if mode & libc::FALLOC_FL_COLLAPSE_RANGE != 0{
+            if mode != libc::FALLOC_FL_COLLAPSE_RANGE || start % BLOCK_SIZE as usize != 0 || end % BLOCK_SIZE as usize != 0 || end >= len{
+                return Err(FsError::InvalidArgument);
+            }
+        } else if mode & libc::FALLOC_FL_ZERO_RANGE == 0 && (mode & libc::FALLOC_FL_KEEP_SIZE != 0 || end <= len){
+            //plain preallocation of an in-memory file that isn't extended only reserves room for the announced size,
+            //spilled contents have no room to reserve, and neither do contents that would spill once they got there
+            if let FileContent::Memory(buffer) = content{
+                if end > buffer.capacity() && spill_threshold.is_none_or(|threshold| end as u64 <= threshold){
+                    let buffer = Arc::make_mut(buffer);
+                    let missing = end - buffer.len();
+                    buffer.try_reserve_exact(missing).map_err(|_| FsError::NoSpace)?;
+                }
+            }
+            return Ok(());
+        }
+        //the range operations work on the contents as they are, so holes and spilled contents stay out of memory
+        let changed = if mode & libc::FALLOC_FL_COLLAPSE_RANGE != 0{
+            content.collapse_range(start as u64..end as u64)
+        } else {
+            let zeroed = if mode & libc::FALLOC_FL_ZERO_RANGE != 0{ content.zero_range(start as u64..end as u64) } else { Ok(()) };
+            zeroed.and_then(|()| if mode & libc::FALLOC_FL_KEEP_SIZE == 0 && end > len{ content.set_len(end as u64) } else { Ok(()) })
+        };
+        if let Err(error) = changed{
+            error!("failed to change the contents of inode {ino}: {error}");
+            self.recount(ino);
+            return Err(FsError::Io);
+        }
+        let now = SystemTime::now();
+        metadata.mtime = now;
+        metadata.ctime = now;
+        let contents = content.clone();
+        if let Some(threshold) = spill_threshold{
+            if let Err(error) = content.spill(threshold){
+                error!("failed to spill contents of inode {ino}: {error}");
+                self.recount(ino);
+                return Err(FsError::Io);
+            }
+        }
+        self.recount(ino);
+        if let (Some(mirror), Some(path)) = (&self.mirror, self.inode_to_file.get(&ino)){
+            mirror.replace_contents(path, &contents);
+        }
+        if let Some(path) = self.inode_to_file.get(&ino){
+            self.watchers.publish(ChangeKind::Modify, path, None);
+        }
+        Ok(())
+    }
+    /// Copies `len` bytes at `offset_in` of the file `ino_in` to `offset_out` of the file `ino_out`, stopping at the end
+    /// of the source. Copying a whole file over the whole of another file shares the contents like a reflink, they are
+    /// only duplicated once either file is modified.
+    #[allow(clippy::too_many_arguments)]
+    pub fn do_copy_file_range(&mut self, ino_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u32) -> Result<u32, FsError>{
+        if flags != 0 || offset_in < 0 || offset_out < 0{
+            return Err(FsError::InvalidArgument);
+        }
+        self.check_inode_writable(ino_out)?;
+        self.check_file(ino_in)?;
+        self.check_file(ino_out)?;
+        self.flush_writes(ino_in)?;
+        self.flush_writes(ino_out)?;
+        self.restore(ino_in)?;
+        self.restore(ino_out)?;
+        let (Some(FileStorageEntry::File(source, _)), Some(FileStorageEntry::File(destination, _))) = (self.get_entry(ino_in), self.get_entry(ino_out)) else {
+            return Err(self.bug("file turned into another kind of entry"));
+        };
+        let len = len.min(source.len().saturating_sub(offset_in as u64)).min(u32::MAX.into());
+        if ino_in == ino_out || offset_in != 0 || offset_out != 0 || len != source.len() || destination.len() > len{
+            let data = match source.read(offset_in as u64, len as usize){
+                Ok(data) => data,
+                Err(error) => {
+                    error!("failed to read spilled contents of inode {ino_in}: {error}");
+                    return Err(FsError::Io);
+                }
+            };
+            return self.do_write(ino_out, fh_out, offset_out, &data);
+        }
+        let source = source.clone();
+        self.check_space(ino_out, len)?;
+        self.record_access(ino_out);
+        if let (Some(mirror), Some(path), FileContent::Memory(data)) = (&self.mirror, self.inode_to_file.get(&ino_out), &source){
+            mirror.replace(path, data);
+        }
+        let Some(FileStorageEntry::File(content, metadata)) = self.get_entry_mut(ino_out) else {
+            return Err(self.bug("file turned into another kind of entry"));
+        };
+        *content = source;
+        let now = SystemTime::now();
+        metadata.mtime = now;
+        metadata.ctime = now;
+        self.recount(ino_out);
+        self.writes += 1;
+        if let Some(path) = self.inode_to_file.get(&ino_out){
+            self.watchers.publish(ChangeKind::Modify, path, None);
+        }
+        if self.config.sync_writes{
+            self.do_syncfs()?;
+        }
+        Ok(len as u32)
+    }
+    /// Maps block `idx` of the file `ino` to a device block. Contents aren't kept in blocks, so blocks inside the file
+    /// map to `idx + 1`, keeping 0 for blocks past its end like the kernel does for holes.
+    pub fn do_bmap(&self, ino: u64, blocksize: u32, idx: u64) -> Result<u64, FsError>{
+        if blocksize == 0{
+            return Err(FsError::InvalidArgument);
+        }
+        let len = match self.get_entry(ino){
+            Some(FileStorageEntry::File(content, _)) => self.file_size(ino, content.len()),
+            Some(_) => return Err(FsError::InvalidArgument),
+            None => return Err(FsError::NotFound),
+        };
+        match idx.checked_mul(blocksize.into()){
+            Some(offset) if offset < len => Ok(idx + 1),
+            _ => Ok(0),
+        }
+    }
+    /// Changes the permission bits of `ino` to those of `mode` and its owner to `uid` and `gid`, truncates or extends
+    /// the file to `size` and updates the given timestamps. The birth time is only
+    /// changed when explicitly given, so it keeps reporting when the entry was created. With `deny_exec`, giving a
+    /// file execute bits fails with `EPERM`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn do_setattr(&mut self, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, crtime: Option<SystemTime>) -> Result<FileAttr, FsError>{
+        self.check_inode_writable(ino)?;
+        if self.config.deny_exec && mode.is_some_and(|mode| mode & 0o111 != 0) && self.get_entry(ino).is_some_and(FileStorageEntry::is_file){
+            return Err(FsError::NotPermitted);
+        }
+        if let Some(uid) = uid{
+            self.check_quota_transfer(ino, uid)?;
+        }
+        let now = SystemTime::now();
+        if let Some(size) = size{
+            self.check_file(ino)?;
+            self.check_space(ino, size)?;
+            self.flush_writes(ino)?;
+            self.restore(ino)?;
+            self.record_access(ino);
+            let spill_threshold = self.config.spill_threshold;
+            match self.get_entry_mut(ino){
+                Some(FileStorageEntry::File(content, metadata)) => {
+                    let resized = content.set_len(size).and_then(|()| match spill_threshold{
+                        Some(threshold) => content.spill(threshold),
+                        None => Ok(()),
+                    });
+                    if let Err(error) = resized{
+                        error!("failed to resize spilled contents of inode {ino}: {error}");
+                        self.recount(ino);
+                        return Err(FsError::Io);
+                    }
+                    metadata.mtime = now;
+                }
+                Some(FileStorageEntry::Directory(..) | FileStorageEntry::Whiteout) => return Err(FsError::IsADir),
+                Some(FileStorageEntry::Symlink(..)) => return Err(FsError::InvalidArgument),
+                None => return Err(FsError::NotFound),
+            }
+            self.recount(ino);
+            if let (Some(mirror), Some(path)) = (&self.mirror, self.inode_to_file.get(&ino)){
+                mirror.truncate(path, size);
+            }
+            if let Some(path) = self.inode_to_file.get(&ino){
+                self.watchers.publish(ChangeKind::Modify, path, None);
+            }
+        }
+        let metadata = self.get_entry_mut(ino).and_then(FileStorageEntry::metadata_mut).ok_or(FsError::NotFound)?;
+        let resolve = |time| match time{
+            TimeOrNow::SpecificTime(time) => time,
+            TimeOrNow::Now => now,
+        };
+        if let Some(atime) = atime{
+            metadata.atime = resolve(atime);
+        }
+        if let Some(mtime) = mtime{
+            metadata.mtime = resolve(mtime);
+        }
+        if let Some(crtime) = crtime{
+            metadata.crtime = crtime;
+        }
+        if let Some(mode) = mode{
+            metadata.perm = permissions(mode);
+        }
+        let chowned = uid.is_some_and(|uid| uid != metadata.uid);
+        if let Some(uid) = uid{
+            metadata.uid = uid;
+        }
+        if let Some(gid) = gid{
+            metadata.gid = gid;
+        }
+        metadata.ctime = now;
+        if let (Some(mode), Some(mirror), Some(path)) = (mode, &self.mirror, self.inode_to_file.get(&ino)){
+            mirror.chmod(path, permissions(mode));
+        }
+        if chowned{
+            self.recount(ino);
+        }
+        self.get_inode_attrs(ino).ok_or(FsError::NotFound)
+    }
+    /// Moves `name` in `parent` to `newname` in `newparent`. An existing destination is replaced if it is of the
+    /// same kind, and for directories only if it is empty. `RENAME_NOREPLACE` fails with `EEXIST` instead and
+    /// `RENAME_WHITEOUT` leaves a whiteout behind that masks the lower layer at the old path. Moving a directory
+    /// into itself or one of its descendants fails with `EINVAL`. The moved entry gets a new change time, both parents
+    /// a new modification and change time. Moving an entry between the live tree and a snapshot fails with `EXDEV`.
+    /// Everything that can reject the rename, read-only paths and `ro_after` included, is checked before the first
+    /// change, so it is either applied as a whole or not at all.
+    pub fn do_rename(&mut self, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), FsError>{
+        if flags & !(libc::RENAME_NOREPLACE | libc::RENAME_WHITEOUT) != 0{
+            return Err(FsError::Unsupported);
+        }
+        self.check_name(newname)?;
+        let path = self.child_path(parent, name)?;
+        let new_path = self.child_path(newparent, newname)?;
+        if self.files.device(&path) != self.files.device(&new_path){
+            return Err(FsError::CrossDevice);
+        }
+        self.check_writable(&path)?;
+        self.check_writable(&new_path)?;
+        //moving a directory above a read-only path would carry the protected subtree to where it can be changed
+        if self.config.read_only_paths.iter().any(|read_only| read_only.starts_with(&path)){
+            return Err(FsError::ReadOnly);
+        }
+        if !self.files.contains(&path){
+            return Err(FsError::NotFound);
+        }
+        if path == new_path{
+            //renaming an entry onto itself leaves it untouched
+            return Ok(());
+        }
+        if new_path.starts_with(&path){
+            return Err(FsError::InvalidArgument);
+        }
+        if flags & libc::RENAME_NOREPLACE != 0 && self.files.contains(&new_path){
+            return Err(FsError::Exists);
+        }
+        let is_directory = self.files.lookup(&path).is_some_and(FileStorageEntry::is_dir);
+        match self.files.lookup(&new_path){
+            Some(FileStorageEntry::Directory(..)) if !is_directory => return Err(FsError::IsADir),
+            Some(FileStorageEntry::Directory(..)) if !self.files.list(&new_path).unwrap_or_default().is_empty() => return Err(FsError::NotEmpty),
+            Some(FileStorageEntry::File(..) | FileStorageEntry::Symlink(..)) if is_directory => return Err(FsError::NotADir),
+            _ => {}
+        }
+        if is_directory{
+            self.check_depth(new_path.depth() + self.files.directory_height(&path))?;
+        }
+        //the checks above cover every way the move can fail, so once the destination is gone it goes through
+        self.replace_destination(&new_path)?;
+        let counted = [self.counted_below(&path), self.counted_below(&new_path)].concat();
+        self.move_checked(&path, &new_path)?;
+        if flags & libc::RENAME_WHITEOUT != 0{
+            self.files.whiteout(&path);
+        }
+        let now = SystemTime::now();
+        for directory in [path.with_popped(), new_path.with_popped()]{
+            if let Some(metadata) = self.files.lookup_mut(&directory).and_then(FileStorageEntry::metadata_mut){
+                metadata.mtime = now;
+                metadata.ctime = now;
+            }
+        }
+        if let Some(metadata) = self.files.lookup_mut(&new_path).and_then(FileStorageEntry::metadata_mut){
+            metadata.ctime = now;
+        }
+        //the moved entries keep their inodes under their new paths
+        let moved = self.inode_to_file.iter().filter(|(_, moved)| moved.starts_with(&path)).map(|(inode, _)| *inode).collect::<Vec<_>>();
+        for inode in moved{
+            if let Some(old_path) = self.inode_to_file.remove(&inode){
+                self.file_to_inode.remove(&old_path);
+                let moved_path = old_path.rebased(&path, &new_path);
+                self.file_to_inode.insert(moved_path.clone(), inode);
+                self.inode_to_file.insert(inode, moved_path);
+            }
+        }
+        self.recount_below(&new_path, counted);
+        if let Some(mirror) = &self.mirror{
+            mirror.rename(&path, &new_path);
+        }
+        self.watchers.publish(ChangeKind::Rename, &path, Some(&new_path));
+        Ok(())
+    }
+    /// Moves the entry at `path` to `new_path` once `do_rename` made sure nothing stands in the way, so a failure
+    /// is a bug rather than an error of the caller.
+    fn move_checked(&mut self, path: &FileStoragePath, new_path: &FileStoragePath) -> Result<(), FsError>{
+        self.files.move_entry(path, new_path).map_err(|_| self.bug("checked rename failed after replacing the destination"))
+    }
+    /// Hard links aren't supported, so linking `ino` as `newname` in `newparent` fails with `EPERM`, or with `EXDEV`
+    /// if `newparent` lies on another device, such as a snapshot of the tree holding `ino`.
+    pub fn do_link(&mut self, ino: u64, newparent: u64, newname: &OsStr) -> Result<FileAttr, FsError>{
+        self.check_name(newname)?;
+        let path = self.inode_to_file.get(&ino).cloned().ok_or(FsError::NotFound)?;
+        let new_path = self.child_path(newparent, newname)?;
+        if self.files.device(&path) != self.files.device(&new_path){
+            return Err(FsError::CrossDevice);
+        }
+        Err(FsError::NotPermitted)
+    }
+    /// Removes the entry a rename is about to overwrite and releases its inode, unless the replaced file is still
+    /// open and lives on like an unlinked one. Inodes still left at or below the path are released as well.
+    fn replace_destination(&mut self, path: &FileStoragePath) -> Result<(), FsError>{
+        let inode = self.file_to_inode.get(path).copied();
+        if let Ok(entry) = self.files.remove(path){
+            self.uncount_entry();
+            match inode{
+                Some(inode) if self.is_open(inode) => {
+                    self.inode_to_file.remove(&inode);
+                    self.file_to_inode.remove(path);
+                    self.unlinked.insert(inode, entry);
+                    self.recount(inode);
+                }
+                Some(inode) => self.remove_inode(inode),
+                None => self.uncount_removed(&entry),
+            }
+        }
+        //unlinked entries keep their inodes until the kernel forgets them, so the paths the moved entries are about
+        //to take over may still be held by such stale inodes
+        let stale = self.inode_to_file.iter().filter(|(_, mapped)| mapped.starts_with(path)).map(|(inode, _)| *inode).collect::<Vec<_>>();
+        for inode in stale{
+            self.remove_inode(inode);
+        }
+        Ok(())
+    }
+    /// Lists the directory `ino` including the `.` and `..` entries.
+    pub fn do_readdir(&mut self, ino: u64) -> Result<Vec<(u64, FileType, OsString)>, FsError>{
+        match self.get_entry(ino){
+            Some(FileStorageEntry::Directory(..) | FileStorageEntry::Whiteout) => {}
+            Some(FileStorageEntry::File(..) | FileStorageEntry::Symlink(..)) => return Err(FsError::NotADir),
+            None => return Err(FsError::NotFound),
+        }
+        let Some(path) = self.inode_to_file.get(&ino).cloned() else {
+            return Err(self.bug("directory inode has no path"));
+        };
+        let Some(mut names) = self.files.list(&path) else {
+            return Err(self.bug("directory could not be listed"));
+        };
+        match self.config.order{
+            //listing assigns inodes to the children, which has to happen in a reproducible order
+            DirectoryOrder::Hash if self.config.deterministic_inodes => names.sort(),
+            DirectoryOrder::Hash => storage::sort_by_hash(&mut names),
+            DirectoryOrder::Insertion => {}
+        }
+        let mut entries = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (self.create_inode(path.with_popped()), FileType::Directory, OsString::from("..")),
+        ];
+        for name in names{
+            let child_path = path.with_pushed(name.as_os_str());
+            let file_type = match self.files.lookup(&child_path){
+                Some(FileStorageEntry::File(..)) => FileType::RegularFile,
+                Some(FileStorageEntry::Symlink(..)) => FileType::Symlink,
+                Some(FileStorageEntry::Directory(..) | FileStorageEntry::Whiteout) => FileType::Directory,
+                None => return Err(self.bug("listed entry could not be looked up")),
+            };
+            entries.push((self.create_inode(child_path), file_type, name));
+        }
+        Ok(entries)
+    }
+    /// Sets the extended attribute `name` of `ino`. `XATTR_CREATE` fails with `EEXIST` if the attribute already
+    /// exists and `XATTR_REPLACE` fails with `ENODATA` if it doesn't. ACLs must be well-formed, and setting the access
+    /// ACL also updates the permission bits to match it.
+    pub fn do_setxattr(&mut self, ino: u64, name: &OsStr, value: &[u8], flags: i32) -> Result<(), FsError>{
+        if name == MIME_TYPE_XATTR{
+            return Err(FsError::NotPermitted);
+        }
+        if name == DEFAULT_MODE_XATTR && parse_default_mode(value).is_none(){
+            return Err(FsError::InvalidArgument);
+        }
+        self.check_inode_writable(ino)?;
+        let acl = if name == acl::ACCESS_XATTR || name == acl::DEFAULT_XATTR{
+            Some(Acl::from_xattr(value).ok_or(FsError::InvalidArgument)?)
+        } else {
+            None
+        };
+        //the configured label counts as an existing one
+        let labeled = name == SELINUX_XATTR && self.config.selinux_context.is_some();
+        let metadata = self.get_entry_mut(ino).and_then(FileStorageEntry::metadata_mut).ok_or(FsError::NotFound)?;
+        let exists = labeled || metadata.xattrs.contains_key(name);
+        if flags & libc::XATTR_CREATE != 0 && exists{
+            return Err(FsError::Exists);
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !exists{
+            return Err(FsError::NoAttribute);
+        }
+        metadata.xattrs.insert(name.to_os_string(), value.to_vec());
+        if let (Some(acl), true) = (acl, name == acl::ACCESS_XATTR){
+            metadata.perm = metadata.perm & !0o777 | acl.mode();
+        }
+        metadata.ctime = SystemTime::now();
+        Ok(())
+    }
+    /// Returns the value of the extended attribute `name` of `ino`. `MIME_TYPE_XATTR` is computed from the contents
+    /// of files instead, and missing if their type isn't recognized. `SELINUX_XATTR` falls back to the configured
+    /// `selinux_context` for entries without a label of their own.
+    pub fn do_getxattr(&mut self, ino: u64, name: &OsStr) -> Result<Vec<u8>, FsError>{
+        if name == MIME_TYPE_XATTR{
+            return self.sniff_mime_type(ino).map(String::into_bytes);
+        }
+        let metadata = self.get_entry(ino).and_then(FileStorageEntry::metadata).ok_or(FsError::NotFound)?;
+        match metadata.xattrs.get(name){
+            Some(value) => Ok(value.clone()),
+            None if name == SELINUX_XATTR => self.config.selinux_context.clone().ok_or(FsError::NoAttribute),
+            None => Err(FsError::NoAttribute),
+        }
+    }
+    /// Returns the names of the extended attributes of `ino`, each terminated by a null byte. `MIME_TYPE_XATTR` is
+    /// only listed with `list_virtual_xattrs`, for files whose type is recognized. `SELINUX_XATTR` is listed for every
+    /// entry once a `selinux_context` is configured.
+    pub fn do_listxattr(&mut self, ino: u64) -> Result<Vec<u8>, FsError>{
+        let mime_type = self.config.list_virtual_xattrs && self.sniff_mime_type(ino).is_ok();
+        let metadata = self.get_entry(ino).and_then(FileStorageEntry::metadata).ok_or(FsError::NotFound)?;
+        let mut names = Vec::new();
+        for name in metadata.xattrs.keys(){
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        if self.config.selinux_context.is_some() && !metadata.xattrs.contains_key(OsStr::new(SELINUX_XATTR)){
+            names.extend_from_slice(SELINUX_XATTR.as_bytes());
+            names.push(0);
+        }
+        if mime_type{
+            names.extend_from_slice(MIME_TYPE_XATTR.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+    /// Tells the MIME type of the file `ino` from its first bytes.
+    fn sniff_mime_type(&mut self, ino: u64) -> Result<String, FsError>{
+        self.flush_writes(ino)?;
+        self.restore(ino)?;
+        match self.get_entry(ino){
+            Some(FileStorageEntry::File(content, _)) => {
+                let head = content.read(0, SNIFF_LEN).map_err(|error| {
+                    error!("failed to read spilled contents of inode {ino}: {error}");
+                    FsError::Io
+                })?;
+                infer::get(&head).map(|kind| kind.mime_type().to_string()).ok_or(FsError::NoAttribute)
+            }
+            Some(_) => Err(FsError::NoAttribute),
+            None => Err(FsError::NotFound),
+        }
+    }
+    pub fn do_removexattr(&mut self, ino: u64, name: &OsStr) -> Result<(), FsError>{
+        if name == MIME_TYPE_XATTR{
+            return Err(FsError::NotPermitted);
+        }
+        self.check_inode_writable(ino)?;
+        let metadata = self.get_entry_mut(ino).and_then(FileStorageEntry::metadata_mut).ok_or(FsError::NotFound)?;
+        if metadata.xattrs.remove(name).is_none(){
+            return Err(FsError::NoAttribute);
+        }
+        metadata.ctime = SystemTime::now();
+        Ok(())
+    }
+    /// Lists the directory `ino` through the handle `fh`, resuming after the entry with the cookie `offset`. Every
+    /// entry comes with its cookie.
+    pub fn do_readdir_from(&mut self, ino: u64, fh: u64, offset: i64) -> Result<Vec<(i64, DirectoryEntry)>, FsError>{
+        let entries = self.do_readdir(ino)?;
+        self.resume_listing(fh, offset, entries, |entry| &entry.2)
+    }
+    /// Drops the entries of `entries` that the handle `fh` offered up to the cookie `offset` and assigns cookies to
+    /// the rest. Entries offered after that cookie weren't taken by the kernel and are offered again.
+    fn resume_listing<T>(&mut self, fh: u64, offset: i64, entries: Vec<T>, name: impl Fn(&T) -> &OsString) -> Result<Vec<(i64, T)>, FsError>{
+        let Some(Handle::Directory(offered)) = self.handles.get_mut(&fh) else {
+            return Err(FsError::BadHandle);
+        };
+        offered.truncate(offset.max(0) as usize);
+        let skipped = offered.iter().cloned().collect::<HashSet<_>>();
+        let mut resumed = Vec::new();
+        for entry in entries{
+            if !skipped.contains(name(&entry)){
+                offered.push(name(&entry).clone());
+                resumed.push((offered.len() as i64, entry));
+            }
+        }
+        Ok(resumed)
+    }
+    /// Lists the directory `ino` like `do_readdir`, together with the attributes of every entry.
+    pub fn do_readdirplus(&mut self, ino: u64) -> Result<Vec<(OsString, FileAttr)>, FsError>{
+        let mut entries = Vec::new();
+        for (inode, _, name) in self.do_readdir(ino)?{
+            let Some(attr) = self.get_inode_attrs(inode) else {
+                return Err(self.bug("listed entry has no attributes"));
+            };
+            entries.push((name, attr));
+        }
+        Ok(entries)
+    }
+    /// Applies the buffered writes of every file and saves the writable layer to the backing file if there is one,
+    /// returning the number of files that had buffered writes. Evicted contents are loaded back so the snapshot
+    /// holds them. A failed file doesn't keep the others or the snapshot from being written.
+    pub fn do_syncfs(&mut self) -> Result<usize, FsError>{
+        let mut result = self.flush_all_writes();
+        if let Some(path) = &self.config.backing_file{
+            if let Err(error) = self.files.upper().save(path){
+                error!("failed to save snapshot to {}: {error}", path.display());
+                result = Err(FsError::Io);
+            }
+        }
+        result
+    }
+    /// Drops the whiteouts that mask nothing in the lower layer and saves the backing file, compacted down to the
+    /// live entries, returning by how many bytes it shrank. Fails with `EINVAL` without a backing file.
+    pub fn do_trim(&mut self) -> Result<u64, FsError>{
+        let Some(path) = self.config.backing_file.clone() else {
+            return Err(FsError::InvalidArgument);
+        };
+        let before = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        self.files.drop_stale_whiteouts();
+        self.do_syncfs()?;
+        let after = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        Ok(before.saturating_sub(after))
+    }
+    /// Captures the current tree as the read-only snapshot `name`, browsable under the `.snapshots` directory.
+    pub fn do_snapshot(&mut self, name: &OsStr) -> Result<(), FsError>{
+        self.check_name(name)?;
+        if name.is_empty() || name == "." || name == ".." || name.as_bytes().contains(&b'/'){
+            return Err(FsError::InvalidName);
+        }
+        //the snapshot has to see the writes still pending and the contents evicted to the host
+        self.flush_all_writes()?;
+        self.files.snapshot(name)
+    }
+    /// Flushes the pending writes of every file and restores the evicted contents, returning how many files had
+    /// writes pending. All files are flushed even if one of them fails.
+    fn flush_all_writes(&mut self) -> Result<usize, FsError>{
+        let inodes = self.pending_writes.values().map(|pending| pending.inode).collect::<HashSet<_>>();
+        let mut result = Ok(inodes.len());
+        for &inode in &inodes{
+            if let Err(error) = self.flush_writes(inode){
+                result = Err(error);
+            }
+        }
+        self.restore_all();
+        result
+    }
+    /// Creates the file `name` in `parent` and opens it, returning its attributes and the new handle. An existing
+    /// file is opened instead, and emptied with `O_TRUNC`, unless `flags` has `O_EXCL`, while an existing directory fails
+    /// with `EISDIR`. New files are owned by `uid` and `gid`.
+    pub fn do_create(&mut self, parent: u64, name: &OsStr, mode: u32, flags: i32, uid: u32, gid: u32) -> Result<(FileAttr, u64), FsError>{
+        self.check_name(name)?;
+        //fail before creating the file, like the host does when no descriptor is left
+        self.check_open_files()?;
+        let path = self.child_path(parent, name)?;
+        self.check_writable(&path)?;
+        let content = Vec::with_capacity(self.config.buffer_initial_capacity);
+        let metadata = self.inherited_metadata(parent, mode, false, uid, gid);
+        //checking for an existing entry and inserting the new one happen in one step, so of several exclusive
+        //creators only one succeeds
+        let existing = match self.files.insert(&path, FileStorageEntry::File(content.into(), metadata)){
+            Ok(()) => {
+                self.count_entry();
+                if let Some(mirror) = &self.mirror{
+                    mirror.create(&path);
+                }
+                self.watchers.publish(ChangeKind::Create, &path, None);
+                false
+            }
+            Err(FsError::Exists) if flags & libc::O_EXCL == 0 => {
+                if self.files.lookup(&path).is_some_and(FileStorageEntry::is_dir){
+                    return Err(FsError::IsADir);
+                }
+                true
+            }
+            Err(error) => return Err(error),
+        };
+        let mut attr = self.entry_attrs(path)?;
+        if existing && flags & libc::O_TRUNC != 0{
+            attr = self.truncate_on_open(attr.ino)?;
+        }
+        let fh = self.allocate_handle(Handle::File(attr.ino))?;
+        Ok((attr, fh))
+    }
+}
+
+impl Filesystem for ICFS {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), i32> {
+        //older kernels keep using readdir followed by lookups and the page cache for writes
+        for capability in CAPABILITIES{
+            if config.add_capabilities(capability).is_ok(){
+                self.capabilities |= capability;
+            }
+        }
+        self.max_write = match config.set_max_write(MAX_WRITE){
+            Ok(_) => MAX_WRITE,
+            Err(nearest) => {
+                let _ = config.set_max_write(nearest);
+                nearest
+            }
+        };
+        if let Some(max_readahead) = self.config.max_readahead{
+            self.max_readahead = match config.set_max_readahead(max_readahead){
+                Ok(_) => max_readahead,
+                Err(nearest) => {
+                    let _ = config.set_max_readahead(nearest);
+                    nearest
+                }
+            };
+        }
+        Ok(())
+    }
+    fn destroy(&mut self) {
+        if let Some(profiler) = &self.profiler{
+            eprint!("{}", profiler.summary());
+        }
+        //failures were already reported
+        let _ = self.do_syncfs();
+        //nothing is traced after unmounting, closing the trace file lets it be replayed right away
+        self.tracer = None;
+    }
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        //looking up a name searches the parent
+        let result = self.dispatch("lookup", |fs| fs.do_access(parent, req.uid(), req.gid(), libc::X_OK).and_then(|()| fs.do_lookup(parent, name)), || TraceRecord{ name: Some(name.into()), ..TraceRecord::new("lookup", parent) });
+        match result{
+            Ok(attr) => reply.entry(&TTL, &attr, self.generation(attr.ino)),
+            //a plain ENOENT isn't cached by the kernel, while an entry without inode is cached as a negative one
+            Err(libc::ENOENT) if !self.config.negative_ttl.is_zero() => reply.entry(&self.config.negative_ttl, &negative_entry(), 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, _nlookup: u64) {
+        let _timer = self.time("forget");
+        self.do_forget(ino);
+        self.trace(&Ok(()), || TraceRecord::new("forget", ino));
+    }
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        //fuser doesn't forward the handle of fstat calls, unlinked files are found by their inode instead
+        let result = self.dispatch("getattr", |fs| fs.do_getattr(ino), || TraceRecord::new("getattr", ino));
+        match result{
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let result = self.dispatch("access", |fs| fs.do_access(ino, req.uid(), req.gid(), mask), || TraceRecord{ mode: Some(mask as u32), ..TraceRecord::new("access", ino) });
+        match result{
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn setattr(&mut self, _req: &Request<'_>, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>, _fh: Option<u64>, crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
+        let result = self.dispatch("setattr", |fs| fs.do_setattr(ino, mode, uid, gid, size, atime, mtime, crtime), || TraceRecord{ mode, size, ..TraceRecord::new("setattr", ino) });
+        match result{
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        let mode = self.creation_mode(parent, mode, umask);
+        let result = self.dispatch("mkdir", |fs| fs.do_mkdir(parent, name, mode, req.uid(), req.gid()), || TraceRecord{ name: Some(name.into()), mode: Some(mode), ..TraceRecord::new("mkdir", parent) });
+        match result{
+            Ok(attr) => reply.entry(&TTL, &attr, self.generation(attr.ino)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry) {
+        let result = self.dispatch("symlink", |fs| fs.do_symlink(parent, link_name, target, req.uid(), req.gid()), || TraceRecord{ name: Some(link_name.into()), data: target.as_os_str().as_bytes().to_vec(), ..TraceRecord::new("symlink", parent) });
+        match result{
+            Ok(attr) => reply.entry(&TTL, &attr, self.generation(attr.ino)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let result = self.dispatch("readlink", |fs| fs.do_readlink(ino), || TraceRecord::new("readlink", ino));
+        match result{
+            Ok(target) => reply.data(&target),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self.dispatch("unlink", |fs| fs.do_unlink(parent, name), || TraceRecord{ name: Some(name.into()), ..TraceRecord::new("unlink", parent) });
+        match result{
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self.dispatch("rmdir", |fs| fs.do_rmdir(parent, name), || TraceRecord{ name: Some(name.into()), ..TraceRecord::new("rmdir", parent) });
+        match result{
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn read(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+        let result = self.dispatch("read", |fs| fs.do_read(ino, offset, size), || TraceRecord{ fh, offset, size: Some(size.into()), ..TraceRecord::new("read", ino) });
+        match result{
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let result = self.dispatch("open", |fs| fs.do_open(ino, flags), || TraceRecord{ flags, ..TraceRecord::new("open", ino) });
+        match result{
+            Ok(fh) => reply.opened(fh, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let result = self.dispatch("flush", |fs| fs.do_flush(fh), || TraceRecord{ fh, ..TraceRecord::new("flush", ino) });
+        match result{
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn release(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        let _timer = self.time("release");
+        let result = self.do_release(ino, fh);
+        self.trace(&result, || TraceRecord{ fh, ..TraceRecord::new("release", ino) });
+        match result{
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(error.to_errno()),
+        }
+    }
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let result = self.dispatch("opendir", |fs| fs.do_opendir(ino), || TraceRecord::new("opendir", ino));
+        match result{
+            Ok(fh) => reply.opened(fh, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn releasedir(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        let _timer = self.time("releasedir");
+        let result = self.do_release(ino, fh);
+        self.trace(&result, || TraceRecord{ fh, ..TraceRecord::new("releasedir", ino) });
+        reply.ok();
+    }
+    fn write(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
+        let result = self.dispatch("write", |fs| fs.do_write(ino, fh, offset, data), || TraceRecord{ fh, offset, data: data.to_vec(), ..TraceRecord::new("write", ino) });
+        match result{
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let result = self.dispatch("fsync", |fs| fs.flush_writes(ino), || TraceRecord{ fh, ..TraceRecord::new("fsync", ino) });
+        match result{
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn fallocate(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        let result = self.dispatch("fallocate", |fs| fs.do_fallocate(ino, offset, length, mode), || TraceRecord{ fh, offset, size: Some(length as u64), mode: Some(mode as u32), ..TraceRecord::new("fallocate", ino) });
+        match result{
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn copy_file_range(&mut self, _req: &Request<'_>, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u32, reply: ReplyWrite) {
+        let result = self.dispatch("copy_file_range", |fs| fs.do_copy_file_range(ino_in, offset_in, ino_out, fh_out, offset_out, len, flags), || TraceRecord{ fh: fh_in, offset: offset_in, newparent: ino_out, newoffset: offset_out, size: Some(len), mode: Some(flags), ..TraceRecord::new("copy_file_range", ino_in) });
+        match result{
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn bmap(&mut self, _req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        let result = self.dispatch("bmap", |fs| fs.do_bmap(ino, blocksize, idx), || TraceRecord{ offset: idx as i64, size: Some(blocksize.into()), ..TraceRecord::new("bmap", ino) });
+        match result{
+            Ok(block) => reply.bmap(block),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn rename(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        let result = self.dispatch("rename", |fs| fs.do_rename(parent, name, newparent, newname, flags), || TraceRecord{ name: Some(name.into()), newparent, newname: Some(newname.into()), mode: Some(flags), ..TraceRecord::new("rename", parent) });
+        match result{
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn link(&mut self, _req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        let result = self.dispatch("link", |fs| fs.do_link(ino, newparent, newname), || TraceRecord{ newparent, newname: Some(newname.into()), ..TraceRecord::new("link", ino) });
+        match result{
+            Ok(attr) => reply.entry(&TTL, &attr, self.generation(attr.ino)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let result = self.dispatch("readdir", |fs| fs.do_readdir_from(ino, fh, offset), || TraceRecord{ fh, offset, ..TraceRecord::new("readdir", ino) });
+        match result{
+            Ok(entries) => {
+                for (cookie, (inode, file_type, name)) in &entries{
+                    if reply.add(*inode, *cookie, *file_type, name){
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn readdirplus(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectoryPlus) {
+        let result = self.dispatch("readdirplus", |fs| fs.do_readdirplus(ino).and_then(|entries| fs.resume_listing(fh, offset, entries, |entry| &entry.0)), || TraceRecord{ fh, offset, ..TraceRecord::new("readdirplus", ino) });
+        match result{
+            Ok(entries) => {
+                for (cookie, (name, attr)) in &entries{
+                    if reply.add(attr.ino, *cookie, name, &TTL, attr, self.generation(attr.ino)){
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+        let mode = self.creation_mode(parent, mode, umask);
+        let result = self.dispatch("create", |fs| fs.do_create(parent, name, mode, flags, req.uid(), req.gid()), || TraceRecord{ name: Some(name.into()), mode: Some(mode), flags, ..TraceRecord::new("create", parent) });
+        match result{
+            Ok((attr, fh)) => reply.created(&TTL, &attr, self.generation(attr.ino), fh, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn statfs(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        let result = self.dispatch("statfs", |fs| fs.do_statfs(), || TraceRecord::new("statfs", ino));
+        match result{
+            Ok(stats) => reply.statfs(stats.blocks, stats.free, stats.available, stats.files, stats.free_files, stats.block_size, self.config.name_max(), stats.block_size),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn setxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: i32, _position: u32, reply: ReplyEmpty) {
+        let result = self.dispatch("setxattr", |fs| fs.do_setxattr(ino, name, value, flags), || TraceRecord{ name: Some(name.into()), data: value.to_vec(), mode: Some(flags as u32), ..TraceRecord::new("setxattr", ino) });
+        match result{
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn getxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let result = self.dispatch("getxattr", |fs| fs.do_getxattr(ino, name), || TraceRecord{ name: Some(name.into()), ..TraceRecord::new("getxattr", ino) });
+        match result{
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let result = self.dispatch("listxattr", |fs| fs.do_listxattr(ino), || TraceRecord::new("listxattr", ino));
+        match result{
+            Ok(names) => reply_xattr(reply, size, &names),
+            Err(errno) => reply.error(errno),
+        }
+    }
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self.dispatch("removexattr", |fs| fs.do_removexattr(ino, name), || TraceRecord{ name: Some(name.into()), ..TraceRecord::new("removexattr", ino) });
+        match result{
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+}
+
+/// Permission bits of `mode`, including the setuid, setgid and sticky bits but not the file type.
+fn permissions(mode: u32) -> u16{
+    (mode & 0o7777) as u16
+}
+/// Parses the octal umask of a `DEFAULT_MODE_XATTR` value, such as `022`.
+fn parse_default_mode(value: &[u8]) -> Option<u32>{
+    let text = std::str::from_utf8(value).ok()?.trim();
+    u32::from_str_radix(text, 8).ok().filter(|mask| *mask <= 0o777)
+}
+
+/// Attributes of a negative lookup reply, which the kernel recognizes by the zero inode.
+fn negative_entry() -> FileAttr{
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: SystemTime::UNIX_EPOCH,
+        mtime: SystemTime::UNIX_EPOCH,
+        ctime: SystemTime::UNIX_EPOCH,
+        crtime: SystemTime::UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
+
+/// Answers an xattr request with the size of `data` when the caller only asks for it (`size` 0), or with `data`
+/// itself if it fits into the caller's buffer.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]){
+    if size == 0{
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize{
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// Runtime behavior settings of the filesystem.
+#[derive(Default)]
+pub struct Config{
+    /// Caps the number of bytes returned by a single `read`, forcing callers to deal with short reads.
+    pub read_chunk_size: Option<u32>,
+    /// Caps the number of bytes accepted by a single `write`, forcing callers to deal with short writes.
+    pub max_write_size: Option<u32>,
+    /// Most bytes the kernel may read ahead of sequential readers, the kernel's default if unset. Spilled contents
+    /// are prefetched from the host by this much after every read.
+    pub max_readahead: Option<u32>,
+    pub atime: AtimePolicy,
+    /// Snapshot file the writable layer is saved to on unmount.
+    pub backing_file: Option<PathBuf>,
+    /// Saves the backing file after every write, so no acknowledged write is lost if the process dies. Every write
+    /// rewrites the whole snapshot.
+    pub sync_writes: bool,
+    /// Rejects new names containing control characters with `EINVAL`, as they can't be exported to most hosts.
+    pub strict_names: bool,
+    /// Masks the execute bits off the modes reported for files and rejects setting them with `EPERM`, so no file
+    /// can be executed. Directories keep theirs, as they allow searching them.
+    pub deny_exec: bool,
+    /// Shares the buffers of files with identical contents, deduplicating them whenever a file is released.
+    pub dedup: bool,
+    /// Lists computed xattrs such as `MIME_TYPE_XATTR` in `listxattr`, which otherwise only lists the stored ones.
+    pub list_virtual_xattrs: bool,
+    /// SELinux label reported in `SELINUX_XATTR` for the entries that weren't given one of their own.
+    pub selinux_context: Option<Vec<u8>>,
+    /// Hash function identifying identical contents for `dedup`.
+    pub hash_algorithm: HashAlgorithm,
+    /// Records per-operation latency histograms and prints a summary on unmount.
+    pub profile: bool,
+    /// Treats names that are equal under Unicode NFC normalization as the same entry, displayed in the form they
+    /// were first created with.
+    pub normalize_names: bool,
+    /// Assigns inodes in an order that only depends on the sequence of operations, for reproducible tests.
+    pub deterministic_inodes: bool,
+    /// Longest name in bytes that new entries may have, `NAME_MAX` by default.
+    pub name_max: Option<u32>,
+    /// Deepest directory nesting allowed below the root, beyond which `mkdir` and `rename` fail with `ENAMETOOLONG`.
+    pub max_depth: Option<usize>,
+    /// How long the kernel may cache failed lookups. Zero, the default, disables negative caching so files created
+    /// right after a miss are found immediately.
+    pub negative_ttl: Duration,
+    /// Bytes reserved up front in the buffers of newly created files.
+    pub buffer_initial_capacity: usize,
+    /// Entries reserved up front in the root and newly created directories, sparing rehashing while they fill up.
+    pub dir_initial_capacity: usize,
+    /// Most file and directory handles open at once, unlimited if unset.
+    pub max_open_files: Option<usize>,
+    /// Size in bytes of the filesystem, beyond which growing files fails with `ENOSPC`. Unlimited if unset.
+    pub size: Option<u64>,
+    /// Where the space reported by `statfs` comes from.
+    pub statfs: StatfsSource,
+    /// Bytes the files owned by each uid may take up, beyond which growing them fails with `EDQUOT`.
+    pub quotas: HashMap<u32, u64>,
+    /// Size in bytes that the contents of the files held in memory may take up.
+    pub capacity: Option<u64>,
+    /// Evicts the least recently accessed file contents next to the backing file once the capacity is exceeded,
+    /// reloading them on their next access.
+    pub evict: bool,
+    /// Size in bytes beyond which the contents of a file move to an anonymous temp file on the host.
+    pub spill_threshold: Option<u64>,
+    /// ACL set as the default ACL of the root directory on startup, inherited by everything created below it.
+    pub default_acl: Option<Acl>,
+    /// Resolves symbolic links inside the filesystem during lookups, so the kernel only ever sees their targets.
+    pub follow_symlinks: bool,
+    /// File every FUSE operation is appended to, for replaying the workload later.
+    pub trace_file: Option<PathBuf>,
+    /// Lets clients of the serve endpoint subscribe to a stream of the changes.
+    pub watch: bool,
+    /// Host directory every mutation is replayed into as it happens.
+    pub mirror: Option<PathBuf>,
+    /// Panics on violated internal invariants instead of failing the request with `EIO`, for debugging.
+    pub panic_on_bug: bool,
+    /// Fails every modification with `EROFS` after this many successful writes, like a disk going read-only.
+    pub ro_after: Option<u64>,
+    /// Subtrees whose entries can't be modified, created, removed or renamed, failing with `EROFS`.
+    pub read_only_paths: Vec<FileStoragePath>,
+    /// Order `readdir` lists the entries of a directory in.
+    pub order: DirectoryOrder,
+    /// Operations that randomly fail with a given errno, for chaos testing.
+    pub inject_errors: Option<ErrorInjection>,
+}
+impl Config{
+    pub fn name_max(&self) -> u32{
+        self.name_max.unwrap_or(NAME_MAX)
+    }
+}
+
+/// Order the entries of a directory are listed in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DirectoryOrder{
+    /// Ordered by the hash of their names, reproducible with the same hash seed or sorted by name with deterministic
+    /// inodes.
+    #[default]
+    Hash,
+    /// Ordered by when they were created or renamed into the directory. Entries of the lower layer come after the
+    /// ones created above them.
+    Insertion,
+}
+
+/// When `read` updates the access time of a file.
+#[derive(Default)]
+pub enum AtimePolicy{
+    /// Never update the access time.
+    Never,
+    /// Update the access time only when it is older than the last modification or change, or more than a day old.
+    #[default]
+    Relative,
+    /// Update the access time on every read.
+    Strict,
+}
+impl AtimePolicy{
+    pub fn should_update(&self, metadata: &Metadata, now: SystemTime) -> bool{
+        match self{
+            AtimePolicy::Never => false,
+            AtimePolicy::Relative => {
+                metadata.atime <= metadata.mtime
+                    || metadata.atime <= metadata.ctime
+                    || now.duration_since(metadata.atime).is_ok_and(|age| age >= Duration::from_secs(24 * 60 * 60))
+            }
+            AtimePolicy::Strict => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::storage::FileStorage;
+
+    fn filesystem() -> ICFS{
+        filesystem_with(Config::default())
+    }
+    fn filesystem_with(config: Config) -> ICFS{
+        ICFS::new(Overlay::new(FileStorage::new(), FileStorage::new()), config)
+    }
+    fn path(path: &str) -> FileStoragePath{
+        path.split('/').filter(|part| !part.is_empty()).fold(FileStoragePath::root(), |path, part| path.with_pushed(OsStr::new(part)))
+    }
+    fn name(name: &str) -> &OsStr{
+        OsStr::new(name)
+    }
+    fn assert_counted(fs: &ICFS){
+        assert_eq!(fs.used_blocks(), fs.count_used_blocks());
+        fs.check_consistency().unwrap();
+    }
+
+    #[test]
+    fn reads_are_cut_to_the_chunk_size(){
+        let mut fs = filesystem_with(Config{ read_chunk_size: Some(4), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        let ino = file.ino;
+        fs.do_write(ino, fh, 0, b"0123456789").unwrap();
+        assert_eq!(&*fs.do_read(ino, 0, 100).unwrap(), b"0123");
+        assert_eq!(&*fs.do_read(ino, 8, 100).unwrap(), b"89");
+        assert_eq!(&*fs.do_read(ino, 2, 1).unwrap(), b"2");
+        assert!(fs.do_read(ino, 10, 100).unwrap().is_empty());
+    }
+    #[test]
+    fn atime_policies_decide_when_reads_update_it(){
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(60 * 60);
+        let metadata = |atime, mtime| Metadata{ atime, mtime, ctime: mtime, ..Metadata::now() };
+        let fresh = metadata(now - hour, now - 2 * hour);
+        let stale = metadata(now - 2 * hour, now - hour);
+        let old = metadata(now - 25 * hour, now - 26 * hour);
+        assert_eq!([&fresh, &stale, &old].map(|metadata| AtimePolicy::Never.should_update(metadata, now)), [false; 3]);
+        assert_eq!([&fresh, &stale, &old].map(|metadata| AtimePolicy::Relative.should_update(metadata, now)), [false, true, true]);
+        assert_eq!([&fresh, &stale, &old].map(|metadata| AtimePolicy::Strict.should_update(metadata, now)), [true; 3]);
+        for (atime, updated) in [(AtimePolicy::Never, false), (AtimePolicy::Strict, true)]{
+            let mut fs = filesystem_with(Config{ atime, ..Config::default() });
+            let before = now - hour;
+            fs.files.insert(&path("f"), FileStorageEntry::File(b"x".to_vec().into(), metadata(before, before - hour))).unwrap();
+            let ino = fs.create_inode(path("f"));
+            fs.do_read(ino, 0, 1).unwrap();
+            assert_eq!(fs.do_getattr(ino).unwrap().atime != before, updated);
+        }
+    }
+    #[test]
+    fn random_operations_keep_the_inode_maps_and_counts_consistent(){
+        let mut fs = filesystem_with(Config{ deterministic_inodes: true, ..Config::default() });
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        for _ in 0..20_000{
+            //few inodes and names, so operations keep running into each other
+            let (inode, other) = (1 + next(16), 1 + next(16));
+            let (entry, other_entry) = (name(["0", "1", "2", "3"][next(4) as usize]), name(["0", "1", "2", "3"][next(4) as usize]));
+            match next(10){
+                0 => { let _ = fs.do_create(inode, entry, 0o644, 0, next(3) as u32, 0).map(|(attr, fh)| fs.do_release(attr.ino, fh)); }
+                1 => { let _ = fs.do_mkdir(inode, entry, 0o755, next(3) as u32, 0); }
+                2 => { let _ = fs.do_write(inode, 0, next(3 * BLOCK_SIZE) as i64, &vec![1; next(2 * BLOCK_SIZE) as usize]); }
+                3 => { let _ = fs.do_setattr(inode, None, None, None, Some(next(3 * BLOCK_SIZE)), None, None, None); }
+                4 => { let _ = fs.do_unlink(inode, entry); }
+                5 => { let _ = fs.do_rmdir(inode, entry); }
+                6 => { let _ = fs.do_rename(inode, entry, other, other_entry, next(8) as u32); }
+                7 => { let _ = fs.do_lookup(inode, entry); }
+                8 => { let _ = fs.do_readdir(inode); }
+                _ if inode != 1 => fs.remove_inode(inode),
+                _ => {}
+            }
+            assert_counted(&fs);
+        }
+    }
+    #[test]
+    fn writes_through_directory_handles_are_bad_handles(){
+        let mut fs = filesystem();
+        let directory = fs.do_mkdir(1, name("directory"), 0o755, 0, 0).unwrap();
+        let fh = fs.do_opendir(directory.ino).unwrap();
+        assert_eq!(fs.do_write(directory.ino, fh, 0, b"data"), Err(FsError::BadHandle));
+        //without a directory handle the target itself is what's wrong
+        assert_eq!(fs.do_write(directory.ino, 0, 0, b"data"), Err(FsError::IsADir));
+        let (file, file_fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        assert_eq!(fs.do_write(file.ino, fh, 0, b"data"), Err(FsError::BadHandle));
+        assert_eq!(fs.do_write(file.ino, file_fh, 0, b"data"), Ok(4));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn failed_entry_replies_release_only_new_inodes(){
+        let mut fs = filesystem();
+        let missing = FileStoragePath::root().with_pushed(name("missing"));
+        assert_eq!(fs.entry_attrs(missing.clone()).unwrap_err(), FsError::NotFound);
+        assert!(!fs.file_to_inode.contains_key(&missing));
+        assert_eq!(fs.inode_to_file.len(), 1);
+        //an inode the kernel already holds stays until it is forgotten
+        let (attr, fh) = fs.do_create(1, name("gone"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(attr.ino, fh).unwrap();
+        fs.do_unlink(1, name("gone")).unwrap();
+        let gone = FileStoragePath::root().with_pushed(name("gone"));
+        assert_eq!(fs.entry_attrs(gone.clone()).unwrap_err(), FsError::NotFound);
+        assert_eq!(fs.file_to_inode.get(&gone), Some(&attr.ino));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn strict_names_reject_control_characters(){
+        let mut strict = filesystem_with(Config{ strict_names: true, ..Config::default() });
+        assert_eq!(strict.do_create(1, name("new\nline"), 0o644, 0, 0, 0).unwrap_err(), FsError::InvalidName);
+        assert_eq!(strict.do_mkdir(1, name("tab\t"), 0o755, 0, 0).unwrap_err(), FsError::InvalidName);
+        strict.do_mkdir(1, name("plain"), 0o755, 0, 0).unwrap();
+        assert_eq!(strict.do_rename(1, name("plain"), 1, name("new\nline"), 0), Err(FsError::InvalidName));
+        assert_eq!(FsError::InvalidName.to_errno(), libc::EINVAL);
+        let mut lenient = filesystem();
+        lenient.do_create(1, name("new\nline"), 0o644, 0, 0, 0).unwrap();
+        lenient.do_mkdir(1, name("tab\t"), 0o755, 0, 0).unwrap();
+        assert_counted(&lenient);
+    }
+    #[test]
+    fn dedup_shares_identical_files_until_one_is_written(){
+        let mut fs = filesystem_with(Config{ dedup: true, ..Config::default() });
+        let create = |fs: &mut ICFS, file: &str| {
+            let (attr, fh) = fs.do_create(1, name(file), 0o644, 0, 0, 0).unwrap();
+            fs.do_write(attr.ino, fh, 0, b"identical").unwrap();
+            fs.do_release(attr.ino, fh).unwrap();
+            attr.ino
+        };
+        let (first, second) = (create(&mut fs, "first"), create(&mut fs, "second"));
+        let buffer = |fs: &ICFS, ino: u64| match fs.get_entry(ino){
+            Some(FileStorageEntry::File(FileContent::Memory(content), _)) => content.clone(),
+            _ => panic!("file {ino} isn't in memory"),
+        };
+        assert!(Arc::ptr_eq(&buffer(&fs, first), &buffer(&fs, second)));
+        let fh = fs.do_open(second, libc::O_WRONLY).unwrap();
+        fs.do_write(second, fh, 0, b"different").unwrap();
+        fs.do_release(second, fh).unwrap();
+        assert_eq!(*buffer(&fs, first), b"identical");
+        assert_eq!(*buffer(&fs, second), b"different");
+        assert_counted(&fs);
+    }
+    #[test]
+    fn unlinked_open_files_keep_their_attributes(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"still here").unwrap();
+        fs.do_unlink(1, name("f")).unwrap();
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 10);
+        fs.do_release(file.ino, fh).unwrap();
+        fs.remove_inode(file.ino);
+        assert_eq!(fs.do_getattr(file.ino).err(), Some(FsError::NotFound));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn profiling_is_only_set_up_when_enabled(){
+        //3 lookups, 2 reads and 4 writes served like the kernel's requests
+        let workload = |fs: &mut ICFS| {
+            let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+            for _ in 0..3{
+                fs.dispatch("lookup", |fs| fs.do_lookup(1, name("f")), || TraceRecord::new("lookup", 1)).unwrap();
+            }
+            for offset in 0..4{
+                fs.dispatch("write", |fs| fs.do_write(file.ino, fh, offset, b"x"), || TraceRecord::new("write", file.ino)).unwrap();
+            }
+            for _ in 0..2{
+                fs.dispatch("read", |fs| fs.do_read(file.ino, 0, 4), || TraceRecord::new("read", file.ino)).unwrap();
+            }
+            fs.do_release(file.ino, fh).unwrap();
+        };
+        let mut fs = filesystem();
+        workload(&mut fs);
+        assert!(fs.profiler().is_none());
+        let mut fs = filesystem_with(Config{ profile: true, ..Config::default() });
+        workload(&mut fs);
+        let profiler = fs.profiler().unwrap();
+        assert_eq!([profiler.count("lookup"), profiler.count("read"), profiler.count("write")], [3, 2, 4]);
+        //operations called directly rather than served aren't timed
+        assert_eq!(profiler.count("create"), 0);
+    }
+    #[test]
+    fn renaming_an_entry_onto_itself_keeps_it(){
+        let mut fs = filesystem();
+        let (attr, fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(attr.ino, fh, 0, b"contents").unwrap();
+        fs.do_release(attr.ino, fh).unwrap();
+        let before = fs.do_getattr(attr.ino).unwrap();
+        for flags in [0, libc::RENAME_NOREPLACE]{
+            fs.do_rename(1, name("file"), 1, name("file"), flags).unwrap();
+        }
+        assert_eq!(fs.do_lookup(1, name("file")).unwrap().ino, attr.ino);
+        assert_eq!(fs.do_getattr(attr.ino).unwrap().ctime, before.ctime);
+        assert_eq!(&*fs.do_read(attr.ino, 0, 100).unwrap(), b"contents");
+        assert_counted(&fs);
+    }
+    #[test]
+    fn directory_sizes_grow_with_their_entries(){
+        let mut fs = filesystem();
+        let directory = fs.do_mkdir(1, name("directory"), 0o755, 0, 0).unwrap();
+        assert_eq!(directory.size, BLOCK_SIZE);
+        let per_block = BLOCK_SIZE / DIRENT_SIZE;
+        //"." and ".." take up two entries of the first block
+        for child in 0..per_block - 1{
+            fs.do_mkdir(directory.ino, name(&child.to_string()), 0o755, 0, 0).unwrap();
+        }
+        assert_eq!(fs.do_getattr(directory.ino).unwrap().size, 2 * BLOCK_SIZE);
+        fs.do_rmdir(directory.ino, name("0")).unwrap();
+        assert_eq!(fs.do_getattr(directory.ino).unwrap().size, BLOCK_SIZE);
+    }
+    #[test]
+    fn writes_past_the_end_leave_a_hole(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"ab").unwrap();
+        assert_eq!(fs.do_write(file.ino, fh, 10, b"cd"), Ok(2));
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 12);
+        assert_eq!(&*fs.do_read(file.ino, 0, 100).unwrap(), b"ab\0\0\0\0\0\0\0\0cd");
+        assert_eq!(fs.do_write(file.ino, fh, -1, b"x"), Err(FsError::InvalidArgument));
+        assert_eq!(fs.do_write(file.ino, fh, i64::MAX / 4, b"x"), Err(FsError::NoSpace));
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 12);
+        fs.do_release(file.ino, fh).unwrap();
+        assert_counted(&fs);
+    }
+    #[test]
+    fn pages_flushed_out_of_order_by_writeback_caching_are_kept(){
+        let mut fs = filesystem();
+        assert_eq!(fs.capabilities(), 0);
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        let pages = (0..4u8).map(|page| vec![page + 1; BLOCK_SIZE as usize]).collect::<Vec<_>>();
+        for page in [2, 0, 3, 1]{
+            fs.do_write(file.ino, fh, page as i64 * BLOCK_SIZE as i64, &pages[page]).unwrap();
+        }
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(&*fs.do_read(file.ino, 0, 4 * BLOCK_SIZE as u32).unwrap(), pages.concat());
+        assert_counted(&fs);
+    }
+    #[test]
+    fn xattr_flags_require_the_attribute_to_be_missing_or_present(){
+        let mut fs = filesystem();
+        let (attr, _) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        assert_eq!(fs.do_setxattr(attr.ino, name("user.key"), b"1", libc::XATTR_REPLACE), Err(FsError::NoAttribute));
+        fs.do_setxattr(attr.ino, name("user.key"), b"1", libc::XATTR_CREATE).unwrap();
+        assert_eq!(fs.do_setxattr(attr.ino, name("user.key"), b"2", libc::XATTR_CREATE), Err(FsError::Exists));
+        assert_eq!(fs.do_getxattr(attr.ino, name("user.key")).unwrap(), b"1");
+        fs.do_setxattr(attr.ino, name("user.key"), b"2", libc::XATTR_REPLACE).unwrap();
+        fs.do_setxattr(attr.ino, name("user.other"), b"3", 0).unwrap();
+        assert_eq!(fs.do_getxattr(attr.ino, name("user.key")).unwrap(), b"2");
+        assert_eq!(fs.do_listxattr(attr.ino).unwrap(), b"user.key\0user.other\0");
+        fs.do_removexattr(attr.ino, name("user.key")).unwrap();
+        assert_eq!(fs.do_removexattr(attr.ino, name("user.key")), Err(FsError::NoAttribute));
+        assert_eq!(fs.do_getxattr(attr.ino, name("user.key")), Err(FsError::NoAttribute));
+        assert_eq!(FsError::NoAttribute.to_errno(), libc::ENODATA);
+    }
+    #[test]
+    fn mirrors_replay_mutations_into_the_host_directory(){
+        let host = std::env::temp_dir().join(format!("icfs-mirror-test-{}", std::process::id()));
+        let mut fs = filesystem_with(Config{ mirror: Some(host.clone()), ..Config::default() });
+        let directory = fs.do_mkdir(1, name("directory"), 0o755, 0, 0).unwrap();
+        let (attr, fh) = fs.do_create(directory.ino, name("file"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(attr.ino, fh, 0, b"mirrored").unwrap();
+        fs.do_release(attr.ino, fh).unwrap();
+        assert_eq!(std::fs::read(host.join("directory/file")).unwrap(), b"mirrored");
+        fs.do_rename(directory.ino, name("file"), 1, name("moved"), 0).unwrap();
+        assert!(!host.join("directory/file").exists());
+        assert_eq!(std::fs::read(host.join("moved")).unwrap(), b"mirrored");
+        fs.do_unlink(1, name("moved")).unwrap();
+        fs.do_rmdir(1, name("directory")).unwrap();
+        assert_eq!(std::fs::read_dir(&host).unwrap().count(), 0);
+        std::fs::remove_dir(&host).unwrap();
+    }
+    #[test]
+    fn negative_entries_carry_no_inode(){
+        //the kernel caches an entry reply with inode 0 as a negative one
+        assert_eq!(negative_entry().ino, 0);
+        let mut fs = filesystem_with(Config{ negative_ttl: Duration::from_secs(1), ..Config::default() });
+        let lookup = |fs: &mut ICFS| fs.dispatch("lookup", |fs| fs.do_lookup(1, name("f")), || TraceRecord::new("lookup", 1));
+        assert_eq!(lookup(&mut fs).err(), Some(libc::ENOENT));
+        //creating the name replaces the negative entry
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let attr = lookup(&mut fs).unwrap();
+        assert_eq!(attr.ino, file.ino);
+        assert_ne!(attr.ino, negative_entry().ino);
+    }
+    #[test]
+    fn operations_fail_with_typed_errors(){
+        let mut fs = filesystem();
+        let (file, _) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        let directory = fs.do_mkdir(1, name("directory"), 0o755, 0, 0).unwrap();
+        fs.do_mkdir(directory.ino, name("child"), 0o755, 0, 0).unwrap();
+        assert_eq!(fs.do_lookup(1, name("missing")), Err(FsError::NotFound));
+        assert_eq!(fs.do_lookup(file.ino, name("child")), Err(FsError::NotADir));
+        assert_eq!(fs.do_unlink(1, name("directory")), Err(FsError::IsADir));
+        assert_eq!(fs.do_mkdir(1, name("file"), 0o755, 0, 0).unwrap_err(), FsError::Exists);
+        assert_eq!(fs.do_rmdir(1, name("directory")), Err(FsError::NotEmpty));
+        assert_eq!(fs.do_rmdir(1, name("file")), Err(FsError::NotADir));
+    }
+    #[test]
+    fn readdirplus_lists_attributes_matching_lookups(){
+        let mut fs = filesystem();
+        let directory = fs.do_mkdir(1, name("directory"), 0o755, 0, 0).unwrap();
+        let (file, fh) = fs.do_create(directory.ino, name("file"), 0o640, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"contents").unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let entries = fs.do_readdirplus(directory.ino).unwrap();
+        let names = entries.iter().map(|(name, _)| name.as_os_str()).collect::<Vec<_>>();
+        assert_eq!(names, [name("."), name(".."), name("file")]);
+        let (_, attr) = &entries[2];
+        assert_eq!((attr.ino, attr.size, attr.perm, attr.kind), (file.ino, 8, 0o640, FileType::RegularFile));
+        assert_eq!(entries[0].1.ino, directory.ino);
+        assert_eq!(entries[1].1.ino, 1);
+        assert_eq!(fs.do_readdirplus(file.ino).unwrap_err(), FsError::NotADir);
+        assert_counted(&fs);
+    }
+    #[test]
+    fn directories_nest_only_up_to_the_max_depth(){
+        let mut fs = filesystem_with(Config{ max_depth: Some(2), ..Config::default() });
+        let a = fs.do_mkdir(1, name("a"), 0o755, 0, 0).unwrap();
+        let b = fs.do_mkdir(a.ino, name("b"), 0o755, 0, 0).unwrap();
+        assert_eq!(fs.do_mkdir(b.ino, name("c"), 0o755, 0, 0).unwrap_err(), FsError::TooDeep);
+        //moving a directory counts the directories nested below it too
+        let c = fs.do_mkdir(1, name("c"), 0o755, 0, 0).unwrap();
+        fs.do_mkdir(c.ino, name("d"), 0o755, 0, 0).unwrap();
+        assert_eq!(fs.do_rename(1, name("c"), a.ino, name("c"), 0).unwrap_err(), FsError::TooDeep);
+        assert!(fs.do_lookup(1, name("c")).is_ok());
+        fs.check_consistency().unwrap();
+    }
+    #[test]
+    fn failed_flushes_are_reported_instead_of_dropped(){
+        let mut fs = filesystem_with(Config{ size: Some(4 * BLOCK_SIZE), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, &[1; BLOCK_SIZE as usize]).unwrap();
+        //appends are checked against the free space when they are buffered
+        assert_eq!(fs.do_write(file.ino, fh, BLOCK_SIZE as i64, &[1; 4 * BLOCK_SIZE as usize]), Err(FsError::NoSpace));
+        fs.do_flush(fh).unwrap();
+        //an append too large to ever apply stands in for a failing host
+        fs.pending_writes.insert(fh, PendingWrite{ inode: file.ino, offset: 1 << 60, data: vec![1] });
+        assert_eq!(fs.do_flush(fh), Err(FsError::NoSpace));
+        assert_eq!(fs.flush_writes(file.ino), Err(FsError::NoSpace));
+        assert!(fs.pending_writes.contains_key(&fh));
+        assert_eq!(fs.do_release(file.ino, fh), Err(FsError::NoSpace));
+        assert!(fs.pending_writes.is_empty() && !fs.handles.contains_key(&fh));
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, BLOCK_SIZE);
+        assert_counted(&fs);
+    }
+    #[test]
+    fn normalized_names_resolve_to_the_first_seen_form(){
+        let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+        let mut fs = filesystem_with(Config{ normalize_names: true, ..Config::default() });
+        let (attr, _) = fs.do_create(1, name(decomposed), 0o644, 0, 0, 0).unwrap();
+        assert_eq!(fs.do_lookup(1, name(composed)).unwrap().ino, attr.ino);
+        assert_eq!(fs.do_create(1, name(composed), 0o644, libc::O_EXCL, 0, 0).unwrap_err(), FsError::Exists);
+        let listed = fs.do_readdir(1).unwrap().into_iter().map(|(_, _, name)| name).collect::<Vec<_>>();
+        assert_eq!(listed, [name("."), name(".."), name(decomposed)]);
+        //names that aren't UTF-8 are kept as they are
+        use std::os::unix::ffi::OsStrExt;
+        fs.do_mkdir(1, OsStr::from_bytes(b"\xff"), 0o755, 0, 0).unwrap();
+        assert!(fs.do_lookup(1, OsStr::from_bytes(b"\xff")).is_ok());
+        let mut plain = filesystem();
+        plain.do_create(1, name(decomposed), 0o644, 0, 0, 0).unwrap();
+        assert_eq!(plain.do_lookup(1, name(composed)).unwrap_err(), FsError::NotFound);
+    }
+    #[test]
+    fn names_are_limited_to_the_reported_name_max(){
+        assert_eq!(Config::default().name_max(), NAME_MAX);
+        let mut fs = filesystem_with(Config{ name_max: Some(8), ..Config::default() });
+        assert_eq!(fs.config.name_max(), 8);
+        fs.do_create(1, name("12345678"), 0o644, 0, 0, 0).unwrap();
+        assert_eq!(fs.do_create(1, name("123456789"), 0o644, 0, 0, 0).unwrap_err(), FsError::NameTooLong);
+        assert_eq!(fs.do_mkdir(1, name("123456789"), 0o755, 0, 0).unwrap_err(), FsError::NameTooLong);
+        assert_eq!(fs.do_rename(1, name("12345678"), 1, name("123456789"), 0), Err(FsError::NameTooLong));
+    }
+    #[test]
+    fn deterministic_inodes_repeat_across_runs(){
+        let run = || {
+            let mut fs = filesystem_with(Config{ deterministic_inodes: true, ..Config::default() });
+            let mut inodes = Vec::new();
+            for directory in ["a", "b", "c"]{
+                let attr = fs.do_mkdir(1, name(directory), 0o755, 0, 0).unwrap();
+                for file in ["x", "y", "z"]{
+                    fs.do_create(attr.ino, name(file), 0o644, 0, 0, 0).unwrap();
+                }
+            }
+            //free a few inodes in between so the reused ones depend on the order of the free set
+            let b = fs.do_lookup(1, name("b")).unwrap().ino;
+            for file in ["z", "x"]{
+                let ino = fs.do_lookup(b, name(file)).unwrap().ino;
+                fs.do_unlink(b, name(file)).unwrap();
+                fs.remove_inode(ino);
+                inodes.push(ino);
+            }
+            for file in ["p", "q", "r"]{
+                inodes.push(fs.do_create(1, name(file), 0o644, 0, 0, 0).unwrap().0.ino);
+            }
+            inodes.extend(fs.do_readdir(1).unwrap().into_iter().map(|(ino, _, _)| ino));
+            inodes
+        };
+        let first = run();
+        //the lowest free inodes are reused first, then new ones follow the highest
+        assert_eq!(&first[2..5], [first[1], first[0], 14]);
+        for _ in 0..8{
+            assert_eq!(run(), first);
+        }
+    }
+    #[test]
+    fn fallocate_zeroes_and_collapses_ranges(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, &[1; 3 * BLOCK_SIZE as usize]).unwrap();
+        fs.do_fallocate(file.ino, 0, 4 * BLOCK_SIZE as i64, libc::FALLOC_FL_KEEP_SIZE).unwrap();
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 3 * BLOCK_SIZE);
+        fs.do_fallocate(file.ino, BLOCK_SIZE as i64, BLOCK_SIZE as i64, libc::FALLOC_FL_ZERO_RANGE).unwrap();
+        fs.do_fallocate(file.ino, 0, 4 * BLOCK_SIZE as i64, 0).unwrap();
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 4 * BLOCK_SIZE);
+        fs.do_fallocate(file.ino, 0, BLOCK_SIZE as i64, libc::FALLOC_FL_COLLAPSE_RANGE).unwrap();
+        let mut expected = vec![0; BLOCK_SIZE as usize];
+        expected.extend([1; BLOCK_SIZE as usize]);
+        expected.resize(3 * BLOCK_SIZE as usize, 0);
+        assert_eq!(&*fs.do_read(file.ino, 0, 4 * BLOCK_SIZE as u32).unwrap(), expected);
+        assert_eq!(fs.do_fallocate(file.ino, 1, BLOCK_SIZE as i64, libc::FALLOC_FL_COLLAPSE_RANGE), Err(FsError::InvalidArgument));
+        assert_eq!(fs.do_fallocate(file.ino, 0, 1, libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE), Err(FsError::Unsupported));
+        fs.do_release(file.ino, fh).unwrap();
+        fs.check_consistency().unwrap();
+    }
+    #[test]
+    fn released_inodes_leave_evicted_contents_on_the_host(){
+        let backing = std::env::temp_dir().join(format!("icfs-evict-test-{}", std::process::id()));
+        let mut fs = filesystem_with(Config{
+            backing_file: Some(backing.clone()), capacity: Some(BLOCK_SIZE / 2), evict: true, ..Config::default()
+        });
+        let write = |fs: &mut ICFS, file: &str| {
+            let (file, fh) = fs.do_create(1, name(file), 0o644, 0, 0, 0).unwrap();
+            fs.do_write(file.ino, fh, 0, &[1; BLOCK_SIZE as usize]).unwrap();
+            fs.do_release(file.ino, fh).unwrap();
+            file.ino
+        };
+        let first = write(&mut fs, "f");
+        let second = write(&mut fs, "g");
+        let spill = fs.spill_path(first);
+        assert!(fs.evicted.contains_key(&first) && spill.exists());
+        //the kernel forgetting a file that's still there mustn't load it back
+        fs.remove_inode(first);
+        assert!(fs.evicted.contains_key(&first) && spill.exists());
+        assert_eq!(fs.do_lookup(1, name("f")).unwrap().ino, first);
+        fs.do_unlink(1, name("f")).unwrap();
+        fs.remove_inode(first);
+        assert!(!fs.evicted.contains_key(&first) && !spill.exists());
+        assert_eq!(fs.do_read(second, 0, BLOCK_SIZE as u32).unwrap().len(), BLOCK_SIZE as usize);
+        assert_counted(&fs);
+        let _ = std::fs::remove_dir_all(spill.parent().unwrap());
+    }
+    #[test]
+    fn renames_over_files_release_the_replaced_inode(){
+        let mut fs = filesystem();
+        let create = |fs: &mut ICFS, file: &str, data: &[u8]| {
+            let (attr, fh) = fs.do_create(1, name(file), 0o644, 0, 0, 0).unwrap();
+            fs.do_write(attr.ino, fh, 0, data).unwrap();
+            fs.do_release(attr.ino, fh).unwrap();
+            attr.ino
+        };
+        let (source, destination) = (create(&mut fs, "source", b"new"), create(&mut fs, "destination", &[0; 3 * BLOCK_SIZE as usize]));
+        let used = fs.used_blocks();
+        fs.do_rename(1, name("source"), 1, name("destination"), 0).unwrap();
+        assert_eq!(fs.do_lookup(1, name("destination")).unwrap().ino, source);
+        assert_eq!(&*fs.do_read(source, 0, 10).unwrap(), b"new");
+        assert_eq!(fs.do_getattr(destination), Err(FsError::NotFound));
+        assert_eq!(fs.used_blocks(), used - 3);
+        assert_counted(&fs);
+        //an open destination keeps its contents until released
+        let other = create(&mut fs, "other", b"other");
+        let fh = fs.do_open(source, libc::O_RDONLY).unwrap();
+        fs.do_rename(1, name("other"), 1, name("destination"), 0).unwrap();
+        assert_eq!(&*fs.do_read(source, 0, 10).unwrap(), b"new");
+        assert_eq!(fs.do_lookup(1, name("destination")).unwrap().ino, other);
+        fs.do_release(source, fh).unwrap();
+        fs.remove_inode(source);
+        assert_eq!(fs.do_getattr(source), Err(FsError::NotFound));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn renames_onto_unlinked_paths_release_their_stale_inodes(){
+        let mut fs = filesystem();
+        let (gone, fh) = fs.do_create(1, name("gone"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(gone.ino, fh).unwrap();
+        //the kernel hasn't forgotten the unlinked file yet, so its inode still maps to the path
+        fs.do_unlink(1, name("gone")).unwrap();
+        let (moved, fh) = fs.do_create(1, name("moved"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(moved.ino, fh).unwrap();
+        fs.do_rename(1, name("moved"), 1, name("gone"), 0).unwrap();
+        assert_eq!(fs.do_lookup(1, name("gone")).unwrap().ino, moved.ino);
+        assert_eq!(fs.do_getattr(gone.ino).unwrap_err(), FsError::NotFound);
+        assert_counted(&fs);
+    }
+    #[test]
+    fn fallocate_bounds_the_range_and_the_preallocation(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"abc").unwrap();
+        assert_eq!(fs.do_fallocate(file.ino, i64::MAX, 1, 0), Err(FsError::FileTooBig));
+        assert_eq!(FsError::FileTooBig.to_errno(), libc::EFBIG);
+        assert_eq!(fs.do_fallocate(file.ino, 0, 1 << 50, libc::FALLOC_FL_KEEP_SIZE), Err(FsError::NoSpace));
+        fs.do_fallocate(file.ino, 0, 1 << 20, libc::FALLOC_FL_KEEP_SIZE).unwrap();
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 3);
+        fs.do_release(file.ino, fh).unwrap();
+        assert_counted(&fs);
+
+        let mut fs = filesystem_with(Config{ spill_threshold: Some(1 << 20), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_fallocate(file.ino, 0, 1 << 50, libc::FALLOC_FL_KEEP_SIZE).unwrap();
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 0);
+        fs.do_release(file.ino, fh).unwrap();
+    }
+    #[test]
+    fn followed_symlinks_resolve_to_their_targets(){
+        let mut fs = filesystem_with(Config{ follow_symlinks: true, ..Config::default() });
+        let directory = fs.do_mkdir(1, name("directory"), 0o755, 0, 0).unwrap();
+        let (file, _) = fs.do_create(directory.ino, name("file"), 0o644, 0, 0, 0).unwrap();
+        fs.do_symlink(1, name("relative"), Path::new("directory"), 0, 0).unwrap();
+        fs.do_symlink(directory.ino, name("absolute"), Path::new("/directory/file"), 0, 0).unwrap();
+        fs.do_symlink(directory.ino, name("parent"), Path::new("../relative/./file"), 0, 0).unwrap();
+        assert_eq!(fs.do_lookup(1, name("relative")).unwrap().ino, directory.ino);
+        assert_eq!(fs.do_lookup(directory.ino, name("absolute")).unwrap().ino, file.ino);
+        assert_eq!(fs.do_lookup(directory.ino, name("parent")).unwrap().ino, file.ino);
+        fs.do_symlink(1, name("loop"), Path::new("back"), 0, 0).unwrap();
+        fs.do_symlink(1, name("back"), Path::new("loop"), 0, 0).unwrap();
+        assert_eq!(fs.do_lookup(1, name("loop")).unwrap_err(), FsError::Loop);
+        fs.do_symlink(1, name("dangling"), Path::new("missing"), 0, 0).unwrap();
+        assert_eq!(fs.do_lookup(1, name("dangling")).unwrap_err(), FsError::NotFound);
+        let mut plain = filesystem();
+        plain.do_mkdir(1, name("directory"), 0o755, 0, 0).unwrap();
+        let link = plain.do_symlink(1, name("relative"), Path::new("directory"), 0, 0).unwrap();
+        assert_eq!(plain.do_lookup(1, name("relative")).unwrap().kind, FileType::Symlink);
+        assert_eq!(plain.do_readlink(link.ino).unwrap(), b"directory");
+    }
+    #[test]
+    fn birth_times_only_change_when_set_explicitly(){
+        let mut fs = filesystem();
+        let (attr, fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        fs.do_write(attr.ino, fh, 0, b"contents").unwrap();
+        fs.do_release(attr.ino, fh).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        fs.do_setattr(attr.ino, Some(0o600), None, None, Some(2), Some(TimeOrNow::SpecificTime(later)), Some(TimeOrNow::SpecificTime(later)), None).unwrap();
+        let changed = fs.do_getattr(attr.ino).unwrap();
+        assert_eq!(changed.crtime, attr.crtime);
+        assert_eq!(changed.mtime, later);
+        let birth = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        fs.do_setattr(attr.ino, None, None, None, None, None, None, Some(birth)).unwrap();
+        assert_eq!(fs.do_getattr(attr.ino).unwrap().crtime, birth);
+    }
+    #[test]
+    fn full_handle_tables_fail_opens_until_a_handle_is_released(){
+        let mut fs = filesystem_with(Config{ max_open_files: Some(2), ..Config::default() });
+        let (attr, first) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        let directory = fs.do_opendir(1).unwrap();
+        assert_eq!(fs.do_open(attr.ino, libc::O_RDONLY), Err(FsError::TooManyOpenFiles));
+        assert_eq!(fs.do_opendir(1), Err(FsError::TooManyOpenFiles));
+        assert_eq!(fs.do_create(1, name("other"), 0o644, 0, 0, 0).unwrap_err(), FsError::TooManyOpenFiles);
+        assert_eq!(fs.do_lookup(1, name("other")).unwrap_err(), FsError::NotFound);
+        fs.do_release(1, directory).unwrap();
+        let second = fs.do_open(attr.ino, libc::O_RDONLY).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(FsError::TooManyOpenFiles.to_errno(), libc::EMFILE);
+    }
+    #[test]
+    fn snapshots_hold_pending_writes(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"pending").unwrap();
+        assert!(!fs.pending_writes.is_empty());
+        fs.do_snapshot(name("s")).unwrap();
+        fs.do_write(file.ino, fh, 7, b" and later").unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let snapshots = fs.do_lookup(1, name(storage::SNAPSHOTS)).unwrap().ino;
+        let snapshot = fs.do_lookup(snapshots, name("s")).unwrap().ino;
+        let copy = fs.do_lookup(snapshot, name("f")).unwrap().ino;
+        assert_eq!(&*fs.do_read(copy, 0, 100).unwrap(), b"pending");
+        assert_eq!(&*fs.do_read(file.ino, 0, 100).unwrap(), b"pending and later");
+    }
+    #[test]
+    fn whiteout_renames_mask_the_lower_entry(){
+        let mut lower = FileStorage::new();
+        lower.insert(&FileStoragePath::root().with_pushed(name("lower")), FileStorageEntry::File(FileContent::default(), Metadata::now())).unwrap();
+        let mut fs = ICFS::new(Overlay::new(lower, FileStorage::new()), Config::default());
+        let lower = fs.do_lookup(1, name("lower")).unwrap();
+        fs.do_rename(1, name("lower"), 1, name("moved"), libc::RENAME_WHITEOUT).unwrap();
+        assert_eq!(fs.do_lookup(1, name("lower")).unwrap_err(), FsError::NotFound);
+        assert_eq!(fs.do_lookup(1, name("moved")).unwrap().ino, lower.ino);
+        let listed = fs.do_readdir(1).unwrap().into_iter().map(|(_, _, name)| name).collect::<Vec<_>>();
+        assert_eq!(listed, [name("."), name(".."), name("moved")]);
+        //the name is free to be taken again
+        fs.do_create(1, name("lower"), 0o644, libc::O_EXCL, 0, 0).unwrap();
+        assert_eq!(fs.do_rename(1, name("moved"), 1, name("lower"), libc::RENAME_NOREPLACE), Err(FsError::Exists));
+        assert_eq!(fs.do_rename(1, name("moved"), 1, name("lower"), 1 << 10), Err(FsError::Unsupported));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn special_mode_bits_are_kept_and_reported(){
+        let mut fs = filesystem();
+        let (file, _) = fs.do_create(1, name("setuid"), 0o4755, 0, 0, 0).unwrap();
+        assert_eq!((file.perm, file.kind), (0o4755, FileType::RegularFile));
+        let directory = fs.do_mkdir(1, name("sticky"), 0o1777, 0, 0).unwrap();
+        assert_eq!((directory.perm, directory.kind), (0o1777, FileType::Directory));
+        //chmod takes the full mode, including the file type bits the kernel passes along
+        fs.do_setattr(file.ino, Some(libc::S_IFREG | 0o2750), None, None, None, None, None, None).unwrap();
+        assert_eq!(fs.do_getattr(file.ino).unwrap().perm, 0o2750);
+        assert_eq!(fs.do_getattr(directory.ino).unwrap().perm, 0o1777);
+    }
+    #[test]
+    fn forgotten_inodes_are_only_logged_at_trace_level(){
+        struct Capture(std::sync::Mutex<Vec<(log::Level, String)>>);
+        impl log::Log for Capture{
+            fn enabled(&self, _metadata: &log::Metadata) -> bool{
+                true
+            }
+            fn log(&self, record: &log::Record){
+                self.0.lock().unwrap().push((record.level(), record.args().to_string()));
+            }
+            fn flush(&self){}
+        }
+        static CAPTURE: Capture = Capture(std::sync::Mutex::new(Vec::new()));
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+        let mut fs = filesystem();
+        let (attr, fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(attr.ino, fh).unwrap();
+        fs.do_unlink(1, name("file")).unwrap();
+        fs.do_forget(attr.ino);
+        assert_eq!(fs.do_getattr(attr.ino), Err(FsError::NotFound));
+        let message = format!("forget inode {}", attr.ino);
+        let logged = CAPTURE.0.lock().unwrap().iter().filter(|(_, logged)| *logged == message).map(|(level, _)| *level).collect::<Vec<_>>();
+        assert_eq!(logged, [log::Level::Trace]);
+    }
+    #[test]
+    fn contents_spill_once_they_grow_past_the_threshold(){
+        let mut fs = filesystem_with(Config{ spill_threshold: Some(2 * BLOCK_SIZE), ..Config::default() });
+        let spilled = |fs: &ICFS, ino: u64| matches!(fs.get_entry(ino), Some(FileStorageEntry::File(content, _)) if content.is_spilled());
+        let (file, fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, &[1; BLOCK_SIZE as usize]).unwrap();
+        fs.do_flush(fh).unwrap();
+        assert!(!spilled(&fs, file.ino));
+        fs.do_write(file.ino, fh, BLOCK_SIZE as i64, &[2; 2 * BLOCK_SIZE as usize]).unwrap();
+        fs.do_flush(fh).unwrap();
+        assert!(spilled(&fs, file.ino));
+        //reads and writes go to the temp file from now on
+        fs.do_write(file.ino, fh, BLOCK_SIZE as i64 - 1, b"xy").unwrap();
+        let read = fs.do_read(file.ino, BLOCK_SIZE as i64 - 2, 4).unwrap();
+        assert_eq!(&*read, [1, b'x', b'y', 2]);
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 3 * BLOCK_SIZE);
+        fs.do_release(file.ino, fh).unwrap();
+        assert_counted(&fs);
+        //files growing through setattr are checked against the threshold too
+        let (other, fh) = fs.do_create(1, name("other"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(other.ino, fh, 0, b"small").unwrap();
+        fs.do_release(other.ino, fh).unwrap();
+        fs.do_setattr(other.ino, None, None, None, Some(4 * BLOCK_SIZE), None, None, None).unwrap();
+        assert!(spilled(&fs, other.ino));
+        assert_eq!(&*fs.do_read(other.ino, 0, 6).unwrap(), b"small\0");
+        assert_counted(&fs);
+    }
+    #[test]
+    fn moving_directories_updates_the_link_counts_of_both_parents(){
+        let mut fs = filesystem();
+        let from = fs.do_mkdir(1, name("from"), 0o755, 0, 0).unwrap();
+        let to = fs.do_mkdir(1, name("to"), 0o755, 0, 0).unwrap();
+        fs.do_mkdir(from.ino, name("moved"), 0o755, 0, 0).unwrap();
+        fs.do_create(from.ino, name("file"), 0o644, 0, 0, 0).unwrap();
+        let nlink = |fs: &mut ICFS, ino: u64| fs.do_getattr(ino).unwrap().nlink;
+        //files don't add to the link count of their parent
+        assert_eq!((nlink(&mut fs, 1), nlink(&mut fs, from.ino), nlink(&mut fs, to.ino)), (4, 3, 2));
+        fs.do_rename(from.ino, name("moved"), to.ino, name("moved"), 0).unwrap();
+        assert_eq!((nlink(&mut fs, from.ino), nlink(&mut fs, to.ino)), (2, 3));
+        fs.do_rename(from.ino, name("file"), to.ino, name("file"), 0).unwrap();
+        assert_eq!((nlink(&mut fs, from.ino), nlink(&mut fs, to.ino)), (2, 3));
+        fs.do_rename(1, name("from"), to.ino, name("from"), 0).unwrap();
+        assert_eq!((nlink(&mut fs, 1), nlink(&mut fs, to.ino)), (3, 4));
+    }
+    #[test]
+    fn access_checks_the_bits_of_the_matching_class(){
+        let mut fs = filesystem();
+        let directory = fs.do_mkdir(1, name("directory"), 0o750, 1000, 100).unwrap();
+        //searching the directory, as lookups below it do
+        assert_eq!(fs.do_access(directory.ino, 1000, 100, libc::X_OK), Ok(()));
+        assert_eq!(fs.do_access(directory.ino, 1001, 100, libc::X_OK), Ok(()));
+        assert_eq!(fs.do_access(directory.ino, 1001, 100, libc::W_OK), Err(FsError::PermissionDenied));
+        assert_eq!(fs.do_access(directory.ino, 1001, 101, libc::X_OK), Err(FsError::PermissionDenied));
+        assert_eq!(fs.do_access(directory.ino, 0, 0, libc::R_OK | libc::W_OK | libc::X_OK), Ok(()));
+        //the owner class applies even if it grants less than the group class
+        fs.do_setattr(directory.ino, Some(0o070), None, None, None, None, None, None).unwrap();
+        assert_eq!(fs.do_access(directory.ino, 1000, 100, libc::X_OK), Err(FsError::PermissionDenied));
+        assert_eq!(fs.do_access(directory.ino, 1000, 100, libc::F_OK), Ok(()));
+        assert_eq!(fs.do_access(99, 0, 0, libc::F_OK), Err(FsError::NotFound));
+    }
+    #[test]
+    fn default_acls_skip_the_umask(){
+        assert!(CAPABILITIES.contains(&fuser::consts::FUSE_DONT_MASK));
+        let mut fs = filesystem_with(Config{ default_acl: Some("u::rw-,g::rw-,o::r--".parse().unwrap()), ..Config::default() });
+        let mode = fs.creation_mode(1, 0o666, 0o077);
+        assert_eq!(mode, 0o666);
+        let (file, _) = fs.do_create(1, name("f"), mode, 0, 0, 0).unwrap();
+        assert_eq!(file.perm, 0o664);
+        let dir = fs.do_mkdir(1, name("d"), 0o777, 0, 0).unwrap().ino;
+        fs.do_removexattr(dir, name(acl::DEFAULT_XATTR)).unwrap();
+        assert_eq!(fs.creation_mode(dir, 0o666, 0o077), 0o600);
+    }
+    #[test]
+    fn writes_run_out_of_space_exactly_when_statfs_says_so(){
+        let mut fs = filesystem_with(Config{ size: Some(8 * BLOCK_SIZE), ..Config::default() });
+        let stats = fs.do_statfs().unwrap();
+        assert_eq!((stats.blocks, stats.free, stats.available, stats.block_size), (8, 8, 8, BLOCK_SIZE as u32));
+        let (file, fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, &[1; 5 * BLOCK_SIZE as usize - 1]).unwrap();
+        fs.do_flush(fh).unwrap();
+        assert_eq!(fs.do_statfs().unwrap().free, 3);
+        //the last byte of a partly used block is free already
+        fs.do_write(file.ino, fh, 5 * BLOCK_SIZE as i64 - 1, b"x").unwrap();
+        let (other, other_fh) = fs.do_create(1, name("other"), 0o644, 0, 0, 0).unwrap();
+        assert_eq!(fs.do_fallocate(other.ino, 0, 3 * BLOCK_SIZE as i64 + 1, 0), Err(FsError::NoSpace));
+        fs.do_fallocate(other.ino, 0, 3 * BLOCK_SIZE as i64, 0).unwrap();
+        assert_eq!(fs.do_statfs().unwrap().free, 0);
+        assert_eq!(fs.do_write(other.ino, other_fh, 3 * BLOCK_SIZE as i64, b"y"), Err(FsError::NoSpace));
+        fs.do_release(other.ino, other_fh).unwrap();
+        fs.do_setattr(file.ino, None, None, None, Some(BLOCK_SIZE), None, None, None).unwrap();
+        assert_eq!(fs.do_statfs().unwrap().free, 4);
+        fs.do_release(file.ino, fh).unwrap();
+        assert_counted(&fs);
+        assert_eq!(filesystem().do_statfs().unwrap().blocks, 0);
+    }
+    #[test]
+    fn growing_through_setattr_leaves_a_hole(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"data").unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        fs.do_setattr(file.ino, None, None, None, Some(1 << 30), None, None, None).unwrap();
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 1 << 30);
+        //the gigabyte of zeroes isn't allocated
+        assert!(matches!(fs.get_entry(file.ino), Some(FileStorageEntry::File(FileContent::Sparse(buffer, _), _)) if buffer.len() == 4));
+        assert_eq!(&*fs.do_read(file.ino, 0, 8).unwrap(), b"data\0\0\0\0");
+        assert_eq!(&*fs.do_read(file.ino, (1 << 30) - 2, 8).unwrap(), [0, 0]);
+        fs.do_setattr(file.ino, None, None, None, Some(2), None, None, None).unwrap();
+        assert_eq!(&*fs.do_read(file.ino, 0, 8).unwrap(), b"da");
+        assert_counted(&fs);
+    }
+    #[test]
+    fn fallocate_keeps_holes_and_spilled_contents_out_of_memory(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, &[1; 3 * BLOCK_SIZE as usize]).unwrap();
+        fs.do_fallocate(file.ino, 0, 1 << 40, 0).unwrap();
+        fs.do_fallocate(file.ino, BLOCK_SIZE as i64, 1 << 39, libc::FALLOC_FL_ZERO_RANGE).unwrap();
+        assert!(matches!(fs.get_entry(file.ino), Some(FileStorageEntry::File(FileContent::Sparse(buffer, _), _)) if buffer.len() == 3 * BLOCK_SIZE as usize));
+        fs.do_fallocate(file.ino, 2 * BLOCK_SIZE as i64, (1 << 40) - 4 * BLOCK_SIZE as i64, libc::FALLOC_FL_COLLAPSE_RANGE).unwrap();
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 4 * BLOCK_SIZE);
+        let mut expected = vec![1; BLOCK_SIZE as usize];
+        expected.resize(4 * BLOCK_SIZE as usize, 0);
+        assert_eq!(&*fs.do_read(file.ino, 0, 4 * BLOCK_SIZE as u32).unwrap(), expected);
+        fs.do_release(file.ino, fh).unwrap();
+        assert_counted(&fs);
+
+        let mut fs = filesystem_with(Config{ spill_threshold: Some(0), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, &[1; 2 * BLOCK_SIZE as usize]).unwrap();
+        fs.do_fallocate(file.ino, 0, BLOCK_SIZE as i64, libc::FALLOC_FL_COLLAPSE_RANGE).unwrap();
+        assert!(matches!(fs.get_entry(file.ino), Some(FileStorageEntry::File(content, _)) if content.is_spilled()));
+        assert_eq!(&*fs.do_read(file.ino, 0, 4 * BLOCK_SIZE as u32).unwrap(), [1; BLOCK_SIZE as usize]);
+        fs.do_release(file.ino, fh).unwrap();
+        assert_counted(&fs);
+    }
+    #[test]
+    fn rmdir_fails_on_non_empty_directories(){
+        let mut fs = filesystem();
+        let dir = fs.do_mkdir(1, name("d"), 0o755, 0, 0).unwrap().ino;
+        let (file, fh) = fs.do_create(dir, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, &[1; 10000]).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(fs.do_rmdir(1, name("d")), Err(FsError::NotEmpty));
+        assert!(fs.do_lookup(dir, name("f")).is_ok());
+        assert_eq!(fs.do_rmdir(dir, name("f")), Err(FsError::NotADir));
+        fs.do_unlink(dir, name("f")).unwrap();
+        fs.do_rmdir(1, name("d")).unwrap();
+        assert_eq!(fs.do_lookup(1, name("d")).err(), Some(FsError::NotFound));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn bmap_maps_blocks_inside_the_file_only(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, &[1; 2 * BLOCK_SIZE as usize + 1]).unwrap();
+        //pending appends count towards the size
+        assert_eq!(fs.do_bmap(file.ino, BLOCK_SIZE as u32, 2), Ok(3));
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(fs.do_bmap(file.ino, BLOCK_SIZE as u32, 0), Ok(1));
+        assert_eq!(fs.do_bmap(file.ino, BLOCK_SIZE as u32, 3), Ok(0));
+        assert_eq!(fs.do_bmap(file.ino, 512, 16), Ok(17));
+        assert_eq!(fs.do_bmap(file.ino, 512, u64::MAX), Ok(0));
+        assert_eq!(fs.do_bmap(file.ino, 0, 0), Err(FsError::InvalidArgument));
+        assert_eq!(fs.do_bmap(1, 512, 0), Err(FsError::InvalidArgument));
+        assert_eq!(fs.do_bmap(99, 512, 0), Err(FsError::NotFound));
+    }
+    #[test]
+    fn reads_ahead_of_spilled_contents_return_the_same_data(){
+        let mut fs = filesystem_with(Config{ spill_threshold: Some(0), max_readahead: Some(1 << 20), ..Config::default() });
+        //the readahead is negotiated with the kernel, which init hasn't done yet
+        assert_eq!(fs.max_readahead(), 0);
+        fs.max_readahead = 1 << 20;
+        let (file, fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        let data = (0..3 * BLOCK_SIZE).map(|byte| byte as u8).collect::<Vec<_>>();
+        fs.do_write(file.ino, fh, 0, &data).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let mut read = Vec::new();
+        while read.len() < data.len(){
+            read.extend_from_slice(&fs.do_read(file.ino, read.len() as i64, 1000).unwrap());
+        }
+        assert_eq!(read, data);
+        assert!(fs.do_read(file.ino, data.len() as i64, 1000).unwrap().is_empty());
+    }
+    #[test]
+    fn exclusive_creates_racing_for_a_name_succeed_once(){
+        let shared = Arc::new(std::sync::Mutex::new(filesystem()));
+        let threads = (0..8).map(|_| {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                (0..16).filter(|round| {
+                    let mut fs = shared.lock().unwrap();
+                    match fs.do_create(1, name(&round.to_string()), 0o644, libc::O_CREAT | libc::O_EXCL, 0, 0){
+                        Ok((attr, fh)) => fs.do_release(attr.ino, fh).is_ok(),
+                        Err(error) => {
+                            assert_eq!(error, FsError::Exists);
+                            false
+                        }
+                    }
+                }).count()
+            })
+        }).collect::<Vec<_>>();
+        let created = threads.into_iter().map(|thread| thread.join().unwrap()).sum::<usize>();
+        assert_eq!(created, 16);
+        let mut fs = shared.lock().unwrap();
+        //without O_EXCL the existing file is opened instead
+        let existing = fs.do_lookup(1, name("0")).unwrap();
+        assert_eq!(fs.do_create(1, name("0"), 0o644, libc::O_CREAT, 0, 0).unwrap().0.ino, existing.ino);
+        assert_counted(&fs);
+    }
+    #[test]
+    fn insertion_order_lists_entries_as_they_were_created(){
+        let names = ["zeta", "alpha", "mu", "beta", "omega"];
+        let listing = |order: DirectoryOrder| {
+            let mut fs = filesystem_with(Config{ order, ..Config::default() });
+            for entry in names{
+                fs.do_mkdir(1, name(entry), 0o755, 0, 0).unwrap();
+            }
+            //renamed and recreated entries count as new ones
+            fs.do_rename(1, name("mu"), 1, name("nu"), 0).unwrap();
+            fs.do_rmdir(1, name("alpha")).unwrap();
+            fs.do_mkdir(1, name("alpha"), 0o755, 0, 0).unwrap();
+            fs.do_readdir(1).unwrap().into_iter().skip(2).map(|(_, _, name)| name).collect::<Vec<_>>()
+        };
+        assert_eq!(listing(DirectoryOrder::Insertion), ["zeta", "beta", "omega", "nu", "alpha"]);
+        let mut hashed = listing(DirectoryOrder::Hash);
+        hashed.sort();
+        assert_eq!(hashed, ["alpha", "beta", "nu", "omega", "zeta"]);
+    }
+    #[test]
+    fn filesystems_turn_read_only_after_the_configured_writes(){
+        let mut fs = filesystem_with(Config{ ro_after: Some(3), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        let directory = fs.do_mkdir(1, name("directory"), 0o755, 0, 0).unwrap();
+        for offset in 0..3{
+            fs.do_write(file.ino, fh, offset, b"x").unwrap();
+        }
+        assert_eq!(fs.do_write(file.ino, fh, 3, b"x"), Err(FsError::ReadOnly));
+        assert_eq!(fs.do_create(1, name("new"), 0o644, 0, 0, 0).unwrap_err(), FsError::ReadOnly);
+        assert_eq!(fs.do_mkdir(1, name("new"), 0o755, 0, 0).unwrap_err(), FsError::ReadOnly);
+        assert_eq!(fs.do_unlink(1, name("file")), Err(FsError::ReadOnly));
+        assert_eq!(fs.do_rmdir(1, name("directory")), Err(FsError::ReadOnly));
+        assert_eq!(fs.do_rename(1, name("file"), directory.ino, name("file"), 0), Err(FsError::ReadOnly));
+        assert_eq!(fs.do_setattr(file.ino, Some(0o600), None, None, None, None, None, None).unwrap_err(), FsError::ReadOnly);
+        //the buffered writes still apply and can be read back
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(&*fs.do_read(file.ino, 0, 10).unwrap(), b"xxx");
+        assert_eq!(FsError::ReadOnly.to_errno(), libc::EROFS);
+        assert_counted(&fs);
+    }
+    #[test]
+    fn attributes_report_the_kind_of_every_entry(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("file"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"data").unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let directory = fs.do_mkdir(1, name("directory"), 0o755, 0, 0).unwrap();
+        let symlink = fs.do_symlink(1, name("symlink"), Path::new("directory"), 0, 0).unwrap();
+        let attrs = |fs: &ICFS, ino: u64| fs.get_inode_attrs(ino).map(|attr| (attr.kind, attr.size, attr.rdev));
+        assert_eq!(attrs(&fs, file.ino), Some((FileType::RegularFile, 4, 0)));
+        assert_eq!(attrs(&fs, directory.ino), Some((FileType::Directory, BLOCK_SIZE, 0)));
+        assert_eq!(attrs(&fs, symlink.ino), Some((FileType::Symlink, "directory".len() as u64, 0)));
+        //whiteouts only mask lower entries and have no attributes of their own
+        let whiteout = FileStoragePath::root().with_pushed(name("whiteout"));
+        fs.files.whiteout(&whiteout);
+        let inode = fs.create_inode(whiteout);
+        assert_eq!(attrs(&fs, inode), None);
+        assert_eq!(fs.do_getattr(inode), Err(FsError::NotFound));
+    }
+    #[test]
+    fn quotas_follow_the_files_of_their_owner(){
+        let mut fs = filesystem_with(Config{ quotas: HashMap::from([(1000, 10000)]), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 1000, 1000).unwrap();
+        fs.do_write(file.ino, fh, 0, &[1; 6000]).unwrap();
+        assert_eq!(fs.do_write(file.ino, fh, 6000, &[1; 6000]), Err(FsError::QuotaExceeded));
+        fs.do_release(file.ino, fh).unwrap();
+        let (other, fh) = fs.do_create(1, name("g"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(other.ino, fh, 0, &[1; 5000]).unwrap();
+        fs.do_release(other.ino, fh).unwrap();
+        assert_eq!(fs.do_setattr(other.ino, None, Some(1000), None, None, None, None, None).err(), Some(FsError::QuotaExceeded));
+        fs.do_setattr(file.ino, None, None, None, Some(1000), None, None, None).unwrap();
+        fs.do_setattr(other.ino, None, Some(1000), None, None, None, None, None).unwrap();
+        assert_eq!(fs.owned_bytes().get(&1000), Some(&6000));
+        assert_counted(&fs);
+        let dir = fs.do_mkdir(1, name("d"), 0o755, 0, 0).unwrap().ino;
+        fs.do_rename(1, name("g"), dir, name("g"), 0).unwrap();
+        assert_counted(&fs);
+        fs.do_unlink(dir, name("g")).unwrap();
+        assert_eq!(fs.owned_bytes().get(&1000), Some(&1000));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn readdir_cookies_survive_changes_between_pages(){
+        let mut fs = filesystem_with(Config{ order: DirectoryOrder::Insertion, ..Config::default() });
+        for entry in ["a", "b", "c", "d", "e"]{
+            fs.do_mkdir(1, name(entry), 0o755, 0, 0).unwrap();
+        }
+        let fh = fs.do_opendir(1).unwrap();
+        let page = |fs: &mut ICFS, offset: i64| fs.do_readdir_from(1, fh, offset).unwrap().into_iter()
+            .map(|(cookie, (_, _, name))| (cookie, name.into_string().unwrap()))
+            .collect::<Vec<_>>();
+        let first = page(&mut fs, 0);
+        assert_eq!(first.iter().map(|(cookie, _)| *cookie).collect::<Vec<_>>(), [1, 2, 3, 4, 5, 6, 7]);
+        //the kernel only took the entries up to "b" before the directory changed
+        fs.do_rmdir(1, name("a")).unwrap();
+        fs.do_rmdir(1, name("d")).unwrap();
+        fs.do_mkdir(1, name("f"), 0o755, 0, 0).unwrap();
+        let rest = page(&mut fs, 4);
+        assert_eq!(rest, [(5, "c".to_string()), (6, "e".to_string()), (7, "f".to_string())]);
+        //the end of the listing stays the end
+        assert_eq!(page(&mut fs, 7), []);
+        assert_eq!(fs.do_readdir_from(1, fh + 1, 0).unwrap_err(), FsError::BadHandle);
+    }
+    #[test]
+    fn read_only_paths_protect_their_subtree_only(){
+        let protected = FileStoragePath::root().with_pushed(name("protected"));
+        let mut fs = filesystem_with(Config{ read_only_paths: vec![protected.with_pushed(name("sub"))], ..Config::default() });
+        let parent = fs.do_mkdir(1, name("protected"), 0o755, 0, 0).unwrap();
+        //the protected path itself can't be created either
+        assert_eq!(fs.do_mkdir(parent.ino, name("sub"), 0o755, 0, 0).unwrap_err(), FsError::ReadOnly);
+        let mut lower = FileStorage::new();
+        lower.insert(&protected, FileStorageEntry::Directory(Arc::default(), Metadata::now())).unwrap();
+        lower.insert(&protected.with_pushed(name("sub")), FileStorageEntry::Directory(Arc::default(), Metadata::now())).unwrap();
+        lower.insert(&protected.with_pushed(name("sub")).with_pushed(name("file")), FileStorageEntry::File(b"kept".to_vec().into(), Metadata::now())).unwrap();
+        let mut fs = ICFS::new(Overlay::new(lower, FileStorage::new()), Config{ read_only_paths: vec![protected.with_pushed(name("sub"))], ..Config::default() });
+        let parent = fs.do_lookup(1, name("protected")).unwrap();
+        let sub = fs.do_lookup(parent.ino, name("sub")).unwrap();
+        let file = fs.do_lookup(sub.ino, name("file")).unwrap();
+        assert_eq!(fs.do_create(sub.ino, name("new"), 0o644, 0, 0, 0).unwrap_err(), FsError::ReadOnly);
+        assert_eq!(fs.do_unlink(sub.ino, name("file")), Err(FsError::ReadOnly));
+        assert_eq!(fs.do_rename(sub.ino, name("file"), parent.ino, name("file"), 0), Err(FsError::ReadOnly));
+        assert_eq!(fs.do_rename(1, name("protected"), 1, name("moved"), 0), Err(FsError::ReadOnly));
+        assert_eq!(fs.do_write(file.ino, 0, 0, b"x"), Err(FsError::ReadOnly));
+        assert_eq!(fs.do_setxattr(file.ino, name("user.key"), b"1", 0), Err(FsError::ReadOnly));
+        assert_eq!(&*fs.do_read(file.ino, 0, 10).unwrap(), b"kept");
+        //the rest of the tree stays writable
+        fs.do_create(parent.ino, name("new"), 0o644, 0, 0, 0).unwrap();
+    }
+    #[test]
+    fn open_flags_check_the_kind_of_the_entry(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let dir = fs.do_mkdir(1, name("d"), 0o755, 0, 0).unwrap();
+        let link = fs.do_symlink(1, name("l"), Path::new("f"), 0, 0).unwrap();
+        assert_eq!(fs.do_open(file.ino, libc::O_RDONLY | libc::O_DIRECTORY).err(), Some(FsError::NotADir));
+        assert_eq!(fs.do_open(link.ino, libc::O_RDONLY | libc::O_NOFOLLOW).err(), Some(FsError::Loop));
+        let fh = fs.do_open(dir.ino, libc::O_RDONLY | libc::O_DIRECTORY).unwrap();
+        fs.do_release(dir.ino, fh).unwrap();
+        let fh = fs.do_open(file.ino, libc::O_RDONLY | libc::O_NOFOLLOW).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+    }
+    #[test]
+    fn injected_errors_only_hit_the_named_operations(){
+        let mut fs = filesystem_with(Config{ inject_errors: Some("read:EIO:1".parse().unwrap()), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        let written = fs.dispatch("write", |fs| fs.do_write(file.ino, fh, 0, b"landed"), || TraceRecord::new("write", file.ino));
+        assert_eq!(written, Ok(6));
+        let read = fs.dispatch("read", |fs| fs.do_read(file.ino, 0, 100), || TraceRecord::new("read", file.ino));
+        assert_eq!(read.err(), Some(libc::EIO));
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(&*fs.do_read(file.ino, 0, 100).unwrap(), b"landed");
+        //without injected errors, requests fail with the errno of their own error
+        let mut fs = filesystem();
+        let read = fs.dispatch("read", |fs| fs.do_read(file.ino, 0, 100), || TraceRecord::new("read", file.ino));
+        assert_eq!(read.err(), Some(libc::ENOENT));
+    }
+    #[test]
+    fn creating_over_a_directory_fails_and_over_a_file_opens_it(){
+        let mut fs = filesystem();
+        let dir = fs.do_mkdir(1, name("d"), 0o755, 0, 0).unwrap();
+        assert_eq!(fs.do_create(1, name("d"), 0o644, 0, 0, 0).err(), Some(FsError::IsADir));
+        assert_eq!(fs.do_getattr(dir.ino).unwrap().kind, FileType::Directory);
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"data").unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let (reopened, fh) = fs.do_create(1, name("f"), 0o600, 0, 0, 0).unwrap();
+        assert_eq!((reopened.ino, reopened.size, reopened.perm), (file.ino, 4, 0o644));
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(fs.do_create(1, name("f"), 0o644, libc::O_EXCL, 0, 0).err(), Some(FsError::Exists));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn reused_inodes_move_to_a_new_generation(){
+        let mut fs = filesystem();
+        let (first, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(first.ino, fh).unwrap();
+        assert_eq!(fs.generation(first.ino), 0);
+        let mut generations = vec![0];
+        for round in 0..3{
+            fs.do_unlink(1, name(if round == 0 { "f" } else { "g" })).unwrap();
+            fs.do_forget(first.ino);
+            let (reused, fh) = fs.do_create(1, name("g"), 0o644, 0, 0, 0).unwrap();
+            fs.do_release(reused.ino, fh).unwrap();
+            assert_eq!(reused.ino, first.ino);
+            generations.push(fs.generation(reused.ino));
+        }
+        assert_eq!(generations, [0, 1, 2, 3]);
+        assert_eq!(fs.generation(1), 0);
+    }
+    #[test]
+    fn free_inodes_at_the_top_are_compacted_away(){
+        let mut fs = filesystem();
+        let mut inodes = Vec::new();
+        for file in 0..1000{
+            let (attr, fh) = fs.do_create(1, name(&file.to_string()), 0o644, 0, 0, 0).unwrap();
+            fs.do_release(attr.ino, fh).unwrap();
+            inodes.push(attr.ino);
+        }
+        //a gap below inodes in use has to stay free
+        for file in [10, 999, 998, 500]{
+            fs.do_unlink(1, name(&file.to_string())).unwrap();
+            fs.do_forget(inodes[file]);
+        }
+        assert_eq!(fs.unused_inodes, HashSet::from([inodes[10], inodes[500]]));
+        for file in (0..998).rev().filter(|file| ![10, 500].contains(file)){
+            fs.do_unlink(1, name(&file.to_string())).unwrap();
+            fs.do_forget(inodes[file]);
+            assert!(fs.unused_inodes.len() <= 2);
+        }
+        assert!(fs.unused_inodes.is_empty() && fs.unused_inodes.capacity() <= 4 * 64);
+        let (attr, fh) = fs.do_create(1, name("new"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(attr.ino, fh).unwrap();
+        assert_eq!(attr.ino, 2);
+        assert_counted(&fs);
+    }
+    #[test]
+    fn new_directories_fill_up_to_their_initial_capacity_without_growing(){
+        let mut fs = filesystem_with(Config{ dir_initial_capacity: 1000, ..Config::default() });
+        let dir = fs.do_mkdir(1, name("d"), 0o755, 0, 0).unwrap();
+        let capacity = |fs: &ICFS, path: &FileStoragePath| match fs.files.lookup(path){
+            Some(FileStorageEntry::Directory(directory, _)) => directory.capacity(),
+            _ => unreachable!(),
+        };
+        let path = fs.inode_to_file[&dir.ino].clone();
+        let initial = capacity(&fs, &path);
+        assert!(initial >= 1000 && capacity(&fs, &FileStoragePath::root()) >= 1000);
+        for file in 0..1000{
+            let (attr, fh) = fs.do_create(dir.ino, name(&file.to_string()), 0o644, 0, 0, 0).unwrap();
+            fs.do_release(attr.ino, fh).unwrap();
+        }
+        assert_eq!(capacity(&fs, &path), initial);
+        assert!(capacity(&fs, &FileStoragePath::root()) >= 1000);
+    }
+    #[test]
+    fn directories_cannot_move_below_themselves(){
+        let mut fs = filesystem();
+        let dir = fs.do_mkdir(1, name("a"), 0o755, 0, 0).unwrap().ino;
+        let child = fs.do_mkdir(dir, name("b"), 0o755, 0, 0).unwrap().ino;
+        assert_eq!(fs.do_rename(1, name("a"), dir, name("a"), 0), Err(FsError::InvalidArgument));
+        assert_eq!(fs.do_rename(1, name("a"), child, name("c"), 0), Err(FsError::InvalidArgument));
+        assert_eq!(fs.do_lookup(dir, name("b")).unwrap().ino, child);
+        assert!(fs.do_readdir(child).unwrap().iter().all(|(_, _, name)| name == "." || name == ".."));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn writes_past_the_cap_are_cut_short(){
+        let mut fs = filesystem_with(Config{ max_write_size: Some(64 * 1024), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        let data = (0..1 << 20).map(|byte| byte as u8).collect::<Vec<_>>();
+        assert_eq!(fs.do_write(file.ino, fh, 0, &data), Ok(64 * 1024));
+        assert_eq!(fs.do_write(file.ino, fh, 64 * 1024, &data[..10]), Ok(10));
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 64 * 1024 + 10);
+        assert_eq!(&*fs.do_read(file.ino, 0, 64 * 1024).unwrap(), &data[..64 * 1024]);
+    }
+    #[test]
+    fn inode_0_is_never_handed_out(){
+        let mut fs = filesystem();
+        assert_eq!(fs.do_getattr(0), Err(FsError::NotFound));
+        assert_eq!(fs.do_lookup(0, name("f")), Err(FsError::NotFound));
+        for round in 0..64{
+            let inodes = (0..round % 8 + 1).map(|i| {
+                let (file, fh) = fs.do_create(1, name(&format!("{round}-{i}")), 0o644, 0, 0, 0).unwrap();
+                fs.do_release(file.ino, fh).unwrap();
+                file.ino
+            }).collect::<Vec<_>>();
+            assert!(!inodes.contains(&0));
+            //free every other new inode, so later rounds recycle them out of gaps as well as from the top
+            for (i, &ino) in inodes.iter().enumerate().filter(|(i, _)| i % 2 == round % 2){
+                fs.do_unlink(1, name(&format!("{round}-{i}"))).unwrap();
+                fs.remove_inode(ino);
+            }
+        }
+        assert!(!fs.unused_inodes.contains(&0) && !fs.inode_to_file.contains_key(&0));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn unmounting_saves_pending_writes_and_closes_the_trace(){
+        let backing = std::env::temp_dir().join(format!("icfs-destroy-test-{}", std::process::id()));
+        let trace = std::env::temp_dir().join(format!("icfs-destroy-trace-{}", std::process::id()));
+        let mut fs = filesystem_with(Config{ backing_file: Some(backing.clone()), trace_file: Some(trace.clone()), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        //appends through the still open handle are only buffered so far
+        fs.do_write(file.ino, fh, 0, b"unmounted").unwrap();
+        assert!(!backing.exists() && fs.tracer.is_some());
+        Filesystem::destroy(&mut fs);
+        assert!(fs.tracer.is_none());
+        let mut saved = ICFS::new(Overlay::new(FileStorage::new(), FileStorage::load(&backing).unwrap()), Config::default());
+        let file = saved.do_lookup(1, name("f")).unwrap();
+        assert_eq!(&*saved.do_read(file.ino, 0, 16).unwrap(), b"unmounted");
+        let _ = std::fs::remove_file(&backing);
+        let _ = std::fs::remove_file(&trace);
+    }
+    #[test]
+    fn mime_types_are_sniffed_and_read_only(){
+        let mut fs = filesystem();
+        let mime_type = OsStr::new(MIME_TYPE_XATTR);
+        let (file, fh) = fs.do_create(1, name("image"), 0o644, 0, 0, 0).unwrap();
+        //left in the write buffer, which sniffing has to see
+        fs.do_write(file.ino, fh, 0, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+        assert_eq!(fs.do_getxattr(file.ino, mime_type), Ok(b"image/png".to_vec()));
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(fs.do_setxattr(file.ino, mime_type, b"text/plain", 0), Err(FsError::NotPermitted));
+        assert_eq!(fs.do_removexattr(file.ino, mime_type), Err(FsError::NotPermitted));
+        assert_eq!(fs.do_getxattr(file.ino, mime_type), Ok(b"image/png".to_vec()));
+        let (text, fh) = fs.do_create(1, name("text"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(text.ino, fh).unwrap();
+        assert_eq!(fs.do_getxattr(text.ino, mime_type), Err(FsError::NoAttribute));
+        assert_eq!(fs.do_getxattr(1, mime_type), Err(FsError::NoAttribute));
+    }
+    #[test]
+    fn directory_umasks_replace_the_request_umask(){
+        let mut fs = filesystem();
+        assert_eq!(fs.creation_mode(1, 0o666, 0o022), 0o644);
+        let dir = fs.do_mkdir(1, name("d"), 0o755, 0, 0).unwrap().ino;
+        fs.do_setxattr(dir, name(DEFAULT_MODE_XATTR), b"077", 0).unwrap();
+        assert_eq!(fs.creation_mode(dir, 0o666, 0o022), 0o600);
+        let subdir = fs.do_mkdir(dir, name("s"), 0o700, 0, 0).unwrap().ino;
+        assert_eq!(fs.creation_mode(subdir, 0o777, 0), 0o700);
+        assert_eq!(fs.do_setxattr(dir, name(DEFAULT_MODE_XATTR), b"not octal", 0), Err(FsError::InvalidArgument));
+        fs.do_removexattr(dir, name(DEFAULT_MODE_XATTR)).unwrap();
+        assert_eq!(fs.creation_mode(dir, 0o666, 0o022), 0o644);
+    }
+    #[test]
+    fn contents_are_addressed_with_the_configured_algorithm(){
+        assert_eq!(filesystem().contents().algorithm(), HashAlgorithm::Xxh3);
+        let fs = filesystem_with(Config{ hash_algorithm: HashAlgorithm::Sha256, ..Config::default() });
+        assert_eq!(fs.contents().algorithm(), HashAlgorithm::Sha256);
+    }
+    #[test]
+    fn renames_touch_both_parents_and_the_change_time_of_the_entry(){
+        let mut fs = filesystem();
+        let from = fs.do_mkdir(1, name("from"), 0o755, 0, 0).unwrap();
+        let to = fs.do_mkdir(1, name("to"), 0o755, 0, 0).unwrap();
+        let (file, fh) = fs.do_create(from.ino, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let before = [from.ino, to.ino, file.ino].map(|ino| fs.do_getattr(ino).unwrap());
+        std::thread::sleep(Duration::from_millis(10));
+        fs.do_rename(from.ino, name("f"), to.ino, name("g"), 0).unwrap();
+        let [from_after, to_after, file_after] = [from.ino, to.ino, file.ino].map(|ino| fs.do_getattr(ino).unwrap());
+        for (before, after) in [(before[0], from_after), (before[1], to_after)]{
+            assert!(after.mtime > before.mtime && after.ctime > before.ctime);
+        }
+        assert!(file_after.ctime > before[2].ctime);
+        assert_eq!(file_after.mtime, before[2].mtime);
+        //renames within a directory leave the directories above it alone
+        let root = fs.do_getattr(1).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        fs.do_rename(to.ino, name("g"), to.ino, name("h"), 0).unwrap();
+        assert!(fs.do_getattr(to.ino).unwrap().mtime > to_after.mtime);
+        assert_eq!(fs.do_getattr(1).unwrap().mtime, root.mtime);
+    }
+    #[test]
+    fn virtual_xattrs_are_only_listed_when_enabled(){
+        for list_virtual_xattrs in [false, true]{
+            let mut fs = filesystem_with(Config{ list_virtual_xattrs, ..Config::default() });
+            let (file, fh) = fs.do_create(1, name("image"), 0o644, 0, 0, 0).unwrap();
+            fs.do_write(file.ino, fh, 0, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+            fs.do_release(file.ino, fh).unwrap();
+            fs.do_setxattr(file.ino, name("user.stored"), b"x", 0).unwrap();
+            let expected = if list_virtual_xattrs { b"user.stored\0user.mime_type\0".to_vec() } else { b"user.stored\0".to_vec() };
+            assert_eq!(fs.do_listxattr(file.ino), Ok(expected));
+            assert_eq!(fs.do_getxattr(file.ino, OsStr::new(MIME_TYPE_XATTR)), Ok(b"image/png".to_vec()));
+            //directories have no type to list
+            assert_eq!(fs.do_listxattr(1), Ok(Vec::new()));
+        }
+    }
+    #[test]
+    fn content_operations_fail_once_the_inode_resolves_to_another_kind(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"data").unwrap();
+        let path = fs.inode_to_file[&file.ino].clone();
+        //swap the entry behind the open handle, the way a racing rename could
+        for (entry, error) in [
+            (FileStorageEntry::Directory(Arc::default(), Metadata::now()), FsError::IsADir),
+            (FileStorageEntry::Symlink("target".into(), Metadata::now()), FsError::InvalidArgument),
+        ]{
+            fs.flush_all_writes().unwrap();
+            fs.files.remove(&path).unwrap();
+            fs.files.insert(&path, entry).unwrap();
+            assert_eq!(fs.do_write(file.ino, fh, 4, b"more"), Err(error));
+            assert_eq!(fs.do_read(file.ino, 0, 4).err(), Some(error));
+            assert_eq!(fs.do_fallocate(file.ino, 0, 8, 0), Err(error));
+            assert_eq!(fs.do_setattr(file.ino, None, None, None, Some(0), None, None, None).err(), Some(error));
+        }
+        fs.do_release(file.ino, fh).unwrap();
+    }
+    #[test]
+    fn subscribers_see_every_change_in_order(){
+        assert_eq!(filesystem().subscribe().err(), Some(FsError::Unsupported));
+        let mut fs = filesystem_with(Config{ watch: true, ..Config::default() });
+        let events = fs.subscribe().unwrap();
+        fs.do_mkdir(1, name("d"), 0o755, 0, 0).unwrap();
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"data").unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        fs.do_rename(1, name("f"), 1, name("g"), 0).unwrap();
+        fs.do_unlink(1, name("g")).unwrap();
+        fs.do_rmdir(1, name("d")).unwrap();
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), [
+            r#"{"event":"create","path":"/d"}"#,
+            r#"{"event":"create","path":"/f"}"#,
+            r#"{"event":"modify","path":"/f"}"#,
+            r#"{"event":"rename","path":"/f","new_path":"/g"}"#,
+            r#"{"event":"delete","path":"/g"}"#,
+            r#"{"event":"delete","path":"/d"}"#,
+        ]);
+    }
+    #[test]
+    fn host_statfs_reports_the_filesystem_of_the_backing_file(){
+        let directory = std::env::temp_dir();
+        let mut fs = filesystem_with(Config{
+            statfs: StatfsSource::Host, backing_file: Some(directory.join("icfs-host-statfs-test")), size: Some(BLOCK_SIZE), ..Config::default()
+        });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"counted against the size only").unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let stats = fs.do_statfs().unwrap();
+        let path = std::ffi::CString::new(directory.as_os_str().as_bytes()).unwrap();
+        let mut host = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        //the path is null terminated and statvfs fills in the whole struct when it succeeds
+        let host = unsafe{
+            assert_eq!(libc::statvfs(path.as_ptr(), host.as_mut_ptr()), 0);
+            host.assume_init()
+        };
+        assert_eq!((stats.blocks, stats.block_size), (host.f_blocks, host.f_frsize as u32));
+        //other programs may use the host filesystem in the meantime
+        assert!(stats.free.abs_diff(host.f_bfree) < host.f_blocks / 100 + 1024);
+        assert!(stats.available <= stats.free);
+        assert_eq!(stats.files, host.f_files);
+        //synthetic space ignores the host
+        fs.config.statfs = StatfsSource::Synthetic;
+        let (files, free_files) = (u64::MAX, u64::MAX - fs.used_inodes());
+        assert_eq!(fs.do_statfs().unwrap(), space::FsStats{ blocks: 1, free: 0, available: 0, files, free_files, block_size: BLOCK_SIZE as u32 });
+        fs.config.statfs = StatfsSource::Host;
+        fs.config.backing_file = Some(directory.join("icfs-missing-directory/backing"));
+        assert_eq!(fs.do_statfs(), Err(FsError::Io));
+    }
+    #[test]
+    fn whole_file_copies_share_contents_until_either_is_written(){
+        let mut fs = filesystem();
+        let (source, fh_in) = fs.do_create(1, name("source"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(source.ino, fh_in, 0, b"shared contents").unwrap();
+        let (copy, fh_out) = fs.do_create(1, name("copy"), 0o644, 0, 0, 0).unwrap();
+        assert_eq!(fs.do_copy_file_range(source.ino, 0, copy.ino, fh_out, 0, u64::MAX, 0), Ok(15));
+        let buffer = |fs: &ICFS, ino: u64| match fs.get_entry(ino){
+            Some(FileStorageEntry::File(FileContent::Memory(buffer), _)) => buffer.clone(),
+            _ => unreachable!(),
+        };
+        assert!(Arc::ptr_eq(&buffer(&fs, source.ino), &buffer(&fs, copy.ino)));
+        assert_counted(&fs);
+        fs.do_write(copy.ino, fh_out, 0, b"SHARED").unwrap();
+        fs.do_release(copy.ino, fh_out).unwrap();
+        assert!(!Arc::ptr_eq(&buffer(&fs, source.ino), &buffer(&fs, copy.ino)));
+        assert_eq!(&*fs.do_read(source.ino, 0, 100).unwrap(), b"shared contents");
+        assert_eq!(&*fs.do_read(copy.ino, 0, 100).unwrap(), b"SHARED contents");
+        //partial ranges are copied through the write path
+        let (partial, fh_out) = fs.do_create(1, name("partial"), 0o644, 0, 0, 0).unwrap();
+        assert_eq!(fs.do_copy_file_range(source.ino, 7, partial.ino, fh_out, 2, 4, 0), Ok(4));
+        fs.do_release(partial.ino, fh_out).unwrap();
+        assert_eq!(&*fs.do_read(partial.ino, 0, 100).unwrap(), b"\0\0cont");
+        assert_eq!(fs.do_copy_file_range(source.ino, -1, partial.ino, 0, 0, 4, 0), Err(FsError::InvalidArgument));
+        assert_eq!(fs.do_copy_file_range(1, 0, partial.ino, 0, 0, 4, 0), Err(FsError::IsADir));
+        fs.do_release(source.ino, fh_in).unwrap();
+        assert_counted(&fs);
+    }
+    #[test]
+    fn truncating_opens_give_back_space_and_quota(){
+        let mut fs = filesystem_with(Config{ size: Some(64 * BLOCK_SIZE), quotas: HashMap::from([(1000, 64 * BLOCK_SIZE)]), ..Config::default() });
+        let data = vec![1; 16 * BLOCK_SIZE as usize];
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 1000, 1000).unwrap();
+        fs.do_write(file.ino, fh, 0, &data).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!((fs.used_blocks(), fs.owned_bytes()[&1000]), (16, 16 * BLOCK_SIZE));
+        let fh = fs.do_open(file.ino, libc::O_WRONLY | libc::O_TRUNC).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!((fs.used_blocks(), fs.owned_bytes()[&1000]), (0, 0));
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 0);
+        assert_counted(&fs);
+        //creating over the file truncates it the same way
+        let fh = fs.do_open(file.ino, libc::O_WRONLY).unwrap();
+        fs.do_write(file.ino, fh, 0, &data).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let (_, fh) = fs.do_create(1, name("f"), 0o644, libc::O_TRUNC, 1000, 1000).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!((fs.used_blocks(), fs.owned_bytes()[&1000]), (0, 0));
+        assert_counted(&fs);
+    }
+    #[test]
+    fn truncating_opens_leave_the_file_alone_when_no_handle_is_left(){
+        let mut fs = filesystem_with(Config{ max_open_files: Some(1), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, b"contents").unwrap();
+        assert_eq!(fs.do_open(file.ino, libc::O_WRONLY | libc::O_TRUNC), Err(FsError::TooManyOpenFiles));
+        assert_eq!(fs.do_getattr(file.ino).unwrap().size, 8);
+        assert_eq!(&*fs.do_read(file.ino, 0, 100).unwrap(), b"contents");
+        fs.do_release(file.ino, fh).unwrap();
+        assert_counted(&fs);
+    }
+    #[test]
+    fn running_counts_match_a_recount_after_every_operation(){
+        let mut fs = filesystem_with(Config{ size: Some(1024 * BLOCK_SIZE), ..Config::default() });
+        let check = |fs: &ICFS, step: &str| {
+            assert_eq!(fs.used_blocks(), fs.count_used_blocks(), "{step}");
+            let owned = fs.owned_bytes().iter().filter(|(_, bytes)| **bytes > 0).map(|(uid, bytes)| (*uid, *bytes)).collect::<HashMap<_, _>>();
+            assert_eq!(owned, fs.count_owned_bytes().into_iter().filter(|(_, bytes)| *bytes > 0).collect(), "{step}");
+            assert_eq!(fs.do_statfs().unwrap().free, 1024 - fs.count_used_blocks(), "{step}");
+            assert_eq!(fs.used_inodes(), fs.count_used_inodes(), "{step}");
+            let stats = fs.do_statfs().unwrap();
+            assert_eq!(stats.files - stats.free_files, fs.count_used_inodes(), "{step}");
+            fs.check_consistency().unwrap();
+        };
+        let dir = fs.do_mkdir(1, name("d"), 0o755, 0, 0).unwrap();
+        check(&fs, "mkdir");
+        for round in 0..50u32{
+            let file = round.to_string();
+            let file = name(&file);
+            let (attr, fh) = fs.do_create(dir.ino, file, 0o644, 0, round % 3, 0).unwrap();
+            check(&fs, "create");
+            fs.do_write(attr.ino, fh, 0, &vec![1; (round as usize + 1) * 1000]).unwrap();
+            check(&fs, "write");
+            fs.do_write(attr.ino, fh, (round as i64 + 1) * 1000, b"append").unwrap();
+            check(&fs, "buffered append");
+            fs.do_release(attr.ino, fh).unwrap();
+            check(&fs, "release");
+            fs.do_setattr(attr.ino, None, None, None, Some(round as u64 * 700), None, None, None).unwrap();
+            check(&fs, "truncate");
+            fs.do_setattr(attr.ino, None, Some(round % 2), None, None, None, None, None).unwrap();
+            check(&fs, "chown");
+            match round % 4{
+                0 => fs.do_unlink(dir.ino, file).unwrap(),
+                1 => fs.do_rename(dir.ino, file, 1, file, 0).unwrap(),
+                2 if round > 2 => fs.do_rename(dir.ino, file, dir.ino, name(&(round - 2).to_string()), 0).unwrap(),
+                _ => fs.do_forget(attr.ino),
+            }
+            check(&fs, "unlink, rename or forget");
+            let link = format!("link{round}");
+            fs.do_symlink(dir.ino, name(&link), Path::new("target"), 0, 0).unwrap();
+            check(&fs, "symlink");
+            let subdir = format!("dir{round}");
+            fs.do_mkdir(dir.ino, name(&subdir), 0o755, 0, 0).unwrap();
+            check(&fs, "mkdir");
+            if round % 2 == 0{
+                fs.do_rmdir(dir.ino, name(&subdir)).unwrap();
+                check(&fs, "rmdir");
+            } else {
+                fs.do_mkdir(1, name(&subdir), 0o755, 0, 0).unwrap();
+                fs.do_rename(1, name(&subdir), dir.ino, name(&subdir), 0).unwrap();
+                check(&fs, "rename over an empty directory");
+            }
+            //creating over an existing file opens it without adding an entry
+            let again = format!("again{round}");
+            let (attr, fh) = fs.do_create(dir.ino, name(&again), 0o644, 0, 0, 0).unwrap();
+            fs.do_release(attr.ino, fh).unwrap();
+            let (attr, fh) = fs.do_create(dir.ino, name(&again), 0o644, 0, 0, 0).unwrap();
+            fs.do_release(attr.ino, fh).unwrap();
+            check(&fs, "create over an existing entry");
+        }
+        fs.do_rename(1, name("d"), 1, name("e"), 0).unwrap();
+        check(&fs, "directory rename");
+        for (file, attr) in fs.do_readdirplus(dir.ino).unwrap().into_iter().filter(|(name, _)| name != "." && name != ".."){
+            match attr.kind{
+                FileType::Directory => fs.do_rmdir(dir.ino, &file).unwrap(),
+                _ => fs.do_unlink(dir.ino, &file).unwrap(),
+            }
+            check(&fs, "unlink or rmdir");
+        }
+        fs.do_rmdir(1, name("e")).unwrap();
+        check(&fs, "rmdir");
+    }
+    #[test]
+    fn failed_checked_moves_are_internal_errors(){
+        let mut fs = filesystem();
+        let missing = FileStoragePath::root().with_pushed(name("missing"));
+        assert_eq!(fs.move_checked(&missing, &FileStoragePath::root().with_pushed(name("moved"))), Err(FsError::Internal));
+    }
+    #[test]
+    #[should_panic(expected = "checked rename failed")]
+    fn failed_checked_moves_panic_on_bug(){
+        let mut fs = filesystem_with(Config{ panic_on_bug: true, ..Config::default() });
+        let missing = FileStoragePath::root().with_pushed(name("missing"));
+        let _ = fs.move_checked(&missing, &FileStoragePath::root().with_pushed(name("moved")));
+    }
+    #[test]
+    fn renames_racing_read_only_apply_as_a_whole(){
+        let mut fs = filesystem_with(Config{ ro_after: Some(200), ..Config::default() });
+        let a = fs.do_mkdir(1, name("a"), 0o755, 0, 0).unwrap().ino;
+        let b = fs.do_mkdir(1, name("b"), 0o755, 0, 0).unwrap().ino;
+        let (scratch, fh) = fs.do_create(1, name("scratch"), 0o644, 0, 0, 0).unwrap();
+        fs.do_mkdir(a, name("d"), 0o755, 0, 0).unwrap();
+        for file in ["e", "f", "g"]{
+            let (file, fh) = fs.do_create(a, name(file), 0o644, 0, 0, 0).unwrap();
+            fs.do_release(file.ino, fh).unwrap();
+        }
+        let shared = Arc::new(std::sync::Mutex::new(fs));
+        let threads = ["d", "e", "f", "g"].map(|entry| {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                let mut at = a;
+                for _ in 0..100{
+                    let mut fs = shared.lock().unwrap();
+                    //every write brings the filesystem closer to turning read-only halfway through
+                    let _ = fs.do_write(scratch.ino, fh, 0, b"x");
+                    let to = if at == a { b } else { a };
+                    if fs.do_rename(at, name(entry), to, name(entry), 0).is_ok(){
+                        at = to;
+                    }
+                    let found = [a, b].map(|parent| fs.do_lookup(parent, name(entry)).is_ok());
+                    assert_eq!(found, [at == a, at == b]);
+                    fs.check_consistency().unwrap();
+                }
+            })
+        });
+        for thread in threads{
+            thread.join().unwrap();
+        }
+        assert_eq!(shared.lock().unwrap().do_rename(a, name("d"), b, name("x"), 0), Err(FsError::ReadOnly));
+    }
+    #[test]
+    fn selinux_labels_fall_back_to_the_configured_context(){
+        let selinux = OsStr::new(SELINUX_XATTR);
+        let context = b"system_u:object_r:fusefs_t:s0".to_vec();
+        assert_eq!(filesystem().do_getxattr(1, selinux), Err(FsError::NoAttribute));
+        let mut fs = filesystem_with(Config{ selinux_context: Some(context.clone()), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(fs.do_getxattr(file.ino, selinux), Ok(context.clone()));
+        assert_eq!(fs.do_listxattr(file.ino), Ok(b"security.selinux\0".to_vec()));
+        //the default counts as a label that already exists
+        assert_eq!(fs.do_setxattr(file.ino, selinux, b"other", libc::XATTR_CREATE), Err(FsError::Exists));
+        fs.do_setxattr(file.ino, selinux, b"user_u:object_r:tmp_t:s0", libc::XATTR_REPLACE).unwrap();
+        assert_eq!(fs.do_getxattr(file.ino, selinux), Ok(b"user_u:object_r:tmp_t:s0".to_vec()));
+        assert_eq!(fs.do_listxattr(file.ino), Ok(b"security.selinux\0".to_vec()));
+        assert_eq!(fs.do_getxattr(1, selinux), Ok(context.clone()));
+        fs.do_removexattr(file.ino, selinux).unwrap();
+        assert_eq!(fs.do_getxattr(file.ino, selinux), Ok(context));
+    }
+    #[test]
+    fn trimming_shrinks_the_backing_file_to_the_live_tree(){
+        assert_eq!(filesystem().do_trim(), Err(FsError::InvalidArgument));
+        let backing = std::env::temp_dir().join(format!("icfs-trim-test-{}", std::process::id()));
+        let mut fs = filesystem_with(Config{ backing_file: Some(backing.clone()), ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("big"), 0o644, 0, 0, 0).unwrap();
+        fs.do_write(file.ino, fh, 0, &vec![1; 64 * 1024]).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        fs.do_syncfs().unwrap();
+        let grown = std::fs::metadata(&backing).unwrap().len();
+        assert!(grown > 64 * 1024);
+        fs.do_unlink(1, name("big")).unwrap();
+        let reclaimed = fs.do_trim().unwrap();
+        assert!(reclaimed >= 64 * 1024);
+        assert_eq!(std::fs::metadata(&backing).unwrap().len(), grown - reclaimed);
+        assert_eq!(fs.do_trim(), Ok(0));
+        let _ = std::fs::remove_file(&backing);
+    }
+    #[test]
+    fn links_and_renames_across_snapshots_are_cross_device(){
+        let mut fs = filesystem();
+        let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        let dir = fs.do_mkdir(1, name("d"), 0o755, 0, 0).unwrap();
+        fs.do_snapshot(name("s")).unwrap();
+        fs.do_snapshot(name("t")).unwrap();
+        let snapshots = fs.do_lookup(1, name(storage::SNAPSHOTS)).unwrap().ino;
+        let [s, t] = ["s", "t"].map(|snapshot| fs.do_lookup(snapshots, name(snapshot)).unwrap().ino);
+        let copy = fs.do_lookup(s, name("f")).unwrap().ino;
+        assert_eq!(fs.do_link(file.ino, s, name("g")).err(), Some(FsError::CrossDevice));
+        assert_eq!(fs.do_link(copy, 1, name("g")).err(), Some(FsError::CrossDevice));
+        assert_eq!(fs.do_link(copy, t, name("g")).err(), Some(FsError::CrossDevice));
+        assert_eq!(fs.do_link(file.ino, dir.ino, name("g")).err(), Some(FsError::NotPermitted));
+        assert_eq!(fs.do_rename(1, name("f"), s, name("f"), 0), Err(FsError::CrossDevice));
+        assert_eq!(fs.do_rename(s, name("f"), 1, name("g"), 0), Err(FsError::CrossDevice));
+        assert_eq!(fs.do_rename(s, name("f"), t, name("g"), 0), Err(FsError::CrossDevice));
+        assert_eq!(fs.do_rename(1, name("d"), snapshots, name("d"), 0), Err(FsError::CrossDevice));
+        //within a snapshot the rename is refused for being read-only instead
+        assert_eq!(fs.do_rename(s, name("f"), s, name("g"), 0), Err(FsError::ReadOnly));
+        fs.do_rename(1, name("f"), dir.ino, name("f"), 0).unwrap();
+        assert_eq!(FsError::CrossDevice.to_errno(), libc::EXDEV);
+        assert_counted(&fs);
+    }
+    #[test]
+    fn large_reads_reassemble_from_kernel_sized_chunks(){
+        let contents = (0..10 << 20).map(|i: u32| (i % 251) as u8).collect::<Vec<_>>();
+        for config in [Config::default(), Config{ spill_threshold: Some(1 << 20), ..Config::default() }]{
+            let mut fs = filesystem_with(config);
+            let (file, fh) = fs.do_create(1, name("f"), 0o644, 0, 0, 0).unwrap();
+            for (i, chunk) in contents.chunks(1 << 20).enumerate(){
+                fs.do_write(file.ino, fh, (i << 20) as i64, chunk).unwrap();
+            }
+            fs.do_release(file.ino, fh).unwrap();
+            for size in [4096, 128 << 10, (128 << 10) - 1]{
+                let mut read = Vec::new();
+                loop{
+                    let data = fs.do_read(file.ino, read.len() as i64, size).unwrap();
+                    assert!(data.len() <= size as usize);
+                    if data.is_empty(){
+                        break;
+                    }
+                    read.extend_from_slice(&data);
+                }
+                assert!(read == contents);
+            }
+            assert_eq!(fs.do_read(file.ino, -1, 4096).err(), Some(FsError::InvalidArgument));
+        }
+    }
+    #[test]
+    fn denying_exec_hides_and_refuses_execute_bits_on_files(){
+        let mut fs = filesystem_with(Config{ deny_exec: true, ..Config::default() });
+        let (file, fh) = fs.do_create(1, name("f"), 0o755, 0, 1000, 1000).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(file.perm, 0o644);
+        assert_eq!(fs.do_getattr(file.ino).unwrap().perm, 0o644);
+        assert_eq!(fs.do_setattr(file.ino, Some(0o100), None, None, None, None, None, None).err(), Some(FsError::NotPermitted));
+        assert_eq!(fs.do_setattr(file.ino, Some(0o600), None, None, None, None, None, None).unwrap().perm, 0o600);
+        for uid in [0, 1000]{
+            assert_eq!(fs.do_access(file.ino, uid, 1000, libc::X_OK), Err(FsError::PermissionDenied));
+            assert_eq!(fs.do_access(file.ino, uid, 1000, libc::R_OK), Ok(()));
+        }
+        //directories keep the bits that let them be searched
+        let dir = fs.do_mkdir(1, name("d"), 0o755, 1000, 1000).unwrap();
+        assert_eq!(dir.perm, 0o755);
+        assert_eq!(fs.do_setattr(dir.ino, Some(0o711), None, None, None, None, None, None).unwrap().perm, 0o711);
+        assert_eq!(fs.do_access(dir.ino, 1000, 1000, libc::X_OK), Ok(()));
+        //the stored bits are reported again without the option
+        fs.config.deny_exec = false;
+        let (file, fh) = fs.do_create(1, name("g"), 0o755, 0, 1000, 1000).unwrap();
+        fs.do_release(file.ino, fh).unwrap();
+        assert_eq!(file.perm, 0o755);
+    }
+}
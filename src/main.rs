@@ -1,427 +1,810 @@
-use std::collections::{HashMap, HashSet};
 use std::env;
-use std::ffi::{OsStr, OsString};
-use std::time::{Duration, SystemTime};
-use fuser::{FileAttr, Filesystem, FileType, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyWrite, Request};
-use fuser::MountOption::NoSuid;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use fuser::{Filesystem, MountOption};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use icfs::{AtimePolicy, Config, DirectoryOrder, ICFS};
+use icfs::content::FileContent;
+use icfs::serve::{self, SharedFilesystem};
+use icfs::space::StatfsSource;
+use icfs::storage::{self, FileStorage, FileStorageEntry, FileStoragePath, Metadata, Overlay, PathChange};
 
-struct ICFS{
-    files: FileStorage,
-    inode_to_file: HashMap<u64, FileStoragePath>,
-    file_to_inode: HashMap<FileStoragePath, u64>,
-    unused_inodes: HashSet<u64>,
+struct Options{
+    mountpoint: Option<String>,
+    lower: Option<PathBuf>,
+    import_tar: Option<PathBuf>,
+    strip_import_prefix: usize,
+    import_exclude: GlobSet,
+    export_tar: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    diff: Option<PathBuf>,
+    trim: bool,
+    serve_addr: Option<String>,
+    max_concurrency: Option<usize>,
+    hash_seed: Option<u64>,
+    name_table: bool,
+    empty_on_start: bool,
+    mount_options: Vec<MountOption>,
+    mkdir_mountpoint: bool,
+    config: Config,
 }
-impl ICFS{
-    pub fn new() -> Self{
-        let mut fs = ICFS{
-            files: FileStorage::new(),
-            inode_to_file: HashMap::new(),
-            file_to_inode: HashMap::new(),
-            unused_inodes: HashSet::new()
-        };
-        fs.create_inode(FileStoragePath::root());
-        fs
-    }
-    pub fn create_inode(&mut self, path: FileStoragePath) -> u64{
-        if let Some(inode) = self.file_to_inode.get(&path){
-            return *inode;
-        }
-        let inode = if let Some(inode) = self.unused_inodes.iter().next().cloned(){
-            self.unused_inodes.remove(&inode);
-            inode
-        } else {
-            self.inode_to_file.len() as u64 + 1
-        };
-        self.file_to_inode.insert(path.clone(), inode);
-        self.inode_to_file.insert(inode, path);
-        inode
-    }
-    pub fn remove_inode(&mut self, inode: u64) {
-        let path = if let Some(path) = self.inode_to_file.remove(&inode){
-            path
-        } else {
-            eprintln!("trying to remove non-existent inode");
-            return;
-        };
-        self.file_to_inode.remove(&path);
-        self.unused_inodes.insert(inode);
-    }
-    pub fn get_entry(&self, inode: u64) -> Option<&FileStorageEntry>{
-        let path = if let Some(path) = self.inode_to_file.get(&inode){
-            path
-        } else {
-            return None;
-        };
-        self.files.lookup(path)
-    }
-    pub fn get_entry_mut(&mut self, inode: u64) -> Option<&mut FileStorageEntry>{
-        let path = if let Some(path) = self.inode_to_file.get(&inode){
-            path
-        } else {
-            return None;
-        };
-        self.files.lookup_mut(path)
-    }
-    pub fn get_inode_attrs(&self, inode: u64) -> FileAttr{
-        let entry = self.get_entry(inode).unwrap();
-        let ts = SystemTime::UNIX_EPOCH;
-        FileAttr {
-            ino: inode,
-            size: match entry{
-                FileStorageEntry::File(data) => data.len() as u64,
-                FileStorageEntry::Directory(_) => 0,
-            },
-            blocks: 0,
-            atime: ts,
-            mtime: ts,
-            ctime: ts,
-            crtime: ts,
-            kind: match entry{
-                FileStorageEntry::File(_) => FileType::RegularFile,
-                FileStorageEntry::Directory(_) => FileType::Directory
-            },
-            perm: 0o777,
-            nlink: 0,
-            uid: 0,
-            gid: 0,
-            rdev: 0,
-            blksize: 0,
-            flags: 0,
-        }
+impl Options{
+    pub fn parse() -> Result<Self, String>{
+        Self::parse_args(env::args().skip(1))
     }
-}
-
-impl Filesystem for ICFS {
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let entry = self.get_entry(parent);
-        match entry{
-            Some(entry) => {
-                match entry{
-                    FileStorageEntry::File(_) => {
-                        reply.error(libc::ENOTDIR);
-                    }
-                    FileStorageEntry::Directory(directory) => {
-                        if !directory.contains_key(name){
-                            reply.error(libc::ENOENT);
-                            return;
-                        }
-                        let inode = self.create_inode(self.inode_to_file.get(&parent).unwrap().with_pushed(name));
-                        reply.entry(&Duration::new(1, 0), &self.get_inode_attrs(inode), 0);
-                    }
+    fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Self, String>{
+        let mut mountpoint = None;
+        let mut lower = None;
+        let mut import_tar = None;
+        let mut strip_import_prefix = 0;
+        let mut import_exclude = GlobSetBuilder::new();
+        let mut excluding = false;
+        let mut export_tar = None;
+        let mut replay = None;
+        let mut diff = None;
+        let mut trim = false;
+        let mut serve_addr = None;
+        let mut max_concurrency = None;
+        let mut hash_seed = None;
+        let mut name_table = false;
+        let mut empty_on_start = false;
+        let mut mkdir_mountpoint = false;
+        let mut mount_options = vec![MountOption::AllowOther, MountOption::AutoUnmount, MountOption::NoSuid];
+        let mut config = Config::default();
+        let mut args = args.into_iter().flat_map(split_inline_value);
+        while let Some(arg) = args.next(){
+            match arg.as_str(){
+                "--lower" => {
+                    lower = Some(PathBuf::from(args.next().ok_or("--lower requires a path")?));
                 }
-            }
-            None => {
-                reply.error(libc::ENOENT)
-            }
-        }
-    }
-    fn forget(&mut self, _req: &Request<'_>, ino: u64, _nlookup: u64) {
-        println!("forget inode {ino}");
-        self.remove_inode(ino);
-    }
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        let entry = self.get_entry(ino);
-        match entry{
-            Some(_) => {
-                let ttl = Duration::new(1, 0);
-                reply.attr(&ttl, &self.get_inode_attrs(ino));
-            }
-            None => {
-                reply.error(libc::ENOENT);
-            }
-        }
-    }
-    fn mkdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, _umask: u32, reply: ReplyEntry) {
-        let entry = self.get_entry_mut(parent);
-        match entry{
-            Some(entry) => {
-                match entry {
-                    FileStorageEntry::File(_) => {
-                        reply.error(libc::ENOTDIR);
-                    }
-                    FileStorageEntry::Directory(directory) => {
-                        if directory.contains_key(name){
-                            reply.error(libc::EEXIST);
-                            return;
-                        }
-                        directory.insert(name.to_os_string(), FileStorageEntry::Directory(HashMap::new()));
-                        let inode = self.create_inode(self.inode_to_file.get(&parent).unwrap().with_pushed(name));
-                        reply.entry(&Duration::new(1, 0), &self.get_inode_attrs(inode), 0);
-                    }
+                "--import-tar" => {
+                    import_tar = Some(PathBuf::from(args.next().ok_or("--import-tar requires a path")?));
                 }
-            }
-            None => {
-                reply.error(libc::ENOENT);
-            }
-        }
-    }
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let entry = self.get_entry_mut(parent);
-        match entry{
-            Some(entry) => {
-                match entry {
-                    FileStorageEntry::File(_) => {
-                        reply.error(libc::ENOTDIR);
-                    }
-                    FileStorageEntry::Directory(directory) => {
-                        directory.remove(name);
-                        reply.ok();
-                    }
+                "--strip-import-prefix" => {
+                    strip_import_prefix = parse_value(&arg, args.next())?;
                 }
-            }
-            None => {
-                reply.error(libc::ENOENT);
-            }
-        }
-    }
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let entry = self.get_entry_mut(parent);
-        match entry{
-            Some(entry) => {
-                match entry {
-                    FileStorageEntry::File(_) => {
-                        reply.error(libc::ENOTDIR);
-                    }
-                    FileStorageEntry::Directory(directory) => {
-                        directory.remove(name);
-                        reply.ok();
-                    }
+                "--import-exclude" => {
+                    let value = args.next().ok_or("--import-exclude requires a pattern")?;
+                    import_exclude.add(Glob::new(&value).map_err(|error| format!("invalid pattern {value} for --import-exclude: {error}"))?);
+                    excluding = true;
                 }
-            }
-            None => {
-                reply.error(libc::ENOENT);
-            }
-        }
-    }
-    fn read(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
-        let entry = self.get_entry(ino);
-        match entry{
-            Some(entry) => {
-                match entry {
-                    FileStorageEntry::File(buffer) => {
-                        let offset = offset as usize;
-                        let size = size as usize;
-                        reply.data(&buffer[offset.min(buffer.len())..(offset+size).min(buffer.len())]);
-                    }
-                    FileStorageEntry::Directory(_) => {
-                        reply.error(libc::EISDIR);
-                    }
+                "--export-tar" => {
+                    export_tar = Some(PathBuf::from(args.next().ok_or("--export-tar requires a path")?));
                 }
-            }
-            None => {
-                reply.error(libc::ENOENT);
-            }
-        }
-    }
-    fn write(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
-        let entry = self.get_entry_mut(ino);
-        match entry{
-            Some(entry) => {
-                match entry {
-                    FileStorageEntry::File(buffer) => {
-                        for (i, byte) in data.iter().enumerate(){
-                            let position = offset as usize + i;
-                            if position == buffer.len(){
-                                buffer.push(*byte);
-                            } else if position < buffer.len(){
-                                buffer[position] = *byte;
-                            } else {
-                                panic!("oob write");
-                            }
-                        }
-                        reply.written(data.len() as u32);
+                "--trace-file" => {
+                    config.trace_file = Some(PathBuf::from(args.next().ok_or("--trace-file requires a path")?));
+                }
+                "--replay" => {
+                    replay = Some(PathBuf::from(args.next().ok_or("--replay requires a path")?));
+                }
+                "--backing-file" => {
+                    config.backing_file = Some(PathBuf::from(args.next().ok_or("--backing-file requires a path")?));
+                }
+                "--empty-on-start" => {
+                    empty_on_start = true;
+                }
+                "--sync-writes" => {
+                    config.sync_writes = true;
+                }
+                "--watch" => {
+                    config.watch = true;
+                }
+                "--mirror" => {
+                    config.mirror = Some(PathBuf::from(args.next().ok_or("--mirror requires a path")?));
+                }
+                "--serve-addr" => {
+                    serve_addr = Some(args.next().ok_or("--serve-addr requires an address")?);
+                }
+                "--max-concurrency" => {
+                    let permits = parse_value(&arg, args.next())?;
+                    if permits == 0{
+                        return Err("--max-concurrency must be at least 1".to_string());
                     }
-                    FileStorageEntry::Directory(_) => {
-                        reply.error(libc::EISDIR);
+                    max_concurrency = Some(permits);
+                }
+                "--mkdir-mountpoint" => {
+                    mkdir_mountpoint = true;
+                }
+                "--mount-options" => {
+                    mount_options = parse_mount_options(&args.next().ok_or("--mount-options requires a list of options")?)?;
+                }
+                "--diff" => {
+                    diff = Some(PathBuf::from(args.next().ok_or("--diff requires a path")?));
+                }
+                "--trim" => {
+                    trim = true;
+                }
+                "--profile" => {
+                    config.profile = true;
+                }
+                "--panic-on-bug" => {
+                    config.panic_on_bug = true;
+                }
+                "--read-only-path" => {
+                    let value = args.next().ok_or("--read-only-path requires a path")?;
+                    config.read_only_paths.push(parse_storage_path(&value)?);
+                }
+                "--inject-errors" => {
+                    config.inject_errors = Some(parse_value(&arg, args.next())?);
+                }
+                "--ro-after" => {
+                    config.ro_after = Some(parse_value(&arg, args.next())?);
+                }
+                "--normalize-names" => {
+                    config.normalize_names = true;
+                }
+                "--deterministic-inodes" => {
+                    config.deterministic_inodes = true;
+                }
+                "--inode-hash-seed" => {
+                    hash_seed = Some(parse_value(&arg, args.next())?);
+                }
+                "--name-table" => {
+                    name_table = true;
+                }
+                "--buffer-initial-capacity" => {
+                    config.buffer_initial_capacity = parse_value(&arg, args.next())?;
+                }
+                "--dir-initial-capacity" => {
+                    config.dir_initial_capacity = parse_value(&arg, args.next())?;
+                }
+                "--max-open-files" => {
+                    config.max_open_files = Some(parse_value(&arg, args.next())?);
+                }
+                "--quota-per-uid" => {
+                    let value = args.next().ok_or("--quota-per-uid requires a quota")?;
+                    let (uid, bytes) = value.split_once(':').ok_or_else(|| format!("invalid value {value} for --quota-per-uid"))?;
+                    let uid = parse_value(&arg, Some(uid.to_string()))?;
+                    config.quotas.insert(uid, parse_value(&arg, Some(bytes.to_string()))?);
+                }
+                "--statfs" => {
+                    config.statfs = match args.next().ok_or("--statfs requires a source")?.as_str(){
+                        "synthetic" => StatfsSource::Synthetic,
+                        "host" => StatfsSource::Host,
+                        source => return Err(format!("invalid value {source} for --statfs")),
+                    };
+                }
+                "--size" => {
+                    config.size = Some(parse_value(&arg, args.next())?);
+                }
+                "--capacity" => {
+                    config.capacity = Some(parse_value(&arg, args.next())?);
+                }
+                "--evict" => {
+                    config.evict = true;
+                }
+                "--spill-threshold" => {
+                    config.spill_threshold = Some(parse_value(&arg, args.next())?);
+                }
+                "--default-acl" => {
+                    config.default_acl = Some(parse_value(&arg, args.next())?);
+                }
+                "--follow-symlinks" => {
+                    config.follow_symlinks = true;
+                }
+                "--dedup" => {
+                    config.dedup = true;
+                }
+                "--strict-names" => {
+                    config.strict_names = true;
+                }
+                "--deny-exec" => {
+                    config.deny_exec = true;
+                }
+                "--noatime" => {
+                    config.atime = AtimePolicy::Never;
+                }
+                "--relatime" => {
+                    config.atime = AtimePolicy::Relative;
+                }
+                "--strictatime" => {
+                    config.atime = AtimePolicy::Strict;
+                }
+                "--list-xattrs-virtual" => {
+                    config.list_virtual_xattrs = true;
+                }
+                "--selinux-context" => {
+                    config.selinux_context = Some(args.next().ok_or("--selinux-context requires a context")?.into_bytes());
+                }
+                "--hash-algorithm" => {
+                    config.hash_algorithm = parse_value(&arg, args.next())?;
+                }
+                "--order" => {
+                    config.order = match args.next().ok_or("--order requires an order")?.as_str(){
+                        "hash" => DirectoryOrder::Hash,
+                        "insertion" => DirectoryOrder::Insertion,
+                        order => return Err(format!("invalid value {order} for --order")),
+                    };
+                }
+                "--name-max" => {
+                    let name_max = parse_value(&arg, args.next())?;
+                    if name_max == 0{
+                        return Err("--name-max must be at least 1".to_string());
                     }
+                    config.name_max = Some(name_max);
                 }
-            }
-            None => {
-                reply.error(libc::ENOENT);
-            }
-        }
-    }
-    fn rename(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, _flags: u32, reply: ReplyEmpty) {
-        let entry_old = self.get_entry_mut(parent);
-        let file = match entry_old{
-            Some(FileStorageEntry::Directory(entry_old)) => {
-                entry_old.remove(name)
-            }
-            Some(FileStorageEntry::File(_)) => {
-                reply.error(libc::ENOTDIR);
-                return;
-            }
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
-        };
-        match file{
-            Some(file) => {
-                //todo: rollback file on error
-                let entry_new = self.get_entry_mut(newparent);
-                match entry_new {
-                    Some(FileStorageEntry::Directory(directory)) => {
-                        if directory.contains_key(newname){
-                            reply.error(libc::EEXIST);
-                            return;
-                        }
-                        directory.insert(newname.to_os_string(), file);
-                        reply.ok();
+                "--max-depth" => {
+                    config.max_depth = Some(parse_value(&arg, args.next())?);
+                }
+                "--negative-ttl" => {
+                    config.negative_ttl = Duration::from_secs(parse_value(&arg, args.next())?);
+                }
+                "--max-readahead" => {
+                    let max_readahead = parse_value(&arg, args.next())?;
+                    if max_readahead == 0{
+                        return Err("--max-readahead must be at least 1".to_string());
                     }
-                    Some(FileStorageEntry::File(_)) => {
-                        reply.error(libc::ENOTDIR);
-                        return;
+                    config.max_readahead = Some(max_readahead);
+                }
+                "--read-chunk-size" => {
+                    let chunk_size = parse_value(&arg, args.next())?;
+                    if chunk_size == 0{
+                        return Err("--read-chunk-size must be at least 1".to_string());
                     }
-                    None => {
-                        reply.error(libc::ENOENT);
-                        return;
+                    config.read_chunk_size = Some(chunk_size);
+                }
+                "--max-write-size" => {
+                    let max_write_size = parse_value(&arg, args.next())?;
+                    if max_write_size == 0{
+                        return Err("--max-write-size must be at least 1".to_string());
                     }
+                    config.max_write_size = Some(max_write_size);
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(format!("unknown option {arg}"));
+                }
+                _ => {
+                    mountpoint = Some(arg);
                 }
-            }
-            None => {
-                reply.error(libc::ENOENT);
             }
         }
-    }
-    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        if offset != 0{
-            reply.ok();
-            return;
+        if config.evict && (config.capacity.is_none() || config.backing_file.is_none()){
+            return Err("--evict requires --capacity and --backing-file".to_string());
         }
-        let entry = self.get_entry(ino);
-        match entry{
-            Some(entry) => {
-                match entry {
-                    FileStorageEntry::File(_) => {
-                        reply.error(libc::ENOTDIR);
-                    }
-                    FileStorageEntry::Directory(directory) => {
-                        let entries = directory.keys().cloned().collect::<Vec<_>>();
-                        let path = self.inode_to_file.get(&ino).unwrap().clone();
-                        let _ = reply.add(ino, 0, FileType::Directory, &".");
-                        let _ = reply.add(self.create_inode(path.with_popped()), 1, FileType::Directory, &"..");
-                        for (i, entry) in entries.iter().enumerate(){
-                            let child_path = path.with_pushed(entry.as_os_str());
-                            let file_type = match self.files.lookup(&child_path).unwrap(){
-                                FileStorageEntry::File(_) => FileType::RegularFile,
-                                FileStorageEntry::Directory(_) => FileType::Directory
-                            };
-                            let _ = reply.add(self.create_inode(child_path), 2 + i as i64, file_type, entry);
-                        }
-                        reply.ok();
-                    }
-                }
-            }
-            None => {
-                reply.error(libc::ENOENT);
-            }
+        if config.watch && serve_addr.is_none(){
+            return Err("--watch requires --serve-addr".to_string());
+        }
+        if max_concurrency.is_some() && serve_addr.is_none(){
+            return Err("--max-concurrency requires --serve-addr".to_string());
+        }
+        if config.statfs == StatfsSource::Host && config.backing_file.is_none(){
+            return Err("--statfs host requires --backing-file".to_string());
+        }
+        if config.sync_writes && config.backing_file.is_none(){
+            return Err("--sync-writes requires --backing-file".to_string());
+        }
+        if empty_on_start && config.backing_file.is_none(){
+            return Err("--empty-on-start requires --backing-file".to_string());
         }
+        //saving on every write would load all evicted contents back into memory each time
+        if config.sync_writes && config.evict{
+            return Err("--sync-writes and --evict can't be combined".to_string());
+        }
+        if config.capacity.is_some() && !config.evict{
+            return Err("--capacity requires --evict".to_string());
+        }
+        if lower.is_some() && import_tar.is_some(){
+            return Err("--lower and --import-tar can't be combined".to_string());
+        }
+        if strip_import_prefix > 0 && lower.is_none() && import_tar.is_none(){
+            return Err("--strip-import-prefix requires --lower or --import-tar".to_string());
+        }
+        if excluding && lower.is_none() && import_tar.is_none(){
+            return Err("--import-exclude requires --lower or --import-tar".to_string());
+        }
+        let import_exclude = import_exclude.build().map_err(|error| format!("invalid --import-exclude patterns: {error}"))?;
+        if config.deny_exec && !mount_options.contains(&MountOption::NoExec){
+            mount_options.push(MountOption::NoExec);
+        }
+        if trim && config.backing_file.is_none(){
+            return Err("--trim requires --backing-file".to_string());
+        }
+        if diff.is_some() && config.backing_file.is_none(){
+            return Err("--diff requires --backing-file".to_string());
+        }
+        if mountpoint.is_none() && diff.is_none() && export_tar.is_none() && replay.is_none() && !trim{
+            return Err("missing mountpoint".to_string());
+        }
+        Ok(Options{
+            mountpoint,
+            lower,
+            import_tar,
+            strip_import_prefix,
+            import_exclude,
+            export_tar,
+            replay,
+            diff,
+            trim,
+            serve_addr,
+            max_concurrency,
+            hash_seed,
+            name_table,
+            empty_on_start,
+            mount_options,
+            mkdir_mountpoint,
+            config,
+        })
     }
-    fn create(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, _umask: u32, _flags: i32, reply: ReplyCreate) {
-        let entry = self.get_entry_mut(parent);
-        match entry{
-            Some(entry) => {
-                match entry {
-                    FileStorageEntry::File(_) => {
-                        reply.error(libc::ENOTDIR);
-                    }
-                    FileStorageEntry::Directory(directory) => {
-                        directory.entry(name.to_os_string()).or_insert(FileStorageEntry::File(Vec::new()));
-                        let inode = self.create_inode(self.inode_to_file.get(&parent).unwrap().with_pushed(name));
-                        reply.created(&Duration::new(1, 0), &self.get_inode_attrs(inode), 0, 0, 0);
-                    }
-                }
-            }
-            None => {
-                reply.error(libc::ENOENT);
-            }
+}
+/// Splits `--option=value` into the option and its value, so both spellings are accepted.
+fn split_inline_value(arg: String) -> Vec<String>{
+    match arg.split_once('='){
+        Some((option, value)) if option.starts_with("--") => vec![option.to_string(), value.to_string()],
+        _ => vec![arg],
+    }
+}
+/// Parses a path inside the filesystem, relative to its root whether or not it starts with a slash.
+fn parse_storage_path(value: &str) -> Result<FileStoragePath, String>{
+    let mut path = FileStoragePath::root();
+    for component in Path::new(value).components(){
+        match component{
+            Component::Normal(name) => path = path.with_pushed(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return Err(format!("invalid path {value}")),
+        }
+    }
+    Ok(path)
+}
+fn parse_value<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, String>{
+    let value = value.ok_or_else(|| format!("{option} requires a value"))?;
+    value.parse().map_err(|_| format!("invalid value {value} for {option}"))
+}
+/// Parses a comma separated list of mount options as accepted by `mount -o`. Tokens without a matching
+/// `MountOption` variant are passed to the kernel unchanged.
+fn parse_mount_options(value: &str) -> Result<Vec<MountOption>, String>{
+    let mut options = Vec::new();
+    for token in value.split(','){
+        options.push(match token{
+            "" => return Err(format!("empty mount option in {value}")),
+            "ro" => MountOption::RO,
+            "rw" => MountOption::RW,
+            "allow_other" => MountOption::AllowOther,
+            "allow_root" => MountOption::AllowRoot,
+            "auto_unmount" => MountOption::AutoUnmount,
+            "default_permissions" => MountOption::DefaultPermissions,
+            "dev" => MountOption::Dev,
+            "nodev" => MountOption::NoDev,
+            "suid" => MountOption::Suid,
+            "nosuid" => MountOption::NoSuid,
+            "exec" => MountOption::Exec,
+            "noexec" => MountOption::NoExec,
+            "sync" => MountOption::Sync,
+            "async" => MountOption::Async,
+            "dirsync" => MountOption::DirSync,
+            "atime" => MountOption::Atime,
+            "noatime" => MountOption::NoAtime,
+            _ => match token.split_once('='){
+                Some(("fsname", name)) => MountOption::FSName(name.to_string()),
+                Some(("subtype", name)) => MountOption::Subtype(name.to_string()),
+                _ => MountOption::CUSTOM(token.to_string()),
+            },
+        });
+    }
+    Ok(options)
+}
+
+/// Prints the changes of the `current` snapshot compared to the `baseline` one.
+fn print_diff(baseline: &Path, current: &Path) -> io::Result<()>{
+    let baseline = FileStorage::load(baseline)?;
+    let current = FileStorage::load(current)?;
+    let root = Path::new("/");
+    for change in current.diff(&baseline){
+        match change{
+            PathChange::Added(path) => println!("A {}", path.to_path(root).display()),
+            PathChange::Removed(path) => println!("D {}", path.to_path(root).display()),
+            PathChange::Modified(path) => println!("M {}", path.to_path(root).display()),
         }
     }
+    Ok(())
+}
+/// Loads the writable layer saved in the backing file, if there is one and `empty_on_start` doesn't skip it.
+fn load_upper(backing_file: Option<&Path>, empty_on_start: bool) -> Result<Option<FileStorage>, String>{
+    match backing_file{
+        Some(path) if path.exists() && !empty_on_start => match FileStorage::load(path){
+            Ok(upper) => Ok(Some(upper)),
+            Err(error) => Err(format!("failed to load snapshot {}: {error}", path.display())),
+        },
+        _ => Ok(None),
+    }
+}
+/// Makes sure `mountpoint` exists before mounting, creating it and its missing parents if `create` is set.
+fn prepare_mountpoint(mountpoint: &str, create: bool) -> Result<(), String>{
+    if Path::new(mountpoint).exists(){
+        return Ok(());
+    }
+    if !create{
+        return Err(format!("mountpoint {mountpoint} doesn't exist, create it first or pass --mkdir-mountpoint"));
+    }
+    fs::create_dir_all(mountpoint).map_err(|error| format!("failed to create mountpoint {mountpoint}: {error}"))
 }
+/// Writes the tree of `overlay` as a tar archive to `archive`, or to stdout if it is `-`.
+fn export_tar(overlay: &Overlay, archive: &Path) -> io::Result<()>{
+    if archive == Path::new("-"){
+        return overlay.export_tar(io::stdout().lock());
+    }
+    overlay.export_tar(BufWriter::new(File::create(archive)?))
+}
+
+const USAGE: &str = "Usage: icfs [OPTIONS] <MOUNTPOINT>
+       icfs --backing-file <FILE> --diff <BASELINE>
+       icfs [--lower <DIR>] --backing-file <FILE> --trim
+       icfs [--lower <DIR>] [--backing-file <FILE>] --export-tar <ARCHIVE>
+       icfs [OPTIONS] --replay <TRACE>
+Options:
+    --lower <DIR>               mount an overlay with DIR imported as the read-only lower layer
+    --import-tar <ARCHIVE>      mount an overlay with the tar ARCHIVE imported as the read-only lower layer
+    --strip-import-prefix <N>   drop the first N components of the paths imported by --lower or --import-tar
+    --import-exclude <GLOB>     skip the entries imported by --lower or --import-tar whose path or name matches GLOB,
+                                along with everything below them; can be repeated
+    --export-tar <ARCHIVE>      write the tree to the tar ARCHIVE, or stdout if it is -, instead of mounting
+    --trace-file <FILE>         append a record of every FUSE operation to FILE
+    --replay <TRACE>            run the operations recorded in TRACE and save the result instead of mounting
+    --backing-file <FILE>       load the writable layer from FILE and save it back on unmount
+    --empty-on-start            start with an empty writable layer instead of loading the backing file, which is
+                                still overwritten on unmount
+    --sync-writes               save the backing file before acknowledging every write, not with --evict
+    --mirror <DIR>              replay every change into the host directory DIR as it happens
+    --serve-addr <ADDR>         serve reads and writes of file contents and snapshots over TCP on ADDR
+    --max-concurrency <N>       handle at most N serve requests at once, further ones wait for one of them to finish
+    --watch                     let serve clients subscribe to a stream of the changes made to the filesystem
+    --mount-options <OPTIONS>   mount with the comma separated OPTIONS (default allow_other,auto_unmount,nosuid)
+    --mkdir-mountpoint          create the mountpoint and its missing parents if it doesn't exist
+    --diff <BASELINE>           print the changes of the backing file compared to the BASELINE snapshot
+    --trim                      compact the backing file instead of mounting, dropping the whiteouts that mask
+                                nothing in the lower layer given with --lower or --import-tar
+    --strict-names              reject names containing control characters
+    --deny-exec                 report files without execute bits, refuse to set them and mount with noexec
+    --normalize-names           treat names that are equal under Unicode NFC as the same entry
+    --deterministic-inodes      assign the same inodes for the same sequence of operations
+    --inode-hash-seed <SEED>    hash directory entries with SEED for a reproducible listing order (default random)
+    --name-table                share the names of path components between all paths naming them
+    --buffer-initial-capacity <BYTES>
+                                reserve BYTES in the buffers of newly created files
+    --dir-initial-capacity <N>  reserve room for N entries in the root and newly created directories
+    --max-open-files <N>        fail opening files with EMFILE while N handles are open
+    --size <BYTES>              size of the filesystem reported by statfs, growing files beyond it fails with ENOSPC
+    --statfs <SOURCE>           report the configured size (synthetic, default) or the space of the host
+                                filesystem holding the backing file (host) in statfs
+    --quota-per-uid <UID:BYTES> fail growing the files owned by UID beyond BYTES with EDQUOT, repeatable
+    --capacity <BYTES>          size the file contents held in memory may take up before --evict kicks in
+    --evict                     move the least recently used contents next to the backing file beyond the capacity
+    --spill-threshold <BYTES>   move the contents of files larger than BYTES to anonymous temp files
+    --default-acl <ACL>         default ACL of the root directory, such as u::rwx,u:1000:rwx,g::r-x,m::rwx,o::r-x
+    --follow-symlinks           resolve symbolic links inside the filesystem during lookups
+    --dedup                     share the contents of identical files once they are closed
+    --list-xattrs-virtual       list computed xattrs such as user.mime_type along with the stored ones
+    --selinux-context <CONTEXT> report CONTEXT in security.selinux for the entries that weren't labeled otherwise
+    --hash-algorithm <HASH>     identify identical contents by xxh3 (default), blake3 or sha256 hashes
+    --profile                   print operation latency percentiles on unmount
+    --panic-on-bug              panic on internal errors instead of failing the request with EIO
+    --read-only-path <PATH>     fail modifications below PATH inside the filesystem with EROFS, repeatable
+    --ro-after <N>              fail all modifications with EROFS after N successful writes
+    --inject-errors <SPEC>      randomly fail operations, such as read:EIO:0.01,write:ENOSPC:0.001 failing reads
+                                with EIO at a chance of 1% and writes with ENOSPC at 0.1%
+    --read-chunk-size <BYTES>   return at most BYTES from a single read
+    --max-write-size <BYTES>    accept at most BYTES in a single write
+    --max-readahead <BYTES>     let the kernel read ahead at most BYTES of sequentially read files
+    --name-max <BYTES>          reject names longer than BYTES (default 255)
+    --max-depth <LEVELS>        refuse to nest directories more than LEVELS deep
+    --negative-ttl <SECONDS>    let the kernel cache failed lookups for SECONDS (default 0)
+    --noatime                   never update access times
+    --relatime                  update access times only when older than the last change (default)
+    --strictatime               update access times on every read
+    --order <ORDER>             list directories in hash order (default) or insertion order";
 
 fn main() {
-    let mountpoint = match env::args().nth(1) {
-        Some(path) => path,
-        None => {
-            println!("Usage: icfs <MOUNTPOINT>");
+    //failures are reported by default, per-operation tracing only with RUST_LOG=icfs=trace
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("error")).init();
+    let options = match Options::parse() {
+        Ok(options) => options,
+        Err(error) => {
+            println!("{error}");
+            println!("{USAGE}");
             return;
         }
     };
-    let mut filesystem = ICFS::new();
-    match &mut filesystem.files.root{
-        FileStorageEntry::File(_) => {}
-        FileStorageEntry::Directory(dir) => {
-            dir.insert(OsString::from("aaa.txt"), FileStorageEntry::File("fgshndiudfhbsduifsd\n".as_bytes().to_vec()));
-            dir.insert(OsString::from("bbb.txt"), FileStorageEntry::File(Vec::new()));
+    //the seed has to be fixed before the first directory is created
+    if let Some(seed) = options.hash_seed{
+        if let Err(existing) = storage::set_hash_seed(seed){
+            println!("failed to set the hash seed, directories already use {existing}");
+            return;
         }
     }
-    fuser::mount2(filesystem, &mountpoint, &[MountOption::AllowOther, MountOption::AutoUnmount, NoSuid]).unwrap();
-}
-
-pub struct FileStorage{
-    root: FileStorageEntry
-}
-impl FileStorage{
-    pub fn new() -> Self{
-        FileStorage{
-            root: FileStorageEntry::Directory(HashMap::new())
+    if options.name_table{
+        storage::enable_name_table();
+    }
+    if let (Some(baseline), Some(current)) = (&options.diff, &options.config.backing_file){
+        if let Err(error) = print_diff(baseline, current){
+            println!("failed to diff snapshots: {error}");
         }
+        return;
     }
-    pub fn lookup(&self, path: &FileStoragePath) -> Option<&FileStorageEntry>{
-        let mut current_entry = &self.root;
-        for part in &path.parts{
-            current_entry = match current_entry {
-                FileStorageEntry::Directory(directory) => match directory.get(part.as_os_str()){
-                    Some(entry) => entry,
-                    None => return None,
-                },
-                FileStorageEntry::File(_) => return None,
+    let lower = match (&options.lower, &options.import_tar){
+        (Some(path), _) => match FileStorage::import(path, options.strip_import_prefix, &options.import_exclude){
+            Ok(lower) => lower,
+            Err(error) => {
+                println!("failed to import lower layer {}: {error}", path.display());
+                return;
             }
-        }
-        Some(current_entry)
-    }
-    pub fn lookup_mut(&mut self, path: &FileStoragePath) -> Option<&mut FileStorageEntry>{
-        let mut current_entry = &mut self.root;
-        for part in &path.parts{
-            current_entry = match current_entry {
-                FileStorageEntry::Directory(directory) => match directory.get_mut(part.as_os_str()){
-                    Some(entry) => entry,
-                    None => return None,
-                },
-                FileStorageEntry::File(_) => return None,
+        },
+        (None, Some(path)) => match FileStorage::import_tar(path, options.strip_import_prefix, &options.import_exclude){
+            Ok(lower) => lower,
+            Err(error) => {
+                println!("failed to import tar archive {}: {error}", path.display());
+                return;
             }
+        },
+        (None, None) => FileStorage::new(),
+    };
+    let upper = match load_upper(options.config.backing_file.as_deref(), options.empty_on_start){
+        Ok(upper) => upper,
+        Err(error) => {
+            println!("{error}");
+            return;
         }
-        Some(current_entry)
+    };
+    if let Some(archive) = &options.export_tar{
+        if let Err(error) = export_tar(&Overlay::new(lower, upper.unwrap_or_default()), archive){
+            println!("failed to export tar archive {}: {error}", archive.display());
+        }
+        return;
     }
-}
-#[derive(Debug)]
-pub enum FileStorageEntry{
-    File(Vec<u8>),
-    Directory(HashMap<OsString,FileStorageEntry>)
-}
-#[derive(Clone, PartialEq, Eq, Hash)]
-pub struct FileStoragePath{
-    parts: Vec<OsString>
-}
-impl FileStoragePath{
-    pub fn root() -> Self{
-        FileStoragePath{
-            parts: Vec::new()
+    let seed = upper.is_none() && !options.empty_on_start;
+    let mut files = Overlay::new(lower, upper.unwrap_or_default());
+    if seed{
+        //the lower layer may have these already, which is fine
+        let _ = files.insert(&FileStoragePath::root().with_pushed(OsStr::new("aaa.txt")), FileStorageEntry::File("fgshndiudfhbsduifsd\n".as_bytes().to_vec().into(), Metadata::now()));
+        let _ = files.insert(&FileStoragePath::root().with_pushed(OsStr::new("bbb.txt")), FileStorageEntry::File(FileContent::default(), Metadata::now()));
+    }
+    let mut filesystem = ICFS::new(files, options.config);
+    if options.trim{
+        match filesystem.do_trim(){
+            Ok(reclaimed) => println!("trimmed {reclaimed} bytes from the backing file"),
+            Err(error) => println!("failed to trim the backing file: {error:?}"),
         }
+        return;
     }
-    pub fn with_pushed(&self, next: &OsStr) -> Self{
-        let mut parts = self.parts.clone();
-        parts.push(next.to_os_string());
-        FileStoragePath{
-            parts
+    if let Some(trace) = &options.replay{
+        let summary = File::open(trace).and_then(|trace| filesystem.replay(BufReader::new(trace)));
+        match summary{
+            Ok(summary) => println!("replayed {} operations, {} with a different result", summary.operations, summary.mismatches),
+            Err(error) => println!("failed to replay {}: {error}", trace.display()),
         }
+        filesystem.destroy();
+        return;
     }
-    pub fn with_popped(&self) -> Self{
-        let mut parts = self.parts.clone();
-        parts.pop();
-        FileStoragePath{
-            parts
+    let mountpoint = options.mountpoint.unwrap();
+    if let Err(error) = prepare_mountpoint(&mountpoint, options.mkdir_mountpoint){
+        println!("{error}");
+        return;
+    }
+    let mount_options = options.mount_options;
+    let mounted = match &options.serve_addr{
+        Some(addr) => {
+            let filesystem = Arc::new(Mutex::new(filesystem));
+            if let Err(error) = serve::serve(addr, filesystem.clone(), options.max_concurrency){
+                println!("failed to serve on {addr}: {error}");
+                return;
+            }
+            fuser::mount2(SharedFilesystem(filesystem), &mountpoint, &mount_options)
         }
+        None => fuser::mount2(filesystem, &mountpoint, &mount_options),
+    };
+    if let Err(error) = mounted{
+        println!("failed to mount on {mountpoint}: {error}");
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, String>{
+        Options::parse_args(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn read_chunk_size_must_be_positive(){
+        assert_eq!(parse("--read-chunk-size 4096 mnt").unwrap().config.read_chunk_size, Some(4096));
+        assert_eq!(parse("--read-chunk-size=1 mnt").unwrap().config.read_chunk_size, Some(1));
+        assert!(parse("--read-chunk-size 0 mnt").is_err());
+        assert!(parse("--read-chunk-size -1 mnt").is_err());
+    }
+    #[test]
+    fn atime_options_pick_the_policy(){
+        assert!(matches!(parse("mnt").unwrap().config.atime, AtimePolicy::Relative));
+        assert!(matches!(parse("--noatime mnt").unwrap().config.atime, AtimePolicy::Never));
+        assert!(matches!(parse("--strictatime mnt").unwrap().config.atime, AtimePolicy::Strict));
+        assert!(matches!(parse("--strictatime --relatime mnt").unwrap().config.atime, AtimePolicy::Relative));
+    }
+    #[test]
+    fn diff_runs_without_a_mountpoint(){
+        let options = parse("--backing-file current --diff baseline").unwrap();
+        assert_eq!(options.diff.as_deref(), Some(Path::new("baseline")));
+        assert!(options.mountpoint.is_none());
+        assert!(parse("--diff").is_err());
+        assert!(parse("--diff a b mnt").is_err());
+        assert!(parse("--diff baseline").is_err());
     }
-}
\ No newline at end of file
+    #[test]
+    fn strict_names_are_off_by_default(){
+        assert!(!parse("mnt").unwrap().config.strict_names);
+        assert!(parse("--strict-names mnt").unwrap().config.strict_names);
+    }
+    #[test]
+    fn negative_lookups_are_not_cached_by_default(){
+        assert!(parse("mnt").unwrap().config.negative_ttl.is_zero());
+        assert_eq!(parse("--negative-ttl 5 mnt").unwrap().config.negative_ttl, Duration::from_secs(5));
+        assert!(parse("--negative-ttl soon mnt").is_err());
+    }
+    #[test]
+    fn name_max_must_be_positive(){
+        assert_eq!(parse("--name-max 64 mnt").unwrap().config.name_max(), 64);
+        assert!(parse("--name-max 0 mnt").is_err());
+    }
+    #[test]
+    fn open_files_are_unlimited_by_default(){
+        assert_eq!(parse("mnt").unwrap().config.max_open_files, None);
+        assert_eq!(parse("--max-open-files 16 mnt").unwrap().config.max_open_files, Some(16));
+    }
+    #[test]
+    fn mount_options_map_to_fuse_options(){
+        assert_eq!(parse_mount_options("ro,allow_root,nodev,noexec,fsname=icfs,subtype=mem,max_read=4096").unwrap(), [
+            MountOption::RO,
+            MountOption::AllowRoot,
+            MountOption::NoDev,
+            MountOption::NoExec,
+            MountOption::FSName("icfs".to_string()),
+            MountOption::Subtype("mem".to_string()),
+            MountOption::CUSTOM("max_read=4096".to_string()),
+        ]);
+        assert!(parse_mount_options("ro,,rw").is_err());
+        assert_eq!(parse("mnt").unwrap().mount_options, [MountOption::AllowOther, MountOption::AutoUnmount, MountOption::NoSuid]);
+        assert_eq!(parse("--mount-options rw,sync mnt").unwrap().mount_options, [MountOption::RW, MountOption::Sync]);
+    }
+    #[test]
+    fn export_tar_runs_without_a_mountpoint(){
+        assert_eq!(parse("--export-tar out.tar").unwrap().export_tar.as_deref(), Some(Path::new("out.tar")));
+        assert!(parse("--export-tar").is_err());
+    }
+    #[test]
+    fn hash_seeds_are_random_unless_given(){
+        assert_eq!(parse("mnt").unwrap().hash_seed, None);
+        assert_eq!(parse("--inode-hash-seed 42 mnt").unwrap().hash_seed, Some(42));
+    }
+    #[test]
+    fn replay_runs_without_a_mountpoint(){
+        assert_eq!(parse("--replay trace").unwrap().replay.as_deref(), Some(Path::new("trace")));
+        assert_eq!(parse("--trace-file trace mnt").unwrap().config.trace_file.as_deref(), Some(Path::new("trace")));
+    }
+    #[test]
+    fn max_readahead_must_be_positive(){
+        assert_eq!(parse("mnt").unwrap().config.max_readahead, None);
+        assert_eq!(parse("--max-readahead 131072 mnt").unwrap().config.max_readahead, Some(131072));
+        assert!(parse("--max-readahead 0 mnt").is_err());
+    }
+    #[test]
+    fn import_prefixes_are_only_stripped_from_imports(){
+        assert_eq!(parse("mnt").unwrap().strip_import_prefix, 0);
+        assert_eq!(parse("--lower host --strip-import-prefix 2 mnt").unwrap().strip_import_prefix, 2);
+        assert_eq!(parse("--import-tar fs.tar --strip-import-prefix 1 mnt").unwrap().strip_import_prefix, 1);
+        assert!(parse("--strip-import-prefix 1 mnt").is_err());
+        assert!(parse("--lower host --strip-import-prefix -1 mnt").is_err());
+    }
+    #[test]
+    fn order_option_picks_the_listing_order(){
+        assert!(matches!(parse("mnt").unwrap().config.order, DirectoryOrder::Hash));
+        assert!(matches!(parse("--order insertion mnt").unwrap().config.order, DirectoryOrder::Insertion));
+        assert!(parse("--order sorted mnt").is_err());
+    }
+    #[test]
+    fn read_only_paths_can_be_repeated(){
+        let paths = parse("--read-only-path /config --read-only-path ./data/./sub mnt").unwrap().config.read_only_paths;
+        assert_eq!(paths, [FileStoragePath::from_components(["config"]), FileStoragePath::from_components(["data", "sub"])]);
+        assert!(parse("--read-only-path ../outside mnt").is_err());
+    }
+    #[test]
+    fn directories_reserve_no_entries_by_default(){
+        assert_eq!(parse("mnt").unwrap().config.dir_initial_capacity, 0);
+        assert_eq!(parse("--dir-initial-capacity 512 mnt").unwrap().config.dir_initial_capacity, 512);
+        assert!(parse("--dir-initial-capacity many mnt").is_err());
+    }
+    #[test]
+    fn max_write_size_must_be_positive(){
+        assert_eq!(parse("mnt").unwrap().config.max_write_size, None);
+        assert_eq!(parse("--max-write-size 4096 mnt").unwrap().config.max_write_size, Some(4096));
+        assert!(parse("--max-write-size 0 mnt").is_err());
+    }
+    #[test]
+    fn sync_writes_and_evict_are_exclusive(){
+        assert!(parse("--backing-file b --sync-writes mnt").unwrap().config.sync_writes);
+        assert!(parse("--backing-file b --capacity 1048576 --evict mnt").unwrap().config.evict);
+        assert_eq!(parse("--backing-file b --capacity 1048576 --evict --sync-writes mnt").err().as_deref(), Some("--sync-writes and --evict can't be combined"));
+    }
+    #[test]
+    fn virtual_xattrs_are_unlisted_by_default(){
+        assert!(!parse("mnt").unwrap().config.list_virtual_xattrs);
+        assert!(parse("--list-xattrs-virtual mnt").unwrap().config.list_virtual_xattrs);
+    }
+    #[test]
+    fn host_statfs_needs_a_backing_file(){
+        assert_eq!(parse("mnt").unwrap().config.statfs, StatfsSource::Synthetic);
+        assert_eq!(parse("--statfs host --backing-file fs.img mnt").unwrap().config.statfs, StatfsSource::Host);
+        assert_eq!(parse("--statfs synthetic mnt").unwrap().config.statfs, StatfsSource::Synthetic);
+        assert!(parse("--statfs host mnt").is_err());
+        assert!(parse("--statfs disk mnt").is_err());
+    }
+    #[test]
+    fn import_excludes_need_an_import_and_valid_patterns(){
+        let options = parse("--lower host --import-exclude *.o --import-exclude target mnt").unwrap();
+        assert!(options.import_exclude.is_match("main.o") && options.import_exclude.is_match("target"));
+        assert!(!options.import_exclude.is_match("main.rs"));
+        assert!(parse("mnt").unwrap().import_exclude.is_empty());
+        assert!(parse("--import-exclude *.o mnt").is_err());
+        assert!(parse("--lower host --import-exclude [ mnt").is_err());
+    }
+    #[test]
+    fn empty_on_start_skips_loading_but_still_saves(){
+        assert!(parse("--empty-on-start mnt").is_err());
+        assert!(parse("--empty-on-start --backing-file fs.img mnt").unwrap().empty_on_start);
+        let backing = env::temp_dir().join(format!("icfs-empty-on-start-test-{}", std::process::id()));
+        let mut saved = FileStorage::new();
+        saved.insert(&FileStoragePath::from_components(["old"]), FileStorageEntry::File(b"old".to_vec().into(), Metadata::now())).unwrap();
+        saved.save(&backing).unwrap();
+        assert!(load_upper(Some(&backing), false).unwrap().is_some_and(|upper| upper.lookup(&FileStoragePath::from_components(["old"])).is_some()));
+        assert!(load_upper(Some(&backing), true).unwrap().is_none());
+        assert!(load_upper(Some(&backing.with_extension("missing")), false).unwrap().is_none());
+        //unmounting overwrites the backing file with the tree started from scratch
+        let config = Config{ backing_file: Some(backing.clone()), ..Config::default() };
+        let mut filesystem = ICFS::new(Overlay::new(FileStorage::new(), FileStorage::new()), config);
+        filesystem.do_mkdir(1, OsStr::new("new"), 0o755, 0, 0).unwrap();
+        filesystem.destroy();
+        let upper = load_upper(Some(&backing), false).unwrap().unwrap();
+        assert!(upper.lookup(&FileStoragePath::from_components(["old"])).is_none());
+        assert!(upper.lookup(&FileStoragePath::from_components(["new"])).is_some());
+        fs::write(&backing, b"garbage").unwrap();
+        assert!(load_upper(Some(&backing), false).is_err());
+        let _ = fs::remove_file(&backing);
+    }
+    #[test]
+    fn selinux_contexts_are_unset_by_default(){
+        assert_eq!(parse("mnt").unwrap().config.selinux_context, None);
+        assert_eq!(parse("--selinux-context system_u:object_r:fusefs_t:s0 mnt").unwrap().config.selinux_context.as_deref(), Some(&b"system_u:object_r:fusefs_t:s0"[..]));
+        assert!(parse("--selinux-context").is_err());
+    }
+    #[test]
+    fn trim_runs_without_a_mountpoint(){
+        assert!(parse("--backing-file fs.img --trim").unwrap().trim);
+        assert!(parse("--trim").is_err());
+    }
+    #[test]
+    fn missing_mountpoints_are_only_created_when_asked_to(){
+        assert!(!parse("mnt").unwrap().mkdir_mountpoint);
+        assert!(parse("--mkdir-mountpoint mnt").unwrap().mkdir_mountpoint);
+        let root = env::temp_dir().join(format!("icfs-mountpoint-test-{}", std::process::id()));
+        let mountpoint = root.join("nested/mnt");
+        let mountpoint = mountpoint.to_str().unwrap();
+        assert!(prepare_mountpoint(mountpoint, false).unwrap_err().contains("--mkdir-mountpoint"));
+        assert!(!root.exists());
+        prepare_mountpoint(mountpoint, true).unwrap();
+        assert!(Path::new(mountpoint).is_dir());
+        //an existing mountpoint is used as it is
+        prepare_mountpoint(mountpoint, false).unwrap();
+        fs::write(root.join("file"), b"").unwrap();
+        assert!(prepare_mountpoint(root.join("file/mnt").to_str().unwrap(), true).unwrap_err().starts_with("failed to create mountpoint"));
+        let _ = fs::remove_dir_all(&root);
+    }
+    #[test]
+    fn denying_exec_mounts_with_noexec_once(){
+        let options = parse("--deny-exec mnt").unwrap();
+        assert!(options.config.deny_exec);
+        assert_eq!(options.mount_options.iter().filter(|option| **option == MountOption::NoExec).count(), 1);
+        let options = parse("--deny-exec --mount-options noexec mnt").unwrap();
+        assert_eq!(options.mount_options.iter().filter(|option| **option == MountOption::NoExec).count(), 1);
+        assert!(!parse("mnt").unwrap().mount_options.contains(&MountOption::NoExec));
+    }
+}
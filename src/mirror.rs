@@ -0,0 +1,66 @@
+//! Live mirror of the filesystem into a host directory, for external tools watching the changes as they happen.
+//!
+//! Every mutation is replayed with the corresponding `std::fs` operation once the in-memory tree was updated.
+//! Failures are logged and otherwise ignored, so the mirror may drift from the filesystem but never fails a request.
+
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io;
+use std::os::unix::fs::{symlink, FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use log::error;
+use crate::content::FileContent;
+use crate::storage::FileStoragePath;
+
+pub struct Mirror{
+    root: PathBuf,
+}
+impl Mirror{
+    pub fn new(root: PathBuf) -> Self{
+        let mirror = Mirror{ root };
+        mirror.apply("mkdir", &FileStoragePath::root(), |host| fs::create_dir_all(host));
+        mirror
+    }
+    pub fn create(&self, path: &FileStoragePath){
+        self.apply("create", path, |host| OpenOptions::new().write(true).create(true).truncate(false).open(host).map(drop));
+    }
+    pub fn write(&self, path: &FileStoragePath, offset: u64, data: &[u8]){
+        self.apply("write", path, |host| OpenOptions::new().write(true).create(true).truncate(false).open(host)?.write_all_at(data, offset));
+    }
+    pub fn truncate(&self, path: &FileStoragePath, size: u64){
+        self.apply("truncate", path, |host| OpenOptions::new().write(true).create(true).truncate(false).open(host)?.set_len(size));
+    }
+    /// Replaces the whole contents of the mirrored file, for changes that aren't simple writes.
+    pub fn replace(&self, path: &FileStoragePath, data: &[u8]){
+        self.apply("write", path, |host| fs::write(host, data));
+    }
+    /// Replaces the whole contents like `replace`, copying holes and spilled contents in chunks.
+    pub fn replace_contents(&self, path: &FileStoragePath, contents: &FileContent){
+        self.apply("write", path, |host| contents.write_to(&mut File::create(host)?));
+    }
+    pub fn mkdir(&self, path: &FileStoragePath){
+        self.apply("mkdir", path, |host| fs::create_dir(host));
+    }
+    pub fn symlink(&self, path: &FileStoragePath, target: &Path){
+        self.apply("symlink", path, |host| symlink(target, host));
+    }
+    pub fn chmod(&self, path: &FileStoragePath, perm: u16){
+        self.apply("chmod", path, |host| fs::set_permissions(host, Permissions::from_mode(perm.into())));
+    }
+    pub fn unlink(&self, path: &FileStoragePath){
+        self.apply("unlink", path, |host| fs::remove_file(host));
+    }
+    pub fn rmdir(&self, path: &FileStoragePath){
+        //the in-memory rmdir drops the whole subtree
+        self.apply("rmdir", path, |host| fs::remove_dir_all(host));
+    }
+    pub fn rename(&self, from: &FileStoragePath, to: &FileStoragePath){
+        let to = to.to_path(&self.root);
+        self.apply("rename", from, |host| fs::rename(host, &to));
+    }
+    fn apply(&self, operation: &str, path: &FileStoragePath, action: impl FnOnce(&Path) -> io::Result<()>){
+        let host = path.to_path(&self.root);
+        if let Err(error) = action(&host){
+            error!("failed to mirror {operation} of {}: {error}", host.display());
+        }
+    }
+}
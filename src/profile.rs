@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Each power of two is split into this many linear sub-buckets, bounding the relative error of the reported
+/// latencies to 1/8.
+const SUB_BUCKETS: u64 = 8;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+const BUCKETS: usize = ((64 - SUB_BUCKET_BITS + 1) * SUB_BUCKETS as u32) as usize;
+
+/// Log-linear histogram of nanosecond latencies.
+pub struct Histogram{
+    buckets: Box<[u64; BUCKETS]>,
+    count: u64,
+    max: u64,
+}
+impl Default for Histogram{
+    fn default() -> Self{
+        Histogram{
+            buckets: Box::new([0; BUCKETS]),
+            count: 0,
+            max: 0,
+        }
+    }
+}
+impl Histogram{
+    pub fn record(&mut self, value: u64){
+        self.buckets[bucket_index(value)] += 1;
+        self.count += 1;
+        self.max = self.max.max(value);
+    }
+    pub fn count(&self) -> u64{
+        self.count
+    }
+    pub fn max(&self) -> u64{
+        self.max
+    }
+    /// Returns the highest value equivalent to the recorded value at the given `quantile` (between 0 and 1).
+    pub fn value_at_quantile(&self, quantile: f64) -> u64{
+        let target = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate(){
+            seen += count;
+            if seen >= target{
+                return bucket_upper_bound(index).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+fn bucket_index(value: u64) -> usize{
+    if value < SUB_BUCKETS{
+        return value as usize;
+    }
+    let magnitude = 63 - value.leading_zeros();
+    let sub_bucket = (value >> (magnitude - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+    ((magnitude - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS + sub_bucket) as usize
+}
+fn bucket_upper_bound(index: usize) -> u64{
+    let index = index as u64;
+    if index < SUB_BUCKETS{
+        return index;
+    }
+    let magnitude = (index / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let sub_bucket = index % SUB_BUCKETS;
+    let lower = (SUB_BUCKETS + sub_bucket) << (magnitude - SUB_BUCKET_BITS);
+    lower.saturating_add((1 << (magnitude - SUB_BUCKET_BITS)) - 1)
+}
+
+/// Per-operation latency histograms shared by the filesystem handlers.
+#[derive(Default)]
+pub struct Profiler{
+    operations: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+impl Profiler{
+    /// Starts timing `operation`, recording the elapsed time once the returned timer is dropped.
+    pub fn time(self: &Arc<Self>, operation: &'static str) -> Timer{
+        Timer{
+            profiler: self.clone(),
+            operation,
+            start: Instant::now(),
+        }
+    }
+    pub fn record(&self, operation: &'static str, elapsed: Duration){
+        let mut operations = self.operations.lock().unwrap();
+        operations.entry(operation).or_default().record(elapsed.as_nanos() as u64);
+    }
+    /// Number of recorded calls of `operation`.
+    pub fn count(&self, operation: &str) -> u64{
+        self.operations.lock().unwrap().get(operation).map_or(0, Histogram::count)
+    }
+    /// Renders a table with the call count and p50/p99/max latencies of each operation.
+    pub fn summary(&self) -> String{
+        let operations = self.operations.lock().unwrap();
+        let mut summary = format!("{:<12} {:>10} {:>12} {:>12} {:>12}\n", "operation", "count", "p50", "p99", "max");
+        for (operation, histogram) in operations.iter(){
+            summary += &format!(
+                "{:<12} {:>10} {:>12?} {:>12?} {:>12?}\n",
+                operation,
+                histogram.count(),
+                Duration::from_nanos(histogram.value_at_quantile(0.5)),
+                Duration::from_nanos(histogram.value_at_quantile(0.99)),
+                Duration::from_nanos(histogram.max()),
+            );
+        }
+        summary
+    }
+}
+
+pub struct Timer{
+    profiler: Arc<Profiler>,
+    operation: &'static str,
+    start: Instant,
+}
+impl Drop for Timer{
+    fn drop(&mut self){
+        self.profiler.record(self.operation, self.start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn quantiles_stay_within_a_bucket_of_the_recorded_values(){
+        let mut histogram = Histogram::default();
+        for value in 1..=1000{
+            histogram.record(value);
+        }
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.max(), 1000);
+        for (quantile, exact) in [(0.5, 500), (0.99, 990), (1.0, 1000)]{
+            let value = histogram.value_at_quantile(quantile);
+            assert!(value >= exact && value <= exact + exact / SUB_BUCKETS, "{quantile} gave {value}");
+        }
+        assert_eq!(Histogram::default().value_at_quantile(0.5), 0);
+    }
+    #[test]
+    fn buckets_cover_every_value_in_order(){
+        for value in (0..4096).chain([u64::MAX / 2, u64::MAX]){
+            let index = bucket_index(value);
+            assert!(index < BUCKETS);
+            assert!(bucket_upper_bound(index) >= value);
+            assert!(index == 0 || bucket_upper_bound(index - 1) < value);
+        }
+    }
+    #[test]
+    fn timers_record_one_call_each(){
+        let profiler = Arc::new(Profiler::default());
+        for _ in 0..3{
+            let _timer = profiler.time("read");
+        }
+        drop(profiler.time("write"));
+        assert_eq!(profiler.count("read"), 3);
+        assert_eq!(profiler.count("write"), 1);
+        assert_eq!(profiler.count("lookup"), 0);
+        let summary = profiler.summary();
+        assert_eq!(summary.lines().count(), 3);
+        assert!(summary.lines().nth(1).unwrap().starts_with("read"));
+    }
+}
@@ -0,0 +1,418 @@
+//! TCP endpoint giving non-FUSE clients access to the file contents of a mounted filesystem.
+//!
+//! Clients send length-prefixed request frames and receive one response frame per request. All integers are little
+//! endian. A request is an opcode byte (0 read, 1 write, 2 snapshot, 3 sync), the path as a u32 length and its bytes,
+//! a u64 offset and a u32 length, followed by that many bytes of data for writes. Paths are `/`-separated and relative
+//! to the root, snapshots take their name in place of the path and ignore the offset and length, syncs ignore all
+//! three. The response starts with an i32 errno, 0 on success, followed by a u32 length and the bytes read for reads,
+//! the u32 number of bytes written for writes, or the u32 number of files whose buffered writes were applied for
+//! syncs. A sync only responds once the backing file is written. A path longer than `PATH_MAX` ends the connection,
+//! and a write of more than `MAX_DATA` bytes fails with `EINVAL` after its data was skipped. Reads return at most
+//! `MAX_DATA` bytes.
+//!
+//! A watch request (opcode 4) ignores the path, offset and length. Once it succeeded, the connection serves no more
+//! requests and streams the changes to the filesystem instead, as lines of JSON described in `watch`.
+//!
+//! A trim request (opcode 5) ignores the path, offset and length as well. It compacts the backing file and responds
+//! with the u64 number of bytes it shrank by.
+//!
+//! The number of requests in flight across all clients can be limited, bounding the memory taken by request data and
+//! replies being assembled to `MAX_DATA` per request. Requests beyond the limit wait after their opcode was read until
+//! another one responded.
+
+use std::ffi::OsStr;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::SystemTime;
+use fuser::{Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow};
+use log::error;
+use crate::ICFS;
+use crate::error::FsError;
+
+const OP_READ: u8 = 0;
+const OP_WRITE: u8 = 1;
+/// Most bytes of data a read or write carries, so the length a client announces can't make the server allocate
+/// gigabytes.
+const MAX_DATA: u32 = 1 << 20;
+const OP_SNAPSHOT: u8 = 2;
+const OP_SYNC: u8 = 3;
+const OP_WATCH: u8 = 4;
+const OP_TRIM: u8 = 5;
+
+/// Starts accepting clients on `addr` in the background, serving each connection on its own thread. At most
+/// `max_concurrency` requests are handled at once if it is given.
+pub fn serve(addr: impl ToSocketAddrs, filesystem: Arc<Mutex<ICFS>>, max_concurrency: Option<usize>) -> io::Result<()>{
+    let listener = TcpListener::bind(addr)?;
+    let limit = max_concurrency.map(|permits| Arc::new(Semaphore::new(permits)));
+    thread::spawn(move || {
+        for stream in listener.incoming(){
+            match stream{
+                Ok(stream) => {
+                    let filesystem = filesystem.clone();
+                    let limit = limit.clone();
+                    thread::spawn(move || {
+                        if let Err(error) = handle_client(stream, &filesystem, limit.as_deref()){
+                            error!("serve client failed: {error}");
+                        }
+                    });
+                }
+                Err(error) => error!("failed to accept serve client: {error}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_client(stream: TcpStream, filesystem: &Mutex<ICFS>, limit: Option<&Semaphore>) -> io::Result<()>{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop{
+        let mut op = [0; 1];
+        match reader.read_exact(&mut op){
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => return Err(error),
+        }
+        //held until the response is flushed, so the reply buffered by the writer counts as well
+        let permit = limit.map(Semaphore::acquire);
+        let path = read_bytes(&mut reader, libc::PATH_MAX as usize)?;
+        let offset = read_u64(&mut reader)?;
+        let length = read_u32(&mut reader)?;
+        match op[0]{
+            OP_READ => {
+                let result = with_inode(filesystem, &path, |filesystem, ino| filesystem.do_read(ino, offset as i64, length.min(MAX_DATA)));
+                match result{
+                    Ok(data) => {
+                        writer.write_all(&0i32.to_le_bytes())?;
+                        writer.write_all(&(data.len() as u32).to_le_bytes())?;
+                        writer.write_all(&data)?;
+                    }
+                    Err(error) => writer.write_all(&error.to_errno().to_le_bytes())?,
+                }
+            }
+            OP_WRITE => {
+                let result = if length > MAX_DATA{
+                    //skipped rather than buffered, the next request follows the data
+                    io::copy(&mut reader.by_ref().take(length.into()), &mut io::sink())?;
+                    Err(FsError::InvalidArgument)
+                } else {
+                    let mut data = vec![0; length as usize];
+                    reader.read_exact(&mut data)?;
+                    with_inode(filesystem, &path, |filesystem, ino| filesystem.do_write(ino, 0, offset as i64, &data))
+                };
+                match result{
+                    Ok(written) => {
+                        writer.write_all(&0i32.to_le_bytes())?;
+                        writer.write_all(&written.to_le_bytes())?;
+                    }
+                    Err(error) => writer.write_all(&error.to_errno().to_le_bytes())?,
+                }
+            }
+            OP_SNAPSHOT => {
+                let result = filesystem.lock().unwrap().do_snapshot(OsStr::from_bytes(&path));
+                let errno = match result{
+                    Ok(()) => 0,
+                    Err(error) => error.to_errno(),
+                };
+                writer.write_all(&errno.to_le_bytes())?;
+            }
+            OP_SYNC => {
+                let result = filesystem.lock().unwrap().do_syncfs();
+                match result{
+                    Ok(flushed) => {
+                        writer.write_all(&0i32.to_le_bytes())?;
+                        writer.write_all(&(flushed as u32).to_le_bytes())?;
+                    }
+                    Err(error) => writer.write_all(&error.to_errno().to_le_bytes())?,
+                }
+            }
+            OP_WATCH => {
+                let result = filesystem.lock().unwrap().subscribe();
+                match result{
+                    Ok(events) => {
+                        writer.write_all(&0i32.to_le_bytes())?;
+                        writer.flush()?;
+                        drop(permit);
+                        //the stream ends with the filesystem or once the client goes away
+                        for event in events{
+                            writeln!(writer, "{event}")?;
+                            writer.flush()?;
+                        }
+                        return Ok(());
+                    }
+                    Err(error) => writer.write_all(&error.to_errno().to_le_bytes())?,
+                }
+            }
+            OP_TRIM => {
+                let result = filesystem.lock().unwrap().do_trim();
+                match result{
+                    Ok(reclaimed) => {
+                        writer.write_all(&0i32.to_le_bytes())?;
+                        writer.write_all(&reclaimed.to_le_bytes())?;
+                    }
+                    Err(error) => writer.write_all(&error.to_errno().to_le_bytes())?,
+                }
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown serve opcode")),
+        }
+        writer.flush()?;
+    }
+}
+
+/// Counting semaphore handing out a fixed number of permits, blocking while all of them are taken.
+pub struct Semaphore{
+    available: Mutex<usize>,
+    released: Condvar,
+}
+impl Semaphore{
+    pub fn new(permits: usize) -> Self{
+        Semaphore{
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+    /// Waits for a permit, which is given back once the returned guard is dropped.
+    pub fn acquire(&self) -> Permit<'_>{
+        let mut available = self.released.wait_while(self.available.lock().unwrap(), |available| *available == 0).unwrap();
+        *available -= 1;
+        Permit{ semaphore: self }
+    }
+}
+pub struct Permit<'a>{
+    semaphore: &'a Semaphore,
+}
+impl Drop for Permit<'_>{
+    fn drop(&mut self){
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+/// Resolves `path` from the root and runs `operation` on the inode it names while holding the filesystem lock.
+fn with_inode<T>(filesystem: &Mutex<ICFS>, path: &[u8], operation: impl FnOnce(&mut ICFS, u64) -> Result<T, FsError>) -> Result<T, FsError>{
+    let mut filesystem = filesystem.lock().unwrap();
+    let mut ino = 1;
+    for name in path.split(|byte| *byte == b'/').filter(|name| !name.is_empty()){
+        ino = filesystem.do_lookup(ino, OsStr::from_bytes(name))?.ino;
+    }
+    operation(&mut filesystem, ino)
+}
+
+/// Reads a u32 length and that many bytes, failing with `InvalidData` if the length exceeds `limit`.
+fn read_bytes(reader: &mut impl Read, limit: usize) -> io::Result<Vec<u8>>{
+    let len = read_u32(reader)? as usize;
+    if len > limit{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "serve request field too long"));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+fn read_u32(reader: &mut impl Read) -> io::Result<u32>{
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+fn read_u64(reader: &mut impl Read) -> io::Result<u64>{
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Filesystem shared with the serve endpoint, forwarding every request to the `ICFS` behind the lock.
+pub struct SharedFilesystem(pub Arc<Mutex<ICFS>>);
+impl Filesystem for SharedFilesystem {
+    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), i32> {
+        self.0.lock().unwrap().init(req, config)
+    }
+    fn destroy(&mut self) {
+        self.0.lock().unwrap().destroy();
+    }
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.0.lock().unwrap().lookup(req, parent, name, reply);
+    }
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.0.lock().unwrap().forget(req, ino, nlookup);
+    }
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        self.0.lock().unwrap().getattr(req, ino, reply);
+    }
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        self.0.lock().unwrap().access(req, ino, mask, reply);
+    }
+    fn setattr(&mut self, req: &Request<'_>, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>, reply: ReplyAttr) {
+        self.0.lock().unwrap().setattr(req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags, reply);
+    }
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        self.0.lock().unwrap().mkdir(req, parent, name, mode, umask, reply);
+    }
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry) {
+        self.0.lock().unwrap().symlink(req, parent, link_name, target, reply);
+    }
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        self.0.lock().unwrap().readlink(req, ino, reply);
+    }
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.0.lock().unwrap().unlink(req, parent, name, reply);
+    }
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.0.lock().unwrap().rmdir(req, parent, name, reply);
+    }
+    fn read(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, size: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyData) {
+        self.0.lock().unwrap().read(req, ino, fh, offset, size, flags, lock_owner, reply);
+    }
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.0.lock().unwrap().open(req, ino, flags, reply);
+    }
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.0.lock().unwrap().flush(req, ino, fh, lock_owner, reply);
+    }
+    fn release(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, lock_owner: Option<u64>, flush: bool, reply: ReplyEmpty) {
+        self.0.lock().unwrap().release(req, ino, fh, flags, lock_owner, flush, reply);
+    }
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.0.lock().unwrap().opendir(req, ino, flags, reply);
+    }
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        self.0.lock().unwrap().releasedir(req, ino, fh, flags, reply);
+    }
+    fn write(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, data: &[u8], write_flags: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyWrite) {
+        self.0.lock().unwrap().write(req, ino, fh, offset, data, write_flags, flags, lock_owner, reply);
+    }
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.0.lock().unwrap().fsync(req, ino, fh, datasync, reply);
+    }
+    fn fallocate(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        self.0.lock().unwrap().fallocate(req, ino, fh, offset, length, mode, reply);
+    }
+    fn copy_file_range(&mut self, req: &Request<'_>, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u32, reply: ReplyWrite) {
+        self.0.lock().unwrap().copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply);
+    }
+    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        self.0.lock().unwrap().bmap(req, ino, blocksize, idx, reply);
+    }
+    fn rename(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        self.0.lock().unwrap().rename(req, parent, name, newparent, newname, flags, reply);
+    }
+    fn link(&mut self, req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        self.0.lock().unwrap().link(req, ino, newparent, newname, reply);
+    }
+    fn readdir(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.0.lock().unwrap().readdir(req, ino, fh, offset, reply);
+    }
+    fn readdirplus(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, reply: ReplyDirectoryPlus) {
+        self.0.lock().unwrap().readdirplus(req, ino, fh, offset, reply);
+    }
+    fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+        self.0.lock().unwrap().create(req, parent, name, mode, umask, flags, reply);
+    }
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        self.0.lock().unwrap().statfs(req, ino, reply);
+    }
+    fn setxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: i32, position: u32, reply: ReplyEmpty) {
+        self.0.lock().unwrap().setxattr(req, ino, name, value, flags, position, reply);
+    }
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.0.lock().unwrap().getxattr(req, ino, name, size, reply);
+    }
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.0.lock().unwrap().listxattr(req, ino, size, reply);
+    }
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.0.lock().unwrap().removexattr(req, ino, name, reply);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::net::SocketAddr;
+    use crate::Config;
+    use crate::storage::{FileStorage, Overlay};
+
+    /// Serves a fresh filesystem with the file `f` to a single client and connects to it.
+    fn connect() -> TcpStream{
+        connect_with(Config::default())
+    }
+    fn connect_with(config: Config) -> TcpStream{
+        let mut filesystem = ICFS::new(Overlay::new(FileStorage::new(), FileStorage::new()), config);
+        let (file, fh) = filesystem.do_create(1, OsStr::new("f"), 0o644, 0, 0, 0).unwrap();
+        filesystem.do_release(file.ino, fh).unwrap();
+        let filesystem = Mutex::new(filesystem);
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = handle_client(stream, &filesystem, None);
+        });
+        TcpStream::connect(addr).unwrap()
+    }
+    fn send(stream: &mut TcpStream, op: u8, path: &[u8], offset: u64, length: u32){
+        stream.write_all(&[op]).unwrap();
+        stream.write_all(&(path.len() as u32).to_le_bytes()).unwrap();
+        stream.write_all(path).unwrap();
+        stream.write_all(&offset.to_le_bytes()).unwrap();
+        stream.write_all(&length.to_le_bytes()).unwrap();
+    }
+    fn errno(stream: &mut TcpStream) -> i32{
+        let mut errno = [0; 4];
+        stream.read_exact(&mut errno).unwrap();
+        i32::from_le_bytes(errno)
+    }
+
+    #[test]
+    fn oversized_writes_are_skipped(){
+        let mut stream = connect();
+        send(&mut stream, OP_WRITE, b"f", 0, MAX_DATA + 1);
+        stream.write_all(&vec![1; MAX_DATA as usize + 1]).unwrap();
+        assert_eq!(errno(&mut stream), libc::EINVAL);
+        send(&mut stream, OP_WRITE, b"f", 0, 2);
+        stream.write_all(b"ok").unwrap();
+        assert_eq!(errno(&mut stream), 0);
+        assert_eq!(read_u32(&mut stream).unwrap(), 2);
+    }
+    #[test]
+    fn oversized_paths_end_the_connection(){
+        let mut stream = connect();
+        stream.write_all(&[OP_READ]).unwrap();
+        stream.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        assert_eq!(stream.read(&mut [0; 4]).unwrap(), 0);
+    }
+    #[test]
+    fn syncs_answer_once_the_backing_file_holds_the_writes(){
+        let backing = std::env::temp_dir().join(format!("icfs-serve-sync-test-{}", std::process::id()));
+        let mut stream = connect_with(Config{ backing_file: Some(backing.clone()), ..Config::default() });
+        send(&mut stream, OP_WRITE, b"f", 0, 5);
+        stream.write_all(b"saved").unwrap();
+        assert_eq!(errno(&mut stream), 0);
+        assert_eq!(read_u32(&mut stream).unwrap(), 5);
+        send(&mut stream, OP_SYNC, b"", 0, 0);
+        assert_eq!(errno(&mut stream), 0);
+        read_u32(&mut stream).unwrap();
+        let mut saved = ICFS::new(Overlay::new(FileStorage::new(), FileStorage::load(&backing).unwrap()), Config::default());
+        let file = saved.do_lookup(1, OsStr::new("f")).unwrap();
+        assert_eq!(&*saved.do_read(file.ino, 0, 16).unwrap(), b"saved");
+        let _ = std::fs::remove_file(&backing);
+    }
+    #[test]
+    fn reads_are_capped(){
+        let mut stream = connect();
+        for (offset, length) in [(0, MAX_DATA), (MAX_DATA.into(), 10)]{
+            send(&mut stream, OP_WRITE, b"f", offset, length);
+            stream.write_all(&vec![1; length as usize]).unwrap();
+            assert_eq!(errno(&mut stream), 0);
+            assert_eq!(read_u32(&mut stream).unwrap(), length);
+        }
+        send(&mut stream, OP_READ, b"f", 0, u32::MAX);
+        assert_eq!(errno(&mut stream), 0);
+        let length = read_u32(&mut stream).unwrap();
+        assert_eq!(length, MAX_DATA);
+        let mut data = vec![0; length as usize];
+        stream.read_exact(&mut data).unwrap();
+        assert!(data.iter().all(|byte| *byte == 1));
+    }
+}
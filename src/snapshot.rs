@@ -0,0 +1,217 @@
+//! Binary snapshot format used to persist a `FileStorage` to a host file.
+//!
+//! A snapshot is the `ICFS` magic followed by a format version and the root entry. Entries are encoded as a tag byte,
+//! their timestamps, permissions and extended attributes and then either the file contents, the list of named
+//! children or the target of a symbolic link. All integers are little endian. Version 1 snapshots predate extended
+//! attributes, version 2 ones permissions and version 3 ones ownership, all of them are still loaded.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use crate::storage::{Directory, FileStorage, FileStorageEntry, Metadata, DEFAULT_PERM};
+
+const MAGIC: &[u8; 4] = b"ICFS";
+const VERSION: u8 = 4;
+/// Last version without extended attributes.
+const VERSION_NO_XATTRS: u8 = 1;
+/// Last version without permission bits, whose entries get `DEFAULT_PERM`.
+const VERSION_NO_PERM: u8 = 2;
+/// Last version without owners, whose entries belong to root.
+const VERSION_NO_OWNER: u8 = 3;
+/// Deepest nesting accepted when loading, so a crafted snapshot can't overflow the stack.
+const MAX_DEPTH: usize = 4096;
+
+const TAG_FILE: u8 = 0;
+const TAG_DIRECTORY: u8 = 1;
+const TAG_WHITEOUT: u8 = 2;
+const TAG_SYMLINK: u8 = 3;
+
+impl FileStorage{
+    /// Writes the snapshot to `path`. It is written next to it first and renamed over it once it reached the disk,
+    /// so a crash in between leaves the previous snapshot intact.
+    pub fn save(&self, path: &Path) -> io::Result<()>{
+        let mut temp = path.as_os_str().to_os_string();
+        temp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        write_entry(&mut writer, &self.root)?;
+        writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+        fs::rename(&temp, path)
+    }
+    pub fn load(path: &Path) -> io::Result<Self>{
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC{
+            return Err(invalid_data("not an icfs snapshot"));
+        }
+        let version = read_u8(&mut reader)?;
+        if !(VERSION_NO_XATTRS..=VERSION).contains(&version){
+            return Err(invalid_data("unsupported snapshot version"));
+        }
+        match read_entry(&mut reader, version, 0)?{
+            root @ FileStorageEntry::Directory(..) => Ok(FileStorage{ root }),
+            _ => Err(invalid_data("snapshot root is not a directory")),
+        }
+    }
+}
+
+fn write_entry(writer: &mut impl Write, entry: &FileStorageEntry) -> io::Result<()>{
+    //children wait on an explicit stack behind their name instead of being written by recursing
+    let mut pending = vec![(None::<&OsString>, entry)];
+    while let Some((name, entry)) = pending.pop(){
+        if let Some(name) = name{
+            let name = name.as_bytes();
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name)?;
+        }
+        match entry{
+            FileStorageEntry::File(data, metadata) => {
+                writer.write_all(&[TAG_FILE])?;
+                write_metadata(writer, metadata)?;
+                writer.write_all(&data.len().to_le_bytes())?;
+                data.write_to(writer)?;
+            }
+            FileStorageEntry::Directory(directory, metadata) => {
+                writer.write_all(&[TAG_DIRECTORY])?;
+                write_metadata(writer, metadata)?;
+                writer.write_all(&(directory.len() as u64).to_le_bytes())?;
+                pending.extend(directory.iter().rev().map(|(name, child)| (Some(name), child)));
+            }
+            FileStorageEntry::Symlink(target, metadata) => {
+                writer.write_all(&[TAG_SYMLINK])?;
+                write_metadata(writer, metadata)?;
+                let target = target.as_bytes();
+                writer.write_all(&(target.len() as u32).to_le_bytes())?;
+                writer.write_all(target)?;
+            }
+            FileStorageEntry::Whiteout => {
+                writer.write_all(&[TAG_WHITEOUT])?;
+            }
+        }
+    }
+    Ok(())
+}
+fn read_entry(reader: &mut impl Read, version: u8, depth: usize) -> io::Result<FileStorageEntry>{
+    Ok(match read_u8(reader)?{
+        TAG_FILE => {
+            let metadata = read_metadata(reader, version)?;
+            let len = read_u64(reader)?;
+            let mut data = Vec::new();
+            reader.by_ref().take(len).read_to_end(&mut data)?;
+            if data.len() as u64 != len{
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            FileStorageEntry::File(data.into(), metadata)
+        }
+        TAG_DIRECTORY => {
+            if depth > MAX_DEPTH{
+                return Err(invalid_data("snapshot is nested too deeply"));
+            }
+            let metadata = read_metadata(reader, version)?;
+            let mut directory = Directory::default();
+            for _ in 0..read_u64(reader)?{
+                let mut name = vec![0; read_u32(reader)? as usize];
+                reader.read_exact(&mut name)?;
+                directory.insert(OsString::from_vec(name), read_entry(reader, version, depth + 1)?);
+            }
+            FileStorageEntry::Directory(Arc::new(directory), metadata)
+        }
+        TAG_SYMLINK => {
+            let metadata = read_metadata(reader, version)?;
+            let mut target = vec![0; read_u32(reader)? as usize];
+            reader.read_exact(&mut target)?;
+            FileStorageEntry::Symlink(OsString::from_vec(target), metadata)
+        }
+        TAG_WHITEOUT => FileStorageEntry::Whiteout,
+        _ => return Err(invalid_data("unknown entry tag")),
+    })
+}
+
+fn write_metadata(writer: &mut impl Write, metadata: &Metadata) -> io::Result<()>{
+    for time in [metadata.atime, metadata.mtime, metadata.ctime, metadata.crtime]{
+        let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        writer.write_all(&since_epoch.as_secs().to_le_bytes())?;
+        writer.write_all(&since_epoch.subsec_nanos().to_le_bytes())?;
+    }
+    writer.write_all(&metadata.perm.to_le_bytes())?;
+    writer.write_all(&metadata.uid.to_le_bytes())?;
+    writer.write_all(&metadata.gid.to_le_bytes())?;
+    writer.write_all(&(metadata.xattrs.len() as u32).to_le_bytes())?;
+    for (name, value) in &metadata.xattrs{
+        writer.write_all(&(name.len() as u32).to_le_bytes())?;
+        writer.write_all(name.as_bytes())?;
+        writer.write_all(&(value.len() as u32).to_le_bytes())?;
+        writer.write_all(value)?;
+    }
+    Ok(())
+}
+fn read_metadata(reader: &mut impl Read, version: u8) -> io::Result<Metadata>{
+    let mut times = [SystemTime::UNIX_EPOCH; 4];
+    for time in &mut times{
+        let secs = read_u64(reader)?;
+        let nanos = read_u32(reader)?;
+        *time = SystemTime::UNIX_EPOCH + Duration::new(secs, nanos);
+    }
+    let [atime, mtime, ctime, crtime] = times;
+    let perm = if version > VERSION_NO_PERM{
+        read_u16(reader)?
+    } else {
+        DEFAULT_PERM
+    };
+    let (uid, gid) = if version > VERSION_NO_OWNER{
+        (read_u32(reader)?, read_u32(reader)?)
+    } else {
+        (0, 0)
+    };
+    let mut xattrs = BTreeMap::new();
+    if version > VERSION_NO_XATTRS{
+        for _ in 0..read_u32(reader)?{
+            let mut name = vec![0; read_u32(reader)? as usize];
+            reader.read_exact(&mut name)?;
+            let mut value = vec![0; read_u32(reader)? as usize];
+            reader.read_exact(&mut value)?;
+            xattrs.insert(OsString::from_vec(name), value);
+        }
+    }
+    Ok(Metadata{
+        atime,
+        mtime,
+        ctime,
+        crtime,
+        perm,
+        uid,
+        gid,
+        xattrs,
+    })
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8>{
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+fn read_u16(reader: &mut impl Read) -> io::Result<u16>{
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+fn read_u32(reader: &mut impl Read) -> io::Result<u32>{
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+fn read_u64(reader: &mut impl Read) -> io::Result<u64>{
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+pub(crate) fn invalid_data(message: &str) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
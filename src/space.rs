@@ -0,0 +1,314 @@
+//! Accounting of the space file contents take up against the configured size of the filesystem and the quotas of
+//! their owners.
+//!
+//! Space is counted in whole blocks of `BLOCK_SIZE`, the unit `statfs` reports in, so a program that sees N free
+//! blocks can grow its files by exactly N blocks before writes fail with `ENOSPC`. Quotas are counted in bytes of
+//! file contents, and growing a file past the quota of its owner fails with `EDQUOT`. Snapshots share the contents of
+//! the live tree and aren't counted.
+//!
+//! The blocks in use and the bytes every user owns are counted up once when the filesystem is created and kept up to
+//! date by the operations changing them, so checking the free space or a quota doesn't walk the tree. Every file with
+//! an inode is counted under it, the ones that weren't looked up yet are counted with the blocks of their contents.
+//! The entries of the live tree are counted the same way, as the inodes `statfs` reports in use. Inodes aren't
+//! limited, so the rest of the inode numbers are reported free.
+//!
+//! With `StatfsSource::Host`, `statfs` reports the space of the host filesystem holding the backing file instead,
+//! which is where the contents end up once they are saved.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use log::error;
+use crate::{ICFS, BLOCK_SIZE};
+use crate::error::FsError;
+use crate::storage::{FileStorageEntry, FileStoragePath, Metadata};
+
+/// Where the space reported by `statfs` comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StatfsSource{
+    /// The configured size of the filesystem and the space its files take up.
+    #[default]
+    Synthetic,
+    /// The host filesystem holding the backing file.
+    Host,
+}
+/// Space a file is counted with, in blocks towards the size of the filesystem and in bytes towards the quota of its
+/// owner.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Usage{
+    blocks: u64,
+    bytes: u64,
+    uid: u32,
+}
+impl Usage{
+    fn of(size: u64, metadata: &Metadata) -> Self{
+        Usage{ blocks: blocks(size), bytes: size, uid: metadata.uid }
+    }
+}
+/// Space reported by `statfs`, in blocks of `block_size` bytes, and the inodes of the filesystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FsStats{
+    pub blocks: u64,
+    pub free: u64,
+    /// Free blocks available to unprivileged users.
+    pub available: u64,
+    pub files: u64,
+    pub free_files: u64,
+    pub block_size: u32,
+}
+
+impl ICFS{
+    /// Space of the filesystem as reported by `statfs`. Without a size limit, the synthetic space is all zeroes.
+    pub fn do_statfs(&self) -> Result<FsStats, FsError>{
+        match (self.config.statfs, &self.config.backing_file){
+            (StatfsSource::Host, Some(backing_file)) => host_stats(backing_file),
+            _ => {
+                let (blocks, free) = self.block_usage().unwrap_or((0, 0));
+                //every inode number but 0, which the kernel reserves, can be handed out
+                let (files, free_files) = (u64::MAX, u64::MAX - self.used_inodes);
+                Ok(FsStats{ blocks, free, available: free, files, free_files, block_size: BLOCK_SIZE as u32 })
+            }
+        }
+    }
+    /// Total and free blocks of the filesystem, or `None` if its size isn't limited.
+    pub fn block_usage(&self) -> Option<(u64, u64)>{
+        let total = self.config.size? / BLOCK_SIZE;
+        Some((total, total.saturating_sub(self.used_blocks)))
+    }
+    /// Blocks taken up by the files of the live tree.
+    pub fn used_blocks(&self) -> u64{
+        self.used_blocks
+    }
+    /// Counts the blocks taken up by the files of the live tree from scratch, which the running count always matches.
+    pub fn count_used_blocks(&self) -> u64{
+        let mut used = 0;
+        self.for_each_file(|size, _| used += blocks(size));
+        used
+    }
+    /// Bytes of file contents owned by every user, kept up to date like the blocks in use.
+    pub fn owned_bytes(&self) -> &HashMap<u32, u64>{
+        &self.owned_bytes
+    }
+    /// Counts the bytes of file contents every user owns from scratch, which the running count always matches for
+    /// the users owning any.
+    pub fn count_owned_bytes(&self) -> HashMap<u32, u64>{
+        let mut owned = HashMap::new();
+        self.for_each_file(|size, metadata| *owned.entry(metadata.uid).or_default() += size);
+        owned
+    }
+    /// Entries of the live tree, each of which takes up an inode once it is looked up.
+    pub fn used_inodes(&self) -> u64{
+        self.used_inodes
+    }
+    /// Counts the entries of the live tree from scratch, the root included, which the running count always matches.
+    pub fn count_used_inodes(&self) -> u64{
+        let mut used = 0;
+        let mut pending = vec![FileStoragePath::root()];
+        while let Some(path) = pending.pop(){
+            used += 1;
+            for name in self.files.list(&path).unwrap_or_default(){
+                let child = path.with_pushed(&name);
+                if !self.files.is_snapshot(&child){
+                    pending.push(child);
+                }
+            }
+        }
+        used
+    }
+    /// Counts an entry created in the live tree.
+    pub(crate) fn count_entry(&mut self){
+        self.used_inodes += 1;
+    }
+    /// Stops counting an entry removed from the live tree.
+    pub(crate) fn uncount_entry(&mut self){
+        self.used_inodes = self.used_inodes.saturating_sub(1);
+    }
+    fn count(&mut self, usage: Usage){
+        self.used_blocks += usage.blocks;
+        *self.owned_bytes.entry(usage.uid).or_default() += usage.bytes;
+    }
+    fn uncount(&mut self, usage: Usage){
+        self.used_blocks = self.used_blocks.saturating_sub(usage.blocks);
+        if let Some(owned) = self.owned_bytes.get_mut(&usage.uid){
+            *owned = owned.saturating_sub(usage.bytes);
+        }
+    }
+    /// Brings the space counted for the file `ino` up to date after its size or owner changed or it was removed.
+    pub(crate) fn recount(&mut self, ino: u64){
+        //an inode left on the path of an unlinked file reaches whatever took its place, which is counted under the
+        //inode owning the path
+        let owner = self.inode_to_file.get(&ino).and_then(|path| self.file_to_inode.get(path)).copied();
+        for inode in [Some(ino), owner.filter(|owner| *owner != ino)].into_iter().flatten(){
+            let usage = self.inode_usage(inode);
+            let counted = self.counted.insert(inode, usage).unwrap_or_default();
+            self.count(usage);
+            self.uncount(counted);
+        }
+    }
+    /// Moves the count of the file at `path` under its new inode `ino`.
+    pub(crate) fn count_inode(&mut self, ino: u64, path: &FileStoragePath){
+        self.counted.insert(ino, self.path_usage(path));
+    }
+    /// Stops counting the file `ino` under its inode once the inode was released. A file still at `path` is counted
+    /// with the size of its contents again.
+    pub(crate) fn uncount_inode(&mut self, ino: u64, path: Option<&FileStoragePath>){
+        let counted = self.counted.remove(&ino).unwrap_or_default();
+        let usage = path.map_or(Usage::default(), |path| self.path_usage(path));
+        self.count(usage);
+        self.uncount(counted);
+    }
+    /// Stops counting the removed `entry`, which had no inode.
+    pub(crate) fn uncount_removed(&mut self, entry: &FileStorageEntry){
+        if let FileStorageEntry::File(content, metadata) = entry{
+            self.uncount(Usage::of(content.len(), metadata));
+        }
+    }
+    /// Space counted for the files at and below `root`, under their inodes or with their contents for the ones
+    /// without.
+    pub(crate) fn counted_below(&self, root: &FileStoragePath) -> Vec<Usage>{
+        let mut counted = self.inode_to_file.iter()
+            .filter(|(_, path)| path.starts_with(root))
+            .map(|(inode, _)| self.counted.get(inode).copied().unwrap_or_default())
+            .collect::<Vec<_>>();
+        let mut pending = vec![root.clone()];
+        while let Some(path) = pending.pop(){
+            match self.files.lookup(&path){
+                Some(FileStorageEntry::File(..)) if !self.file_to_inode.contains_key(&path) => counted.push(self.path_usage(&path)),
+                Some(FileStorageEntry::Directory(..)) => {
+                    for name in self.files.list(&path).unwrap_or_default(){
+                        pending.push(path.with_pushed(&name));
+                    }
+                }
+                _ => {}
+            }
+        }
+        counted
+    }
+    /// Recounts the files at and below `root` once entries moved there, given the space `counted_below` reported
+    /// for the entries involved before the move.
+    pub(crate) fn recount_below(&mut self, root: &FileStoragePath, before: Vec<Usage>){
+        let inodes = self.inode_to_file.iter().filter(|(_, path)| path.starts_with(root)).map(|(inode, _)| *inode).collect::<Vec<_>>();
+        for inode in inodes{
+            self.counted.insert(inode, self.inode_usage(inode));
+        }
+        for usage in self.counted_below(root){
+            self.count(usage);
+        }
+        for usage in before{
+            self.uncount(usage);
+        }
+    }
+    fn inode_usage(&self, ino: u64) -> Usage{
+        if let Some(path) = self.inode_to_file.get(&ino){
+            //an inode left on the path of an unlinked file doesn't own whatever took its place
+            if self.files.is_snapshot(path) || self.file_to_inode.get(path) != Some(&ino){
+                return Usage::default();
+            }
+        }
+        match self.get_entry(ino){
+            Some(FileStorageEntry::File(content, metadata)) => Usage::of(self.file_size(ino, content.len()), metadata),
+            _ => Usage::default(),
+        }
+    }
+    fn path_usage(&self, path: &FileStoragePath) -> Usage{
+        match self.files.lookup(path){
+            Some(FileStorageEntry::File(content, metadata)) if !self.files.is_snapshot(path) => Usage::of(content.len(), metadata),
+            _ => Usage::default(),
+        }
+    }
+    /// Fails with `ENOSPC` if growing the file `ino` to `size` bytes needs more blocks than are free, or with `EDQUOT`
+    /// if it takes its owner past their quota.
+    pub(crate) fn check_space(&self, ino: u64, size: u64) -> Result<(), FsError>{
+        let (current, owner) = match self.get_entry(ino){
+            Some(FileStorageEntry::File(content, metadata)) => (self.file_size(ino, content.len()), metadata.uid),
+            _ => (0, 0),
+        };
+        if let Some((_, free)) = self.block_usage(){
+            if blocks(size).saturating_sub(blocks(current)) > free{
+                return Err(FsError::NoSpace);
+            }
+        }
+        if let Some(&quota) = self.config.quotas.get(&owner){
+            if size > current && self.owned(owner) + (size - current) > quota{
+                return Err(FsError::QuotaExceeded);
+            }
+        }
+        Ok(())
+    }
+    /// Fails with `EDQUOT` if handing the file `ino` to `uid` takes them past their quota.
+    pub(crate) fn check_quota_transfer(&self, ino: u64, uid: u32) -> Result<(), FsError>{
+        let Some(&quota) = self.config.quotas.get(&uid) else {
+            return Ok(());
+        };
+        let size = match self.get_entry(ino){
+            Some(FileStorageEntry::File(content, metadata)) if metadata.uid != uid => self.file_size(ino, content.len()),
+            _ => return Ok(()),
+        };
+        if self.owned(uid) + size > quota{
+            return Err(FsError::QuotaExceeded);
+        }
+        Ok(())
+    }
+    fn owned(&self, uid: u32) -> u64{
+        self.owned_bytes.get(&uid).copied().unwrap_or(0)
+    }
+    /// Calls `visit` with the size and metadata of every file of the live tree, including unlinked ones still open.
+    fn for_each_file(&self, mut visit: impl FnMut(u64, &Metadata)){
+        let mut pending = vec![FileStoragePath::root()];
+        while let Some(path) = pending.pop(){
+            match self.files.lookup(&path){
+                Some(FileStorageEntry::File(content, metadata)) => {
+                    visit(match self.file_to_inode.get(&path){
+                        Some(inode) => self.file_size(*inode, content.len()),
+                        None => content.len(),
+                    }, metadata);
+                }
+                Some(FileStorageEntry::Directory(..)) => {
+                    for name in self.files.list(&path).unwrap_or_default(){
+                        let child = path.with_pushed(&name);
+                        if !self.files.is_snapshot(&child){
+                            pending.push(child);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (inode, entry) in &self.unlinked{
+            if let FileStorageEntry::File(content, metadata) = entry{
+                visit(self.file_size(*inode, content.len()), metadata);
+            }
+        }
+    }
+}
+
+/// Space of the host filesystem holding `backing_file`, which may not have been written yet.
+fn host_stats(backing_file: &Path) -> Result<FsStats, FsError>{
+    let directory = match backing_file.parent(){
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let path = CString::new(directory.as_os_str().as_bytes()).map_err(|_| FsError::InvalidArgument)?;
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+    //the path is null terminated and statvfs fills in the whole struct when it succeeds
+    let stats = unsafe{
+        if libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) != 0{
+            error!("failed to query the space of {}: {}", directory.display(), std::io::Error::last_os_error());
+            return Err(FsError::Io);
+        }
+        stats.assume_init()
+    };
+    Ok(FsStats{
+        blocks: stats.f_blocks,
+        free: stats.f_bfree,
+        available: stats.f_bavail,
+        files: stats.f_files,
+        free_files: stats.f_ffree,
+        block_size: stats.f_frsize as u32,
+    })
+}
+fn blocks(size: u64) -> u64{
+    size.div_ceil(BLOCK_SIZE)
+}
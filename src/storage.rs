@@ -0,0 +1,1361 @@
+use std::collections::{BTreeMap, HashSet};
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use globset::GlobSet;
+use indexmap::IndexMap;
+use indexmap::map::Entry;
+use crate::content::FileContent;
+use crate::error::FsError;
+
+/// Most symbolic links followed while resolving a single path, matching the limit of Linux.
+const MAX_SYMLINK_HOPS: usize = 40;
+/// Read-only directory under the root listing the snapshots, present once the first one was taken.
+pub const SNAPSHOTS: &str = ".snapshots";
+/// Permissions of entries that weren't given any, such as the seeded files.
+pub const DEFAULT_PERM: u16 = 0o777;
+
+/// Union of a read-only lower `FileStorage` and a writable upper one. Lookups fall through to the lower layer,
+/// mutations copy the affected entries up first and deletions of lower entries leave a whiteout behind.
+pub struct Overlay{
+    lower: FileStorage,
+    upper: FileStorage,
+    /// Read-only copies of the overlay by name, sharing all entries that weren't modified since.
+    snapshots: BTreeMap<OsString, Overlay>,
+    /// Entry reported for the `SNAPSHOTS` directory.
+    snapshots_entry: FileStorageEntry,
+}
+impl Overlay{
+    pub fn new(lower: FileStorage, upper: FileStorage) -> Self{
+        Overlay{
+            lower,
+            upper,
+            snapshots: BTreeMap::new(),
+            snapshots_entry: FileStorageEntry::Directory(Arc::default(), Metadata{ perm: 0o555, ..Metadata::now() }),
+        }
+    }
+    /// Captures the current tree as the snapshot `name`, browsable under `SNAPSHOTS`. Only the roots of the layers
+    /// are copied, later modifications copy the directories along their path instead of changing the snapshot.
+    pub fn snapshot(&mut self, name: &OsStr) -> Result<(), FsError>{
+        if self.snapshots.contains_key(name){
+            return Err(FsError::Exists);
+        }
+        let snapshot = Overlay::new(self.lower.clone(), self.upper.clone());
+        self.snapshots.insert(name.to_os_string(), snapshot);
+        if let Some(metadata) = self.snapshots_entry.metadata_mut(){
+            let now = SystemTime::now();
+            metadata.mtime = now;
+            metadata.ctime = now;
+        }
+        Ok(())
+    }
+    /// Whether `path` lies within the read-only `SNAPSHOTS` directory, including the directory itself.
+    pub fn is_snapshot(&self, path: &FileStoragePath) -> bool{
+        !self.snapshots.is_empty() && path.parts.first().is_some_and(|part| &**part == SNAPSHOTS)
+    }
+    /// Logical device holding the entry at `path`.
+    pub fn device<'a>(&self, path: &'a FileStoragePath) -> Device<'a>{
+        if !self.is_snapshot(path){
+            return Device::Live;
+        }
+        match path.parts.get(1){
+            Some(name) => Device::Snapshot(name),
+            None => Device::Snapshots,
+        }
+    }
+    /// Splits a path below the `SNAPSHOTS` directory into its snapshot and the path within that snapshot.
+    fn snapshot_path(&self, path: &FileStoragePath) -> Option<(&Overlay, FileStoragePath)>{
+        let snapshot = self.snapshots.get(&**path.parts.get(1)?)?;
+        Some((snapshot, FileStoragePath{ parts: path.parts[2..].to_vec() }))
+    }
+    /// The writable layer, including whiteouts for deleted lower entries.
+    pub fn upper(&self) -> &FileStorage{
+        &self.upper
+    }
+    /// Resolves the symbolic links along `path`, including its last component. Absolute targets are resolved from the
+    /// root and relative ones from the directory containing the link.
+    pub fn resolve(&self, path: &FileStoragePath) -> Result<FileStoragePath, FsError>{
+        let mut resolved = FileStoragePath::root();
+        let mut remaining = path.parts.iter().rev().cloned().collect::<Vec<_>>();
+        let mut hops = 0;
+        while let Some(part) = remaining.pop(){
+            if &*part == "."{
+                continue;
+            }
+            if &*part == ".."{
+                resolved = resolved.with_popped();
+                continue;
+            }
+            let next = resolved.with_pushed(&part);
+            match self.lookup(&next){
+                Some(FileStorageEntry::Symlink(target, _)) => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS{
+                        return Err(FsError::Loop);
+                    }
+                    let target = Path::new(target);
+                    if target.has_root(){
+                        resolved = FileStoragePath::root();
+                    }
+                    for component in target.components().rev(){
+                        match component{
+                            Component::Normal(name) => remaining.push(intern(name)),
+                            Component::ParentDir => remaining.push(intern(OsStr::new(".."))),
+                            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+                        }
+                    }
+                }
+                Some(_) => resolved = next,
+                None => return Err(FsError::NotFound),
+            }
+        }
+        Ok(resolved)
+    }
+    pub fn lookup(&self, path: &FileStoragePath) -> Option<&FileStorageEntry>{
+        if self.is_snapshot(path){
+            if path.depth() == 1{
+                return Some(&self.snapshots_entry);
+            }
+            let (snapshot, path) = self.snapshot_path(path)?;
+            return snapshot.lookup(&path);
+        }
+        match self.upper.lookup_layer(path){
+            LayerLookup::Found(entry) => Some(entry),
+            LayerLookup::Masked => None,
+            LayerLookup::Missing => self.lower.lookup(path),
+        }
+    }
+    pub fn lookup_mut(&mut self, path: &FileStoragePath) -> Option<&mut FileStorageEntry>{
+        if self.is_snapshot(path){
+            return None;
+        }
+        self.copy_up(path)?;
+        self.upper.lookup_mut(path)
+    }
+    /// Returns the entry at `path` only if it's already present in the upper layer, without copying it up.
+    pub fn lookup_upper_mut(&mut self, path: &FileStoragePath) -> Option<&mut FileStorageEntry>{
+        if self.is_snapshot(path){
+            return None;
+        }
+        self.upper.lookup_mut(path)
+    }
+    pub fn contains(&self, path: &FileStoragePath) -> bool{
+        self.lookup(path).is_some()
+    }
+    /// Lists the merged children of the directory at `path`.
+    pub fn list(&self, path: &FileStoragePath) -> Option<Vec<OsString>>{
+        if self.is_snapshot(path){
+            if path.depth() == 1{
+                return Some(self.snapshots.keys().cloned().collect());
+            }
+            let (snapshot, path) = self.snapshot_path(path)?;
+            return snapshot.list(&path);
+        }
+        let lower = self.lower.lookup(path).and_then(FileStorageEntry::as_dir);
+        let (upper, lower) = match self.upper.lookup_layer(path){
+            LayerLookup::Found(FileStorageEntry::Directory(directory, _)) => (Some(directory), lower),
+            LayerLookup::Found(_) | LayerLookup::Masked => return None,
+            LayerLookup::Missing => (None, Some(lower?)),
+        };
+        let mut names = Vec::new();
+        if let Some(upper) = upper{
+            names.extend(upper.iter().filter(|(_, entry)| !matches!(entry, FileStorageEntry::Whiteout)).map(|(name, _)| name.clone()));
+        }
+        if let Some(lower) = lower{
+            names.extend(lower.keys().filter(|name| upper.is_none_or(|upper| !upper.contains_key(*name))).cloned());
+        }
+        if path.depth() == 0 && !self.snapshots.is_empty(){
+            //the snapshots directory shadows an entry of the same name
+            names.retain(|name| name != SNAPSHOTS);
+            names.push(OsString::from(SNAPSHOTS));
+        }
+        Some(names)
+    }
+    /// Deepest directory nesting below the merged directory at `path`, 0 if it has no subdirectories. Walks the tree
+    /// without recursion so arbitrarily deep trees can't overflow the stack.
+    pub fn directory_height(&self, path: &FileStoragePath) -> usize{
+        let mut height = 0;
+        let mut pending = vec![path.clone()];
+        while let Some(directory) = pending.pop(){
+            height = height.max(directory.depth() - path.depth());
+            for name in self.list(&directory).unwrap_or_default(){
+                let child = directory.with_pushed(&name);
+                if self.lookup(&child).is_some_and(FileStorageEntry::is_dir){
+                    pending.push(child);
+                }
+            }
+        }
+        height
+    }
+    /// Places `entry` at `path`, replacing any whiteout there but failing if the merged tree has an entry there
+    /// already. Directories inserted over a lower directory are made opaque so the lower children don't show through.
+    pub fn insert(&mut self, path: &FileStoragePath, entry: FileStorageEntry) -> Result<(), FsError>{
+        if self.is_snapshot(path){
+            return Err(FsError::ReadOnly);
+        }
+        if self.contains(path){
+            return Err(FsError::Exists);
+        }
+        let is_directory = entry.is_dir();
+        self.copy_up(&path.with_popped()).ok_or(FsError::NotFound)?;
+        self.upper.insert(path, entry)?;
+        if is_directory{
+            self.mask_lower(path);
+        }
+        Ok(())
+    }
+    /// Places a whiteout at `path` in the upper layer, masking whatever the lower layer has there.
+    pub fn whiteout(&mut self, path: &FileStoragePath){
+        if self.is_snapshot(path){
+            return;
+        }
+        if let (Some(name), Some(directory)) = (path.file_name(), self.lookup_mut(&path.with_popped()).and_then(FileStorageEntry::as_dir_mut)){
+            directory.insert(name, FileStorageEntry::Whiteout);
+        }
+    }
+    /// Removes the entry at `path` and returns it with the lower layer merged in.
+    pub fn remove(&mut self, path: &FileStoragePath) -> Result<FileStorageEntry, FsError>{
+        if self.is_snapshot(path){
+            return Err(FsError::ReadOnly);
+        }
+        let entry = self.materialize(path).ok_or(FsError::NotFound)?;
+        self.copy_up(&path.with_popped()).ok_or(FsError::NotFound)?;
+        if self.lower.lookup(path).is_some(){
+            let name = path.file_name().ok_or(FsError::InvalidArgument)?;
+            self.upper.get_mut_parent(path)?.insert(name, FileStorageEntry::Whiteout);
+        } else {
+            self.upper.remove(path)?;
+        }
+        Ok(entry)
+    }
+    /// Removes the empty directory at `path` like `remove`, failing with `ENOTDIR` if it isn't a directory and with
+    /// `ENOTEMPTY` if the merged directory still has children.
+    pub fn remove_dir(&mut self, path: &FileStoragePath) -> Result<FileStorageEntry, FsError>{
+        match self.lookup(path){
+            Some(FileStorageEntry::Directory(..)) => {}
+            Some(_) => return Err(FsError::NotADir),
+            None => return Err(FsError::NotFound),
+        }
+        if !self.list(path).unwrap_or_default().is_empty(){
+            return Err(FsError::NotEmpty);
+        }
+        self.remove(path)
+    }
+    /// Moves the entry at `from` and everything below it to `to`, failing if `from` is missing, `to` is taken or
+    /// lies inside `from`. Nothing is moved on failure.
+    pub fn move_entry(&mut self, from: &FileStoragePath, to: &FileStoragePath) -> Result<(), FsError>{
+        if self.is_snapshot(from) || self.is_snapshot(to){
+            return Err(FsError::ReadOnly);
+        }
+        check_move(from, to, self.lookup(from), self.lookup(to), self.lookup(&to.with_popped()))?;
+        let entry = self.remove(from)?;
+        self.insert(to, entry)
+    }
+    /// Removes the whiteouts of the upper layer that mask nothing in the lower layer, returning how many there were.
+    pub fn drop_stale_whiteouts(&mut self) -> usize{
+        let stale = self.upper.walk()
+            .filter(|(path, entry)| matches!(entry, FileStorageEntry::Whiteout) && self.lower.lookup(path).is_none())
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        for path in &stale{
+            if let (Some(name), Ok(directory)) = (path.file_name(), self.upper.get_mut_parent(path)){
+                directory.shift_remove(&name);
+            }
+        }
+        stale.len()
+    }
+    fn copy_up(&mut self, path: &FileStoragePath) -> Option<()>{
+        match self.upper.lookup_layer(path){
+            LayerLookup::Found(_) => return Some(()),
+            LayerLookup::Masked => return None,
+            LayerLookup::Missing => {}
+        }
+        let copy = match self.lower.lookup(path)?{
+            FileStorageEntry::File(data, metadata) => FileStorageEntry::File(data.clone(), metadata.clone()),
+            FileStorageEntry::Directory(_, metadata) => FileStorageEntry::Directory(Arc::default(), metadata.clone()),
+            FileStorageEntry::Symlink(target, metadata) => FileStorageEntry::Symlink(target.clone(), metadata.clone()),
+            FileStorageEntry::Whiteout => return None,
+        };
+        let parent = path.with_popped();
+        self.copy_up(&parent)?;
+        self.upper.lookup_mut(&parent)?.as_dir_mut()?.insert(path.file_name()?, copy);
+        Some(())
+    }
+    fn materialize(&self, path: &FileStoragePath) -> Option<FileStorageEntry>{
+        let mut copies = Vec::new();
+        let mut pending = vec![(0, path.clone())];
+        while let Some((parent, path)) = pending.pop(){
+            let copy = match self.lookup(&path)?{
+                FileStorageEntry::File(data, metadata) => FileStorageEntry::File(data.clone(), metadata.clone()),
+                FileStorageEntry::Symlink(target, metadata) => FileStorageEntry::Symlink(target.clone(), metadata.clone()),
+                FileStorageEntry::Directory(_, metadata) => {
+                    for name in self.list(&path)?{
+                        pending.push((copies.len(), path.with_pushed(&name)));
+                    }
+                    FileStorageEntry::Directory(Arc::default(), metadata.clone())
+                }
+                FileStorageEntry::Whiteout => return None,
+            };
+            copies.push((parent, path.file_name().unwrap_or_default(), copy));
+        }
+        assemble(copies)
+    }
+    fn mask_lower(&mut self, path: &FileStoragePath){
+        let mut pending = vec![path.clone()];
+        while let Some(path) = pending.pop(){
+            let Some(names) = self.lower.lookup(&path).and_then(FileStorageEntry::as_dir).map(|directory| directory.keys().cloned().collect::<Vec<_>>()) else {
+                continue;
+            };
+            for name in names{
+                let child_path = path.with_pushed(&name);
+                match self.upper.lookup_mut(&path).and_then(FileStorageEntry::as_dir_mut){
+                    Some(directory) => match directory.entry(name){
+                        Entry::Occupied(_) => pending.push(child_path),
+                        Entry::Vacant(vacant) => {
+                            vacant.insert(FileStorageEntry::Whiteout);
+                        }
+                    },
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+/// Builds a tree out of entries listed parents first, each with the index of its parent and its name. Directories are
+/// listed empty and filled in from the last entry backwards, so deep trees are built without recursing.
+fn assemble(mut entries: Vec<(usize, OsString, FileStorageEntry)>) -> Option<FileStorageEntry>{
+    while entries.len() > 1{
+        let (parent, name, entry) = entries.pop()?;
+        if let FileStorageEntry::Directory(directory, _) = &mut entries[parent].2{
+            Arc::make_mut(directory).insert(name, entry);
+        }
+    }
+    entries.pop().map(|(_, _, entry)| entry)
+}
+
+/// Logical device an entry of an `Overlay` lives on. Entries can't be linked or renamed across devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Device<'a>{
+    /// The live tree, with the lower layer merged into the upper one.
+    Live,
+    /// The `SNAPSHOTS` directory itself.
+    Snapshots,
+    /// The snapshot of the given name.
+    Snapshot(&'a OsStr),
+}
+/// Children of a directory by name, in the order they were inserted.
+pub type Directory = IndexMap<OsString, FileStorageEntry, DirectoryHasher>;
+/// Seed of every `DirectoryHasher`, chosen randomly on first use unless set before with `set_hash_seed`.
+static HASH_SEED: OnceLock<u64> = OnceLock::new();
+/// Fixes the seed the directory maps are hashed with, so their iteration order is reproducible across runs. Fails if
+/// a directory was already created with another seed.
+pub fn set_hash_seed(seed: u64) -> Result<(), u64>{
+    match HASH_SEED.set(seed){
+        Err(_) if HASH_SEED.get() != Some(&seed) => Err(*HASH_SEED.get().unwrap()),
+        _ => Ok(()),
+    }
+}
+/// SipHash seeded with the process wide hash seed, which keeps the directory maps resistant against crafted
+/// colliding names while allowing a reproducible iteration order.
+#[derive(Clone, Debug)]
+pub struct DirectoryHasher{
+    seed: u64,
+}
+impl Default for DirectoryHasher{
+    fn default() -> Self{
+        DirectoryHasher{
+            seed: *HASH_SEED.get_or_init(|| RandomState::new().hash_one(0u8)),
+        }
+    }
+}
+impl BuildHasher for DirectoryHasher{
+    type Hasher = DefaultHasher;
+    fn build_hasher(&self) -> DefaultHasher{
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(self.seed);
+        hasher
+    }
+}
+/// Checks that the entry at `from` can move to `to`, given what is found at both and in the new parent, so the
+/// removal and insertion making up the move can't fail halfway.
+fn check_move(from: &FileStoragePath, to: &FileStoragePath, entry: Option<&FileStorageEntry>, destination: Option<&FileStorageEntry>, parent: Option<&FileStorageEntry>) -> Result<(), FsError>{
+    if from.depth() == 0 || to.starts_with(from){
+        return Err(FsError::InvalidArgument);
+    }
+    if entry.is_none(){
+        return Err(FsError::NotFound);
+    }
+    if destination.is_some(){
+        return Err(FsError::Exists);
+    }
+    match parent{
+        Some(FileStorageEntry::Directory(..)) => Ok(()),
+        Some(_) => Err(FsError::NotADir),
+        None => Err(FsError::NotFound),
+    }
+}
+/// Sorts `names` by their hash under the process wide hash seed, an order that looks random but is reproducible
+/// with the same seed.
+pub fn sort_by_hash(names: &mut [OsString]){
+    let hasher = DirectoryHasher::default();
+    names.sort_by_cached_key(|name| hasher.hash_one(name));
+}
+/// Whether the imported `path`, relative to the root of the import, matches `exclude` by itself or by its name.
+pub(crate) fn is_excluded(exclude: &GlobSet, path: &Path) -> bool{
+    exclude.is_match(path) || path.file_name().is_some_and(|name| exclude.is_match(name))
+}
+
+#[derive(Clone)]
+pub struct FileStorage{
+    pub(crate) root: FileStorageEntry
+}
+impl Default for FileStorage{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+impl FileStorage{
+    pub fn new() -> Self{
+        FileStorage{
+            root: FileStorageEntry::Directory(Arc::default(), Metadata::now())
+        }
+    }
+    /// Builds a storage mirroring the regular files and directories found under the host directory `path`. The first
+    /// `strip` components of every path are dropped, along with the entries that have no more components than that.
+    /// Entries matching `exclude` are skipped together with everything below them.
+    pub fn import(path: &Path, strip: usize, exclude: &GlobSet) -> io::Result<Self>{
+        let mut entries = vec![(0, OsString::new(), FileStorageEntry::Directory(Arc::default(), Metadata::from_host(&fs::metadata(path)?)))];
+        let mut pending = vec![(path.to_path_buf(), PathBuf::new(), strip, 0)];
+        while let Some((path, imported, strip, parent)) = pending.pop(){
+            for child in fs::read_dir(&path)?{
+                let child = child?;
+                let file_type = child.file_type()?;
+                if strip > 0{
+                    //the children of stripped directories end up in the directory the stripped one was in
+                    if file_type.is_dir(){
+                        pending.push((child.path(), imported.clone(), strip - 1, parent));
+                    }
+                    continue;
+                }
+                let imported = imported.join(child.file_name());
+                if is_excluded(exclude, &imported){
+                    continue;
+                }
+                let entry = if file_type.is_dir(){
+                    pending.push((child.path(), imported, 0, entries.len()));
+                    FileStorageEntry::Directory(Arc::default(), Metadata::from_host(&fs::metadata(child.path())?))
+                } else if file_type.is_file(){
+                    let metadata = Metadata::from_host(&child.metadata()?);
+                    FileStorageEntry::File(fs::read(child.path())?.into(), metadata)
+                } else if file_type.is_symlink(){
+                    let metadata = Metadata::from_host(&fs::symlink_metadata(child.path())?);
+                    FileStorageEntry::Symlink(fs::read_link(child.path())?.into_os_string(), metadata)
+                } else {
+                    continue;
+                };
+                entries.push((parent, child.file_name(), entry));
+            }
+        }
+        Ok(FileStorage{
+            root: assemble(entries).expect("the root is always listed"),
+        })
+    }
+    /// Lists the paths that were added, removed or whose contents changed in `self` compared to `other`. Entries
+    /// inside added or removed directories are listed individually.
+    pub fn diff(&self, other: &FileStorage) -> Vec<PathChange>{
+        fn children(entry: &FileStorageEntry) -> impl Iterator<Item = (&OsString, &FileStorageEntry)>{
+            let directory = match entry{
+                FileStorageEntry::Directory(directory, _) => Some(directory),
+                _ => None,
+            };
+            directory.into_iter().flat_map(|directory| directory.iter()).filter(|(_, child)| !matches!(child, FileStorageEntry::Whiteout))
+        }
+        enum Step<'a>{
+            /// Lists the entry and everything below it as the given change.
+            Subtree(FileStoragePath, &'a FileStorageEntry, fn(FileStoragePath) -> PathChange),
+            /// Compares the old entry with the new one.
+            Compare(FileStoragePath, &'a FileStorageEntry, &'a FileStorageEntry),
+        }
+        //the steps of each entry are taken before those of the entries after it, like a recursive walk would, but
+        //from an explicit stack so deep trees can't overflow the stack
+        let mut changes = Vec::new();
+        let mut steps = vec![Step::Compare(FileStoragePath::root(), &other.root, &self.root)];
+        while let Some(step) = steps.pop(){
+            let mut next = Vec::new();
+            match step{
+                Step::Subtree(path, entry, change) => {
+                    changes.push(change(path.clone()));
+                    for (name, child) in children(entry){
+                        next.push(Step::Subtree(path.with_pushed(name), child, change));
+                    }
+                }
+                Step::Compare(path, old, new) => match (old, new){
+                    (FileStorageEntry::File(old, _), FileStorageEntry::File(new, _)) => {
+                        if old != new{
+                            changes.push(PathChange::Modified(path));
+                        }
+                    }
+                    (FileStorageEntry::Symlink(old, _), FileStorageEntry::Symlink(new, _)) => {
+                        if old != new{
+                            changes.push(PathChange::Modified(path));
+                        }
+                    }
+                    (FileStorageEntry::Directory(old_directory, _), FileStorageEntry::Directory(new_directory, _)) => {
+                        for (name, old_child) in children(old){
+                            match new_directory.get(name){
+                                Some(new_child) if !matches!(new_child, FileStorageEntry::Whiteout) => {
+                                    next.push(Step::Compare(path.with_pushed(name), old_child, new_child));
+                                }
+                                _ => next.push(Step::Subtree(path.with_pushed(name), old_child, PathChange::Removed)),
+                            }
+                        }
+                        for (name, new_child) in children(new){
+                            if !old_directory.get(name).is_some_and(|old_child| !matches!(old_child, FileStorageEntry::Whiteout)){
+                                next.push(Step::Subtree(path.with_pushed(name), new_child, PathChange::Added));
+                            }
+                        }
+                    }
+                    _ => {
+                        next.push(Step::Subtree(path.clone(), old, PathChange::Removed));
+                        next.push(Step::Subtree(path, new, PathChange::Added));
+                    }
+                },
+            }
+            steps.extend(next.into_iter().rev());
+        }
+        changes
+    }
+    pub fn lookup(&self, path: &FileStoragePath) -> Option<&FileStorageEntry>{
+        match self.lookup_layer(path){
+            LayerLookup::Found(entry) => Some(entry),
+            LayerLookup::Masked | LayerLookup::Missing => None,
+        }
+    }
+    fn lookup_layer(&self, path: &FileStoragePath) -> LayerLookup<'_>{
+        let mut current_entry = &self.root;
+        for part in &path.parts{
+            current_entry = match current_entry {
+                FileStorageEntry::Directory(directory, _) => match directory.get(&**part){
+                    Some(FileStorageEntry::Whiteout) => return LayerLookup::Masked,
+                    Some(entry) => entry,
+                    None => return LayerLookup::Missing,
+                },
+                FileStorageEntry::File(..) | FileStorageEntry::Symlink(..) | FileStorageEntry::Whiteout => return LayerLookup::Masked,
+            }
+        }
+        LayerLookup::Found(current_entry)
+    }
+    /// Returns the directory holding the entry at `path`, failing if it doesn't exist or isn't a directory.
+    pub fn get_mut_parent(&mut self, path: &FileStoragePath) -> Result<&mut Directory, FsError>{
+        if path.depth() == 0{
+            //the root has no parent to hold it
+            return Err(FsError::InvalidArgument);
+        }
+        match self.lookup_mut(&path.with_popped()){
+            Some(entry) => entry.as_dir_mut().ok_or(FsError::NotADir),
+            None => Err(FsError::NotFound),
+        }
+    }
+    /// Creates the directory at `path` along with its missing parents, like `mkdir -p`. Directories that exist
+    /// already are left alone. A file or symbolic link along the way fails with `ENOTDIR` and a whiteout with `EEXIST`,
+    /// since a directory in its place would no longer mask the lower layer. Nothing is created on failure.
+    pub fn ensure_dirs(&mut self, path: &FileStoragePath) -> Result<(), FsError>{
+        let mut existing = 0;
+        let mut current = &self.root;
+        for part in &path.parts{
+            match current.as_dir().ok_or(FsError::NotADir)?.get(&**part){
+                Some(FileStorageEntry::Whiteout) => return Err(FsError::Exists),
+                Some(entry) => current = entry,
+                None => break,
+            }
+            existing += 1;
+        }
+        if !current.is_dir(){
+            return Err(FsError::NotADir);
+        }
+        for depth in existing..path.parts.len(){
+            let missing = FileStoragePath{ parts: path.parts[..depth + 1].to_vec() };
+            self.insert(&missing, FileStorageEntry::Directory(Arc::default(), Metadata::now()))?;
+        }
+        Ok(())
+    }
+    /// Places `entry` at `path`, replacing a whiteout but failing if another entry is there already.
+    pub fn insert(&mut self, path: &FileStoragePath, entry: FileStorageEntry) -> Result<(), FsError>{
+        let name = path.file_name().ok_or(FsError::Exists)?;
+        match self.get_mut_parent(path)?.entry(name){
+            Entry::Occupied(mut occupied) if matches!(occupied.get(), FileStorageEntry::Whiteout) => {
+                occupied.insert(entry);
+            }
+            Entry::Occupied(_) => return Err(FsError::Exists),
+            Entry::Vacant(vacant) => {
+                vacant.insert(entry);
+            }
+        }
+        Ok(())
+    }
+    /// Removes the entry at `path` and returns it, failing if there is none.
+    pub fn remove(&mut self, path: &FileStoragePath) -> Result<FileStorageEntry, FsError>{
+        let name = path.file_name().ok_or(FsError::InvalidArgument)?;
+        match self.get_mut_parent(path)?.entry(name){
+            Entry::Occupied(occupied) if !matches!(occupied.get(), FileStorageEntry::Whiteout) => Ok(occupied.shift_remove()),
+            _ => Err(FsError::NotFound),
+        }
+    }
+    /// Removes the empty directory at `path` like `remove`, failing with `ENOTDIR` if it isn't a directory and with
+    /// `ENOTEMPTY` if it has children other than whiteouts.
+    pub fn remove_dir(&mut self, path: &FileStoragePath) -> Result<FileStorageEntry, FsError>{
+        match self.lookup(path){
+            Some(FileStorageEntry::Directory(directory, _)) if directory.values().any(|child| !matches!(child, FileStorageEntry::Whiteout)) => {
+                return Err(FsError::NotEmpty);
+            }
+            Some(FileStorageEntry::Directory(..)) => {}
+            Some(_) => return Err(FsError::NotADir),
+            None => return Err(FsError::NotFound),
+        }
+        self.remove(path)
+    }
+    /// Moves the entry at `from` and everything below it to `to`, failing if `from` is missing, `to` is taken or
+    /// lies inside `from`. Nothing is moved on failure.
+    pub fn move_entry(&mut self, from: &FileStoragePath, to: &FileStoragePath) -> Result<(), FsError>{
+        check_move(from, to, self.lookup(from), self.lookup(to), self.lookup(&to.with_popped()))?;
+        let entry = self.remove(from)?;
+        self.insert(to, entry)
+    }
+    pub fn lookup_mut(&mut self, path: &FileStoragePath) -> Option<&mut FileStorageEntry>{
+        let mut current_entry = &mut self.root;
+        for part in &path.parts{
+            current_entry = match current_entry {
+                FileStorageEntry::Directory(directory, _) => match Arc::make_mut(directory).get_mut(&**part)?{
+                    FileStorageEntry::Whiteout => return None,
+                    entry => entry,
+                },
+                FileStorageEntry::File(..) | FileStorageEntry::Symlink(..) | FileStorageEntry::Whiteout => return None,
+            }
+        }
+        Some(current_entry)
+    }
+    /// Visits every entry depth first, starting with the root, parents before their children and siblings in the
+    /// order of their directory. Whiteouts are visited like any other entry of the layer.
+    pub fn walk(&self) -> Walk<'_>{
+        Walk{
+            pending: vec![(FileStoragePath::root(), &self.root)],
+        }
+    }
+}
+/// Depth first traversal of a `FileStorage`, returned by `FileStorage::walk`. It only holds the unvisited siblings
+/// along the current branch, never the paths of the whole tree.
+pub struct Walk<'a>{
+    pending: Vec<(FileStoragePath, &'a FileStorageEntry)>,
+}
+impl<'a> Iterator for Walk<'a>{
+    type Item = (FileStoragePath, &'a FileStorageEntry);
+    fn next(&mut self) -> Option<Self::Item>{
+        let (path, entry) = self.pending.pop()?;
+        if let FileStorageEntry::Directory(directory, _) = entry{
+            //pushed in reverse so the first child is visited next
+            self.pending.extend(directory.iter().rev().map(|(name, child)| (path.with_pushed(name), child)));
+        }
+        Some((path, entry))
+    }
+}
+/// Result of resolving a path within a single layer of an `Overlay`.
+enum LayerLookup<'a>{
+    Found(&'a FileStorageEntry),
+    /// A whiteout or a non-directory hides the path, so lower layers must not be consulted.
+    Masked,
+    Missing,
+}
+#[derive(Clone, Debug)]
+pub enum FileStorageEntry{
+    /// File contents, which may have been spilled to the host.
+    File(FileContent, Metadata),
+    /// Directories are shared the same way, so cloning a whole tree only copies its root.
+    Directory(Arc<Directory>, Metadata),
+    /// Symbolic link to the contained target, which is stored as given and only resolved when followed.
+    Symlink(OsString, Metadata),
+    /// Marks an entry of the lower `Overlay` layer as deleted.
+    Whiteout,
+}
+impl FileStorageEntry{
+    pub fn metadata(&self) -> Option<&Metadata>{
+        match self{
+            FileStorageEntry::File(_, metadata) | FileStorageEntry::Directory(_, metadata) | FileStorageEntry::Symlink(_, metadata) => Some(metadata),
+            FileStorageEntry::Whiteout => None,
+        }
+    }
+    pub fn metadata_mut(&mut self) -> Option<&mut Metadata>{
+        match self{
+            FileStorageEntry::File(_, metadata) | FileStorageEntry::Directory(_, metadata) | FileStorageEntry::Symlink(_, metadata) => Some(metadata),
+            FileStorageEntry::Whiteout => None,
+        }
+    }
+    pub fn is_dir(&self) -> bool{
+        matches!(self, FileStorageEntry::Directory(..))
+    }
+    pub fn is_file(&self) -> bool{
+        matches!(self, FileStorageEntry::File(..))
+    }
+    /// Children of a directory.
+    pub fn as_dir(&self) -> Option<&Directory>{
+        match self{
+            FileStorageEntry::Directory(directory, _) => Some(directory),
+            FileStorageEntry::File(..) | FileStorageEntry::Symlink(..) | FileStorageEntry::Whiteout => None,
+        }
+    }
+    /// Children of a directory for modification, copied first if other entries share them.
+    pub fn as_dir_mut(&mut self) -> Option<&mut Directory>{
+        match self{
+            FileStorageEntry::Directory(directory, _) => Some(Arc::make_mut(directory)),
+            FileStorageEntry::File(..) | FileStorageEntry::Symlink(..) | FileStorageEntry::Whiteout => None,
+        }
+    }
+    /// Contents of a file for modification.
+    pub fn as_file_mut(&mut self) -> Option<&mut FileContent>{
+        match self{
+            FileStorageEntry::File(content, _) => Some(content),
+            FileStorageEntry::Directory(..) | FileStorageEntry::Symlink(..) | FileStorageEntry::Whiteout => None,
+        }
+    }
+}
+#[derive(Clone, Debug)]
+pub struct Metadata{
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub crtime: SystemTime,
+    /// Permission bits including the setuid, setgid and sticky bits, without the file type.
+    pub perm: u16,
+    /// Owner of the entry, root unless it was created by or handed to another user.
+    pub uid: u32,
+    pub gid: u32,
+    /// Extended attributes, ordered by name so they are listed deterministically.
+    pub xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+impl Metadata{
+    pub fn now() -> Self{
+        let now = SystemTime::now();
+        Metadata{
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            perm: DEFAULT_PERM,
+            uid: 0,
+            gid: 0,
+            xattrs: BTreeMap::new(),
+        }
+    }
+    pub fn from_host(metadata: &fs::Metadata) -> Self{
+        let now = SystemTime::now();
+        let mtime = metadata.modified().unwrap_or(now);
+        Metadata{
+            atime: metadata.accessed().unwrap_or(now),
+            mtime,
+            ctime: mtime,
+            crtime: metadata.created().unwrap_or(mtime),
+            perm: (metadata.permissions().mode() & 0o7777) as u16,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            xattrs: BTreeMap::new(),
+        }
+    }
+}
+/// A single difference reported by `FileStorage::diff`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PathChange{
+    Added(FileStoragePath),
+    Removed(FileStoragePath),
+    Modified(FileStoragePath),
+}
+/// Path from the root of the tree. Components are reference counted, so cloning a path and deriving its parent and
+/// children doesn't copy the names, and with the name table the paths naming the same component share it.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct FileStoragePath{
+    parts: Vec<Arc<OsStr>>
+}
+impl FileStoragePath{
+    pub fn root() -> Self{
+        FileStoragePath{
+            parts: Vec::new()
+        }
+    }
+    /// Path of the components in order, starting from the root.
+    pub fn from_components<I: IntoIterator<Item: AsRef<OsStr>>>(components: I) -> Self{
+        FileStoragePath{
+            parts: components.into_iter().map(|name| intern(name.as_ref())).collect()
+        }
+    }
+    pub fn with_pushed(&self, next: &OsStr) -> Self{
+        let mut parts = self.parts.clone();
+        parts.push(intern(next));
+        FileStoragePath{
+            parts
+        }
+    }
+    pub fn with_popped(&self) -> Self{
+        let mut parts = self.parts.clone();
+        parts.pop();
+        FileStoragePath{
+            parts
+        }
+    }
+    /// Whether `prefix` is this path or one of its ancestors.
+    pub fn starts_with(&self, prefix: &FileStoragePath) -> bool{
+        self.parts.starts_with(&prefix.parts)
+    }
+    /// Replaces the leading `from` of this path with `to`.
+    pub fn rebased(&self, from: &FileStoragePath, to: &FileStoragePath) -> Self{
+        let mut parts = to.parts.clone();
+        parts.extend_from_slice(&self.parts[from.parts.len()..]);
+        FileStoragePath{
+            parts
+        }
+    }
+    /// Last component, `None` for the root.
+    pub fn file_name(&self) -> Option<OsString>{
+        self.parts.last().map(|part| part.to_os_string())
+    }
+    /// Number of components, 0 for the root.
+    pub fn depth(&self) -> usize{
+        self.parts.len()
+    }
+    /// Maps the path onto the host filesystem below `root`.
+    pub fn to_path(&self, root: &Path) -> PathBuf{
+        let mut path = root.to_path_buf();
+        path.extend(self.parts.iter().map(|part| &**part));
+        path
+    }
+}
+/// Renders the components joined by `/` after a leading one, replacing invalid UTF-8.
+impl fmt::Display for FileStoragePath{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        if self.parts.is_empty(){
+            return f.write_str("/");
+        }
+        for part in &self.parts{
+            write!(f, "/{}", part.to_string_lossy())?;
+        }
+        Ok(())
+    }
+}
+/// Names shared by the components of all paths once `enable_name_table` was called.
+static NAME_TABLE: OnceLock<Mutex<NameTable>> = OnceLock::new();
+/// Makes the paths created from now on share their components with all other paths naming the same component,
+/// instead of each holding its own copy.
+pub fn enable_name_table(){
+    let _ = NAME_TABLE.set(Mutex::default());
+}
+fn intern(name: &OsStr) -> Arc<OsStr>{
+    match NAME_TABLE.get(){
+        Some(table) => table.lock().unwrap().intern(name),
+        None => Arc::from(name),
+    }
+}
+#[derive(Default)]
+struct NameTable{
+    names: HashSet<Arc<OsStr>>,
+    /// Number of names at which the ones no path refers to anymore are dropped.
+    prune_at: usize,
+}
+impl NameTable{
+    fn intern(&mut self, name: &OsStr) -> Arc<OsStr>{
+        if let Some(interned) = self.names.get(name){
+            return interned.clone();
+        }
+        if self.names.len() >= self.prune_at{
+            self.names.retain(|name| Arc::strong_count(name) > 1);
+            self.prune_at = (self.names.len() * 2).max(1024);
+        }
+        let interned = Arc::<OsStr>::from(name);
+        self.names.insert(interned.clone());
+        interned
+    }
+}
+impl fmt::Debug for FileStoragePath{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "\"{self}\"")
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn path(path: &str) -> FileStoragePath{
+        FileStoragePath::from_components(path.split('/').filter(|part| !part.is_empty()))
+    }
+    fn file(data: &[u8]) -> FileStorageEntry{
+        FileStorageEntry::File(data.to_vec().into(), Metadata::now())
+    }
+    fn dir() -> FileStorageEntry{
+        FileStorageEntry::Directory(Arc::default(), Metadata::now())
+    }
+    /// Chain of `depth` directories named `d`, built from the bottom up.
+    fn chain(depth: usize) -> FileStorageEntry{
+        let mut entry = file(b"x");
+        for _ in 0..depth{
+            let mut directory = Directory::default();
+            directory.insert("d".into(), entry);
+            entry = FileStorageEntry::Directory(Arc::new(directory), Metadata::now());
+        }
+        entry
+    }
+
+    #[test]
+    fn overlay_copies_up_and_whites_out_lower_entries(){
+        let mut lower = FileStorage::new();
+        lower.insert(&path("d"), dir()).unwrap();
+        lower.insert(&path("d/f"), file(b"lower")).unwrap();
+        lower.insert(&path("d/g"), file(b"g")).unwrap();
+        let mut overlay = Overlay::new(lower, FileStorage::new());
+        let contents = |entry: Option<&FileStorageEntry>| match entry{
+            Some(FileStorageEntry::File(content, _)) => content.read(0, 10).unwrap(),
+            _ => Vec::new(),
+        };
+        //modifying a lower file copies it up along with its parents
+        let Some(FileStorageEntry::File(data, _)) = overlay.lookup_mut(&path("d/f")) else { unreachable!() };
+        data.write(0, b"upper").unwrap();
+        assert_eq!(contents(overlay.lookup(&path("d/f"))), b"upper");
+        assert_eq!(contents(overlay.upper.lookup(&path("d/f"))), b"upper");
+        assert_eq!(contents(overlay.lower.lookup(&path("d/f"))), b"lower");
+        assert!(overlay.upper.lookup(&path("d/g")).is_none());
+        //removing a lower entry leaves a whiteout masking it
+        overlay.remove(&path("d/g")).unwrap();
+        assert!(overlay.lookup(&path("d/g")).is_none());
+        assert!(matches!(overlay.upper.lookup_layer(&path("d/g")), LayerLookup::Masked));
+        assert!(overlay.lower.lookup(&path("d/g")).is_some());
+        assert_eq!(overlay.list(&path("d")).unwrap(), ["f"]);
+        overlay.insert(&path("d/g"), file(b"new")).unwrap();
+        assert_eq!(contents(overlay.lookup(&path("d/g"))), b"new");
+        //a directory taking the place of a lower one doesn't show the lower children
+        overlay.remove(&path("d")).unwrap();
+        overlay.insert(&path("d"), dir()).unwrap();
+        assert_eq!(overlay.list(&path("d")).unwrap(), Vec::<OsString>::new());
+        assert!(overlay.lookup(&path("d/f")).is_none());
+    }
+    #[test]
+    fn diff_lists_added_removed_and_modified_paths(){
+        let mut baseline = Overlay::new(FileStorage::new(), FileStorage::new());
+        baseline.insert(&path("same"), file(b"same")).unwrap();
+        baseline.insert(&path("changed"), file(b"old")).unwrap();
+        baseline.insert(&path("removed"), chain(1)).unwrap();
+        baseline.insert(&path("replaced"), file(b"file")).unwrap();
+        assert_eq!(baseline.upper().diff(baseline.upper()), []);
+        let mut current = Overlay::new(FileStorage::new(), FileStorage::new());
+        current.insert(&path("same"), file(b"same")).unwrap();
+        current.insert(&path("changed"), file(b"new")).unwrap();
+        current.insert(&path("replaced"), dir()).unwrap();
+        current.insert(&path("added"), chain(1)).unwrap();
+        let sorted = |mut changes: Vec<PathChange>| {
+            changes.sort_by_key(|change| format!("{change:?}"));
+            changes
+        };
+        assert_eq!(sorted(current.upper().diff(baseline.upper())), sorted(vec![
+            PathChange::Added(path("added")),
+            PathChange::Added(path("added/d")),
+            PathChange::Added(path("replaced")),
+            PathChange::Modified(path("changed")),
+            PathChange::Removed(path("removed")),
+            PathChange::Removed(path("removed/d")),
+            PathChange::Removed(path("replaced")),
+        ]));
+    }
+    #[test]
+    fn deep_trees_are_walked_without_recursing(){
+        const DEPTH: usize = 2000;
+        //the stack is too small for a recursive walk down to the end of the chain
+        let walked = std::thread::Builder::new().stack_size(64 * 1024).spawn(|| {
+            let deep = FileStorage{ root: chain(DEPTH) };
+            let changes = deep.diff(&FileStorage::new());
+            assert_eq!(changes.len(), DEPTH);
+            assert!(changes.iter().all(|change| matches!(change, PathChange::Added(_))));
+            let same = FileStorage{ root: chain(DEPTH) };
+            assert!(deep.diff(&same).is_empty());
+            let snapshot = std::env::temp_dir().join(format!("icfs-deep-{}", std::process::id()));
+            deep.save(&snapshot).unwrap();
+            std::fs::remove_file(&snapshot).unwrap();
+
+            let mut overlay = Overlay::new(deep, FileStorage::new());
+            let removed = overlay.remove(&path("d")).unwrap();
+            assert!(overlay.lookup(&path("d")).is_none());
+            //moving a deep directory back over the lower one masks the lower chain all the way down
+            overlay.insert(&path("d"), chain(DEPTH - 1)).unwrap();
+            (overlay, removed, same)
+        }).unwrap().join().unwrap();
+        drop(walked);
+    }
+    #[test]
+    fn import_tar_keeps_modes_times_and_links(){
+        let archive = std::env::temp_dir().join(format!("icfs-import-links-{}.tar", std::process::id()));
+        let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+        let header = |entry_type: tar::EntryType, mode: u32, size: u64| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(mode);
+            header.set_mtime(1_000_000);
+            header.set_size(size);
+            header
+        };
+        builder.append_data(&mut header(tar::EntryType::Directory, 0o750, 0), "d/", io::empty()).unwrap();
+        builder.append_data(&mut header(tar::EntryType::Regular, 0o4755, 4), "d/f", &b"data"[..]).unwrap();
+        builder.append_link(&mut header(tar::EntryType::Symlink, 0o777, 0), "d/symlink", "f").unwrap();
+        builder.append_link(&mut header(tar::EntryType::Link, 0o644, 0), "hardlink", "d/f").unwrap();
+        builder.append_data(&mut header(tar::EntryType::Fifo, 0o644, 0), "fifo", io::empty()).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+        let storage = FileStorage::import_tar(&archive, 0, &GlobSet::empty()).unwrap();
+        fs::remove_file(&archive).unwrap();
+        let metadata = |name: &str| storage.lookup(&path(name)).and_then(FileStorageEntry::metadata).unwrap().clone();
+        assert_eq!(metadata("d").perm, 0o750);
+        assert_eq!(metadata("d/f").perm, 0o4755);
+        assert_eq!(metadata("d/f").mtime, SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000));
+        assert!(matches!(storage.lookup(&path("d/symlink")), Some(FileStorageEntry::Symlink(target, _)) if target == "f"));
+        match storage.lookup(&path("hardlink")){
+            Some(FileStorageEntry::File(content, _)) => assert_eq!(content.read(0, 10).unwrap(), b"data"),
+            _ => panic!("hard link wasn't imported as a file"),
+        }
+        assert!(storage.lookup(&path("fifo")).is_none());
+    }
+    #[test]
+    fn exported_archives_import_back_to_the_same_tree(){
+        let long = "long-".repeat(30);
+        let mut storage = FileStorage::new();
+        storage.insert(&path("d"), dir()).unwrap();
+        storage.insert(&path(&format!("d/{long}")), file(b"long name")).unwrap();
+        storage.insert(&path("d/private"), FileStorageEntry::File(b"secret".to_vec().into(), Metadata{ perm: 0o600, ..Metadata::now() })).unwrap();
+        storage.insert(&path("link"), FileStorageEntry::Symlink("d/private".into(), Metadata::now())).unwrap();
+        let export = |storage: FileStorage| {
+            let mut archive = Vec::new();
+            Overlay::new(storage, FileStorage::new()).export_tar(&mut archive).unwrap();
+            let entries = tar::Archive::new(archive.as_slice()).entries().unwrap().map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut data = Vec::new();
+                io::Read::read_to_end(&mut entry, &mut data).unwrap();
+                let link = entry.link_name().unwrap().map(|link| link.into_owned());
+                (entry.path().unwrap().into_owned(), entry.header().entry_type(), entry.header().mode().unwrap(), link, data)
+            }).collect::<Vec<_>>();
+            (archive, entries)
+        };
+        let (archive, entries) = export(storage);
+        assert_eq!(entries.len(), 5);
+        assert!(entries.iter().any(|(name, _, mode, _, data)| name.ends_with("private") && *mode == 0o600 && data == b"secret"));
+        assert!(entries.iter().any(|(name, _, _, link, _)| name.ends_with("link") && link.as_deref() == Some(Path::new("d/private"))));
+        let host = std::env::temp_dir().join(format!("icfs-export-{}.tar", std::process::id()));
+        fs::write(&host, archive).unwrap();
+        let imported = FileStorage::import_tar(&host, 0, &GlobSet::empty());
+        fs::remove_file(&host).unwrap();
+        assert_eq!(export(imported.unwrap()).1, entries);
+    }
+    #[test]
+    fn hash_seeds_are_fixed_once_set(){
+        //other tests may have hashed already, which fixes a random seed for the whole process
+        let seed = DirectoryHasher::default().seed;
+        assert_eq!(set_hash_seed(seed), Ok(()));
+        assert_eq!(set_hash_seed(seed.wrapping_add(1)), Err(seed));
+        let names = (0..64).map(|name| OsString::from(name.to_string())).collect::<Vec<_>>();
+        let (mut first, mut second) = (names.clone(), names.clone());
+        second.reverse();
+        sort_by_hash(&mut first);
+        sort_by_hash(&mut second);
+        assert_eq!(first, second);
+        let hash = |seed: u64| DirectoryHasher{ seed }.hash_one("name");
+        assert_eq!(hash(1), hash(1));
+        assert_ne!(hash(1), hash(2));
+    }
+    #[test]
+    fn insert_checks_the_parent_and_destination(){
+        let mut storage = FileStorage::new();
+        storage.insert(&path("d"), dir()).unwrap();
+        storage.insert(&path("d/f"), file(b"x")).unwrap();
+        assert_eq!(storage.insert(&path("d/f"), file(b"y")), Err(FsError::Exists));
+        assert_eq!(storage.insert(&path("missing/f"), file(b"y")), Err(FsError::NotFound));
+        assert_eq!(storage.insert(&path("d/f/g"), file(b"y")), Err(FsError::NotADir));
+        assert_eq!(storage.insert(&FileStoragePath::root(), dir()), Err(FsError::Exists));
+    }
+    #[test]
+    fn entry_helpers_match_their_variant(){
+        let mut entries = [dir(), file(b"x"), FileStorageEntry::Symlink("t".into(), Metadata::now()), FileStorageEntry::Whiteout];
+        let kinds = entries.iter_mut()
+            .map(|entry| [entry.is_dir(), entry.is_file(), entry.as_dir().is_some(), entry.as_dir_mut().is_some(), entry.as_file_mut().is_some()])
+            .collect::<Vec<_>>();
+        let (yes, no) = (true, false);
+        assert_eq!(kinds, [[yes, no, yes, yes, no], [no, yes, no, no, yes], [no; 5], [no; 5]]);
+        let mut shared = dir();
+        shared.as_dir_mut().unwrap().insert("f".into(), file(b"x"));
+        let mut copy = shared.clone();
+        copy.as_dir_mut().unwrap().clear();
+        assert_eq!(shared.as_dir().unwrap().len(), 1);
+        let mut entry = file(b"x");
+        entry.as_file_mut().unwrap().write(1, b"y").unwrap();
+        assert!(matches!(&entry, FileStorageEntry::File(content, _) if content.read(0, 10).unwrap() == b"xy"));
+    }
+    #[test]
+    fn ensure_dirs_creates_missing_parents_only(){
+        let mut storage = FileStorage::new();
+        storage.ensure_dirs(&path("a/b/c/d")).unwrap();
+        assert!(storage.lookup(&path("a/b/c/d")).is_some_and(FileStorageEntry::is_dir));
+        storage.insert(&path("a/b/f"), file(b"x")).unwrap();
+        storage.ensure_dirs(&path("a/b")).unwrap();
+        assert_eq!(storage.lookup(&path("a/b")).and_then(FileStorageEntry::as_dir).unwrap().len(), 2);
+        assert_eq!(storage.ensure_dirs(&path("a/b/f")), Err(FsError::NotADir));
+        assert_eq!(storage.ensure_dirs(&path("a/b/f/g")), Err(FsError::NotADir));
+        //whiteouts keep masking the lower layer
+        storage.get_mut_parent(&path("a/w")).unwrap().insert("w".into(), FileStorageEntry::Whiteout);
+        assert_eq!(storage.ensure_dirs(&path("a/w/x")), Err(FsError::Exists));
+        assert!(matches!(storage.lookup_layer(&path("a/w")), LayerLookup::Masked));
+    }
+    #[test]
+    fn remove_returns_the_entry(){
+        let mut storage = FileStorage::new();
+        storage.insert(&path("f"), file(b"x")).unwrap();
+        assert!(matches!(storage.remove(&path("f")), Ok(FileStorageEntry::File(..))));
+        assert_eq!(storage.remove(&path("f")).err(), Some(FsError::NotFound));
+        assert_eq!(storage.remove(&FileStoragePath::root()).err(), Some(FsError::InvalidArgument));
+    }
+    #[test]
+    fn get_mut_parent_needs_a_directory(){
+        let mut storage = FileStorage::new();
+        storage.insert(&path("f"), file(b"x")).unwrap();
+        assert!(storage.get_mut_parent(&path("g")).is_ok());
+        assert_eq!(storage.get_mut_parent(&path("f/g")).err(), Some(FsError::NotADir));
+        assert_eq!(storage.get_mut_parent(&path("missing/g")).err(), Some(FsError::NotFound));
+        assert_eq!(storage.get_mut_parent(&FileStoragePath::root()).err(), Some(FsError::InvalidArgument));
+    }
+    #[test]
+    fn remove_dir_only_removes_empty_directories(){
+        let mut storage = FileStorage::new();
+        storage.insert(&path("d"), dir()).unwrap();
+        storage.insert(&path("d/f"), file(b"x")).unwrap();
+        assert_eq!(storage.remove_dir(&path("d")).err(), Some(FsError::NotEmpty));
+        assert_eq!(storage.remove_dir(&path("d/f")).err(), Some(FsError::NotADir));
+        storage.remove(&path("d/f")).unwrap();
+        assert!(storage.remove_dir(&path("d")).is_ok());
+        assert_eq!(storage.remove_dir(&path("d")).err(), Some(FsError::NotFound));
+    }
+    #[test]
+    fn overlay_remove_dir_sees_lower_children(){
+        let mut lower = FileStorage::new();
+        lower.insert(&path("d"), dir()).unwrap();
+        lower.insert(&path("d/f"), file(b"x")).unwrap();
+        let mut overlay = Overlay::new(lower, FileStorage::new());
+        assert_eq!(overlay.remove_dir(&path("d")).err(), Some(FsError::NotEmpty));
+        overlay.remove(&path("d/f")).unwrap();
+        assert!(overlay.remove_dir(&path("d")).is_ok());
+        assert!(!overlay.contains(&path("d")));
+    }
+    #[test]
+    fn import_tar_strips_leading_components(){
+        let archive = std::env::temp_dir().join(format!("icfs-import-strip-{}.tar", std::process::id()));
+        let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+        for name in ["release/", "release/top", "release/bin/tool", "other/bin/lib"]{
+            let (entry_type, data) = if name.ends_with('/') { (tar::EntryType::Directory, &b""[..]) } else { (tar::EntryType::Regular, &b"x"[..]) };
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, name, data).unwrap();
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_size(0);
+        builder.append_link(&mut header, "release/link", "release/top").unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+        let import = |strip| FileStorage::import_tar(&archive, strip, &GlobSet::empty()).unwrap();
+        let (once, twice) = (import(1), import(2));
+        fs::remove_file(&archive).unwrap();
+        let names = |storage: &FileStorage| storage.walk().map(|(path, _)| path.to_string()).collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(names(&once), ["/", "/bin", "/bin/lib", "/bin/tool", "/link", "/top"].map(String::from).into());
+        //entries with no components left are dropped along with the prefix
+        assert_eq!(names(&twice), ["/", "/lib", "/tool"].map(String::from).into());
+        assert!(matches!(once.lookup(&path("link")), Some(FileStorageEntry::File(content, _)) if content.read(0, 10).unwrap() == b"x"));
+    }
+    #[test]
+    fn walks_visit_parents_before_children_in_directory_order(){
+        let mut storage = FileStorage::new();
+        storage.insert(&path("b"), dir()).unwrap();
+        storage.insert(&path("b/y"), file(b"y")).unwrap();
+        storage.insert(&path("b/x"), chain(1)).unwrap();
+        storage.insert(&path("a"), file(b"a")).unwrap();
+        storage.root.as_dir_mut().unwrap().insert("w".into(), FileStorageEntry::Whiteout);
+        let visited = storage.walk().map(|(path, entry)| (path.to_string(), entry.is_dir())).collect::<Vec<_>>();
+        assert_eq!(visited, [
+            ("/".to_string(), true),
+            ("/b".to_string(), true),
+            ("/b/y".to_string(), false),
+            ("/b/x".to_string(), true),
+            ("/b/x/d".to_string(), false),
+            ("/a".to_string(), false),
+            ("/w".to_string(), false),
+        ]);
+        assert_eq!(FileStorage::new().walk().count(), 1);
+    }
+    #[test]
+    fn paths_render_their_components(){
+        use std::os::unix::ffi::OsStrExt;
+        let nested = FileStoragePath::from_components(["a", "b c", "d"]);
+        assert_eq!(nested, FileStoragePath::root().with_pushed(OsStr::new("a")).with_pushed(OsStr::new("b c")).with_pushed(OsStr::new("d")));
+        assert_eq!(FileStoragePath::from_components(nested.parts.iter()), nested);
+        assert_eq!(nested.to_string(), "/a/b c/d");
+        assert_eq!(format!("{nested:?}"), "\"/a/b c/d\"");
+        assert_eq!(FileStoragePath::root().to_string(), "/");
+        assert_eq!(FileStoragePath::from_components([OsStr::from_bytes(b"x\xff")]).to_string(), "/x\u{fffd}");
+    }
+    #[test]
+    fn moves_carry_whole_subtrees_or_nothing(){
+        let mut storage = FileStorage::new();
+        storage.insert(&path("a"), chain(2)).unwrap();
+        storage.insert(&path("b"), dir()).unwrap();
+        storage.insert(&path("f"), file(b"f")).unwrap();
+        storage.move_entry(&path("a"), &path("b/a")).unwrap();
+        assert!(storage.lookup(&path("a")).is_none());
+        assert!(storage.lookup(&path("b/a/d/d")).is_some_and(FileStorageEntry::is_file));
+        storage.move_entry(&path("f"), &path("b/a/g")).unwrap();
+        assert!(matches!(storage.lookup(&path("b/a/g")), Some(FileStorageEntry::File(content, _)) if content.read(0, 10).unwrap() == b"f"));
+        let failures = [
+            ("missing", "c", FsError::NotFound),
+            ("b/a/g", "b/a", FsError::Exists),
+            ("b/a/g", "missing/g", FsError::NotFound),
+            ("b/a/d", "b/a/g/d", FsError::NotADir),
+            ("b", "b/a/b", FsError::InvalidArgument),
+            ("", "c", FsError::InvalidArgument),
+        ];
+        for (from, to, error) in failures{
+            assert_eq!(storage.move_entry(&path(from), &path(to)), Err(error), "{from} -> {to}");
+        }
+        assert!(storage.lookup(&path("b/a/d/d")).is_some() && storage.lookup(&path("b/a/g")).is_some());
+    }
+    #[test]
+    fn overlay_moves_copy_up_and_mask_the_lower_entry(){
+        let mut lower = FileStorage::new();
+        lower.insert(&path("d"), chain(1)).unwrap();
+        let mut overlay = Overlay::new(lower, FileStorage::new());
+        overlay.move_entry(&path("d"), &path("e")).unwrap();
+        assert!(overlay.lookup(&path("d")).is_none());
+        assert!(overlay.lookup(&path("e/d")).is_some_and(FileStorageEntry::is_file));
+        assert_eq!(overlay.move_entry(&path("d"), &path("f")), Err(FsError::NotFound));
+    }
+    #[test]
+    fn name_tables_share_names_and_drop_unused_ones(){
+        let mut table = NameTable::default();
+        let (first, second) = (table.intern(OsStr::new("name")), table.intern(OsStr::new("name")));
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &table.intern(OsStr::new("other"))));
+        drop(second);
+        for name in 0..2000{
+            table.intern(OsStr::new(&name.to_string()));
+        }
+        //only the names still held by someone outlive pruning
+        assert!(table.names.len() < 1100 && table.names.contains(OsStr::new("name")));
+        assert!(Arc::ptr_eq(&first, &table.intern(OsStr::new("name"))));
+    }
+    #[test]
+    fn paths_compare_and_hash_by_their_names_alone(){
+        let state = RandomState::new();
+        let hash = |path: &FileStoragePath| state.hash_one(path);
+        let pushed = FileStoragePath::root().with_pushed(OsStr::new("a")).with_pushed(OsStr::new("b"));
+        let built = path("a/b");
+        assert!(!Arc::ptr_eq(&pushed.parts[1], &built.parts[1]));
+        assert_eq!(pushed, built);
+        assert_eq!(hash(&pushed), hash(&built));
+        //derived paths share the components they keep
+        let child = pushed.with_pushed(OsStr::new("c"));
+        assert!(Arc::ptr_eq(&child.parts[0], &pushed.parts[0]) && Arc::ptr_eq(&child.with_popped().parts[1], &pushed.parts[1]));
+        assert_ne!(child, pushed);
+    }
+    #[test]
+    fn import_tar_skips_excluded_subtrees_and_links_into_them(){
+        let archive = std::env::temp_dir().join(format!("icfs-import-exclude-{}.tar", std::process::id()));
+        let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+        for name in ["src/main.rs", "src/main.o", "target/debug/app", "docs/target", "docs/readme"]{
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(1);
+            builder.append_data(&mut header, name, &b"x"[..]).unwrap();
+        }
+        for (name, target) in [("linked.o", "src/main.rs"), ("app", "target/debug/app")]{
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Link);
+            header.set_mode(0o644);
+            header.set_mtime(0);
+            header.set_size(0);
+            builder.append_link(&mut header, name, target).unwrap();
+        }
+        builder.finish().unwrap();
+        drop(builder);
+        let exclude = globset::GlobSetBuilder::new().add(globset::Glob::new("*.o").unwrap()).add(globset::Glob::new("target").unwrap()).build().unwrap();
+        let storage = FileStorage::import_tar(&archive, 0, &exclude);
+        fs::remove_file(&archive).unwrap();
+        let storage = storage.unwrap();
+        let names = storage.walk().map(|(path, _)| path.to_string()).collect::<std::collections::BTreeSet<_>>();
+        //patterns without a slash match names at any depth, files and directories alike
+        assert_eq!(names, ["/", "/src", "/src/main.rs", "/docs", "/docs/readme"].map(String::from).into());
+    }
+    #[test]
+    fn import_strips_and_excludes(){
+        let host = std::env::temp_dir().join(format!("icfs-import-{}", std::process::id()));
+        fs::create_dir_all(host.join("top/a/b")).unwrap();
+        fs::create_dir_all(host.join("top/skipped")).unwrap();
+        fs::write(host.join("top/a/b/f"), b"f").unwrap();
+        fs::write(host.join("top/a/g.o"), b"g").unwrap();
+        fs::write(host.join("top/skipped/h"), b"h").unwrap();
+        let exclude = globset::GlobSetBuilder::new().add(globset::Glob::new("*.o").unwrap()).add(globset::Glob::new("skipped").unwrap()).build().unwrap();
+        let storage = FileStorage::import(&host, 1, &exclude);
+        fs::remove_dir_all(&host).unwrap();
+        let storage = storage.unwrap();
+        assert!(matches!(storage.lookup(&path("a/b/f")), Some(FileStorageEntry::File(content, _)) if content.read(0, 10).unwrap() == b"f"));
+        assert!(storage.lookup(&path("a/g.o")).is_none());
+        assert!(storage.lookup(&path("skipped")).is_none());
+        assert!(storage.lookup(&path("top")).is_none());
+    }
+    #[test]
+    fn only_whiteouts_masking_nothing_are_stale(){
+        let mut lower = FileStorage::new();
+        lower.insert(&path("lower"), file(b"x")).unwrap();
+        lower.insert(&path("d"), chain(1)).unwrap();
+        let mut overlay = Overlay::new(lower, FileStorage::new());
+        overlay.remove(&path("lower")).unwrap();
+        overlay.remove(&path("d/d")).unwrap();
+        overlay.whiteout(&path("stale"));
+        overlay.whiteout(&path("d/stale"));
+        assert_eq!(overlay.drop_stale_whiteouts(), 2);
+        assert_eq!(overlay.drop_stale_whiteouts(), 0);
+        let whiteouts = overlay.upper().walk().filter(|(_, entry)| matches!(entry, FileStorageEntry::Whiteout)).map(|(path, _)| path).collect::<Vec<_>>();
+        assert_eq!(whiteouts.len(), 2);
+        assert!(whiteouts.contains(&path("lower")) && whiteouts.contains(&path("d/d")));
+        assert!(overlay.lookup(&path("lower")).is_none() && overlay.lookup(&path("d/d")).is_none());
+    }
+    #[test]
+    fn import_tar_creates_missing_parents(){
+        let import = |files: &[&str]| {
+            let archive = std::env::temp_dir().join(format!("icfs-import-{}.tar", std::process::id()));
+            let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+            for name in files{
+                let mut header = tar::Header::new_gnu();
+                header.set_size(1);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append_data(&mut header, name, &b"x"[..]).unwrap();
+            }
+            builder.finish().unwrap();
+            let storage = FileStorage::import_tar(&archive, 0, &GlobSet::empty());
+            fs::remove_file(&archive).unwrap();
+            storage
+        };
+        let storage = import(&["a/b/f", "a/g"]).unwrap();
+        assert!(storage.lookup(&path("a/b")).is_some_and(FileStorageEntry::is_dir));
+        assert!(storage.lookup(&path("a/b/f")).is_some_and(FileStorageEntry::is_file));
+        assert!(storage.lookup(&path("a/g")).is_some_and(FileStorageEntry::is_file));
+        assert_eq!(import(&["a/f", "a/f/g"]).err().map(|error| error.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
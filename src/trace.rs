@@ -0,0 +1,374 @@
+//! Recording of the FUSE operations a filesystem handles, and their replay against another filesystem.
+//!
+//! Every operation is appended to the trace file as one line: the operation name followed by `key=value` fields.
+//! Names and data are hex encoded, fields at their default value are left out. `errno` is the error the operation
+//! failed with and `value` what it returned: the inode of entry replies, the number of bytes for data, the handle
+//! of `open` and `opendir` and the block of `bmap`. Handles returned by `create` are recorded in `fh`, the block
+//! index of `bmap` in `offset`. `copy_file_range` records its destination in `newparent` and `newoffset`, but not
+//! the destination handle. Timestamps set through `setattr` aren't recorded.
+//!
+//! A replay maps the recorded inodes and handles to the ones the replaying filesystem returns, so it reaches the
+//! same tree even if inodes are assigned differently.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufRead, LineWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use fuser::FileAttr;
+use log::error;
+use crate::{DirectoryEntry, ICFS};
+use crate::content::ReadData;
+use crate::error::FsError;
+use crate::snapshot::invalid_data;
+use crate::space::FsStats;
+
+/// One operation of a trace.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceRecord{
+    pub op: String,
+    /// Inode the operation targets, the parent for operations on names.
+    pub ino: u64,
+    pub fh: u64,
+    pub name: Option<OsString>,
+    pub newparent: u64,
+    pub newname: Option<OsString>,
+    pub offset: i64,
+    pub newoffset: i64,
+    /// Requested size of reads and `copy_file_range`, new size of `setattr` and length of `fallocate`.
+    pub size: Option<u64>,
+    /// Mode of new entries and `setattr`, and the flags or mask of other operations.
+    pub mode: Option<u32>,
+    /// Open flags of `open` and `create`.
+    pub flags: i32,
+    /// Written data, xattr values and symbolic link targets.
+    pub data: Vec<u8>,
+    pub errno: i32,
+    pub value: u64,
+}
+impl TraceRecord{
+    pub fn new(op: &str, ino: u64) -> Self{
+        TraceRecord{ op: op.to_string(), ino, ..TraceRecord::default() }
+    }
+    fn parse(line: &str) -> io::Result<Self>{
+        let mut fields = line.split_whitespace();
+        let mut record = TraceRecord::new(fields.next().ok_or_else(|| invalid_data("empty trace record"))?, 0);
+        for field in fields{
+            let (key, value) = field.split_once('=').ok_or_else(|| invalid_data("trace field without a value"))?;
+            let number = || value.parse().map_err(|_| invalid_data("invalid number in trace record"));
+            match key{
+                "ino" => record.ino = number()?,
+                "fh" => record.fh = number()?,
+                "name" => record.name = Some(OsString::from_vec(decode_hex(value)?)),
+                "newparent" => record.newparent = number()?,
+                "newname" => record.newname = Some(OsString::from_vec(decode_hex(value)?)),
+                "offset" => record.offset = value.parse().map_err(|_| invalid_data("invalid offset in trace record"))?,
+                "newoffset" => record.newoffset = value.parse().map_err(|_| invalid_data("invalid offset in trace record"))?,
+                "size" => record.size = Some(number()?),
+                "mode" => record.mode = Some(value.parse().map_err(|_| invalid_data("invalid mode in trace record"))?),
+                "flags" => record.flags = value.parse().map_err(|_| invalid_data("invalid flags in trace record"))?,
+                "data" => record.data = decode_hex(value)?,
+                "errno" => record.errno = value.parse().map_err(|_| invalid_data("invalid errno in trace record"))?,
+                "value" => record.value = number()?,
+                _ => return Err(invalid_data("unknown trace field")),
+            }
+        }
+        Ok(record)
+    }
+    fn format(&self) -> String{
+        let mut line = format!("{} ino={}", self.op, self.ino);
+        //writing to a string can't fail
+        if self.fh != 0{
+            let _ = write!(line, " fh={}", self.fh);
+        }
+        if let Some(name) = &self.name{
+            let _ = write!(line, " name={}", encode_hex(name.as_bytes()));
+        }
+        if self.newparent != 0{
+            let _ = write!(line, " newparent={}", self.newparent);
+        }
+        if let Some(newname) = &self.newname{
+            let _ = write!(line, " newname={}", encode_hex(newname.as_bytes()));
+        }
+        if self.offset != 0{
+            let _ = write!(line, " offset={}", self.offset);
+        }
+        if self.newoffset != 0{
+            let _ = write!(line, " newoffset={}", self.newoffset);
+        }
+        if let Some(size) = self.size{
+            let _ = write!(line, " size={size}");
+        }
+        if let Some(mode) = self.mode{
+            let _ = write!(line, " mode={mode}");
+        }
+        if self.flags != 0{
+            let _ = write!(line, " flags={}", self.flags);
+        }
+        if !self.data.is_empty(){
+            let _ = write!(line, " data={}", encode_hex(&self.data));
+        }
+        if self.errno != 0{
+            let _ = write!(line, " errno={}", self.errno);
+        }
+        if self.value != 0{
+            let _ = write!(line, " value={}", self.value);
+        }
+        line
+    }
+}
+
+/// Appends the records of the operations to a trace file, a line at a time.
+pub struct Tracer{
+    writer: LineWriter<File>,
+}
+impl Tracer{
+    pub fn open(path: &Path) -> io::Result<Self>{
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Tracer{ writer: LineWriter::new(file) })
+    }
+    fn record(&mut self, record: &TraceRecord){
+        if let Err(error) = writeln!(self.writer, "{}", record.format()){
+            error!("failed to write trace record: {error}");
+        }
+    }
+}
+
+/// Result of an operation as it is recorded in the trace.
+pub(crate) trait Traced{
+    fn trace(&self, record: &mut TraceRecord);
+}
+impl Traced for (){
+    fn trace(&self, _record: &mut TraceRecord){}
+}
+impl Traced for u32{
+    fn trace(&self, record: &mut TraceRecord){
+        record.value = (*self).into();
+    }
+}
+/// Handles returned by `open` and `opendir`, and blocks returned by `bmap`.
+impl Traced for u64{
+    fn trace(&self, record: &mut TraceRecord){
+        record.value = *self;
+    }
+}
+impl Traced for FileAttr{
+    fn trace(&self, record: &mut TraceRecord){
+        record.value = self.ino;
+    }
+}
+/// Entry and handle returned by `create`.
+impl Traced for (FileAttr, u64){
+    fn trace(&self, record: &mut TraceRecord){
+        record.value = self.0.ino;
+        record.fh = self.1;
+    }
+}
+impl Traced for FsStats{
+    fn trace(&self, _record: &mut TraceRecord){}
+}
+impl Traced for Vec<u8>{
+    fn trace(&self, record: &mut TraceRecord){
+        record.value = self.len() as u64;
+    }
+}
+impl Traced for ReadData{
+    fn trace(&self, record: &mut TraceRecord){
+        record.value = self.len() as u64;
+    }
+}
+impl Traced for Vec<(i64, DirectoryEntry)>{
+    fn trace(&self, record: &mut TraceRecord){
+        record.value = self.len() as u64;
+    }
+}
+impl Traced for Vec<(i64, (OsString, FileAttr))>{
+    fn trace(&self, record: &mut TraceRecord){
+        record.value = self.len() as u64;
+    }
+}
+
+/// Outcome of replaying a trace.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplaySummary{
+    pub operations: u64,
+    /// Operations whose errno differed from the recorded one.
+    pub mismatches: u64,
+}
+
+impl ICFS{
+    /// Appends the record built by `record` to the trace file, completed with `result`.
+    pub(crate) fn trace<T: Traced>(&mut self, result: &Result<T, FsError>, record: impl FnOnce() -> TraceRecord){
+        let Some(tracer) = &mut self.tracer else {
+            return;
+        };
+        let mut record = record();
+        match result{
+            Ok(value) => value.trace(&mut record),
+            Err(error) => record.errno = error.to_errno(),
+        }
+        tracer.record(&record);
+    }
+    /// Drives the filesystem through the operations recorded in `trace`. Permission checks of the recorded requests
+    /// aren't repeated and new entries belong to root, as the trace doesn't identify the users.
+    pub fn replay(&mut self, trace: impl BufRead) -> io::Result<ReplaySummary>{
+        let mut inodes = HashMap::from([(1, 1)]);
+        let mut handles = HashMap::new();
+        let mut summary = ReplaySummary::default();
+        for line in trace.lines(){
+            let line = line?;
+            if line.trim().is_empty(){
+                continue;
+            }
+            let record = TraceRecord::parse(&line)?;
+            let ino = inodes.get(&record.ino).copied().unwrap_or(record.ino);
+            let fh = handles.get(&record.fh).copied().unwrap_or(record.fh);
+            let name = record.name.as_deref().unwrap_or_default();
+            let newname = record.newname.as_deref().unwrap_or_default();
+            let mode = record.mode.unwrap_or(0);
+            let result = match record.op.as_str(){
+                "lookup" => remember(&mut inodes, record.value, self.do_lookup(ino, name)),
+                "forget" => {
+                    self.remove_inode(ino);
+                    inodes.remove(&record.ino);
+                    Ok(())
+                }
+                "getattr" => self.do_getattr(ino).map(drop),
+                "access" => self.do_access(ino, 0, 0, mode as i32),
+                "setattr" => self.do_setattr(ino, record.mode, None, None, record.size, None, None, None).map(drop),
+                "mkdir" => remember(&mut inodes, record.value, self.do_mkdir(ino, name, mode, 0, 0)),
+                "symlink" => remember(&mut inodes, record.value, self.do_symlink(ino, name, Path::new(OsStr::from_bytes(&record.data)), 0, 0)),
+                "readlink" => self.do_readlink(ino).map(drop),
+                "unlink" => self.do_unlink(ino, name),
+                "rmdir" => self.do_rmdir(ino, name),
+                "read" => self.do_read(ino, record.offset, record.size.unwrap_or(0) as u32).map(drop),
+                "open" | "opendir" => {
+                    let result = if record.op == "open" { self.do_open(ino, record.flags) } else { self.do_opendir(ino) };
+                    result.map(|opened| {
+                        handles.insert(record.value, opened);
+                    })
+                }
+                "flush" => self.do_flush(fh),
+                "release" | "releasedir" => {
+                    let released = self.do_release(ino, fh);
+                    handles.remove(&record.fh);
+                    released
+                }
+                "write" => self.do_write(ino, fh, record.offset, &record.data).map(drop),
+                "fsync" => self.flush_writes(ino),
+                "fallocate" => self.do_fallocate(ino, record.offset, record.size.unwrap_or(0) as i64, mode as i32),
+                "copy_file_range" => {
+                    let newparent = inodes.get(&record.newparent).copied().unwrap_or(record.newparent);
+                    self.do_copy_file_range(ino, record.offset, newparent, 0, record.newoffset, record.size.unwrap_or(0), mode).map(drop)
+                }
+                "bmap" => self.do_bmap(ino, record.size.unwrap_or(0) as u32, record.offset as u64).map(drop),
+                "rename" => {
+                    let newparent = inodes.get(&record.newparent).copied().unwrap_or(record.newparent);
+                    self.do_rename(ino, name, newparent, newname, mode)
+                }
+                "link" => {
+                    let newparent = inodes.get(&record.newparent).copied().unwrap_or(record.newparent);
+                    remember(&mut inodes, record.value, self.do_link(ino, newparent, newname))
+                }
+                "readdir" => self.do_readdir_from(ino, fh, record.offset).map(drop),
+                "readdirplus" => self.do_readdirplus(ino).and_then(|entries| self.resume_listing(fh, record.offset, entries, |entry| &entry.0)).map(drop),
+                "create" => self.do_create(ino, name, mode, record.flags, 0, 0).map(|(attr, created)| {
+                    inodes.insert(record.value, attr.ino);
+                    handles.insert(record.fh, created);
+                }),
+                "statfs" => self.do_statfs().map(drop),
+                "setxattr" => self.do_setxattr(ino, name, &record.data, mode as i32),
+                "getxattr" => self.do_getxattr(ino, name).map(drop),
+                "listxattr" => self.do_listxattr(ino).map(drop),
+                "removexattr" => self.do_removexattr(ino, name),
+                _ => return Err(invalid_data("unknown operation in trace")),
+            };
+            summary.operations += 1;
+            if result.err().map_or(0, FsError::to_errno) != record.errno{
+                summary.mismatches += 1;
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// Maps the recorded inode of an entry reply to the inode the replay got.
+fn remember(inodes: &mut HashMap<u64, u64>, recorded: u64, result: Result<FileAttr, FsError>) -> Result<(), FsError>{
+    result.map(|attr| {
+        inodes.insert(recorded, attr.ino);
+    })
+}
+fn encode_hex(bytes: &[u8]) -> String{
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+fn decode_hex(text: &str) -> io::Result<Vec<u8>>{
+    if !text.len().is_multiple_of(2){
+        return Err(invalid_data("odd length of hex data in trace record"));
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()).ok_or_else(|| invalid_data("invalid hex data in trace record")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    use crate::{Config, FileType};
+    use crate::storage::{FileStorage, Overlay};
+
+    #[test]
+    fn records_parse_back_from_their_lines(){
+        let record = TraceRecord{
+            fh: 3,
+            name: Some(OsString::from_vec(b"na me\xff".to_vec())),
+            newparent: 7,
+            newname: Some("new".into()),
+            offset: -1,
+            size: Some(4096),
+            mode: Some(0o644),
+            flags: libc::O_EXCL,
+            data: b"data".to_vec(),
+            errno: libc::ENOENT,
+            value: 12,
+            ..TraceRecord::new("rename", 1)
+        };
+        assert_eq!(TraceRecord::parse(&record.format()).unwrap(), record);
+        assert_eq!(TraceRecord::new("getattr", 1).format(), "getattr ino=1");
+        assert!(TraceRecord::parse("read ino=1 bogus=2").is_err());
+        assert!(TraceRecord::parse("write ino=1 data=abc").is_err());
+    }
+    #[test]
+    fn replays_reach_the_recorded_tree(){
+        let path = std::env::temp_dir().join(format!("icfs-trace-{}", std::process::id()));
+        let mut traced = ICFS::new(Overlay::new(FileStorage::new(), FileStorage::new()), Config{ trace_file: Some(path.clone()), ..Config::default() });
+        //the handlers record their operations like this
+        let created = traced.do_create(1, OsStr::new("file"), 0o644, 0, 0, 0);
+        traced.trace(&created, || TraceRecord{ name: Some("file".into()), mode: Some(0o644), ..TraceRecord::new("create", 1) });
+        let (attr, fh) = created.unwrap();
+        let written = traced.do_write(attr.ino, fh, 0, b"traced");
+        traced.trace(&written, || TraceRecord{ fh, data: b"traced".to_vec(), ..TraceRecord::new("write", attr.ino) });
+        let released = traced.do_release(attr.ino, fh);
+        traced.trace(&released, || TraceRecord{ fh, ..TraceRecord::new("release", attr.ino) });
+        let missing = traced.do_lookup(1, OsStr::new("missing"));
+        traced.trace(&missing, || TraceRecord{ name: Some("missing".into()), ..TraceRecord::new("lookup", 1) });
+        drop(traced);
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(trace.lines().count(), 4);
+        //a replaying filesystem with inodes taken already assigns different ones
+        let mut replaying = ICFS::new(Overlay::new(FileStorage::new(), FileStorage::new()), Config::default());
+        replaying.do_mkdir(1, OsStr::new("taken"), 0o755, 0, 0).unwrap();
+        let mkdir = "mkdir ino=1 name=6464 mode=493 value=9\n";
+        let summary = replaying.replay(format!("{trace}{mkdir}rename ino=1 name=66696c65 newparent=9 newname=6d6f766564 errno=2\n").as_bytes()).unwrap();
+        //the rename failed when it was recorded, but goes through now
+        assert_eq!(summary, ReplaySummary{ operations: 6, mismatches: 1 });
+        let directory = replaying.do_lookup(1, OsStr::new("dd")).unwrap();
+        let file = replaying.do_lookup(directory.ino, OsStr::new("moved")).unwrap();
+        assert_eq!(file.kind, FileType::RegularFile);
+        assert_eq!(&*replaying.do_read(file.ino, 0, 100).unwrap(), b"traced");
+        assert!(replaying.replay("unknown ino=1".as_bytes()).is_err());
+    }
+}
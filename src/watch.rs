@@ -0,0 +1,100 @@
+//! Change events of the filesystem, streamed to the clients of the serve endpoint that subscribed to them.
+//!
+//! Every event is one line of JSON naming the kind of change and the affected path, such as
+//! `{"event":"create","path":"/a/b"}`. Renames name the new path in `new_path` as well. Files modified through
+//! several writes report every write.
+
+use std::fmt::Write as _;
+use std::sync::mpsc::{self, Receiver, Sender};
+use crate::storage::FileStoragePath;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind{
+    Create,
+    Modify,
+    Delete,
+    Rename,
+}
+impl ChangeKind{
+    fn name(self) -> &'static str{
+        match self{
+            ChangeKind::Create => "create",
+            ChangeKind::Modify => "modify",
+            ChangeKind::Delete => "delete",
+            ChangeKind::Rename => "rename",
+        }
+    }
+}
+
+/// Subscribers to the change events, each receiving its own copy of every event.
+#[derive(Default)]
+pub struct Watchers{
+    subscribers: Vec<Sender<String>>,
+}
+impl Watchers{
+    /// Returns the receiving end of the events published from now on.
+    pub fn subscribe(&mut self) -> Receiver<String>{
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+    /// Sends the event to every subscriber, dropping the ones that went away.
+    pub fn publish(&mut self, kind: ChangeKind, path: &FileStoragePath, new_path: Option<&FileStoragePath>){
+        if self.subscribers.is_empty(){
+            return;
+        }
+        let mut event = format!("{{\"event\":\"{}\",\"path\":{}", kind.name(), json_string(&path.to_string()));
+        if let Some(new_path) = new_path{
+            //writing to a string can't fail
+            let _ = write!(event, ",\"new_path\":{}", json_string(&new_path.to_string()));
+        }
+        event.push('}');
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+fn json_string(text: &str) -> String{
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for char in text.chars(){
+        match char{
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            char if char.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", char as u32);
+            }
+            char => quoted.push(char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn events_are_json_lines_sent_to_every_subscriber(){
+        let mut watchers = Watchers::default();
+        let path = FileStoragePath::from_components(["d", "quote\" tab\t"]);
+        //nobody listens yet, so nothing is kept
+        watchers.publish(ChangeKind::Create, &path, None);
+        let (first, second) = (watchers.subscribe(), watchers.subscribe());
+        watchers.publish(ChangeKind::Create, &path, None);
+        watchers.publish(ChangeKind::Rename, &path, Some(&FileStoragePath::from_components(["e\u{1}"])));
+        for receiver in [&first, &second]{
+            assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [
+                r#"{"event":"create","path":"/d/quote\" tab\t"}"#,
+                r#"{"event":"rename","path":"/d/quote\" tab\t","new_path":"/e\u0001"}"#,
+            ]);
+        }
+        drop(first);
+        watchers.publish(ChangeKind::Delete, &path, None);
+        assert_eq!(watchers.subscribers.len(), 1);
+        assert_eq!(second.try_recv().unwrap(), r#"{"event":"delete","path":"/d/quote\" tab\t"}"#);
+    }
+}